@@ -1,9 +1,85 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Result, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Inputs of the build identifier, besides the enabled features.
+const BUILD_INPUTS: &[&str] = &[
+    "Cargo.toml",
+    "Cargo.lock",
+    "build.rs",
+    "linker.lds",
+    "rust-toolchain",
+    "x86_64.json",
+    "src",
+    "crates",
+];
 
 fn main() -> Result<()> {
     gen_vector_asm()?;
+    gen_build_info()?;
+    Ok(())
+}
+
+/// Collect the files under `path`, skipping build outputs.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if path.file_name().map_or(false, |name| name != "target") {
+                collect_files(&path, files)?;
+            }
+        }
+    } else if path.is_file() {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Generate the build identifier, a hash of the sources, the toolchain, the
+/// target and the enabled features. Nothing depending on the build host or
+/// time is included, so that two builds from the same source have the same
+/// identifier and are expected to be bit-identical.
+fn gen_build_info() -> Result<()> {
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let mut f = File::create(out_path.join("build_info.rs"))?;
+
+    let mut files = Vec::new();
+    for input in BUILD_INPUTS {
+        println!("cargo:rerun-if-changed={}", input);
+        collect_files(Path::new(input), &mut files)?;
+    }
+    files.sort();
+
+    let mut features = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|name| name.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+    let features = features.join(",");
+    let profile = std::env::var("PROFILE").unwrap();
+    let target = std::env::var("TARGET").unwrap();
+
+    let mut hasher = Sha256::new();
+    for file in &files {
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(file)?);
+    }
+    for s in [&features, &profile, &target] {
+        hasher.update(s.as_bytes());
+        hasher.update([0]);
+    }
+    let build_id: [u8; 32] = hasher.finalize().into();
+
+    writeln!(f, "// generated by build.rs - do not edit")?;
+    writeln!(f, "pub const BUILD_ID: [u8; 32] = {:?};", build_id)?;
+    writeln!(f, "pub const FEATURES: &str = {:?};", features)?;
+    writeln!(f, "pub const PROFILE: &str = {:?};", profile)?;
+    writeln!(f, "pub const TARGET: &str = {:?};", target)?;
     Ok(())
 }
 
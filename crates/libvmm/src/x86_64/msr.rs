@@ -7,16 +7,39 @@ use x86::msr::{rdmsr, wrmsr};
 pub enum Msr {
     IA32_APIC_BASE = 0x1b,
 
+    MSR_SMI_COUNT = 0x34,
+
     IA32_FEATURE_CONTROL = 0x3a,
 
+    IA32_SPEC_CTRL = 0x48,
+
+    MSR_PLATFORM_INFO = 0xce,
+    MSR_PKG_CST_CONFIG_CONTROL = 0xe2,
+
     IA32_SYSENTER_CS = 0x174,
     IA32_SYSENTER_ESP = 0x175,
     IA32_SYSENTER_EIP = 0x176,
 
+    IA32_MCG_CAP = 0x179,
+    IA32_MCG_STATUS = 0x17a,
+
+    IA32_PERF_CTL = 0x199,
+    IA32_THERM_INTERRUPT = 0x19b,
+    IA32_MISC_ENABLE = 0x1a0,
+    MSR_TURBO_RATIO_LIMIT = 0x1ad,
+    IA32_ENERGY_PERF_BIAS = 0x1b0,
+    IA32_PACKAGE_THERM_STATUS = 0x1b1,
+    IA32_DEBUGCTL = 0x1d9,
+
     IA32_PAT = 0x277,
     IA32_MTRR_DEF_TYPE = 0x2ff,
     IA32_PERF_GLOBAL_CTRL = 0x38f,
 
+    IA32_MC0_CTL = 0x400,
+    IA32_MC0_STATUS = 0x401,
+    IA32_MC0_ADDR = 0x402,
+    IA32_MC0_MISC = 0x403,
+
     IA32_VMX_BASIC = 0x480,
     IA32_VMX_PINBASED_CTLS = 0x481,
     IA32_VMX_PROCBASED_CTLS = 0x482,
@@ -34,6 +57,16 @@ pub enum Msr {
     IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
     IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
 
+    IA32_TSC_DEADLINE = 0x6e0,
+
+    IA32_PM_ENABLE = 0x770,
+    IA32_HWP_REQUEST_PKG = 0x772,
+    IA32_HWP_INTERRUPT = 0x773,
+    IA32_HWP_REQUEST = 0x774,
+
+    IA32_TME_CAPABILITY = 0x981,
+    IA32_TME_ACTIVATE = 0x982,
+
     IA32_EFER = 0xc000_0080,
     IA32_STAR = 0xc000_0081,
     IA32_LSTAR = 0xc000_0082,
@@ -44,6 +77,12 @@ pub enum Msr {
     IA32_KERNEL_GSBASE = 0xc000_0102,
     IA32_TSC_AUX = 0xc000_0103,
 
+    SYSCFG = 0xc001_0010,
+
+    /// Override of the features reported by CPUID leaf 0x8000_0001 (EDX in
+    /// the low half, ECX in the high half), on AMD family 10h and later.
+    CPUID_EXT_FEATURES = 0xc001_1005,
+
     // SVM Related MSRs:
     VM_CR = 0xc001_0114,
     IGNNE = 0xc001_0115,
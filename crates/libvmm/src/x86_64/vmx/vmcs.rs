@@ -360,3 +360,37 @@ impl EptViolationInfo {
         })
     }
 }
+
+#[derive(Debug)]
+pub struct IoExitInfo {
+    pub port: u16,
+    /// Access size in bytes: 1, 2 or 4.
+    pub size: u8,
+    pub is_in: bool,
+    pub is_string: bool,
+    pub is_rep: bool,
+    /// Address size in bytes of INS/OUTS (2, 4 or 8), available only if
+    /// `VmxBasic::io_exit_info` is set.
+    pub addr_size: Option<u8>,
+}
+
+impl IoExitInfo {
+    pub fn new(io_exit_info: bool) -> VmResult<Self> {
+        let qualification = VmcsField64ReadOnly::EXIT_QUALIFICATION.read()?;
+        let is_string = qualification.get_bit(4);
+        let addr_size = if is_string && io_exit_info {
+            let instr_info = VmcsField32ReadOnly::VMX_INSTRUCTION_INFO.read()?;
+            Some(2u8 << instr_info.get_bits(7..10))
+        } else {
+            None
+        };
+        Ok(Self {
+            port: qualification.get_bits(16..32) as u16,
+            size: qualification.get_bits(0..3) as u8 + 1,
+            is_in: qualification.get_bit(3),
+            is_string,
+            is_rep: qualification.get_bit(5),
+            addr_size,
+        })
+    }
+}
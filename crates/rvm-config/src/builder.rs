@@ -0,0 +1,643 @@
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+
+use crate::layout::*;
+use crate::{CpuSet, MemFlags};
+
+const PAGE_SIZE: u64 = 0x1000;
+/// Signature of cell descriptors, as written by the Jailhouse driver.
+const CELL_SIGNATURE: [u8; 6] = *b"JHCELL";
+
+/// Why a configuration can not be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    CpuOutOfRange(u32),
+    NoRootCpu,
+    CpusOverlapped,
+    NameTooLong,
+    /// A region is not page aligned, with its start address.
+    Unaligned(u64),
+    /// An I/O region collides with RAM, the hypervisor or the RTOS memory.
+    IoCollision(u64),
+    WriteCombineNotIo(u64),
+    TooManyPciDevices,
+    HypercallLimitWithoutBurst,
+    /// Hypervisor CPUID leaves hidden with the hypervisor-present bit set.
+    CpuidLeavesWithPresentBit,
+    InvalidLogLevel(u32),
+    TooManyExitPolicies,
+    /// An MMIO UART console without address.
+    NoConsoleAddress,
+    /// A console baud rate out of the range of the UART clock.
+    InvalidBaudRate(u32),
+    /// Console data or stop bits out of range.
+    InvalidConsoleLine,
+    InvalidPhysAddrBits(u8),
+    /// A vector pool with exceptions, past vector 255 or too large, with its
+    /// first vector.
+    InvalidVectorPool(u8),
+    /// A region beyond the physical address width of the cell, with its
+    /// guest start address.
+    BeyondPhysAddrWidth(u64),
+    /// A region past the end of the address space, with its start address.
+    RegionOverflow(u64),
+    /// RTOS memory without room for its boot information page, with its size.
+    RtosMemoryTooSmall(u64),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Self::CpuOutOfRange(id) => write!(f, "CPU {} out of range", id),
+            Self::NoRootCpu => write!(f, "no CPU assigned to the root cell"),
+            Self::CpusOverlapped => write!(f, "root cell and RTOS CPUs overlapped"),
+            Self::NameTooLong => write!(f, "cell name is too long"),
+            Self::Unaligned(start) => write!(f, "region {:#x} is not page aligned", start),
+            Self::IoCollision(start) => write!(f, "I/O region {:#x} collides with RAM", start),
+            Self::WriteCombineNotIo(start) => {
+                write!(f, "write-combining region {:#x} is not I/O", start)
+            }
+            Self::TooManyPciDevices => write!(f, "too many RTOS PCI devices"),
+            Self::HypercallLimitWithoutBurst => write!(f, "hypercall limit without burst"),
+            Self::CpuidLeavesWithPresentBit => {
+                write!(f, "hypervisor CPUID leaves hidden but present bit reported")
+            }
+            Self::InvalidLogLevel(level) => write!(f, "invalid log level {}", level),
+            Self::TooManyExitPolicies => write!(f, "too many exit policies"),
+            Self::NoConsoleAddress => write!(f, "MMIO UART console without address"),
+            Self::InvalidBaudRate(rate) => write!(f, "console baud rate {} not reachable", rate),
+            Self::InvalidConsoleLine => write!(f, "invalid console data or stop bits"),
+            Self::InvalidPhysAddrBits(bits) => {
+                write!(f, "invalid physical address width {}", bits)
+            }
+            Self::InvalidVectorPool(start) => write!(f, "invalid vector pool {:#x}", start),
+            Self::BeyondPhysAddrWidth(start) => {
+                write!(f, "region {:#x} beyond the physical address width", start)
+            }
+            Self::RegionOverflow(start) => {
+                write!(f, "region {:#x} overflows the address space", start)
+            }
+            Self::RtosMemoryTooSmall(size) => {
+                write!(f, "RTOS memory of {:#x} bytes is too small", size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn region(phys_start: u64, virt_start: u64, size: u64, flags: MemFlags) -> HvMemoryRegion {
+    HvMemoryRegion {
+        phys_start,
+        virt_start,
+        size,
+        flags,
+    }
+}
+
+/// Returns the end of the guest range of `r`, or an error if it overflows.
+fn region_end(r: &HvMemoryRegion) -> Result<u64, ConfigError> {
+    let (start, size) = (r.virt_start, r.size);
+    start
+        .checked_add(size)
+        .ok_or(ConfigError::RegionOverflow(start))
+}
+
+fn overlapped(a: &HvMemoryRegion, b: &HvMemoryRegion) -> Result<bool, ConfigError> {
+    let (a_start, b_start) = (a.virt_start, b.virt_start);
+    let (a_end, b_end) = (region_end(a)?, region_end(b)?);
+    Ok(a_start < b_end && b_start < a_end)
+}
+
+/// Builder of the root cell descriptor and its memory regions.
+#[derive(Debug)]
+pub struct CellBuilder {
+    name: String,
+    cpus: Vec<u32>,
+    mem_regions: Vec<HvMemoryRegion>,
+    hypercall_mask: u64,
+    cpuid_policy: CpuidPolicyFlags,
+    phys_addr_bits: u8,
+    vector_pool: (u8, u8),
+}
+
+impl Default for CellBuilder {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            cpus: Vec::new(),
+            mem_regions: Vec::new(),
+            hypercall_mask: 0,
+            cpuid_policy: CpuidPolicyFlags::empty(),
+            phys_addr_bits: 0,
+            vector_pool: (0, 0),
+        }
+    }
+}
+
+impl CellBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Assigns the CPUs with hardware IDs `ids` to the cell.
+    pub fn cpus(mut self, ids: &[u32]) -> Self {
+        self.cpus.extend_from_slice(ids);
+        self
+    }
+
+    /// Only allows the hypercalls numbered `nrs` (all by default).
+    pub fn allow_hypercalls(mut self, nrs: &[u32]) -> Self {
+        for &nr in nrs.iter().filter(|&&nr| nr < 64) {
+            self.hypercall_mask |= 1 << nr;
+        }
+        self
+    }
+
+    /// Hides the hypervisor from the CPUID of the cell, see `CpuidPolicyFlags`.
+    pub fn cpuid_policy(mut self, flags: CpuidPolicyFlags) -> Self {
+        self.cpuid_policy = flags;
+        self
+    }
+
+    /// Reports a physical address width of `bits` to the cell instead of the
+    /// one of the CPU. All guest physical addresses must fit in it.
+    pub fn phys_addr_bits(mut self, bits: u8) -> Self {
+        self.phys_addr_bits = bits;
+        self
+    }
+
+    /// Lets the hypervisor allocate the `size` vectors from `start` for its
+    /// notifications to the cell, which must never use them otherwise.
+    pub fn vector_pool(mut self, start: u8, size: u8) -> Self {
+        self.vector_pool = (start, size);
+        self
+    }
+
+    /// Maps `[phys_start, phys_start + size)` at `virt_start` in the cell.
+    pub fn mem_region(
+        mut self,
+        phys_start: u64,
+        virt_start: u64,
+        size: u64,
+        flags: MemFlags,
+    ) -> Self {
+        self.mem_regions
+            .push(region(phys_start, virt_start, size, flags));
+        self
+    }
+
+    fn desc(&self) -> Result<HvCellDesc, ConfigError> {
+        let mut name = [0; HV_CELL_NAME_MAXLEN + 1];
+        if self.name.len() > HV_CELL_NAME_MAXLEN {
+            return Err(ConfigError::NameTooLong);
+        }
+        name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        Ok(HvCellDesc {
+            signature: CELL_SIGNATURE,
+            revision: CONFIG_REVISION,
+            name,
+            id: 0,
+            cpu_set: CpuSet::from_ids(&self.cpus)?,
+            num_memory_regions: self.mem_regions.len() as u32,
+            hypercall_mask: self.hypercall_mask,
+            cpuid_policy: self.cpuid_policy,
+            phys_addr_bits: self.phys_addr_bits,
+            vector_pool_start: self.vector_pool.0,
+            vector_pool_size: self.vector_pool.1,
+            _reserved: 0,
+        })
+    }
+}
+
+/// Builder of the system configuration blob.
+#[derive(Debug)]
+pub struct SystemConfigBuilder {
+    hypervisor_memory: HvMemoryRegion,
+    rtos_memory: HvMemoryRegion,
+    acpi_rsdp: u64,
+    rtos_cpu_policy: (RtCpuPolicyFlags, u32, u32),
+    rtos_cpus: Vec<u32>,
+    intercept_profile: InterceptProfile,
+    stats_window_gpa: u64,
+    pci_devices: Vec<(u16, [u64; 6])>,
+    hypercall_limit: (u32, u32),
+    log_level: u32,
+    watchdog_timeout_ms: u32,
+    exit_policies: Vec<(u32, ExitPolicy)>,
+    console: (ConsoleType, u64),
+    console_line: (u32, ConsoleParity, u8, u8),
+    uart_clock_hz: u32,
+    flags: HvSystemFlags,
+    root_cell: CellBuilder,
+}
+
+impl SystemConfigBuilder {
+    /// Starts a configuration with the hypervisor and RTOS physical memory.
+    pub fn new(hv_start: u64, hv_size: u64, rtos_start: u64, rtos_size: u64) -> Self {
+        Self {
+            hypervisor_memory: region(hv_start, hv_start, hv_size, MemFlags::empty()),
+            rtos_memory: region(rtos_start, rtos_start, rtos_size, MemFlags::empty()),
+            acpi_rsdp: 0,
+            rtos_cpu_policy: (RtCpuPolicyFlags::empty(), 0, 0),
+            rtos_cpus: Vec::new(),
+            intercept_profile: InterceptProfile::Default,
+            stats_window_gpa: 0,
+            pci_devices: Vec::new(),
+            hypercall_limit: (0, 0),
+            log_level: 0,
+            watchdog_timeout_ms: 0,
+            exit_policies: Vec::new(),
+            console: (ConsoleType::LegacyUart, 0),
+            console_line: (0, ConsoleParity::None, 0, 0),
+            uart_clock_hz: 0,
+            flags: HvSystemFlags::empty(),
+            root_cell: CellBuilder::default(),
+        }
+    }
+
+    pub fn acpi_rsdp(mut self, paddr: u64) -> Self {
+        self.acpi_rsdp = paddr;
+        self
+    }
+
+    pub fn rtos_cpus(mut self, ids: &[u32]) -> Self {
+        self.rtos_cpus.extend_from_slice(ids);
+        self
+    }
+
+    pub fn rtos_cpu_policy(
+        mut self,
+        flags: RtCpuPolicyFlags,
+        perf_ratio: u32,
+        cstate_limit: u32,
+    ) -> Self {
+        self.rtos_cpu_policy = (flags, perf_ratio, cstate_limit);
+        self
+    }
+
+    pub fn intercept_profile(mut self, profile: InterceptProfile) -> Self {
+        self.intercept_profile = profile;
+        self
+    }
+
+    pub fn stats_window_gpa(mut self, gpa: u64) -> Self {
+        self.stats_window_gpa = gpa;
+        self
+    }
+
+    /// Assigns the PCI device `bdf` to the RTOS, with the sizes of its memory
+    /// BARs (0 for unused ones).
+    pub fn rtos_pci_device(mut self, bdf: u16, bar_sizes: [u64; 6]) -> Self {
+        self.pci_devices.push((bdf, bar_sizes));
+        self
+    }
+
+    /// Limits the root cell to `rate` hypercalls per second on average and
+    /// `burst` at once.
+    pub fn hypercall_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.hypercall_limit = (rate, burst);
+        self
+    }
+
+    /// Caps the log level of the hypervisor (1 = error, ..., 5 = trace).
+    pub fn log_level(mut self, level: u32) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    pub fn watchdog_timeout_ms(mut self, ms: u32) -> Self {
+        self.watchdog_timeout_ms = ms;
+        self
+    }
+
+    /// Handles the VM exit reason `exit_reason` without handler with `policy`
+    /// instead of injecting #GP.
+    pub fn exit_policy(mut self, exit_reason: u32, policy: ExitPolicy) -> Self {
+        self.exit_policies.push((exit_reason, policy));
+        self
+    }
+
+    /// Selects the console backend, with the I/O port or the physical address
+    /// of the UART.
+    pub fn console(mut self, console_type: ConsoleType, address: u64) -> Self {
+        self.console = (console_type, address);
+        self
+    }
+
+    /// Sets the baud rate, parity, data bits and stop bits of the UART
+    /// console, 0 for the default of each (115200, 8, 1).
+    pub fn console_line(
+        mut self,
+        baud_rate: u32,
+        parity: ConsoleParity,
+        data_bits: u8,
+        stop_bits: u8,
+    ) -> Self {
+        self.console_line = (baud_rate, parity, data_bits, stop_bits);
+        self
+    }
+
+    /// Sets the input clock of the UART console, for boards whose UART does
+    /// not run at the PC clock of 1.8432 MHz.
+    pub fn uart_clock_hz(mut self, hz: u32) -> Self {
+        self.uart_clock_hz = hz;
+        self
+    }
+
+    /// Freezes the configuration once the root cell runs, see
+    /// `HvSystemFlags::LOCKDOWN`.
+    pub fn lockdown(mut self) -> Self {
+        self.flags |= HvSystemFlags::LOCKDOWN;
+        self
+    }
+
+    /// Allows read-only hypercalls from root cell user space, see
+    /// `HvSystemFlags::USER_HYPERCALLS`.
+    pub fn user_hypercalls(mut self) -> Self {
+        self.flags |= HvSystemFlags::USER_HYPERCALLS;
+        self
+    }
+
+    /// Refuses RTOS CPUs sharing a core with root cell CPUs, see
+    /// `HvSystemFlags::STRICT_SMT`.
+    pub fn strict_smt(mut self) -> Self {
+        self.flags |= HvSystemFlags::STRICT_SMT;
+        self
+    }
+
+    /// Isolates the device interrupts of the cells, see
+    /// `HvSystemFlags::IRQ_REMAPPING`.
+    pub fn irq_remapping(mut self) -> Self {
+        self.flags |= HvSystemFlags::IRQ_REMAPPING;
+        self
+    }
+
+    pub fn root_cell(mut self, cell: CellBuilder) -> Self {
+        self.root_cell = cell;
+        self
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let regions = &self.root_cell.mem_regions;
+        for r in regions
+            .iter()
+            .chain([&self.hypervisor_memory, &self.rtos_memory])
+        {
+            let (phys, virt, size) = (r.phys_start, r.virt_start, r.size);
+            if (phys | virt | size) % PAGE_SIZE != 0 {
+                return Err(ConfigError::Unaligned(virt));
+            }
+        }
+        // The last page of the RTOS memory holds its boot information.
+        if self.rtos_memory.size <= PAGE_SIZE {
+            return Err(ConfigError::RtosMemoryTooSmall(self.rtos_memory.size));
+        }
+        for r in regions {
+            let flags = r.flags;
+            if flags.contains(MemFlags::WRITE_COMBINE) && !flags.contains(MemFlags::IO) {
+                return Err(ConfigError::WriteCombineNotIo(r.virt_start));
+            }
+            if !flags.contains(MemFlags::IO) {
+                continue;
+            }
+            let others = regions
+                .iter()
+                .filter(|o| {
+                    let flags = o.flags;
+                    !flags.contains(MemFlags::IO)
+                })
+                .chain([&self.hypervisor_memory, &self.rtos_memory]);
+            for o in others {
+                if overlapped(r, o)? {
+                    return Err(ConfigError::IoCollision(r.virt_start));
+                }
+            }
+        }
+        if self.pci_devices.len() > MAX_RTOS_PCI_DEVICES {
+            return Err(ConfigError::TooManyPciDevices);
+        }
+        if self.hypercall_limit.0 != 0 && self.hypercall_limit.1 == 0 {
+            return Err(ConfigError::HypercallLimitWithoutBurst);
+        }
+        if self.log_level > MAX_LOG_LEVEL {
+            return Err(ConfigError::InvalidLogLevel(self.log_level));
+        }
+        if self.exit_policies.len() > MAX_EXIT_POLICIES {
+            return Err(ConfigError::TooManyExitPolicies);
+        }
+        if self.console == (ConsoleType::MmioUart, 0) {
+            return Err(ConfigError::NoConsoleAddress);
+        }
+        let (baud_rate, _, data_bits, stop_bits) = self.console_line;
+        let or_default = |value, default| if value == 0 { default } else { value };
+        let divisor = or_default(self.uart_clock_hz, DEFAULT_UART_CLOCK_HZ)
+            / 16
+            / or_default(baud_rate, DEFAULT_UART_BAUD_RATE);
+        if !(1..=0xffff).contains(&divisor) {
+            return Err(ConfigError::InvalidBaudRate(baud_rate));
+        }
+        if !matches!(data_bits, 0 | 5..=8) || stop_bits > 2 {
+            return Err(ConfigError::InvalidConsoleLine);
+        }
+        let cpuid_policy = self.root_cell.cpuid_policy;
+        if cpuid_policy.contains(CpuidPolicyFlags::HIDE_LEAVES)
+            && !cpuid_policy.contains(CpuidPolicyFlags::HIDE_PRESENT_BIT)
+        {
+            return Err(ConfigError::CpuidLeavesWithPresentBit);
+        }
+        let bits = self.root_cell.phys_addr_bits;
+        if bits != 0 {
+            if !(MIN_PHYS_ADDR_BITS..=MAX_GUEST_PHYS_ADDR_BITS).contains(&bits) {
+                return Err(ConfigError::InvalidPhysAddrBits(bits));
+            }
+            for r in &self.root_cell.mem_regions {
+                if region_end(r)? > 1 << bits {
+                    return Err(ConfigError::BeyondPhysAddrWidth(r.virt_start));
+                }
+            }
+        }
+        let (start, size) = self.root_cell.vector_pool;
+        if size != 0
+            && (start < 32 || size > MAX_VECTOR_POOL_SIZE || start.checked_add(size - 1).is_none())
+        {
+            return Err(ConfigError::InvalidVectorPool(start));
+        }
+        let root_cpus = CpuSet::from_ids(&self.root_cell.cpus)?;
+        if root_cpus.count() == 0 {
+            return Err(ConfigError::NoRootCpu);
+        }
+        if root_cpus.overlaps(&CpuSet::from_ids(&self.rtos_cpus)?) {
+            return Err(ConfigError::CpusOverlapped);
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration and returns its binary form.
+    pub fn build(&self) -> Result<Vec<u8>, ConfigError> {
+        self.validate()?;
+        let mut rtos_pci_devices = [(); MAX_RTOS_PCI_DEVICES].map(|_| HvPciDevice {
+            bdf: 0,
+            _reserved: [0; 6],
+            bar_sizes: [0; 6],
+        });
+        for (dev, &(bdf, bar_sizes)) in rtos_pci_devices.iter_mut().zip(&self.pci_devices) {
+            dev.bdf = bdf;
+            dev.bar_sizes = bar_sizes;
+        }
+        let mut exit_policies = [HvExitPolicy {
+            exit_reason: 0,
+            policy: 0,
+        }; MAX_EXIT_POLICIES];
+        for (entry, &(exit_reason, policy)) in exit_policies.iter_mut().zip(&self.exit_policies) {
+            entry.exit_reason = exit_reason;
+            entry.policy = policy as u32;
+        }
+        let (flags, perf_ratio, cstate_limit) = self.rtos_cpu_policy;
+        let copy = |r: &HvMemoryRegion| region(r.phys_start, r.virt_start, r.size, r.flags);
+        let config = HvSystemConfig {
+            signature: CONFIG_SIGNATURE,
+            revision: CONFIG_REVISION,
+            hypervisor_memory: copy(&self.hypervisor_memory),
+            rtos_memory: copy(&self.rtos_memory),
+            acpi_rsdp: self.acpi_rsdp,
+            rtos_cpu_policy: HvRtCpuPolicy {
+                flags,
+                perf_ratio,
+                cstate_limit,
+            },
+            rtos_cpus: CpuSet::from_ids(&self.rtos_cpus)?,
+            intercept_profile: self.intercept_profile as u32,
+            stats_window_gpa: self.stats_window_gpa,
+            num_rtos_pci_devices: self.pci_devices.len() as u32,
+            rtos_pci_devices,
+            hypercall_limit: HvHypercallLimit {
+                rate: self.hypercall_limit.0,
+                burst: self.hypercall_limit.1,
+            },
+            log_level: self.log_level,
+            watchdog_timeout_ms: self.watchdog_timeout_ms,
+            num_exit_policies: self.exit_policies.len() as u32,
+            exit_policies,
+            console: HvConsole {
+                console_type: self.console.0 as u32,
+                baud_rate: self.console_line.0,
+                address: self.console.1,
+                clock_hz: self.uart_clock_hz,
+                parity: self.console_line.1 as u8,
+                data_bits: self.console_line.2,
+                stop_bits: self.console_line.3,
+                _reserved: 0,
+            },
+            flags: self.flags,
+            root_cell: self.root_cell.desc()?,
+        };
+
+        let mut blob = Vec::new();
+        blob.extend_from_slice(as_bytes(&config));
+        for r in &self.root_cell.mem_regions {
+            blob.extend_from_slice(as_bytes(r));
+        }
+        Ok(blob)
+    }
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root_cell() -> CellBuilder {
+        CellBuilder::new("root").cpus(&[0, 1]).mem_region(
+            0,
+            0,
+            0x8000_0000,
+            MemFlags::READ | MemFlags::WRITE,
+        )
+    }
+
+    fn system_config() -> SystemConfigBuilder {
+        SystemConfigBuilder::new(0x1_0000_0000, 0x400_0000, 0x1_0400_0000, 0x400_0000)
+    }
+
+    #[test]
+    fn test_build() {
+        let blob = system_config()
+            .rtos_cpus(&[2, 3])
+            .root_cell(root_cell())
+            .build()
+            .unwrap();
+        assert_eq!(
+            blob.len(),
+            size_of::<HvSystemConfig>() + size_of::<HvMemoryRegion>()
+        );
+        assert_eq!(&blob[..6], &CONFIG_SIGNATURE);
+        assert_eq!(blob[6..8], CONFIG_REVISION.to_le_bytes());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = system_config();
+        let cell = root_cell().mem_region(0x1000, 0x1000, 0x1000, MemFlags::IO);
+        assert_eq!(
+            config.root_cell(cell).build(),
+            Err(ConfigError::IoCollision(0x1000))
+        );
+        let config = system_config().rtos_cpus(&[1]).root_cell(root_cell());
+        assert_eq!(config.build(), Err(ConfigError::CpusOverlapped));
+        let config =
+            system_config().root_cell(root_cell().cpuid_policy(CpuidPolicyFlags::HIDE_LEAVES));
+        assert_eq!(config.build(), Err(ConfigError::CpuidLeavesWithPresentBit));
+        let config = system_config().root_cell(root_cell()).log_level(6);
+        assert_eq!(config.build(), Err(ConfigError::InvalidLogLevel(6)));
+        let config = (0..=MAX_EXIT_POLICIES as u32)
+            .fold(system_config().root_cell(root_cell()), |config, reason| {
+                config.exit_policy(reason, ExitPolicy::Skip)
+            });
+        assert_eq!(config.build(), Err(ConfigError::TooManyExitPolicies));
+        let config =
+            system_config()
+                .root_cell(root_cell())
+                .console_line(230400, ConsoleParity::None, 0, 0);
+        assert_eq!(config.build(), Err(ConfigError::InvalidBaudRate(230400)));
+        let config = system_config()
+            .root_cell(root_cell())
+            .console_line(230400, ConsoleParity::Even, 7, 2)
+            .uart_clock_hz(48_000_000);
+        assert!(config.build().is_ok());
+        let config = system_config().root_cell(root_cell().vector_pool(0xf0, 17));
+        assert_eq!(config.build(), Err(ConfigError::InvalidVectorPool(0xf0)));
+        let config = system_config().root_cell(root_cell().vector_pool(0x10, 4));
+        assert_eq!(config.build(), Err(ConfigError::InvalidVectorPool(0x10)));
+        let config = system_config().root_cell(root_cell().vector_pool(0xe0, 16));
+        assert!(config.build().is_ok());
+        let config = system_config().root_cell(root_cell().phys_addr_bits(31));
+        assert_eq!(config.build(), Err(ConfigError::InvalidPhysAddrBits(31)));
+        let cell = root_cell().phys_addr_bits(32).mem_region(
+            0x2_0000_0000,
+            0x1_0000_0000,
+            0x1000,
+            MemFlags::READ,
+        );
+        let config = system_config().root_cell(cell);
+        assert_eq!(
+            config.build(),
+            Err(ConfigError::BeyondPhysAddrWidth(0x1_0000_0000))
+        );
+        let config = SystemConfigBuilder::new(0x1_0000_0000, 0x400_0000, 0x1_0400_0000, 0x1000)
+            .root_cell(root_cell());
+        assert_eq!(config.build(), Err(ConfigError::RtosMemoryTooSmall(0x1000)));
+        let cell = root_cell().mem_region(0xffff_f000, 0xffff_ffff_ffff_f000, 0x2000, MemFlags::IO);
+        let config = system_config().root_cell(cell);
+        assert_eq!(
+            config.build(),
+            Err(ConfigError::RegionOverflow(0xffff_ffff_ffff_f000))
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! Binary layout of the system configuration.
+//!
+//! Shared by the hypervisor (`src/config.rs`) and the `rvm-config` crate: the
+//! parent module must provide `CpuSet` and `MemFlags` with the layout of the
+//! hypervisor ones. Bump `CONFIG_REVISION` on any change.
+
+use bitflags::bitflags;
+use numeric_enum_macro::numeric_enum;
+
+use super::{CpuSet, MemFlags};
+
+pub const CONFIG_SIGNATURE: [u8; 6] = *b"RVMSYS";
+pub const CONFIG_REVISION: u16 = 31;
+
+pub const HV_CELL_NAME_MAXLEN: usize = 31;
+
+/// Range of a non-zero `HvCellDesc::phys_addr_bits`. The upper bound is what
+/// a 4-level EPT/NPT walk translates.
+pub const MIN_PHYS_ADDR_BITS: u8 = 32;
+pub const MAX_GUEST_PHYS_ADDR_BITS: u8 = 48;
+
+/// Line parameters of a UART console for zero fields of `HvConsole`.
+pub const DEFAULT_UART_BAUD_RATE: u32 = 115200;
+pub const DEFAULT_UART_CLOCK_HZ: u32 = 1843200;
+
+/// Max number of vectors in the vector pool of a cell.
+pub const MAX_VECTOR_POOL_SIZE: u8 = 32;
+
+/// Max number of PCI devices assigned to the RTOS.
+pub const MAX_RTOS_PCI_DEVICES: usize = 4;
+
+/// The jailhouse cell configuration.
+///
+/// @note Keep Config._HEADER_FORMAT in jailhouse-cell-linux in sync with this
+/// structure.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvCellDesc {
+    pub(super) signature: [u8; 6],
+    pub(super) revision: u16,
+    pub(super) name: [u8; HV_CELL_NAME_MAXLEN + 1],
+    pub(super) id: u32, // set by the driver
+    /// Hardware IDs of the CPUs assigned to the cell.
+    pub(super) cpu_set: CpuSet,
+    pub(super) num_memory_regions: u32,
+    /// Hypercalls allowed to the cell: bit `n` allows the hypercall numbered
+    /// `n` in its privilege class. 0 allows all hypercalls.
+    pub(super) hypercall_mask: u64,
+    /// How the cell may discover the hypervisor with CPUID.
+    pub(super) cpuid_policy: CpuidPolicyFlags,
+    /// Physical address width reported to the cell (`CPUID.80000008H:EAX[7:0]`),
+    /// 0 for the one of the CPU. The guest physical addresses of the cell
+    /// must fit in it.
+    pub(super) phys_addr_bits: u8,
+    /// Vectors `[vector_pool_start, vector_pool_start + vector_pool_size)`
+    /// are allocated by the hypervisor for its notifications to the cell.
+    /// They must be ones the cell never uses otherwise, e.g. vectors its
+    /// kernel reserves. A size of 0 for no pool.
+    pub(super) vector_pool_start: u8,
+    pub(super) vector_pool_size: u8,
+    pub(super) _reserved: u8,
+}
+
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvMemoryRegion {
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub size: u64,
+    pub flags: MemFlags,
+}
+
+/// A PCI device (usually an SR-IOV virtual function) assigned to the RTOS.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvPciDevice {
+    /// Bus, device and function number.
+    pub bdf: u16,
+    pub(super) _reserved: [u8; 6],
+    /// Size of each memory BAR used by the RTOS, 0 for the others and for the
+    /// upper halves of 64-bit BARs. For a virtual function, the size of the
+    /// BAR of one VF, the VF BARs of the physical function being split evenly.
+    pub bar_sizes: [u64; 6],
+}
+
+bitflags! {
+    pub struct RtCpuPolicyFlags: u32 {
+        /// Pin RT CPUs to the fixed performance ratio `perf_ratio`.
+        const FIXED_PSTATE      = 1 << 0;
+        /// Disable turbo on RT CPUs.
+        const DISABLE_TURBO     = 1 << 1;
+        /// Mask thermal and HWP interrupts on RT CPUs.
+        const MASK_THERMAL_INT  = 1 << 2;
+        /// Limit the C-states of RT CPUs to `cstate_limit`.
+        const LIMIT_CSTATE      = 1 << 3;
+        /// Disable MONITOR/MWAIT on RT CPUs, so the RTOS can only idle in C1
+        /// with HLT.
+        const DISABLE_MWAIT     = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// Hypervisor presence reported to a cell by CPUID. By default, both the
+    /// hypervisor-present bit and the hypervisor leaves are reported.
+    pub struct CpuidPolicyFlags: u32 {
+        /// Clear the hypervisor-present bit (`CPUID.1:ECX[31]`).
+        const HIDE_PRESENT_BIT  = 1 << 0;
+        /// Return the hardware values of leaves `0x4000_0000..=0x4000_00ff`
+        /// instead of the hypervisor signature and features. Requires
+        /// `HIDE_PRESENT_BIT`, as software probes these leaves only once
+        /// the bit is set. On AMD CPUs, hiding both lets the cell execute
+        /// CPUID without VM exits.
+        const HIDE_LEAVES       = 1 << 1;
+    }
+}
+
+bitflags! {
+    pub struct HvSystemFlags: u32 {
+        /// Refuse the hypercalls changing the configuration or the memory
+        /// layout once the root cell runs, until the hypervisor is disabled.
+        const LOCKDOWN          = 1 << 0;
+        /// Allow the read-only hypercalls (`HypervisorGetInfo` queries
+        /// without side effects, `ConsoleWrite`) from CPL 3 of the root
+        /// cell, e.g. for monitoring daemons.
+        const USER_HYPERCALLS   = 1 << 1;
+        /// Refuse to enable if an RTOS CPU is an SMT sibling of a root cell
+        /// CPU, instead of only warning: the threads of a core share its
+        /// execution units and caches, which ruins the RTOS determinism.
+        /// Also refuses if the siblings cannot be determined, without an
+        /// ACPI MADT or CPUID leaf 0xB.
+        const STRICT_SMT        = 1 << 2;
+        /// Remap the interrupts of the devices with VT-d, so that they can
+        /// only target the CPUs of the cell owning the device. The RTOS must
+        /// then program the MSIs of its devices in remappable format.
+        const IRQ_REMAPPING     = 1 << 3;
+    }
+}
+
+/// Frequency/thermal/idle policy applied to RT CPUs before entering the RTOS.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvRtCpuPolicy {
+    pub flags: RtCpuPolicyFlags,
+    /// Performance ratio (in units of bus clock, usually 100MHz).
+    pub perf_ratio: u32,
+    /// Deepest C-state allowed, as the model specific package C-state limit
+    /// encoding of `MSR_PKG_CST_CONFIG_CONTROL` (0 means C0/C1).
+    pub cstate_limit: u32,
+}
+
+/// Most verbose value of `HvSystemConfig::log_level` (trace).
+pub const MAX_LOG_LEVEL: u32 = 5;
+
+/// Max number of entries of `HvSystemConfig::exit_policies`.
+pub const MAX_EXIT_POLICIES: usize = 8;
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum ExitPolicy {
+        /// Inject #GP into the guest, the default.
+        Fault = 0,
+        /// Skip the instruction which caused the VM exit, with a warning.
+        /// Exits not caused by an instruction are only logged.
+        Skip = 1,
+        /// Stop the hypervisor with a panic, dumping the guest state.
+        Panic = 2,
+    }
+}
+
+/// `ExitPolicy` of a VM exit reason the hypervisor does not handle.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvExitPolicy {
+    /// VMX basic exit reason, or SVM exit code.
+    pub exit_reason: u32,
+    pub policy: u32,
+}
+
+/// Rate limit of the hypercalls of a cell, see `hypercall::limit`.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvHypercallLimit {
+    /// Average number of hypercalls allowed per second, 0 for no limit.
+    pub rate: u32,
+    /// Number of hypercalls allowed in a burst.
+    pub burst: u32,
+}
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum InterceptProfile {
+        /// Intercept everything the hypervisor may want to observe.
+        Default = 0,
+        /// Keep only the intercepts required for correctness, to minimize VM
+        /// exits of latency sensitive root cells.
+        LowLatency = 1,
+    }
+}
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum ConsoleType {
+        /// 16550 UART at an I/O port.
+        LegacyUart = 0,
+        /// 16550 UART with 32-bit registers at a physical MMIO address.
+        MmioUart = 1,
+        /// Only the log ring of the stats window.
+        MemoryRing = 2,
+        /// No console output.
+        Null = 3,
+    }
+}
+
+numeric_enum! {
+    #[repr(u8)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum ConsoleParity {
+        None = 0,
+        Odd = 1,
+        Even = 2,
+    }
+}
+
+/// Backend of the hypervisor console. The line parameters apply to both UART
+/// types, 0 selects the default of each.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvConsole {
+    /// `ConsoleType` of the backend.
+    pub console_type: u32,
+    /// Baud rate of the UART, 0 for `DEFAULT_UART_BAUD_RATE`.
+    pub baud_rate: u32,
+    /// I/O port of a legacy UART (0 for 0x3f8), or physical address of an
+    /// MMIO UART.
+    pub address: u64,
+    /// Input clock of the UART in Hz, 0 for `DEFAULT_UART_CLOCK_HZ`.
+    pub clock_hz: u32,
+    /// `ConsoleParity` of the UART.
+    pub parity: u8,
+    /// Data bits (5 to 8), 0 for 8.
+    pub data_bits: u8,
+    /// Stop bits (1 or 2), 0 for 1.
+    pub stop_bits: u8,
+    pub _reserved: u8,
+}
+
+/// General descriptor of the system.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvSystemConfig {
+    pub signature: [u8; 6],
+    pub revision: u16,
+    /// RVM location in memory
+    pub hypervisor_memory: HvMemoryRegion,
+    /// RTOS location in memory
+    pub rtos_memory: HvMemoryRegion,
+    /// Physical address of the ACPI RSDP, 0 if not available.
+    pub acpi_rsdp: u64,
+    /// Frequency/thermal/idle policy of the RTOS CPUs.
+    pub rtos_cpu_policy: HvRtCpuPolicy,
+    /// Hardware IDs of the RTOS CPUs.
+    pub rtos_cpus: CpuSet,
+    /// `InterceptProfile` of the root cell.
+    pub intercept_profile: u32,
+    /// GPA of the read-only stats window in the root cell, 0 if disabled.
+    pub stats_window_gpa: u64,
+    pub num_rtos_pci_devices: u32,
+    /// PCI devices assigned to the RTOS, hidden from the root cell.
+    pub rtos_pci_devices: [HvPciDevice; MAX_RTOS_PCI_DEVICES],
+    /// Hypercall rate limit of the root cell. Reloadable.
+    pub hypercall_limit: HvHypercallLimit,
+    /// Max log level (1 = error, ..., 5 = trace), 0 for the level of the
+    /// build, which can not be exceeded. Reloadable.
+    pub log_level: u32,
+    /// Time in milliseconds a CPU may stay in its VM exit handler before the
+    /// watchdog reports it, 0 for the default. Reloadable.
+    pub watchdog_timeout_ms: u32,
+    pub num_exit_policies: u32,
+    /// Handling of the VM exit reasons without handler, `ExitPolicy::Fault`
+    /// for the others. Reloadable.
+    pub exit_policies: [HvExitPolicy; MAX_EXIT_POLICIES],
+    pub console: HvConsole,
+    pub flags: HvSystemFlags,
+    pub root_cell: HvCellDesc,
+    // CellConfigLayout placed here.
+}
@@ -0,0 +1,63 @@
+//! Host-side builder of RVM system configurations.
+//!
+//! The binary layout is shared with the hypervisor through the `layout`
+//! module, so that a revision change only happens in one place. Use
+//! [`SystemConfigBuilder`] to produce the blob loaded by the driver, which is
+//! validated the way the hypervisor does when enabling.
+
+#![allow(unaligned_references)]
+
+mod builder;
+mod layout;
+
+use bitflags::bitflags;
+
+pub use builder::{CellBuilder, ConfigError, SystemConfigBuilder};
+pub use layout::*;
+
+bitflags! {
+    /// Flags of a memory region, with the values of the hypervisor.
+    pub struct MemFlags: u64 {
+        const READ          = 1 << 0;
+        const WRITE         = 1 << 1;
+        const EXECUTE       = 1 << 2;
+        const DMA           = 1 << 3;
+        const IO            = 1 << 4;
+        const WRITE_COMBINE = 1 << 5;
+        const NO_HUGEPAGES  = 1 << 8;
+        const USER          = 1 << 9;
+    }
+}
+
+const CPU_SET_WORDS: usize = 4;
+
+/// A bitmap of hardware CPU IDs (local APIC IDs).
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CpuSet {
+    bits: [u64; CPU_SET_WORDS],
+}
+
+impl CpuSet {
+    /// Max number of CPUs in a set, hardware IDs must be less than it.
+    pub const CAPACITY: usize = CPU_SET_WORDS * 64;
+
+    pub fn from_ids(ids: &[u32]) -> Result<Self, ConfigError> {
+        let mut set = Self::default();
+        for &id in ids {
+            if id as usize >= Self::CAPACITY {
+                return Err(ConfigError::CpuOutOfRange(id));
+            }
+            set.bits[id as usize / 64] |= 1 << (id % 64);
+        }
+        Ok(set)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|w| w.count_ones()).sum()
+    }
+
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.bits.iter().zip(&other.bits).any(|(a, b)| a & b != 0)
+    }
+}
@@ -0,0 +1,50 @@
+//! Interface of the Jailhouse driver loading the hypervisor.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+const DEVICE_PATH: &str = "/dev/jailhouse";
+const ENABLED_PATH: &str = "/sys/devices/jailhouse/enabled";
+
+/// `_IOW(0, 0, void *)`: enables the hypervisor with the given configuration.
+const JAILHOUSE_ENABLE: libc::c_ulong = 0x4008_0000;
+/// `_IO(0, 1)`: disables the hypervisor.
+const JAILHOUSE_DISABLE: libc::c_ulong = 0x0001;
+
+pub struct Driver {
+    dev: File,
+}
+
+impl Driver {
+    pub fn open() -> io::Result<Self> {
+        let dev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(DEVICE_PATH)?;
+        Ok(Self { dev })
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, arg: usize) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.dev.as_raw_fd(), request, arg) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Loads the hypervisor image from the firmware directory and enables it
+    /// on all CPUs with the binary system configuration `config`.
+    pub fn enable(&self, config: &[u8]) -> io::Result<()> {
+        self.ioctl(JAILHOUSE_ENABLE, config.as_ptr() as usize)
+    }
+
+    pub fn disable(&self) -> io::Result<()> {
+        self.ioctl(JAILHOUSE_DISABLE, 0)
+    }
+}
+
+/// Whether the hypervisor is enabled, as reported by the driver.
+pub fn enabled() -> io::Result<bool> {
+    Ok(fs::read_to_string(ENABLED_PATH)?.trim() == "1")
+}
@@ -0,0 +1,268 @@
+//! Command line tool operating the RVM hypervisor from Linux userspace.
+//!
+//! Enabling and disabling go through the ioctls of the Jailhouse driver.
+//! Statistics, logs and the VM exit trace (exported in the Chrome trace
+//! format) are read from the stats window, mapped read-only through
+//! `/dev/mem` at the GPA of the system configuration, without any VM exit.
+//! Hypercalls (e.g. starting the RTOS) can only be issued at CPL 0, so they
+//! are left to kernel components using `rvm-rt-guest`.
+
+mod driver;
+mod trace;
+mod window;
+
+use std::io::Write;
+use std::mem::size_of;
+use std::process::exit;
+use std::time::Duration;
+use std::{env, fs, io, thread};
+
+use rvm_config::{ExitPolicy, HvSystemConfig, HvSystemFlags, MAX_EXIT_POLICIES};
+use rvm_config::{CONFIG_REVISION, CONFIG_SIGNATURE};
+
+use driver::Driver;
+use window::{StatsWindow, ERROR_SUBSYSTEMS, INIT_PHASES};
+use window::{MEM_MAP_OWNERS, MEM_MAP_READ, MEM_MAP_WRITE};
+
+const USAGE: &str = "\
+Usage: rvm-ctl <command> [args]
+
+Commands:
+    enable <config>         Enable the hypervisor with a system configuration
+    disable                 Disable the hypervisor
+    status [config]         Show whether the hypervisor is enabled
+    stats <config>          Show per-CPU statistics and error counts
+    log [-f] <config>       Print the hypervisor log, -f to keep following it
+    trace <config>          Print the recent VM exits as Chrome trace JSON
+    memmap <config>         Show the physical memory taken by the hypervisor
+                            and the RTOS
+";
+
+fn read_config(path: &str) -> io::Result<Vec<u8>> {
+    let blob = fs::read(path)?;
+    if blob.len() < size_of::<HvSystemConfig>() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "configuration too short",
+        ));
+    }
+    let config = unsafe { (blob.as_ptr() as *const HvSystemConfig).read_unaligned() };
+    let revision = config.revision;
+    if config.signature != CONFIG_SIGNATURE || revision != CONFIG_REVISION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not a system configuration of revision {}", CONFIG_REVISION),
+        ));
+    }
+    Ok(blob)
+}
+
+fn map_window(path: &str) -> io::Result<StatsWindow> {
+    let blob = read_config(path)?;
+    let config = unsafe { (blob.as_ptr() as *const HvSystemConfig).read_unaligned() };
+    let gpa = config.stats_window_gpa;
+    match gpa {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "stats window disabled in the configuration",
+        )),
+        gpa => StatsWindow::map(gpa),
+    }
+}
+
+fn status(config: Option<&str>) -> io::Result<()> {
+    let enabled = driver::enabled()?;
+    println!(
+        "Hypervisor: {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    if let (true, Some(path)) = (enabled, config) {
+        let blob = read_config(path)?;
+        let config = unsafe { (blob.as_ptr() as *const HvSystemConfig).read_unaligned() };
+        let flags = config.flags;
+        println!("Lockdown: {}", flags.contains(HvSystemFlags::LOCKDOWN));
+        println!("Strict SMT: {}", flags.contains(HvSystemFlags::STRICT_SMT));
+        println!(
+            "Interrupt remapping: {}",
+            flags.contains(HvSystemFlags::IRQ_REMAPPING)
+        );
+        let num_policies = (config.num_exit_policies as usize).min(MAX_EXIT_POLICIES);
+        for entry in &config.exit_policies[..num_policies] {
+            let (reason, policy) = (entry.exit_reason, entry.policy);
+            match ExitPolicy::try_from(policy) {
+                Ok(policy) => println!("Unhandled exit {:#x}: {:?}", reason, policy),
+                Err(_) => println!("Unhandled exit {:#x}: invalid policy {}", reason, policy),
+            }
+        }
+        let header = map_window(path)?.header();
+        println!("CPUs: {}", header.num_cpus);
+        println!("Statistics: {}", header.stats_enabled != 0);
+        println!("Log: {} bytes written", header.log_written);
+    }
+    Ok(())
+}
+
+fn stats(config: &str) -> io::Result<()> {
+    let window = map_window(config)?;
+    let header = window.header();
+    if header.stats_enabled == 0 {
+        println!("Statistics collection is disabled, counters may be stale.");
+    }
+    println!(
+        "{:>4} {:>14} {:>14} {:>18} {:>10}",
+        "CPU", "VM exits", "hypercalls", "exit cycles", "SMIs"
+    );
+    for cpu_id in 0..header.num_cpus {
+        let s = window.cpu_stats(cpu_id).unwrap();
+        println!(
+            "{:>4} {:>14} {:>14} {:>18} {:>10}",
+            cpu_id, s.vm_exits, s.hypercalls, s.exit_cycles, s.smi_count
+        );
+    }
+    println!("\nReported by the RTOS:");
+    for (apic_id, irqs) in header
+        .rt_reported_apic_ids
+        .iter()
+        .zip(&header.rt_reported_irqs)
+        .filter(|&(_, &irqs)| irqs != 0)
+    {
+        println!("  APIC {}: {} device IRQs", apic_id, irqs);
+    }
+    println!("  DMA: {} bytes", header.rt_reported_dma_bytes);
+    let (mut slow, mut slow_cycles, mut fast, mut fast_cycles) = (0, 0, 0, 0);
+    for cpu_id in 0..header.num_cpus {
+        let s = window.cpu_stats(cpu_id).unwrap();
+        slow += s.hypercalls.saturating_sub(s.fast_hypercalls);
+        slow_cycles += s.hypercall_cycles;
+        fast += s.fast_hypercalls;
+        fast_cycles += s.fast_hypercall_cycles;
+    }
+    if fast != 0 {
+        println!(
+            "Hypercall latency: {} cycles with VMCALL, {} cycles on the fast path",
+            slow_cycles / slow.max(1),
+            fast_cycles / fast
+        );
+    }
+    let tsc_mhz = header.tsc_mhz.max(1) as u64;
+    for cpu_id in 0..header.num_cpus {
+        let s = window.cpu_stats(cpu_id).unwrap();
+        if s.rt_cycles == 0 {
+            continue;
+        }
+        print!(
+            "RT CPU {}: {} cycles, {} deadline misses",
+            cpu_id, s.rt_cycles, s.deadline_misses
+        );
+        if s.deadline_misses != 0 {
+            print!(
+                ", the last one {}us late with {} root cell VM exits ({} cycles)",
+                s.last_miss_overrun / tsc_mhz,
+                s.last_miss_exits,
+                s.last_miss_exit_cycles
+            );
+        }
+        println!();
+    }
+    println!("\nInit phases (cycles of the last run):");
+    for (phase, name) in INIT_PHASES.iter().enumerate() {
+        let cycles: Vec<_> = (0..header.num_cpus)
+            .map(|cpu_id| window.cpu_stats(cpu_id).unwrap().init_cycles[phase])
+            .collect();
+        println!("{:>14}: {:?}", name, cycles);
+    }
+    println!("\nErrors:");
+    for (name, count) in ERROR_SUBSYSTEMS.iter().zip(header.error_counts) {
+        println!("{:>14}: {}", name, count);
+    }
+    Ok(())
+}
+
+fn log(config: &str, follow: bool) -> io::Result<()> {
+    let window = map_window(config)?;
+    let mut pos = 0;
+    loop {
+        let (log, written) = window.read_log(pos);
+        print!("{}", String::from_utf8_lossy(&log));
+        io::stdout().flush()?;
+        pos = written;
+        if !follow {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn trace(config: &str) -> io::Result<()> {
+    let window = map_window(config)?;
+    let header = window.header();
+    if header.stats_enabled == 0 {
+        eprintln!("Statistics collection is disabled, no exits are traced.");
+    }
+    trace::write_chrome_trace(&mut io::stdout().lock(), &header, &window.exit_trace())
+}
+
+fn memmap(config: &str) -> io::Result<()> {
+    let window = map_window(config)?;
+    let entries = loop {
+        match window.mem_map() {
+            Some(entries) => break entries,
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    println!(
+        "{:>18} {:>18} {:>20} {:>18} {:>6}",
+        "start", "end", "owner", "root cell GPA", "access"
+    );
+    for e in entries {
+        let owner = MEM_MAP_OWNERS
+            .get((e.owner as usize).wrapping_sub(1))
+            .unwrap_or(&"unknown");
+        let gpa = match e.root_gpa {
+            u64::MAX => "-".into(),
+            gpa => format!("{:#x}", gpa),
+        };
+        let access = match (e.access & MEM_MAP_READ != 0, e.access & MEM_MAP_WRITE != 0) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "-",
+        };
+        println!(
+            "{:>#18x} {:>#18x} {:>20} {:>18} {:>6}",
+            e.start,
+            e.start + e.size,
+            owner,
+            gpa,
+            access
+        );
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> io::Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["enable", config] => Driver::open()?.enable(&read_config(config)?),
+        ["disable"] => Driver::open()?.disable(),
+        ["status"] => status(None),
+        ["status", config] => status(Some(config)),
+        ["stats", config] => stats(config),
+        ["log", config] => log(config, false),
+        ["log", "-f", config] => log(config, true),
+        ["trace", config] => trace(config),
+        ["memmap", config] => memmap(config),
+        _ => {
+            eprint!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("rvm-ctl: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,155 @@
+//! Conversion of the exit trace ring to the Chrome trace event format.
+//!
+//! The JSON output opens in `chrome://tracing` and in the Perfetto UI. Each
+//! CPU gets two tracks: one with all its VM exits, one with the hypercalls
+//! among them.
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use crate::window::{ExitTraceRecord, StatsHeader};
+
+const VMX_EXIT_REASONS: &[(u32, &str)] = &[
+    (0, "exception-nmi"),
+    (1, "external-interrupt"),
+    (2, "triple-fault"),
+    (10, "cpuid"),
+    (18, "vmcall"),
+    (28, "cr-access"),
+    (30, "io-instruction"),
+    (31, "msr-read"),
+    (32, "msr-write"),
+    (48, "ept-violation"),
+    (49, "ept-misconfig"),
+];
+
+const SVM_EXIT_CODES: &[(u32, &str)] = &[
+    (0x61, "nmi"),
+    (0x72, "cpuid"),
+    (0x7b, "ioio"),
+    (0x7c, "msr"),
+    (0x7f, "shutdown"),
+    (0x81, "vmmcall"),
+    (0x400, "npf"),
+];
+
+/// Names of `HyperCallCode`.
+const HYPERCALLS: &[&str] = &[
+    "hypervisor-disable",
+    "rt-start",
+    "rt-shutdown",
+    "get-info",
+    "event-channel-setup",
+    "attest",
+    "protect-range",
+    "async-submit",
+    "event-channel-route",
+    "stats-control",
+    "mem-release",
+    "kexec-prepare",
+    "latency-range-setup",
+    "vector-alloc",
+    "vector-free",
+    "crash-prepare",
+    "config-reload",
+    "console-write",
+    "log-notify-setup",
+    "log-drained",
+    "query-gpa",
+    "exit-policy-set",
+];
+
+fn exit_name(vendor: u32, reason: u32) -> String {
+    let names = match vendor {
+        2 if (0x40..0x60).contains(&reason) => return format!("exception-{}", reason - 0x40),
+        2 => SVM_EXIT_CODES,
+        _ => VMX_EXIT_REASONS,
+    };
+    match names.iter().find(|&&(r, _)| r == reason) {
+        Some((_, name)) => name.to_string(),
+        None => format!("exit-{:#x}", reason),
+    }
+}
+
+fn hypercall_name(code: u32) -> String {
+    match HYPERCALLS.get(code as usize) {
+        Some(name) => name.to_string(),
+        None => format!("hypercall-{}", code),
+    }
+}
+
+/// Writes `records` as a Chrome trace JSON object to `out`.
+pub fn write_chrome_trace(
+    out: &mut impl Write,
+    header: &StatsHeader,
+    records: &[ExitTraceRecord],
+) -> io::Result<()> {
+    let tsc_mhz = header.tsc_mhz.max(1) as f64;
+    let base_tsc = records.iter().map(|r| r.tsc).min().unwrap_or(0);
+    let us = |cycles: u64| cycles as f64 / tsc_mhz;
+
+    writeln!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+    let cpus: BTreeSet<u32> = records.iter().map(|r| r.cpu_id).collect();
+    let mut first = true;
+    let mut sep = |out: &mut dyn Write| -> io::Result<()> {
+        if !std::mem::replace(&mut first, false) {
+            writeln!(out, ",")?;
+        }
+        Ok(())
+    };
+    for &cpu_id in &cpus {
+        for (tid, track) in [(cpu_id * 2, "exits"), (cpu_id * 2 + 1, "hypercalls")] {
+            sep(out)?;
+            write!(
+                out,
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\
+                 \"args\":{{\"name\":\"CPU {} {}\"}}}}",
+                tid, cpu_id, track
+            )?;
+        }
+    }
+    for r in records {
+        sep(out)?;
+        write!(
+            out,
+            "{{\"name\":\"{}\",\"cat\":\"exit\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\
+             \"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"reason\":{}}}}}",
+            exit_name(header.exit_reason_vendor, r.reason),
+            r.cpu_id * 2,
+            us(r.tsc - base_tsc),
+            us(r.cycles),
+            r.reason
+        )?;
+        if r.hypercall != 0 {
+            sep(out)?;
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"hypercall\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\
+                 \"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"code\":{}}}}}",
+                hypercall_name(r.hypercall - 1),
+                r.cpu_id * 2 + 1,
+                us(r.tsc - base_tsc),
+                us(r.cycles),
+                r.hypercall - 1
+            )?;
+        }
+    }
+    writeln!(out, "\n]}}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_names() {
+        assert_eq!(hypercall_name(0), "hypervisor-disable");
+        assert_eq!(hypercall_name(16), "config-reload");
+        assert_eq!(hypercall_name(17), "console-write");
+        assert_eq!(hypercall_name(21), "exit-policy-set");
+        assert_eq!(hypercall_name(22), "hypercall-22");
+        assert_eq!(exit_name(1, 18), "vmcall");
+        assert_eq!(exit_name(1, 0x100), "exit-0x100");
+        assert_eq!(exit_name(2, 0x81), "vmmcall");
+        assert_eq!(exit_name(2, 0x4e), "exception-14");
+    }
+}
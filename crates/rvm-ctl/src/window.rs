@@ -0,0 +1,251 @@
+//! Reader of the stats window, mapped through `/dev/mem`.
+//!
+//! Mirrors the layout of `src/stats_window.rs`, keep them in sync.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{read_volatile, NonNull};
+use std::sync::atomic::{fence, Ordering};
+
+pub const STATS_WINDOW_MAGIC: u32 = u32::from_le_bytes(*b"RVMS");
+pub const STATS_WINDOW_VERSION: u32 = 12;
+
+pub const NUM_ERROR_SUBSYSTEMS: usize = ERROR_SUBSYSTEMS.len();
+pub const NUM_INIT_PHASES: usize = 7;
+pub const MAX_RT_REPORTED_CPUS: usize = 32;
+
+/// Names of `HvErrorSubsystem`, in the order of the variants.
+pub const ERROR_SUBSYSTEMS: &[&str] = &[
+    "other",
+    "memory",
+    "vmm",
+    "apic",
+    "config",
+    "cell",
+    "hypercall",
+];
+
+/// Names of `InitPhase`.
+pub const INIT_PHASES: [&str; NUM_INIT_PHASES] = [
+    "memory-init",
+    "hv-page-table",
+    "cell-init",
+    "cpu-init",
+    "vcpu-setup",
+    "rt-start",
+    "disable",
+];
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct StatsHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub num_cpus: u32,
+    pub cpu_stats_offset: u32,
+    pub cpu_stats_size: u32,
+    pub log_offset: u32,
+    pub log_size: u32,
+    pub stats_enabled: u32,
+    pub log_written: u64,
+    pub error_counts: [u64; NUM_ERROR_SUBSYSTEMS],
+    pub trace_offset: u32,
+    pub trace_size: u32,
+    pub trace_written: u64,
+    pub exit_trace_offset: u32,
+    pub exit_trace_size: u32,
+    pub exit_trace_written: u64,
+    pub tsc_mhz: u32,
+    pub exit_reason_vendor: u32,
+    pub mem_map_offset: u32,
+    pub mem_map_size: u32,
+    pub mem_map_count: u32,
+    _reserved: u32,
+    pub rt_reported_apic_ids: [u32; MAX_RT_REPORTED_CPUS],
+    pub rt_reported_irqs: [u64; MAX_RT_REPORTED_CPUS],
+    pub rt_reported_dma_bytes: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+pub struct CpuStats {
+    pub seq: u32,
+    _reserved: u32,
+    pub vm_exits: u64,
+    pub hypercalls: u64,
+    pub exit_cycles: u64,
+    pub init_cycles: [u64; NUM_INIT_PHASES],
+    pub smi_count: u64,
+    pub smi_sample_tsc: u64,
+    pub rt_cycles: u64,
+    pub deadline_misses: u64,
+    pub last_miss_tsc: u64,
+    pub last_miss_overrun: u64,
+    pub last_miss_exits: u64,
+    pub last_miss_exit_cycles: u64,
+    pub hypercall_cycles: u64,
+    pub fast_hypercalls: u64,
+    pub fast_hypercall_cycles: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ExitTraceRecord {
+    pub seq: u64,
+    pub tsc: u64,
+    pub cycles: u64,
+    pub cpu_id: u32,
+    pub reason: u32,
+    /// Hypercall code plus one, 0 if the exit was not a hypercall.
+    pub hypercall: u32,
+    _reserved: u32,
+}
+
+/// Names of `MemMapOwner`, from 1.
+pub const MEM_MAP_OWNERS: [&str; 5] = [
+    "hypervisor",
+    "hypervisor-shared",
+    "hypervisor-released",
+    "rtos",
+    "rtos-boot-info",
+];
+
+pub const MEM_MAP_READ: u32 = 1 << 0;
+pub const MEM_MAP_WRITE: u32 = 1 << 1;
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemMapEntry {
+    pub start: u64,
+    pub size: u64,
+    /// Guest physical address in the root cell, `u64::MAX` if not mapped.
+    pub root_gpa: u64,
+    pub owner: u32,
+    pub access: u32,
+}
+
+/// A read-only mapping of the stats window.
+pub struct StatsWindow {
+    base: NonNull<u8>,
+    size: usize,
+}
+
+impl StatsWindow {
+    /// Maps the window at guest physical address `gpa` of the root cell.
+    pub fn map(gpa: u64) -> io::Result<Self> {
+        let page = Self::map_range(gpa, size_of::<StatsHeader>())?;
+        let header = page.header();
+        if header.magic != STATS_WINDOW_MAGIC || header.version != STATS_WINDOW_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "no stats window of version {} at {:#x}",
+                    STATS_WINDOW_VERSION, gpa
+                ),
+            ));
+        }
+        let mem_map_size = header.mem_map_size as usize * size_of::<MemMapEntry>();
+        Self::map_range(gpa, header.mem_map_offset as usize + mem_map_size)
+    }
+
+    fn map_range(gpa: u64, size: usize) -> io::Result<Self> {
+        let mem = OpenOptions::new().read(true).open("/dev/mem")?;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                mem.as_raw_fd(),
+                gpa as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            base: NonNull::new(ptr as *mut u8).unwrap(),
+            size,
+        })
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.size);
+        unsafe { read_volatile(self.base.as_ptr().add(offset) as *const T) }
+    }
+
+    pub fn header(&self) -> StatsHeader {
+        self.read(0)
+    }
+
+    /// Returns a consistent snapshot of the statistics of `cpu_id`.
+    pub fn cpu_stats(&self, cpu_id: u32) -> Option<CpuStats> {
+        let header = self.header();
+        if cpu_id >= header.num_cpus {
+            return None;
+        }
+        let offset = (header.cpu_stats_offset + cpu_id * header.cpu_stats_size) as usize;
+        loop {
+            let stats: CpuStats = self.read(offset);
+            fence(Ordering::Acquire);
+            if stats.seq % 2 == 0 && self.read::<u32>(offset) == stats.seq {
+                return Some(stats);
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Returns the log ring content written since `from`, and the new
+    /// position. Overwritten content is skipped.
+    pub fn read_log(&self, from: u64) -> (Vec<u8>, u64) {
+        let header = self.header();
+        let written = header.log_written;
+        let size = header.log_size as u64;
+        let start = from.max(written.saturating_sub(size));
+        let log = (start..written)
+            .map(|pos| self.read::<u8>(header.log_offset as usize + (pos % size) as usize))
+            .collect();
+        (log, written)
+    }
+
+    /// Returns the complete records of the exit trace ring, oldest first.
+    pub fn exit_trace(&self) -> Vec<ExitTraceRecord> {
+        let header = self.header();
+        let size = header.exit_trace_size as u64;
+        let written = header.exit_trace_written;
+        (written.saturating_sub(size)..written)
+            .filter_map(|n| {
+                let offset = header.exit_trace_offset as usize
+                    + (n % size) as usize * size_of::<ExitTraceRecord>();
+                let record: ExitTraceRecord = self.read(offset);
+                fence(Ordering::Acquire);
+                // Skip records being written or already overwritten.
+                Some(record).filter(|r| r.seq == n + 1 && self.read::<u64>(offset) == n + 1)
+            })
+            .collect()
+    }
+
+    /// Returns the memory map, or `None` while the hypervisor rewrites it.
+    pub fn mem_map(&self) -> Option<Vec<MemMapEntry>> {
+        let header = self.header();
+        let count = header.mem_map_count.min(header.mem_map_size) as usize;
+        fence(Ordering::Acquire);
+        let entries = (0..count)
+            .map(|i| self.read(header.mem_map_offset as usize + i * size_of::<MemMapEntry>()))
+            .collect();
+        fence(Ordering::Acquire);
+        Some(entries).filter(|_| count > 0 && self.header().mem_map_count as usize == count)
+    }
+}
+
+impl Drop for StatsWindow {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base.as_ptr() as _, self.size) };
+    }
+}
@@ -0,0 +1,247 @@
+//! Entry state of RT CPUs.
+//!
+//! Each RT CPU enters the RTOS at the entry address given to the `RtStart`
+//! hypercall, in 32-bit protected mode:
+//!
+//! - paging and interrupts are disabled;
+//! - CS is [`CODE32_SELECTOR`], DS/ES/FS/GS/SS are [`DATA_SELECTOR`], in a
+//!   temporary flat GDT which also has [`CODE64_SELECTOR`];
+//! - ESP points to a temporary stack shared by all RT CPUs, so the RTOS must
+//!   switch to its own stack before anything else;
+//! - the MSRs of the RT CPU policy (P-state, turbo, C-states, MWAIT) have
+//!   been programmed.
+//!
+//! The temporary GDT and stack live in the low memory start page, which is
+//! restored once all RT CPUs are started: the RTOS must load its own GDT
+//! early. RT CPUs are started one after another, all with EAX set to
+//! [`RT_BOOT_MAGIC`] and EBX to the physical address of the [`RtBootInfo`]
+//! in the last page of RTOS memory (0 if above 4 GiB). The RTOS identifies
+//! the CPU with [`apic_id()`].
+//!
+//! Before the RT CPUs are reset (`RtShutdown`, or a reset of the machine by
+//! the root cell), an RTOS which called [`RtBootInfo::enable_stop_request()`]
+//! is asked to stop, and gets [`RT_STOP_TIMEOUT_US`] to park its devices and
+//! call [`RtBootInfo::ack_stop()`]. It must keep the boot information page
+//! then.
+//!
+//! The hypervisor does not see the interrupts and DMA of the devices assigned
+//! to the RTOS. An RTOS keeping the page counts them with
+//! [`RtBootInfo::count_irq()`] and [`RtBootInfo::count_dma()`], and the
+//! hypervisor publishes them in its statistics.
+//!
+//! With interrupt remapping ([`RtBootInfo::irq_remapping`]), the interrupts
+//! in compatibility format are blocked. The RTOS programs the MSIs of its
+//! devices with [`RtBootInfo::msi_message()`], which selects the remapping
+//! table entry of the device, RT CPU and vector set up by the hypervisor.
+//!
+//! Likewise, an RTOS running periodic cycles reports them with
+//! [`RtBootInfo::cycle_start()`] and [`RtBootInfo::cycle_end()`]. The
+//! hypervisor flags the cycles which ended past their deadline in its
+//! statistics, with the VM exits of the root cell during the missed cycle.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Flat 32-bit code segment of the temporary GDT.
+pub const CODE32_SELECTOR: u16 = 0x08;
+/// Flat 64-bit code segment of the temporary GDT, to enter long mode.
+pub const CODE64_SELECTOR: u16 = 0x10;
+/// Flat data segment of the temporary GDT.
+pub const DATA_SELECTOR: u16 = 0x18;
+
+/// Value of EAX when entering the RTOS.
+pub const RT_BOOT_MAGIC: u32 = u32::from_le_bytes(*b"RVMB");
+/// Version of [`RtBootInfo`] described here.
+pub const RT_BOOT_INFO_VERSION: u32 = 5;
+/// Maximum number of RT CPUs listed in [`RtBootInfo::apic_ids`].
+pub const MAX_RT_BOOT_CPUS: usize = 32;
+/// Maximum number of PCI devices listed in [`RtBootInfo::pci_bdfs`].
+pub const MAX_RT_PCI_DEVICES: usize = 4;
+/// Lowest vector which can be remapped, see [`RtBootInfo::msi_message()`].
+pub const FIRST_REMAPPED_VECTOR: u8 = 0x10;
+
+/// [`RtBootInfo::stop_flags`]: ask the RTOS to stop before resetting its CPUs.
+pub const RT_STOP_COOPERATIVE: u32 = 1 << 0;
+/// Time the hypervisor waits for [`RtBootInfo::ack_stop()`].
+pub const RT_STOP_TIMEOUT_US: u64 = 100 * 1000;
+
+/// Boot information written by the hypervisor before starting the RT CPUs.
+/// Stays valid until the next `RtStart`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RtBootInfo {
+    pub magic: u32,
+    pub version: u32,
+    /// RTOS memory, without the boot information page.
+    pub rtos_mem_start: u64,
+    pub rtos_mem_size: u64,
+    pub tsc_mhz: u32,
+    /// I/O port of the UART shared with the hypervisor, see [`crate::console`].
+    pub console_port: u16,
+    /// Number of valid entries of `apic_ids`.
+    pub num_cpus: u16,
+    /// APIC IDs of the RT CPUs.
+    pub apic_ids: [u32; MAX_RT_BOOT_CPUS],
+    /// Written by the RTOS, see [`RT_STOP_COOPERATIVE`].
+    pub stop_flags: AtomicU32,
+    /// Vector raised on every RT CPU with a stop request, 0 for none.
+    pub stop_vector: AtomicU32,
+    /// Set to 1 by the hypervisor to ask the RTOS to stop.
+    pub stop_request: AtomicU32,
+    /// Set to 1 by the RTOS once stopped.
+    pub stop_ack: AtomicU32,
+    /// Device interrupts handled by each RT CPU, indexed like `apic_ids`.
+    pub irq_counts: [AtomicU64; MAX_RT_BOOT_CPUS],
+    /// Bytes transferred by DMA of the RTOS devices.
+    pub dma_bytes: AtomicU64,
+    /// Cycles of each RT CPU, indexed like `apic_ids`.
+    pub deadlines: [RtDeadlines; MAX_RT_BOOT_CPUS],
+    /// Number of valid entries of `pci_bdfs`.
+    pub num_pci_devices: u16,
+    /// 1 if the hypervisor remaps the device interrupts.
+    pub irq_remapping: u16,
+    /// Bus, device and function numbers of the PCI devices assigned to the
+    /// RTOS.
+    pub pci_bdfs: [u16; MAX_RT_PCI_DEVICES],
+}
+
+/// Periodic cycles of a RT CPU and their deadline misses. Times are TSC
+/// values.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RtDeadlines {
+    pub cycles: AtomicU64,
+    /// Incremented after the `last_miss_*` fields are written.
+    pub misses: AtomicU64,
+    pub last_miss_start: AtomicU64,
+    pub last_miss_deadline: AtomicU64,
+    pub last_miss_end: AtomicU64,
+}
+
+/// A cycle started by [`RtBootInfo::cycle_start()`].
+#[derive(Debug)]
+pub struct RtCycle {
+    cpu: usize,
+    start: u64,
+    deadline: u64,
+}
+
+impl RtBootInfo {
+    /// Returns the boot information from the entry values of EAX and EBX, if
+    /// they are valid. Paging must be off or identity map the page.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with the entry registers, before the RTOS reuses the
+    /// last page of its memory.
+    pub unsafe fn from_entry<'a>(eax: u32, ebx: u32) -> Option<&'a Self> {
+        if eax != RT_BOOT_MAGIC || ebx == 0 {
+            return None;
+        }
+        let info = &*(ebx as usize as *const Self);
+        (info.magic == RT_BOOT_MAGIC && info.version == RT_BOOT_INFO_VERSION).then(|| info)
+    }
+
+    pub fn apic_ids(&self) -> &[u32] {
+        &self.apic_ids[..(self.num_cpus as usize).min(MAX_RT_BOOT_CPUS)]
+    }
+
+    pub fn pci_bdfs(&self) -> &[u16] {
+        &self.pci_bdfs[..(self.num_pci_devices as usize).min(MAX_RT_PCI_DEVICES)]
+    }
+
+    /// Returns the MSI address and data delivering `vector` to the RT CPU
+    /// with index `cpu` in `apic_ids`, for the device with index `device` in
+    /// `pci_bdfs`. `None` if an index is out of range, or if `vector` is
+    /// below [`FIRST_REMAPPED_VECTOR`] with interrupt remapping.
+    pub fn msi_message(&self, device: usize, cpu: usize, vector: u8) -> Option<(u32, u32)> {
+        let apic_id = *self.apic_ids().get(cpu)?;
+        self.pci_bdfs().get(device)?;
+        if self.irq_remapping == 0 {
+            return Some((0xfee0_0000 | (apic_id & 0xff) << 12, vector as u32));
+        }
+        if vector < FIRST_REMAPPED_VECTOR {
+            return None;
+        }
+        let handle = ((device * self.apic_ids().len() + cpu) * 256) as u32 + vector as u32;
+        // Remappable format, the handle in bits 19:5 and 2.
+        let addr = 0xfee0_0000 | (handle & 0x7fff) << 5 | 1 << 4 | (handle >> 15) << 2;
+        Some((addr, 0))
+    }
+
+    /// Asks to be notified before the RT CPUs are reset, by `vector` (if not
+    /// 0) on every RT CPU and by [`stop_requested()`](Self::stop_requested).
+    pub fn enable_stop_request(&self, vector: u8) {
+        self.stop_vector.store(vector as u32, Ordering::Relaxed);
+        self.stop_flags
+            .fetch_or(RT_STOP_COOPERATIVE, Ordering::Release);
+    }
+
+    /// Whether the hypervisor is about to reset the RT CPUs.
+    pub fn stop_requested(&self) -> bool {
+        self.stop_request.load(Ordering::Acquire) != 0
+    }
+
+    /// Counts a device interrupt handled by the RT CPU with index `cpu` in
+    /// `apic_ids`.
+    pub fn count_irq(&self, cpu: usize) {
+        if let Some(count) = self.irq_counts.get(cpu) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts `bytes` transferred by DMA of a RTOS device.
+    pub fn count_dma(&self, bytes: u64) {
+        self.dma_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Starts a cycle of the RT CPU with index `cpu` in `apic_ids`, which
+    /// must end before the TSC reaches `deadline`.
+    pub fn cycle_start(&self, cpu: usize, deadline: u64) -> RtCycle {
+        RtCycle {
+            cpu,
+            start: unsafe { _rdtsc() },
+            deadline,
+        }
+    }
+
+    /// Ends `cycle`, and reports it as a deadline miss if it is late.
+    pub fn cycle_end(&self, cycle: RtCycle) {
+        let end = unsafe { _rdtsc() };
+        let deadlines = match self.deadlines.get(cycle.cpu) {
+            Some(deadlines) => deadlines,
+            None => return,
+        };
+        if end > cycle.deadline {
+            deadlines
+                .last_miss_start
+                .store(cycle.start, Ordering::Relaxed);
+            deadlines
+                .last_miss_deadline
+                .store(cycle.deadline, Ordering::Relaxed);
+            deadlines.last_miss_end.store(end, Ordering::Relaxed);
+            deadlines.misses.fetch_add(1, Ordering::Release);
+        }
+        deadlines.cycles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Tells the hypervisor that the RTOS is stopped and its CPUs can be
+    /// reset right away.
+    pub fn ack_stop(&self) {
+        self.stop_ack.store(1, Ordering::Release);
+    }
+}
+
+/// Returns the x2APIC ID of the current CPU, or the xAPIC ID if CPUID leaf
+/// 0xB is not supported.
+pub fn apic_id() -> u32 {
+    unsafe {
+        if __cpuid(0).eax >= 0xb {
+            let leaf = __cpuid(0xb);
+            if leaf.ebx != 0 {
+                return leaf.edx;
+            }
+        }
+        __cpuid(1).ebx >> 24
+    }
+}
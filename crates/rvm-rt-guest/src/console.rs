@@ -0,0 +1,55 @@
+//! A 16550 UART writer, for early RTOS output.
+//!
+//! The hypervisor prints on the same port, so the output of the RTOS and the
+//! hypervisor may interleave.
+
+use core::arch::asm;
+use core::fmt::{Result, Write};
+
+/// I/O port of COM1, also used by the hypervisor.
+pub const COM1_PORT: u16 = 0x3f8;
+
+const LINE_STATUS: u16 = 5;
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
+}
+
+/// Writes to a UART already initialized by the firmware or the hypervisor.
+pub struct SerialConsole {
+    port: u16,
+}
+
+impl SerialConsole {
+    pub const fn new(port: u16) -> Self {
+        Self { port }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while inb(self.port + LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            outb(self.port, byte);
+        }
+    }
+}
+
+impl Write for SerialConsole {
+    fn write_str(&mut self, s: &str) -> Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+//! Event ring shared with the hypervisor.
+//!
+//! The ring is one page of root cell RAM registered with
+//! [`event_channel_setup()`](crate::hypercall::event_channel_setup). The
+//! hypervisor appends events at `head` and raises the registered vector, the
+//! consumer takes them at `tail`. Both are free running counters.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Number of events in the ring.
+pub const EVENT_RING_SIZE: usize = 64;
+
+/// Event types.
+#[repr(u32)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum HvEventType {
+    /// `data[0]` is the new package thermal status.
+    ThermalAlert = 1,
+    /// `data` is `[bank, status, addr]`.
+    MachineCheck = 2,
+    /// `data` is `[gpaddr, rip, access]`, `access` is 1 for write, 2 for fetch.
+    IntegrityViolation = 3,
+    /// `data` is `[gpaddr, size, 0]`.
+    IntegrityModified = 4,
+    /// `data` is `[token, status, 0]`.
+    AsyncComplete = 5,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvEvent {
+    /// An `HvEventType`, unknown types must be ignored.
+    pub event_type: u32,
+    /// The CPU which detected the event.
+    pub cpu_id: u32,
+    pub data: [u64; 3],
+}
+
+/// Layout of the event ring page.
+#[repr(C)]
+pub struct EventRing {
+    /// Written by the hypervisor only.
+    pub head: AtomicU32,
+    /// Written by the consumer only.
+    pub tail: AtomicU32,
+    /// Number of events dropped since the ring was full.
+    pub dropped: AtomicU32,
+    _reserved: u32,
+    pub events: [HvEvent; EVENT_RING_SIZE],
+}
+
+impl EventRing {
+    /// Takes the oldest event, if any.
+    pub fn pop(&self) -> Option<HvEvent> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let event =
+            unsafe { core::ptr::read_volatile(&self.events[tail as usize % EVENT_RING_SIZE]) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(event)
+    }
+}
@@ -0,0 +1,306 @@
+//! Hypercalls, issued by the root cell with VMCALL (VMMCALL with the `svm`
+//! feature).
+//!
+//! The code is passed in RAX and the arguments in RDI and RSI. Privileged
+//! hypercalls (all of the current ones) must be issued from CPL 0 and return
+//! in RAX a non-negative value or a negative errno. If the configuration
+//! sets `USER_HYPERCALLS`, [`get_info()`] (but `IntegrityCheck`) and
+//! [`console_write()`] may also be issued from CPL 3, the same way.
+//!
+//! With VMX, `HypervisorGetInfo` and `ConsoleWrite` may also be issued from
+//! CPL 0 through [`fast_hypercall()`], whose VM exits skip the periodic work
+//! of the hypervisor. It is available if bit 0 of EAX is set in CPUID leaf
+//! 0x40000001.
+
+use core::arch::asm;
+
+/// Hypercall numbers.
+#[repr(u32)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum HyperCallCode {
+    HypervisorDisable = 0,
+    RtStart = 1,
+    RtShutdown = 2,
+    HypervisorGetInfo = 3,
+    EventChannelSetup = 4,
+    Attest = 5,
+    ProtectRange = 6,
+    AsyncSubmit = 7,
+    EventChannelRoute = 8,
+    StatsControl = 9,
+    MemRelease = 10,
+    KexecPrepare = 11,
+    LatencyRangeSetup = 12,
+    VectorAlloc = 13,
+    VectorFree = 14,
+    CrashPrepare = 15,
+    ConfigReload = 16,
+    ConsoleWrite = 17,
+    LogNotifySetup = 18,
+    LogDrained = 19,
+    QueryGpa = 20,
+    ExitPolicySet = 21,
+}
+
+/// Information types of `HypervisorGetInfo`.
+#[repr(u64)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum HvInfoType {
+    NumRtCpus = 0,
+    RtCpuStatus = 1,
+    MemEncryption = 2,
+    ErrorCount = 3,
+    IntegrityCheck = 4,
+    Intercepts = 5,
+    MemUsage = 6,
+    HypercallAnomalies = 7,
+    MemHeatChunkSize = 8,
+    MemHeat = 9,
+    InjectedFaults = 10,
+    RipLatency = 11,
+    CellState = 12,
+    IrqStorms = 13,
+    IsolationAudit = 14,
+}
+
+/// Handling of a VM exit reason without handler, see [`exit_policy_set()`].
+#[repr(u32)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ExitPolicy {
+    Fault = 0,
+    Skip = 1,
+    Panic = 2,
+}
+
+/// `MemRelease`: release the memory instead of only reporting its size.
+pub const MEM_RELEASE_APPLY: u64 = 1 << 0;
+
+/// `ProtectRange`: remove write permission.
+pub const PROTECT_NO_WRITE: u64 = 1 << 0;
+/// `ProtectRange`: remove execute permission.
+pub const PROTECT_NO_EXEC: u64 = 1 << 1;
+/// `ProtectRange`: monitor the integrity of the range.
+pub const PROTECT_MONITOR: u64 = 1 << 2;
+
+/// `QueryGpa`: shift of the page size of the mapping in the result, 0 for
+/// 4 KiB, 1 for 2 MiB and 2 for 1 GiB.
+pub const QUERY_GPA_SIZE_SHIFT: u32 = 10;
+/// `QueryGpa`: mask of the flags of the mapping in the result (bit 0 read,
+/// 1 write, 2 execute, 4 I/O, 5 write-combining).
+pub const QUERY_GPA_FLAGS_MASK: usize = (1 << QUERY_GPA_SIZE_SHIFT) - 1;
+
+/// A negative errno returned by a hypercall.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct HvError(pub i32);
+
+pub type HvResult<T = usize> = Result<T, HvError>;
+
+/// Issues the hypercall `code` with `arg0` and `arg1`.
+///
+/// # Safety
+///
+/// Must be called in the root cell, at CPL 0 unless allowed from user space.
+/// Some hypercalls access guest memory given by physical address in the
+/// arguments.
+pub unsafe fn hypercall(code: HyperCallCode, arg0: u64, arg1: u64) -> HvResult {
+    let ret: i64;
+    #[cfg(not(feature = "svm"))]
+    asm!("vmcall", inout("rax") code as u64 => ret, in("rdi") arg0, in("rsi") arg1);
+    #[cfg(feature = "svm")]
+    asm!("vmmcall", inout("rax") code as u64 => ret, in("rdi") arg0, in("rsi") arg1);
+    if ret < 0 {
+        Err(HvError(ret as i32))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Synthetic MSR of the hypercall fast path.
+pub const FAST_HYPERCALL_MSR: u32 = 0x4000_0080;
+
+/// Issues the hypercall `code` with `arg0` and `arg1` by a WRMSR to
+/// [`FAST_HYPERCALL_MSR`]. Other codes than `HypervisorGetInfo` and
+/// `ConsoleWrite` return `ENOSYS`.
+///
+/// # Safety
+///
+/// Must be called in the root cell, at CPL 0, with the fast path available.
+/// See [`hypercall()`].
+pub unsafe fn fast_hypercall(code: HyperCallCode, arg0: u64, arg1: u64) -> HvResult {
+    let ret: i64;
+    asm!(
+        "wrmsr",
+        in("ecx") FAST_HYPERCALL_MSR,
+        inout("rax") code as u64 => ret,
+        in("edx") 0,
+        in("rdi") arg0,
+        in("rsi") arg1,
+    );
+    if ret < 0 {
+        Err(HvError(ret as i32))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Starts the RTOS at `entry_paddr`, after measuring its first `image_size`
+/// bytes (0 for the whole RTOS memory). The last page of RTOS memory is
+/// reserved for the [boot information](crate::boot::RtBootInfo), and never
+/// part of the image.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn rt_start(entry_paddr: u64, image_size: u64) -> HvResult {
+    hypercall(HyperCallCode::RtStart, entry_paddr, image_size)
+}
+
+/// Stops the RT CPUs.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn rt_shutdown() -> HvResult {
+    hypercall(HyperCallCode::RtShutdown, 0, 0)
+}
+
+/// Queries hypervisor information `info_type`, indexed by `arg`.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn get_info(info_type: HvInfoType, arg: u64) -> HvResult {
+    hypercall(HyperCallCode::HypervisorGetInfo, info_type as u64, arg)
+}
+
+/// Registers the page aligned event ring at `ring_paddr`, notified with
+/// interrupt `vector`. With a `vector` of 0, one is allocated from the vector
+/// pool of the cell. Returns the vector used.
+///
+/// # Safety
+///
+/// See [`hypercall()`]. The ring must stay allocated until the hypervisor is
+/// disabled.
+pub unsafe fn event_channel_setup(ring_paddr: u64, vector: u8) -> HvResult {
+    hypercall(HyperCallCode::EventChannelSetup, ring_paddr, vector as u64)
+}
+
+/// Records the VM exit latency of guest RIP `[start, start + size)` in range
+/// `idx` (0..8), read back with `HvInfoType::RipLatency`. A size of 0 removes
+/// the range.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn latency_range_setup(idx: u8, start: u64, size: u32) -> HvResult {
+    hypercall(
+        HyperCallCode::LatencyRangeSetup,
+        start,
+        size as u64 | (idx as u64) << 32,
+    )
+}
+
+/// Allocates `count` consecutive interrupt vectors from the pool of the cell,
+/// aligned to the next power of two of `count`. Returns the first one.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn vector_alloc(count: u8) -> HvResult {
+    hypercall(HyperCallCode::VectorAlloc, count as u64, 0)
+}
+
+/// Frees `count` vectors from `first`, allocated with [`vector_alloc()`].
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn vector_free(first: u8, count: u8) -> HvResult {
+    hypercall(HyperCallCode::VectorFree, first as u64, count as u64)
+}
+
+/// Asks for `vector` once half of the log ring of the stats window is
+/// unread, on the current CPU. A `vector` of 0 is allocated from the pool of
+/// the cell. Returns the vector.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn log_notify_setup(vector: u8) -> HvResult {
+    hypercall(HyperCallCode::LogNotifySetup, vector as u64, 0)
+}
+
+/// Reports that the log ring is read up to `position`, a value of
+/// `log_written`, which re-arms the notification.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn log_drained(position: u64) -> HvResult {
+    hypercall(HyperCallCode::LogDrained, position, 0)
+}
+
+/// Prepares for jumping into the crash kernel loaded in `[start, start +
+/// size)`, from the panicking CPU. The hypervisor stays enabled, the event
+/// channel is stopped and the RTOS keeps running.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn crash_prepare(start: u64, size: u64) -> HvResult {
+    hypercall(HyperCallCode::CrashPrepare, start, size)
+}
+
+/// Reloads the hypercall limit, the log level and the watchdog timeout from
+/// the system configuration of `size` bytes at `gpaddr`, whose other fields
+/// must be unchanged.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn config_reload(gpaddr: u64, size: u64) -> HvResult {
+    hypercall(HyperCallCode::ConfigReload, gpaddr, size)
+}
+
+/// Prints `buf`, at most 256 bytes (32 from CPL 3), on the hypervisor
+/// console. Returns the number of bytes printed.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn console_write(buf: &[u8]) -> HvResult {
+    hypercall(
+        HyperCallCode::ConsoleWrite,
+        buf.as_ptr() as u64,
+        buf.len() as u64,
+    )
+}
+
+/// Translates `gpaddr` through the nested page table of the root cell.
+/// Returns the page aligned host address of the page mapping it, or'd with
+/// its size (see [`QUERY_GPA_SIZE_SHIFT`]) and flags (see
+/// [`QUERY_GPA_FLAGS_MASK`]). Fails with `-ENOENT` if not mapped.
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn query_gpa(gpaddr: u64) -> HvResult {
+    hypercall(HyperCallCode::QueryGpa, gpaddr, 0)
+}
+
+/// Handles the VM exit reason `exit_reason` (VMX basic exit reason, or SVM
+/// exit code) without handler with `policy`: inject #GP (the default), skip
+/// the instruction with a warning, or panic the hypervisor. Fails with
+/// `-ENOMEM` if 8 reasons already have another policy than
+/// [`ExitPolicy::Fault`].
+///
+/// # Safety
+///
+/// See [`hypercall()`].
+pub unsafe fn exit_policy_set(exit_reason: u32, policy: ExitPolicy) -> HvResult {
+    hypercall(
+        HyperCallCode::ExitPolicySet,
+        exit_reason as u64,
+        policy as u64,
+    )
+}
@@ -0,0 +1,19 @@
+//! Guest-side ABI of the RVM hypervisor.
+//!
+//! The RTOS runs natively on the RT CPUs: it does not run in VMX/SVM
+//! non-root mode, so it can not issue hypercalls and gets no boot information
+//! other than its entry state, see [`boot`]. The hypercall and event channel
+//! bindings are for the root cell side of an RTOS integration (e.g. a
+//! bare-metal Rust component or a driver running in the root cell).
+//!
+//! - [`boot`]: state of RT CPUs when entering the RTOS.
+//! - [`hypercall`]: hypercall numbers and wrappers.
+//! - [`event`]: layout and consumer of the event ring.
+//! - [`console`]: a 16550 UART writer.
+
+#![no_std]
+
+pub mod boot;
+pub mod console;
+pub mod event;
+pub mod hypercall;
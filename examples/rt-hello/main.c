@@ -0,0 +1,171 @@
+/*
+ * A minimal RT payload: every RT CPU prints the boot information on the
+ * console shared with the hypervisor, then counts TSC seconds.
+ */
+
+#include <stdint.h>
+
+#define RT_BOOT_MAGIC		0x424d5652	/* "RVMB" */
+#define RT_BOOT_INFO_VERSION	5
+#define MAX_RT_BOOT_CPUS	32
+#define MAX_RT_PCI_DEVICES	4
+#define RT_STOP_COOPERATIVE	(1 << 0)
+#define COM1_PORT		0x3f8
+
+/* Mirrors rvm_rt_guest::boot::RtBootInfo. */
+struct rt_boot_info {
+	uint32_t magic;
+	uint32_t version;
+	uint64_t rtos_mem_start;
+	uint64_t rtos_mem_size;
+	uint32_t tsc_mhz;
+	uint16_t console_port;
+	uint16_t num_cpus;
+	uint32_t apic_ids[MAX_RT_BOOT_CPUS];
+	uint32_t stop_flags;
+	uint32_t stop_vector;
+	uint32_t stop_request;
+	uint32_t stop_ack;
+	uint64_t irq_counts[MAX_RT_BOOT_CPUS];
+	uint64_t dma_bytes;
+	struct {
+		uint64_t cycles;
+		uint64_t misses;	/* incremented after last_miss_* */
+		uint64_t last_miss_start;
+		uint64_t last_miss_deadline;
+		uint64_t last_miss_end;
+	} deadlines[MAX_RT_BOOT_CPUS];
+	uint16_t num_pci_devices;
+	uint16_t irq_remapping;
+	uint16_t pci_bdfs[MAX_RT_PCI_DEVICES];
+};
+
+static uint16_t console_port = COM1_PORT;
+static volatile uint32_t console_lock;
+
+static inline uint8_t inb(uint16_t port)
+{
+	uint8_t value;
+	asm volatile("inb %1, %0" : "=a"(value) : "Nd"(port));
+	return value;
+}
+
+static inline void outb(uint16_t port, uint8_t value)
+{
+	asm volatile("outb %0, %1" : : "a"(value), "Nd"(port));
+}
+
+static inline uint64_t rdtsc(void)
+{
+	uint32_t lo, hi;
+	asm volatile("rdtsc" : "=a"(lo), "=d"(hi));
+	return (uint64_t)hi << 32 | lo;
+}
+
+static void con_putc(char c)
+{
+	/* The UART is initialized by the hypervisor. */
+	while (!(inb(console_port + 5) & 0x20))
+		;
+	outb(console_port, c);
+}
+
+static void con_puts(const char *s)
+{
+	for (; *s; s++) {
+		if (*s == '\n')
+			con_putc('\r');
+		con_putc(*s);
+	}
+}
+
+static void con_puthex(uint64_t value)
+{
+	int shift;
+
+	con_puts("0x");
+	for (shift = 60; shift >= 0; shift -= 4)
+		con_putc("0123456789abcdef"[(value >> shift) & 0xf]);
+}
+
+static void con_putdec(uint32_t value)
+{
+	char buf[11];
+	int i = sizeof(buf) - 1;
+
+	buf[i] = '\0';
+	do {
+		buf[--i] = '0' + value % 10;
+		value /= 10;
+	} while (value);
+	con_puts(&buf[i]);
+}
+
+static void lock(void)
+{
+	while (__atomic_exchange_n(&console_lock, 1, __ATOMIC_ACQUIRE))
+		asm volatile("pause");
+}
+
+static void unlock(void)
+{
+	__atomic_store_n(&console_lock, 0, __ATOMIC_RELEASE);
+}
+
+void rt_main(uint32_t cpu, uint32_t magic, struct rt_boot_info *info)
+{
+	uint64_t tsc_per_sec, next;
+	uint32_t seconds = 0;
+
+	if (magic != RT_BOOT_MAGIC || !info || info->magic != RT_BOOT_MAGIC ||
+	    info->version != RT_BOOT_INFO_VERSION)
+		info = 0;
+
+	lock();
+	if (info && cpu == 0)
+		console_port = info->console_port;
+	con_puts("rt-hello: CPU ");
+	con_putdec(cpu);
+	if (info) {
+		con_puts(" of ");
+		con_putdec(info->num_cpus);
+		con_puts(", memory ");
+		con_puthex(info->rtos_mem_start);
+		con_puts("+");
+		con_puthex(info->rtos_mem_size);
+		con_puts(", TSC ");
+		con_putdec(info->tsc_mhz);
+		con_puts(" MHz\n");
+	} else {
+		con_puts(", no boot information\n");
+	}
+	unlock();
+
+	if (!info || cpu != 0)
+		return;
+
+	/* Poll for stop requests, no vector. */
+	__atomic_or_fetch(&info->stop_flags, RT_STOP_COOPERATIVE, __ATOMIC_RELEASE);
+
+	tsc_per_sec = (uint64_t)info->tsc_mhz * 1000000;
+	next = rdtsc() + tsc_per_sec;
+	for (;;) {
+		while (rdtsc() < next) {
+			if (__atomic_load_n(&info->stop_request, __ATOMIC_ACQUIRE)) {
+				lock();
+				con_puts("rt-hello: stopping\n");
+				unlock();
+				__atomic_store_n(&info->stop_ack, 1, __ATOMIC_RELEASE);
+				for (;;)
+					asm volatile("cli; hlt");
+			}
+			asm volatile("pause");
+		}
+		next += tsc_per_sec;
+		lock();
+		con_puts("rt-hello: ");
+		con_putdec(++seconds);
+		con_puts(" s\n");
+		unlock();
+	}
+}
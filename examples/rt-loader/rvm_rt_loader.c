@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Loads an RT payload into RTOS memory and starts it with the RtStart
+ * hypercall, which must be issued at CPL 0 in the root cell. Unloading the
+ * module stops the RT CPUs with RtShutdown.
+ *
+ *   insmod rvm_rt_loader.ko image=rt-hello.bin base=0x7000000
+ *
+ * The image is looked up in the firmware directory (e.g. /lib/firmware) and
+ * copied to `base`, which is also the entry address unless `entry` is given.
+ */
+
+#include <linux/firmware.h>
+#include <linux/io.h>
+#include <linux/module.h>
+
+#define RVM_HC_RT_START		1
+#define RVM_HC_RT_SHUTDOWN	2
+
+static char *image = "rt-hello.bin";
+module_param(image, charp, 0444);
+MODULE_PARM_DESC(image, "RT payload in the firmware directory");
+
+static ulong base;
+module_param(base, ulong, 0444);
+MODULE_PARM_DESC(base, "Physical load address, rtos_memory.phys_start");
+
+static ulong entry;
+module_param(entry, ulong, 0444);
+MODULE_PARM_DESC(entry, "Physical entry address, defaults to base");
+
+static bool svm;
+module_param(svm, bool, 0444);
+MODULE_PARM_DESC(svm, "Use VMMCALL (AMD) instead of VMCALL (Intel)");
+
+static long rvm_hypercall(unsigned long code, unsigned long arg0,
+			  unsigned long arg1)
+{
+	long ret;
+
+	if (svm)
+		asm volatile("vmmcall"
+			     : "=a"(ret)
+			     : "a"(code), "D"(arg0), "S"(arg1)
+			     : "memory");
+	else
+		asm volatile("vmcall"
+			     : "=a"(ret)
+			     : "a"(code), "D"(arg0), "S"(arg1)
+			     : "memory");
+	return ret;
+}
+
+static int __init rvm_rt_loader_init(void)
+{
+	const struct firmware *fw;
+	void *dst;
+	long ret;
+
+	if (!base)
+		return -EINVAL;
+	if (!entry)
+		entry = base;
+
+	ret = request_firmware(&fw, image, NULL);
+	if (ret)
+		return ret;
+
+	dst = memremap(base, fw->size, MEMREMAP_WB);
+	if (!dst) {
+		release_firmware(fw);
+		return -ENOMEM;
+	}
+	memcpy(dst, fw->data, fw->size);
+	memunmap(dst);
+
+	/* Measure the image only, not the rest of RTOS memory. */
+	ret = rvm_hypercall(RVM_HC_RT_START, entry, fw->size);
+	release_firmware(fw);
+	if (ret < 0) {
+		pr_err("rvm_rt_loader: RtStart failed: %ld\n", ret);
+		return ret;
+	}
+	pr_info("rvm_rt_loader: %s started at %#lx\n", image, entry);
+	return 0;
+}
+
+static void __exit rvm_rt_loader_exit(void)
+{
+	long ret = rvm_hypercall(RVM_HC_RT_SHUTDOWN, 0, 0);
+
+	if (ret < 0)
+		pr_err("rvm_rt_loader: RtShutdown failed: %ld\n", ret);
+}
+
+module_init(rvm_rt_loader_init);
+module_exit(rvm_rt_loader_exit);
+MODULE_LICENSE("GPL");
+MODULE_DESCRIPTION("RT payload loader of the RVM hypervisor");
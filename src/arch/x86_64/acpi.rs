@@ -0,0 +1,543 @@
+//! Minimal ACPI table parsing, only for what the hypervisor needs.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+use spin::Once;
+
+use super::cpu;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::memory::addr::{align_down, align_up, phys_to_virt, PhysAddr};
+use crate::memory::{hv_page_table, GenericPageTableImmut, MemFlags, MemoryRegion, PAGE_SIZE};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const DMAR_SIGNATURE: &[u8; 4] = b"DMAR";
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+/// FADT offsets of the fields used (ACPI 6.4, section 5.2.9).
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1B_CNT_BLK: usize = 68;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+/// FADT flags: the reset register is supported.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+/// Generic Address Structure address space ID of system I/O.
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// MADT interrupt controller structure types of the Processor Local APIC and
+/// Processor Local x2APIC Structures.
+const MADT_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_TYPE_LOCAL_X2APIC: u8 = 9;
+/// MADT interrupt controller structure type of the I/O APIC Structure.
+const MADT_TYPE_IOAPIC: u8 = 1;
+/// Local APIC flags: the processor is enabled, or can be onlined later.
+const MADT_APIC_ENABLED: u32 = 1 << 0;
+const MADT_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// MADT interrupt controller structure type of the Multiprocessor Wakeup Structure.
+const MADT_TYPE_MP_WAKEUP: u8 = 0x10;
+
+/// DMAR offset of the remapping structures (VT-d 4.1, section 8.1).
+const DMAR_STRUCTURES: usize = 48;
+/// DMAR remapping structure type of the DMA Remapping Hardware Unit Definition.
+const DMAR_TYPE_DRHD: u16 = 0;
+/// DRHD flags: the unit covers all PCI devices of its segment not covered by
+/// other units.
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+/// DRHD device scope type of an I/O APIC.
+const DMAR_SCOPE_IOAPIC: u8 = 3;
+
+/// MCFG offset of the configuration space base address allocation
+/// structures (PCI Firmware 3.3, section 4.1.2).
+const MCFG_ALLOCATIONS: usize = 44;
+
+/// Mailbox command to wake up an AP.
+const MP_WAKEUP_COMMAND_WAKEUP: u16 = 1;
+
+/// Time to wait for the firmware to acknowledge a mailbox command.
+const MP_WAKEUP_TIMEOUT_US: u64 = 100 * 1000; // 100ms
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Fields below are only valid since ACPI 2.0 (revision >= 2).
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+struct MadtEntryHeader {
+    entry_type: u8,
+    length: u8,
+}
+
+/// Multiprocessor Wakeup Structure in MADT (ACPI 6.4, section 5.2.12.19).
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct MadtMpWakeup {
+    header: MadtEntryHeader,
+    mailbox_version: u16,
+    reserved: u32,
+    mailbox_address: u64,
+}
+
+/// DMA Remapping Hardware Unit Definition (VT-d 4.1, section 8.3).
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct DmarDrhd {
+    entry_type: u16,
+    length: u16,
+    flags: u8,
+    /// Size of the register set, 2^N pages (0 for 1 page with older tables).
+    size: u8,
+    segment: u16,
+    register_base: u64,
+}
+
+/// Configuration space base address allocation structure of the MCFG (PCI
+/// Firmware 3.3, section 4.1.2).
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct McfgAllocation {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+/// Multiprocessor Wakeup Mailbox (ACPI 6.4, section 5.2.12.19).
+#[allow(dead_code)]
+#[repr(C)]
+struct MpWakeupMailbox {
+    command: u16,
+    reserved: u16,
+    apic_id: u32,
+    wakeup_vector: u64,
+}
+
+/// Power management ports found in the FADT.
+#[derive(Debug, Default)]
+pub(super) struct AcpiPowerPorts {
+    /// PM1a and PM1b control blocks, 0 if absent.
+    pub pm1_cnt: [u16; 2],
+    /// Port of the reset register and the value resetting the system, if the
+    /// reset register is supported and in the I/O space.
+    pub reset: Option<(u16, u8)>,
+}
+
+/// A DMA remapping hardware unit (VT-d) found in the DMAR.
+#[derive(Debug)]
+pub(super) struct DmarUnit {
+    /// Physical address of the register set.
+    pub reg_base: PhysAddr,
+    /// Size of the register set, in bytes.
+    pub reg_size: usize,
+    /// PCI segment of the devices behind the unit.
+    pub segment: u16,
+    /// Whether the unit covers all the devices of its segment not covered
+    /// by other units.
+    pub include_all: bool,
+    /// I/O APIC IDs and requester IDs of the I/O APICs behind the unit.
+    pub ioapics: Vec<(u8, u16)>,
+}
+
+/// An I/O APIC found in the MADT.
+#[derive(Debug)]
+pub(super) struct IoApicInfo {
+    pub id: u8,
+    /// Physical address of the registers.
+    pub base: PhysAddr,
+}
+
+/// A memory mapped configuration space (ECAM) range found in the MCFG.
+#[derive(Debug)]
+pub(super) struct EcamRange {
+    /// Physical address of the configuration space of bus 0.
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+static MP_WAKEUP_MAILBOX: Once<PhysAddr> = Once::new();
+static POWER_PORTS: Once<AcpiPowerPorts> = Once::new();
+static DMAR_UNITS: Once<Vec<DmarUnit>> = Once::new();
+static MADT_APIC_IDS: Once<Vec<u32>> = Once::new();
+static MADT_IOAPICS: Once<Vec<IoApicInfo>> = Once::new();
+static ECAM_RANGES: Once<Vec<EcamRange>> = Once::new();
+
+/// Maps the physical range into the hypervisor page table if not mapped yet.
+fn map_phys(paddr: PhysAddr, size: usize) -> HvResult<usize> {
+    let mut hv_pt = hv_page_table().write();
+    let mut page_paddr = align_down(paddr);
+    while page_paddr < align_up(paddr + size) {
+        let vaddr = phys_to_virt(page_paddr);
+        if hv_pt.page_table().query(vaddr).is_err() {
+            hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+                vaddr,
+                page_paddr,
+                PAGE_SIZE,
+                MemFlags::READ | MemFlags::WRITE,
+            ))?;
+        }
+        page_paddr += PAGE_SIZE;
+    }
+    Ok(phys_to_virt(paddr))
+}
+
+fn checksum_ok(vaddr: usize, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(vaddr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Maps a whole system description table and verifies its length and
+/// checksum. The returned table is at least as long as its header, so that
+/// the walks of its entries do not underflow.
+fn map_sdt(paddr: PhysAddr) -> HvResult<&'static SdtHeader> {
+    let vaddr = map_phys(paddr, size_of::<SdtHeader>())?;
+    let len = unsafe { &*(vaddr as *const SdtHeader) }.length as usize;
+    if len < size_of::<SdtHeader>() {
+        return hv_result_err!(EINVAL, "ACPI table at {:#x} shorter than its header", paddr);
+    }
+    let vaddr = map_phys(paddr, len)?;
+    if !checksum_ok(vaddr, len) {
+        return hv_result_err!(EINVAL, "Invalid ACPI table checksum at {:#x}", paddr);
+    }
+    Ok(unsafe { &*(vaddr as *const SdtHeader) })
+}
+
+fn find_sdt(rsdp_paddr: PhysAddr, signature: &[u8; 4]) -> HvResult<Option<&'static SdtHeader>> {
+    let rsdp_vaddr = map_phys(rsdp_paddr, size_of::<Rsdp>())?;
+    let rsdp = unsafe { &*(rsdp_vaddr as *const Rsdp) };
+    if &rsdp.signature != RSDP_SIGNATURE || !checksum_ok(rsdp_vaddr, 20) {
+        return hv_result_err!(EINVAL, "Invalid ACPI RSDP");
+    }
+
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (map_sdt(rsdp.xsdt_address as _)?, 8)
+    } else {
+        (map_sdt(rsdp.rsdt_address as _)?, 4)
+    };
+    let entries_start = root as *const _ as usize + size_of::<SdtHeader>();
+    let entry_count = (root.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for i in 0..entry_count {
+        let entry_ptr = entries_start + i * entry_size;
+        let paddr = unsafe {
+            if entry_size == 8 {
+                core::ptr::read_unaligned(entry_ptr as *const u64) as PhysAddr
+            } else {
+                core::ptr::read_unaligned(entry_ptr as *const u32) as PhysAddr
+            }
+        };
+        let sdt = map_sdt(paddr)?;
+        if &sdt.signature == signature {
+            return Ok(Some(sdt));
+        }
+    }
+    Ok(None)
+}
+
+fn find_mp_wakeup_mailbox(madt: &SdtHeader) -> Option<PhysAddr> {
+    // MADT: header, local interrupt controller address (u32), flags (u32), entries.
+    let madt_start = madt as *const _ as usize;
+    let mut entry = madt_start + size_of::<SdtHeader>() + 8;
+    let madt_end = madt_start + madt.length as usize;
+    while entry + size_of::<MadtEntryHeader>() <= madt_end {
+        let header = unsafe { &*(entry as *const MadtEntryHeader) };
+        if header.length == 0 {
+            break;
+        }
+        if header.entry_type == MADT_TYPE_MP_WAKEUP
+            && header.length as usize >= size_of::<MadtMpWakeup>()
+        {
+            let wakeup = unsafe { &*(entry as *const MadtMpWakeup) };
+            return Some(wakeup.mailbox_address as _);
+        }
+        entry += header.length as usize;
+    }
+    None
+}
+
+/// Returns the I/O APICs in the device scopes `[start, end)` of a DRHD, with
+/// their requester IDs. Only scopes directly on their start bus are
+/// supported.
+fn parse_drhd_ioapics(mut scope: usize, end: usize) -> Vec<(u8, u16)> {
+    let mut ioapics = Vec::new();
+    // Device scopes: type, length, flags, reserved, enumeration ID, start
+    // bus, then (device, function) path entries.
+    while scope + 6 <= end {
+        let bytes = unsafe { core::slice::from_raw_parts(scope as *const u8, end - scope) };
+        let length = bytes[1] as usize;
+        if length < 6 || length > bytes.len() {
+            break;
+        }
+        if bytes[0] == DMAR_SCOPE_IOAPIC && length == 8 {
+            let sid = (bytes[5] as u16) << 8 | (bytes[6] as u16) << 3 | bytes[7] as u16;
+            ioapics.push((bytes[4], sid));
+        }
+        scope += length;
+    }
+    ioapics
+}
+
+fn parse_dmar(dmar: &SdtHeader) -> Vec<DmarUnit> {
+    let dmar_start = dmar as *const _ as usize;
+    let dmar_end = dmar_start + dmar.length as usize;
+    let mut entry = dmar_start + DMAR_STRUCTURES;
+    let mut units = Vec::new();
+    // Remapping structures have a 16-bit type and a 16-bit length.
+    while entry + 4 <= dmar_end {
+        let (entry_type, length) = unsafe {
+            let ptr = entry as *const u16;
+            (ptr.read_unaligned(), ptr.add(1).read_unaligned() as usize)
+        };
+        if length == 0 {
+            break;
+        }
+        if entry_type == DMAR_TYPE_DRHD && length >= size_of::<DmarDrhd>() {
+            let drhd = unsafe { (entry as *const DmarDrhd).read_unaligned() };
+            units.push(DmarUnit {
+                reg_base: drhd.register_base as _,
+                reg_size: PAGE_SIZE << (drhd.size & 0xf),
+                segment: drhd.segment,
+                include_all: drhd.flags & DRHD_INCLUDE_PCI_ALL != 0,
+                ioapics: parse_drhd_ioapics(entry + size_of::<DmarDrhd>(), entry + length),
+            });
+        }
+        entry += length;
+    }
+    units
+}
+
+fn parse_mcfg(mcfg: &SdtHeader) -> Vec<EcamRange> {
+    let mcfg_start = mcfg as *const _ as usize;
+    let mcfg_end = mcfg_start + mcfg.length as usize;
+    let mut entry = mcfg_start + MCFG_ALLOCATIONS;
+    let mut ranges = Vec::new();
+    while entry + size_of::<McfgAllocation>() <= mcfg_end {
+        let alloc = unsafe { (entry as *const McfgAllocation).read_unaligned() };
+        ranges.push(EcamRange {
+            base: alloc.base_address as _,
+            segment: alloc.segment,
+            start_bus: alloc.start_bus,
+            end_bus: alloc.end_bus,
+        });
+        entry += size_of::<McfgAllocation>();
+    }
+    ranges
+}
+
+/// Returns the I/O APICs of the MADT.
+fn parse_madt_ioapics(madt: &SdtHeader) -> Vec<IoApicInfo> {
+    let madt_start = madt as *const _ as usize;
+    let mut entry = madt_start + size_of::<SdtHeader>() + 8;
+    let madt_end = madt_start + madt.length as usize;
+    let mut ioapics = Vec::new();
+    while entry + size_of::<MadtEntryHeader>() <= madt_end {
+        let header = unsafe { &*(entry as *const MadtEntryHeader) };
+        if header.length == 0 {
+            break;
+        }
+        // I/O APIC ID (u8), reserved (u8), address (u32), GSI base (u32).
+        if header.entry_type == MADT_TYPE_IOAPIC && header.length >= 12 {
+            let id = unsafe { *((entry + 2) as *const u8) };
+            let base = unsafe { ((entry + 4) as *const u32).read_unaligned() };
+            ioapics.push(IoApicInfo {
+                id,
+                base: base as _,
+            });
+        }
+        entry += header.length as usize;
+    }
+    ioapics
+}
+
+/// Returns the APIC IDs of the usable processors of the MADT.
+fn parse_madt_apic_ids(madt: &SdtHeader) -> Vec<u32> {
+    let madt_start = madt as *const _ as usize;
+    let mut entry = madt_start + size_of::<SdtHeader>() + 8;
+    let madt_end = madt_start + madt.length as usize;
+    let mut apic_ids = Vec::new();
+    while entry + size_of::<MadtEntryHeader>() <= madt_end {
+        let header = unsafe { &*(entry as *const MadtEntryHeader) };
+        if header.length == 0 {
+            break;
+        }
+        let field = |offset: usize| unsafe { ((entry + offset) as *const u32).read_unaligned() };
+        let cpu = match (header.entry_type, header.length) {
+            (MADT_TYPE_LOCAL_APIC, 8..) => Some(((field(2) >> 8) & 0xff, field(4))),
+            (MADT_TYPE_LOCAL_X2APIC, 16..) => Some((field(4), field(8))),
+            _ => None,
+        };
+        if let Some((apic_id, flags)) = cpu {
+            if flags & (MADT_APIC_ENABLED | MADT_APIC_ONLINE_CAPABLE) != 0
+                && !apic_ids.contains(&apic_id)
+            {
+                apic_ids.push(apic_id);
+            }
+        }
+        entry += header.length as usize;
+    }
+    apic_ids
+}
+
+/// Reads the field at `offset` of `sdt`, if within the table.
+fn sdt_field<T: Copy>(sdt: &SdtHeader, offset: usize) -> Option<T> {
+    if offset + size_of::<T>() > sdt.length as usize {
+        return None;
+    }
+    let ptr = (sdt as *const _ as usize + offset) as *const T;
+    Some(unsafe { ptr.read_unaligned() })
+}
+
+fn parse_fadt(fadt: &SdtHeader) -> AcpiPowerPorts {
+    let pm1_cnt = [FADT_PM1A_CNT_BLK, FADT_PM1B_CNT_BLK]
+        .map(|offset| sdt_field::<u32>(fadt, offset).unwrap_or(0) as u16);
+    let flags = sdt_field::<u32>(fadt, FADT_FLAGS).unwrap_or(0);
+    let reset = match (
+        sdt_field::<u8>(fadt, FADT_RESET_REG),
+        sdt_field::<u64>(fadt, FADT_RESET_REG + 4),
+        sdt_field::<u8>(fadt, FADT_RESET_VALUE),
+    ) {
+        (Some(GAS_SYSTEM_IO), Some(port), Some(value)) if flags & FADT_RESET_REG_SUP != 0 => {
+            Some((port as u16, value))
+        }
+        (Some(space_id), _, _) if flags & FADT_RESET_REG_SUP != 0 => {
+            info!(
+                "ACPI reset register in address space {} is not intercepted.",
+                space_id
+            );
+            None
+        }
+        _ => None,
+    };
+    AcpiPowerPorts { pm1_cnt, reset }
+}
+
+pub(super) fn init() -> HvResult {
+    let rsdp_paddr = HvSystemConfig::get().acpi_rsdp as PhysAddr;
+    if rsdp_paddr == 0 {
+        info!("No ACPI RSDP provided.");
+        return Ok(());
+    }
+    if let Some(madt) = find_sdt(rsdp_paddr, MADT_SIGNATURE)? {
+        let apic_ids = parse_madt_apic_ids(madt);
+        info!("ACPI MADT processors: APIC IDs {:?}", apic_ids);
+        MADT_APIC_IDS.call_once(|| apic_ids);
+        MADT_IOAPICS.call_once(|| parse_madt_ioapics(madt));
+        if let Some(mailbox_paddr) = find_mp_wakeup_mailbox(madt) {
+            info!("Found ACPI MP wakeup mailbox at {:#x}.", mailbox_paddr);
+            map_phys(mailbox_paddr, PAGE_SIZE)?;
+            MP_WAKEUP_MAILBOX.call_once(|| mailbox_paddr);
+        }
+    }
+    if let Some(fadt) = find_sdt(rsdp_paddr, FADT_SIGNATURE)? {
+        let ports = parse_fadt(fadt);
+        info!("ACPI power management ports: {:#x?}", ports);
+        POWER_PORTS.call_once(|| ports);
+    }
+    if let Some(dmar) = find_sdt(rsdp_paddr, DMAR_SIGNATURE)? {
+        let units = parse_dmar(dmar);
+        info!("ACPI DMA remapping units: {:#x?}", units);
+        DMAR_UNITS.call_once(|| units);
+    }
+    if let Some(mcfg) = find_sdt(rsdp_paddr, MCFG_SIGNATURE)? {
+        let ranges = parse_mcfg(mcfg);
+        info!("ACPI PCI ECAM ranges: {:#x?}", ranges);
+        ECAM_RANGES.call_once(|| ranges);
+    }
+    Ok(())
+}
+
+/// Power management ports of the FADT, if found.
+pub(super) fn power_ports() -> Option<&'static AcpiPowerPorts> {
+    POWER_PORTS.get()
+}
+
+/// APIC IDs of the enabled or online capable processors of the MADT, if
+/// found.
+pub(super) fn madt_apic_ids() -> Option<&'static [u32]> {
+    MADT_APIC_IDS.get().map(|ids| ids.as_slice())
+}
+
+/// I/O APICs of the MADT, empty if there is none.
+pub(super) fn madt_ioapics() -> &'static [IoApicInfo] {
+    MADT_IOAPICS.get().map_or(&[], |ioapics| ioapics.as_slice())
+}
+
+/// DMA remapping units of the DMAR, empty if there is none.
+pub(super) fn dmar_units() -> &'static [DmarUnit] {
+    DMAR_UNITS.get().map_or(&[], |units| units.as_slice())
+}
+
+/// Memory mapped configuration space ranges of the MCFG, empty if there is
+/// none.
+pub(super) fn ecam_ranges() -> &'static [EcamRange] {
+    ECAM_RANGES.get().map_or(&[], |ranges| ranges.as_slice())
+}
+
+/// Whether APs should be started through the ACPI MP wakeup mailbox instead
+/// of INIT-SIPI-SIPI.
+pub(super) fn has_mp_wakeup_mailbox() -> bool {
+    MP_WAKEUP_MAILBOX.get().is_some()
+}
+
+/// Wakes up the AP by the ACPI MP wakeup mailbox. The AP jumps to
+/// `wakeup_vector` in 64-bit mode with identity-mapped paging.
+pub(super) unsafe fn mp_wakeup(apic_id: u32, wakeup_vector: PhysAddr) -> HvResult {
+    let mailbox_paddr = match MP_WAKEUP_MAILBOX.get() {
+        Some(paddr) => *paddr,
+        None => return hv_result_err!(ENODEV),
+    };
+    let mailbox = phys_to_virt(mailbox_paddr) as *mut MpWakeupMailbox;
+    let command = core::ptr::addr_of_mut!((*mailbox).command);
+    if command.read_volatile() != 0 {
+        return hv_result_err!(EBUSY, "ACPI MP wakeup mailbox is busy");
+    }
+
+    info!("Waking up RT cpu {} by ACPI mailbox...", apic_id);
+    core::ptr::addr_of_mut!((*mailbox).apic_id).write_volatile(apic_id);
+    core::ptr::addr_of_mut!((*mailbox).wakeup_vector).write_volatile(wakeup_vector as u64);
+    fence(Ordering::SeqCst);
+    command.write_volatile(MP_WAKEUP_COMMAND_WAKEUP);
+
+    // The firmware clears the command field once the AP has received it.
+    let cycle_end = cpu::current_cycle() + MP_WAKEUP_TIMEOUT_US * cpu::frequency() as u64;
+    while command.read_volatile() != 0 {
+        if cpu::current_cycle() >= cycle_end {
+            return hv_result_err!(
+                EIO,
+                format!("APIC {} was not acknowledged by the ACPI mailbox", apic_id)
+            );
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
@@ -2,17 +2,71 @@ mod npt;
 mod vcpu;
 mod vmexit;
 
+use bit_field::BitField;
 use libvmm::svm::flags::{VmCr, VmCrFlags};
 
+use crate::arch::cpuid::cpuid;
+use crate::arch::vmm::InterceptFlags;
+use crate::config::{HvSystemConfig, InterceptProfile};
 use crate::error::HvResult;
 
 pub use npt::NestedPageTable;
 pub use vcpu::Vcpu;
 
 pub fn check_hypervisor_feature() -> HvResult {
+    // CPUID Fn8000_0001_ECX[SVM].
+    if !cpuid!(0x8000_0001).ecx.get_bit(2) {
+        return hv_result_err!(ENODEV, "Feature SVM not supported!");
+    }
     if VmCr::read().contains(VmCrFlags::SVMDIS) {
         return hv_result_err!(ENODEV, "SVM disabled by BIOS!");
     }
-    // TODO: check cpuid
+    // CPUID Fn8000_000A_EDX: nested paging, and flushing the TLB by ASID
+    // with `VmcbTlbControl::FlushAsid`.
+    let features = cpuid!(0x8000_000a).edx;
+    if !features.get_bit(0) {
+        return hv_result_err!(ENODEV, "Nested paging not supported!");
+    }
+    if !features.get_bit(6) {
+        return hv_result_err!(ENODEV, "TLB flush by ASID not supported!");
+    }
     Ok(())
 }
+
+/// Whether the guest executes CPUID without VM exits. This needs a cell hiding
+/// the hypervisor from CPUID entirely, with the physical address width of
+/// the CPU, leaving only the SVM feature to hide,
+/// which the `CPUID_EXT_FEATURES` override MSR masks in hardware. Unlike the
+/// CPUID faulting of Intel CPUs, which does not apply to VMX guests, the
+/// override masks bits but can not filter leaves.
+fn cpuid_passthrough(config: &HvSystemConfig) -> bool {
+    let eax = cpuid!(1).eax;
+    let family = eax.get_bits(8..12) + eax.get_bits(20..28);
+    let cell = config.root_cell.config();
+    cell.cpuid_policy().is_all() && cell.phys_addr_bits() == 0 && family >= 0x10
+}
+
+/// Intercepts of the configured profile. CPUID is intercepted to hide SVM
+/// from the guest, unless `cpuid_passthrough()`, and MSR accesses are never
+/// intercepted. Legacy PIC ports
+/// are always intercepted, as well as PCI configuration ports if PCI devices
+/// are assigned to the RTOS.
+pub fn intercepts() -> InterceptFlags {
+    let config = HvSystemConfig::get();
+    let flags = match config.intercept_profile() {
+        InterceptProfile::Default => InterceptFlags::all() - InterceptFlags::MSR,
+        InterceptProfile::LowLatency => {
+            InterceptFlags::CPUID | InterceptFlags::LEGACY_IRQ | InterceptFlags::RESET
+        }
+    };
+    let flags = if cpuid_passthrough(config) {
+        flags - InterceptFlags::CPUID
+    } else {
+        flags
+    };
+    if config.rtos_pci_devices().is_empty() {
+        flags
+    } else {
+        flags | InterceptFlags::PCI_CONFIG
+    }
+}
@@ -1,6 +1,8 @@
+use crate::arch::nested_tlb;
 use crate::arch::page_table::PTEntry;
+use crate::hal::NestedPaging;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
-use crate::memory::{GenericPTE, Level4PageTable, MemFlags, PagingInstr};
+use crate::memory::{GenericPTE, GenericPageTableImmut, Level4PageTable, MemFlags, PagingInstr};
 
 #[repr(transparent)]
 #[derive(Clone, Debug)]
@@ -37,13 +39,35 @@ impl GenericPTE for NPTEntry {
     fn clear(&mut self) {
         self.0.clear()
     }
+    fn test_and_clear_accessed(&mut self) -> bool {
+        self.0.test_and_clear_accessed()
+    }
 }
 
 pub struct NPTInstr;
 
 impl PagingInstr for NPTInstr {
     unsafe fn activate(_root_paddr: HostPhysAddr) {}
-    fn flush(_vaddr: Option<usize>) {}
+
+    /// The TLB is flushed on VMRUN, see `nested_tlb` for the CPUs running
+    /// their guest meanwhile.
+    fn flush(_vaddr: Option<usize>) {
+        nested_tlb::flush();
+    }
+
+    fn flush_generation() -> u64 {
+        nested_tlb::generation()
+    }
+
+    fn is_flushed(generation: u64) -> bool {
+        nested_tlb::is_flushed(generation)
+    }
 }
 
 pub type NestedPageTable = Level4PageTable<GuestPhysAddr, NPTEntry, NPTInstr>;
+
+impl NestedPaging for NestedPageTable {
+    fn nested_root(&self) -> u64 {
+        self.root_paddr() as u64 | crate::arch::mem_encrypt::encrypt_mask()
+    }
+}
@@ -3,7 +3,8 @@ use core::fmt::{Debug, Formatter, Result};
 
 use libvmm::msr::Msr;
 use libvmm::svm::flags::{InterruptType, VmcbCleanBits, VmcbIntInfo, VmcbTlbControl};
-use libvmm::svm::{vmcb::VmcbSegment, SvmExitCode, SvmIntercept, Vmcb};
+use libvmm::svm::vmcb::{VmcbSegment, VmcbStateSaveArea};
+use libvmm::svm::{SvmExitCode, SvmIntercept, Vmcb};
 use x86::{segmentation, segmentation::SegmentSelector, task};
 use x86_64::addr::VirtAddr;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
@@ -11,14 +12,41 @@ use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::rflags::RFlags;
 use x86_64::structures::DescriptorTablePointer;
 
-use crate::arch::segmentation::Segment;
-use crate::arch::vmm::VcpuAccessGuestState;
+use crate::arch::segmentation::{Segment, SegmentAccessRights};
+use crate::arch::vmm::{efer_after_cr0_write, GuestMsr, GuestSegment};
+use crate::arch::vmm::{InterceptFlags, VcpuAccessGuestState};
 use crate::arch::{GeneralRegisters, GuestPageTableImmut, LinuxContext};
 use crate::cell::Cell;
 use crate::error::HvResult;
-use crate::memory::{addr::virt_to_phys, Frame, GenericPageTableImmut};
+use crate::hal::{NestedPaging, Vcpu as HalVcpu};
+use crate::memory::{addr::virt_to_phys, AlignedPage, Frame, GenericPageTableImmut};
 use crate::percpu::PerCpu;
 
+/// I/O permissions map, one bit per port. The third page covers the ports
+/// spanned by accesses starting near 0xFFFF.
+/// (AMD APM Volume 2, Section 15.10.1, I/O Permissions Map)
+struct IoPermissionMap([AlignedPage; 3]);
+
+impl IoPermissionMap {
+    /// A map intercepting `ports`.
+    fn new(ports: impl Iterator<Item = u16>) -> Self {
+        let mut map = Self([AlignedPage::new(), AlignedPage::new(), AlignedPage::new()]);
+        for port in ports.map(|p| p as usize) {
+            map.0[port / 8 / 4096][port / 8 % 4096] |= 1 << (port % 8);
+        }
+        map
+    }
+
+    fn paddr(&self) -> usize {
+        virt_to_phys(self.0.as_ptr() as usize)
+    }
+}
+
+lazy_static! {
+    static ref IOPM: IoPermissionMap =
+        IoPermissionMap::new(crate::arch::pio::intercepted_ports(super::intercepts()));
+}
+
 #[repr(C)]
 pub struct Vcpu {
     /// Save guest general registers when handle VM exits.
@@ -29,12 +57,18 @@ pub struct Vcpu {
     host_stack_top: u64,
     /// host state-save area.
     host_save_area: Frame,
+    /// VMSAVE target to read the live VMLOAD state of the guest.
+    vmsave_area: Frame,
     /// Virtual machine control block.
     pub(super) vmcb: Vmcb,
+    /// `CPUID_EXT_FEATURES` to restore on exit, if CPUID is not intercepted.
+    saved_cpuid_ext_features: Option<u64>,
+    /// NPT generation whose translations are in the TLB of this CPU.
+    npt_generation: u64,
 }
 
-impl Vcpu {
-    pub fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self> {
+impl HalVcpu for Vcpu {
+    fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self> {
         super::check_hypervisor_feature()?;
 
         // make sure all perf counters are off
@@ -60,6 +94,16 @@ impl Vcpu {
         unsafe { Msr::VM_HSAVE_PA.write(host_save_area.start_paddr() as _) };
         info!("successed to turn on SVM.");
 
+        let saved_cpuid_ext_features = if super::intercepts().contains(InterceptFlags::CPUID) {
+            None
+        } else {
+            /// CPUID 0x8000_0001 ECX bit 2 (SVM), in the high half.
+            const CPUID_EXT_FEATURES_SVM: u64 = 1 << 34;
+            let features = Msr::CPUID_EXT_FEATURES.read();
+            unsafe { Msr::CPUID_EXT_FEATURES.write(features & !CPUID_EXT_FEATURES_SVM) };
+            Some(features)
+        };
+
         // bring CR0 and CR4 into well-defined states.
         unsafe {
             Cr0::write(super::super::HOST_CR0);
@@ -72,14 +116,18 @@ impl Vcpu {
             host_tp: cpu_data as *const _ as _,
             host_stack_top: cpu_data.stack_top() as _,
             host_save_area,
+            vmsave_area: Frame::new_zero()?,
             vmcb: Default::default(),
+            saved_cpuid_ext_features,
+            npt_generation: 0,
         };
         ret.vmcb_setup(linux, cell);
 
         Ok(ret)
     }
 
-    pub fn enter(&mut self, linux: &LinuxContext) -> HvResult {
+    fn enter(&mut self, linux: &LinuxContext) -> HvResult {
+        self.sync_nested_tlb()?;
         let vmcb_paddr = virt_to_phys(&self.vmcb as *const _ as usize);
         let regs = self.regs_mut();
         regs.rax = vmcb_paddr as _;
@@ -103,18 +151,22 @@ impl Vcpu {
         }
     }
 
-    pub fn exit(&self, linux: &mut LinuxContext) -> HvResult {
+    fn exit(&self, linux: &mut LinuxContext) -> HvResult {
+        crate::arch::nested_tlb::leave_guest(PerCpu::current().id);
         self.load_vmcb_guest(linux);
         unsafe {
             asm!("stgi");
             Efer::write(Efer::read() - EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE);
             Msr::VM_HSAVE_PA.write(0);
+            if let Some(features) = self.saved_cpuid_ext_features {
+                Msr::CPUID_EXT_FEATURES.write(features);
+            }
         }
         info!("successed to turn off SVM.");
         Ok(())
     }
 
-    pub fn inject_fault(&mut self) -> HvResult {
+    fn inject_fault(&mut self) -> HvResult {
         self.vmcb.inject_event(
             VmcbIntInfo::from(
                 InterruptType::Exception,
@@ -125,22 +177,35 @@ impl Vcpu {
         Ok(())
     }
 
-    pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
-        self.vmcb.save.rip += instr_len as u64;
-        Ok(())
-    }
-
-    pub fn guest_is_privileged(&self) -> bool {
+    fn guest_is_privileged(&self) -> bool {
         self.vmcb.save.cpl == 0
     }
 
-    pub fn in_hypercall(&self) -> bool {
+    fn in_hypercall(&self) -> bool {
         matches!(
             self.vmcb.control.exit_code.try_into(),
             Ok(SvmExitCode::VMMCALL)
         )
     }
 
+    fn num_frames(&self) -> usize {
+        2 // host state-save and VMSAVE areas, the VMCB is in the per-CPU data
+    }
+}
+
+impl Vcpu {
+    pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
+        self.vmcb.save.rip += instr_len as u64;
+        Ok(())
+    }
+
+    /// Records the NPT generation the guest resumes with. `tlb_control` flushes
+    /// the translations of the guest ASID on every VMRUN.
+    pub fn sync_nested_tlb(&mut self) -> HvResult {
+        let cpu_id = PerCpu::current().id;
+        crate::arch::nested_tlb::resume_guest(cpu_id, &mut self.npt_generation, || Ok(()))
+    }
+
     pub fn guest_page_table(&self) -> GuestPageTableImmut {
         use crate::memory::addr::align_down;
         unsafe { GuestPageTableImmut::from_root(align_down(self.vmcb.save.cr3 as _)) }
@@ -160,9 +225,29 @@ impl Vcpu {
         vmcb_seg.base = seg.base;
     }
 
+    fn vmcb_segment(vmcb_seg: &VmcbSegment) -> Segment {
+        Segment {
+            selector: SegmentSelector::from_raw(vmcb_seg.selector),
+            base: vmcb_seg.base,
+            limit: vmcb_seg.limit,
+            access_rights: SegmentAccessRights::from_svm_segment_attributes(vmcb_seg.attr),
+        }
+    }
+
+    /// Returns the VMLOAD state (FS, GS, TR, LDTR and the syscall MSRs),
+    /// which is loaded once in `enter` and then stays live in the CPU, also
+    /// during VM exits. Its GS base is the hypervisor one, the guest one is in
+    /// the VMCB. Only called in VM exits of this vCPU.
+    fn vmload_state(&self) -> &VmcbStateSaveArea {
+        unsafe {
+            asm!("vmsave rax", in("rax") self.vmsave_area.start_paddr());
+            &(*(self.vmsave_area.as_ptr() as *const Vmcb)).save
+        }
+    }
+
     fn vmcb_setup(&mut self, linux: &LinuxContext, cell: &Cell) {
-        self.set_cr(0, linux.cr0.bits());
         self.set_cr(4, linux.cr4.bits());
+        self.set_cr(0, linux.cr0.bits());
         self.set_cr(3, linux.cr3);
 
         let vmcb = &mut self.vmcb.save;
@@ -199,11 +284,22 @@ impl Vcpu {
         vmcb.np_enable = 1;
         vmcb.guest_asid = 1; // No more than one guest owns the CPU
         vmcb.clean_bits = VmcbCleanBits::empty(); // Explicitly mark all of the state as new
-        vmcb.nest_cr3 = cell.gpm.page_table().root_paddr() as _;
+        vmcb.nest_cr3 = cell.gpm.read().page_table().nested_root();
         vmcb.tlb_control = VmcbTlbControl::FlushAsid as _;
 
-        self.vmcb.set_intercept(SvmIntercept::NMI);
-        self.vmcb.set_intercept(SvmIntercept::CPUID);
+        let intercepts = super::intercepts();
+        if intercepts.contains(InterceptFlags::NMI) {
+            self.vmcb.set_intercept(SvmIntercept::NMI);
+        }
+        if intercepts.intersects(
+            InterceptFlags::PCI_CONFIG | InterceptFlags::LEGACY_IRQ | InterceptFlags::RESET,
+        ) {
+            self.vmcb.control.iopm_base_pa = IOPM.paddr() as _;
+            self.vmcb.set_intercept(SvmIntercept::IOIO_PROT);
+        }
+        if intercepts.contains(InterceptFlags::CPUID) {
+            self.vmcb.set_intercept(SvmIntercept::CPUID);
+        }
         self.vmcb.set_intercept(SvmIntercept::SHUTDOWN);
         self.vmcb.set_intercept(SvmIntercept::VMRUN);
         self.vmcb.set_intercept(SvmIntercept::VMMCALL);
@@ -259,6 +355,10 @@ impl VcpuAccessGuestState for Vcpu {
         self.vmcb.save.rsp
     }
 
+    fn set_instr_pointer(&mut self, ip: u64) {
+        self.vmcb.save.rip = ip
+    }
+
     fn set_stack_pointer(&mut self, sp: u64) {
         self.vmcb.save.rsp = sp
     }
@@ -267,6 +367,10 @@ impl VcpuAccessGuestState for Vcpu {
         self.vmcb.save.rflags
     }
 
+    fn set_rflags(&mut self, rflags: u64) {
+        self.vmcb.save.rflags = rflags
+    }
+
     fn fs_base(&self) -> u64 {
         Msr::IA32_FS_BASE.read()
     }
@@ -286,12 +390,62 @@ impl VcpuAccessGuestState for Vcpu {
 
     fn set_cr(&mut self, cr_idx: usize, val: u64) {
         match cr_idx {
-            0 => self.vmcb.save.cr0 = val & !Cr0Flags::NOT_WRITE_THROUGH.bits(),
+            0 => {
+                // Keep EFER.LMA consistent with CR0.PG. A write raising #GP
+                // leaves CR0 unchanged.
+                let (cr4, efer) = (self.vmcb.save.cr4, self.vmcb.save.efer);
+                match efer_after_cr0_write(val, cr4, efer) {
+                    Some(efer) => {
+                        self.vmcb.save.cr0 = val & !Cr0Flags::NOT_WRITE_THROUGH.bits();
+                        self.vmcb.save.efer = efer;
+                    }
+                    None => self.inject_fault().unwrap(),
+                }
+            }
             3 => self.vmcb.save.cr3 = val,
             4 => self.vmcb.save.cr4 = val,
             _ => unreachable!(),
         }
     }
+
+    fn segment(&self, seg: GuestSegment) -> Segment {
+        let vmcb = &self.vmcb.save;
+        match seg {
+            GuestSegment::Es => Self::vmcb_segment(&vmcb.es),
+            GuestSegment::Cs => Self::vmcb_segment(&vmcb.cs),
+            GuestSegment::Ss => Self::vmcb_segment(&vmcb.ss),
+            GuestSegment::Ds => Self::vmcb_segment(&vmcb.ds),
+            GuestSegment::Fs => Self::vmcb_segment(&self.vmload_state().fs),
+            GuestSegment::Gs => Segment {
+                base: vmcb.gs.base,
+                ..Self::vmcb_segment(&self.vmload_state().gs)
+            },
+            GuestSegment::Ldtr => Self::vmcb_segment(&self.vmload_state().ldtr),
+            GuestSegment::Tr => Self::vmcb_segment(&self.vmload_state().tr),
+            GuestSegment::Gdtr => Segment {
+                base: vmcb.gdtr.base,
+                limit: vmcb.gdtr.limit,
+                ..Segment::invalid()
+            },
+            GuestSegment::Idtr => Segment {
+                base: vmcb.idtr.base,
+                limit: vmcb.idtr.limit,
+                ..Segment::invalid()
+            },
+        }
+    }
+
+    fn msr(&self, msr: GuestMsr) -> u64 {
+        let vmcb = &self.vmcb.save;
+        match msr {
+            GuestMsr::DebugCtl => vmcb.dbgctl,
+            GuestMsr::Pat => vmcb.g_pat,
+            GuestMsr::Efer => vmcb.efer,
+            GuestMsr::GsBase => vmcb.gs.base,
+            // Part of the VMLOAD state, live in the CPU.
+            _ => msr.msr().read(),
+        }
+    }
 }
 
 impl Debug for Vcpu {
@@ -338,5 +492,7 @@ extern "sysv64" fn vmexit_handler_wrapper(cpu_data: &mut PerCpu) {
     cpu_data.vcpu.vmcb.save.gs.base = guest_tp;
     unsafe { Msr::IA32_GS_BASE.write(cpu_data as *const _ as u64) };
     crate::arch::vmm::vmexit_handler();
+    // The guest GS_BASE may have been updated during the VM exit.
+    let guest_tp = PerCpu::current().vcpu.vmcb.save.gs.base;
     unsafe { Msr::IA32_GS_BASE.write(guest_tp) };
 }
@@ -1,8 +1,10 @@
 use libvmm::svm::flags::VmcbCleanBits;
 use libvmm::svm::{SvmExitCode, VmExitInfo};
 
+use crate::arch::pio::IoInstrInfo;
 use crate::arch::vmm::{VcpuAccessGuestState, VmExit};
 use crate::error::HvResult;
+use crate::hal::Vcpu;
 
 impl VmExit<'_> {
     fn handle_nmi(&mut self) -> HvResult {
@@ -21,6 +23,22 @@ impl VmExit<'_> {
 
     fn handle_nested_page_fault(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let guest_paddr = exit_info.exit_info_2;
+        // (AMD APM Volume 2, Section 15.25.6, Nested versus Guest Page Faults)
+        let (write, fetch) = (
+            exit_info.exit_info_1 & (1 << 1),
+            exit_info.exit_info_1 & (1 << 4),
+        );
+        if crate::integrity::check_violation(
+            guest_paddr as _,
+            write != 0,
+            fetch != 0,
+            exit_info.guest_rip,
+        ) {
+            return hv_result_err!(EPERM, "Access to monitored range");
+        }
+        if let Some(res) = self.handle_mmio(guest_paddr as _) {
+            return res;
+        }
         warn!(
             "#VMEXIT(NPF) @ {:#x} RIP({:#x}, {:#x})",
             guest_paddr, exit_info.guest_rip, exit_info.guest_next_rip,
@@ -28,7 +46,25 @@ impl VmExit<'_> {
         hv_result_err!(ENOSYS)
     }
 
+    fn handle_ioio(&mut self, exit_info: &VmExitInfo) -> HvResult {
+        // (AMD APM Volume 2, Section 15.10.2, IN and OUT Behavior)
+        let info = exit_info.exit_info_1;
+        let size = (info >> 4) & 0b111; // SZ8, SZ16, SZ32: 1, 2, 4
+        let addr_size = (info >> 7) & 0b111; // A16, A32, A64: 1, 2, 4
+        self.handle_io(&IoInstrInfo {
+            port: (info >> 16) as u16,
+            size: size as u8,
+            is_in: info & (1 << 0) != 0,
+            is_string: info & (1 << 2) != 0,
+            is_rep: info & (1 << 3) != 0,
+            addr_size: addr_size as u8 * 2,
+            instr_len: (exit_info.exit_info_2 - exit_info.guest_rip) as u8,
+        })
+    }
+
     pub fn handle_exit(&mut self) -> HvResult {
+        self.exit_reason = self.cpu_data.vcpu.vmcb.control.exit_code as u32;
+        crate::logging::note_exit_reason(self.exit_reason);
         let vcpu = &mut self.cpu_data.vcpu;
         vcpu.regs_mut().rax = vcpu.vmcb.save.rax;
 
@@ -46,12 +82,17 @@ impl VmExit<'_> {
         };
 
         let res = match exit_code {
-            SvmExitCode::INVALID => panic!("VM entry failed: {:#x?}\n{:#x?}", exit_info, vcpu.vmcb),
+            SvmExitCode::INVALID => {
+                error!("VM entry failed: {:#x?}\n{:#x?}", exit_info, vcpu.vmcb);
+                let err = hv_err!(ENOEXEC, "VM entry failed");
+                self.cpu_data.abort_entry(err)
+            }
             SvmExitCode::EXCP(vec) => self.handle_exception(vec, &exit_info),
             SvmExitCode::NMI => self.handle_nmi(),
             SvmExitCode::CPUID => self.handle_cpuid(),
             SvmExitCode::VMMCALL => self.handle_hypercall(),
             SvmExitCode::NPF => self.handle_nested_page_fault(&exit_info),
+            SvmExitCode::IOIO => self.handle_ioio(&exit_info),
             SvmExitCode::MSR => match exit_info.exit_info_1 {
                 0 => self.handle_msr_read(),
                 1 => self.handle_msr_write(),
@@ -62,11 +103,15 @@ impl VmExit<'_> {
                 self.cpu_data.vcpu.inject_fault()?;
                 Ok(())
             }
-            _ => hv_result_err!(ENOSYS),
+            // The next RIP is only reported for intercepted instructions.
+            _ => {
+                let instr_len = exit_info.guest_next_rip.saturating_sub(exit_info.guest_rip);
+                self.handle_unhandled(instr_len as u8)
+            }
         };
 
         let vcpu = &mut self.cpu_data.vcpu;
-        if res.is_err() {
+        if res.is_err() && crate::logging::is_boosted() {
             warn!(
                 "#VMEXIT handler returned {:?}:\n\
                 {:#x?}\n\n\
@@ -74,6 +119,8 @@ impl VmExit<'_> {
                 {:#x?}",
                 res, exit_info, vcpu,
             );
+        } else if res.is_err() {
+            warn!("#VMEXIT {:?} handler returned {:?}", exit_code, res);
         }
         vcpu.vmcb.save.rax = vcpu.regs().rax;
         res
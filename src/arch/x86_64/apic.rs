@@ -5,11 +5,43 @@ use x86::apic::{x2apic::X2APIC, xapic::XAPIC, ApicControl, ApicId};
 use alloc::sync::Arc;
 
 use crate::error::HvResult;
+use crate::hal::LocalIrqChip;
 use crate::memory::addr::{phys_to_virt, PhysAddr};
 use crate::memory::{hv_page_table, MemFlags, MemoryRegion, PAGE_SIZE};
 
 const APIC_BASE: PhysAddr = 0xFEE0_0000;
-const MAX_APIC_ID: u32 = 254;
+/// Max APIC ID of a CPU managed by the hypervisor (0xff is the xAPIC
+/// broadcast), sizing the APIC ID to CPU ID map. CPUs with larger x2APIC IDs
+/// are rejected when they enter.
+pub(super) const MAX_APIC_ID: u32 = 254;
+
+/// Base MSR index of x2APIC registers, each xAPIC MMIO offset `off` maps to
+/// MSR `X2APIC_MSR_BASE + (off >> 4)`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Error Status Register (ESR) offset.
+const APIC_ESR: u32 = 0x280;
+/// LVT Timer Register offset.
+const APIC_LVT_TIMER: u32 = 0x320;
+/// Interrupt Command Register (ICR) offsets.
+const APIC_ICR_LOW: u32 = 0x300;
+const APIC_ICR_HIGH: u32 = 0x310;
+/// ICR delivery mode NMI.
+const APIC_ICR_DM_NMI: u32 = 0b100 << 8;
+
+bitflags::bitflags! {
+    /// Error Status Register (ESR) bits.
+    struct ApicError: u32 {
+        const SEND_CHECKSUM     = 1 << 0;
+        const RECV_CHECKSUM     = 1 << 1;
+        const SEND_ACCEPT       = 1 << 2;
+        const RECV_ACCEPT       = 1 << 3;
+        const REDIRECTABLE_IPI  = 1 << 4;
+        const SEND_ILLEGAL_VEC  = 1 << 5;
+        const RECV_ILLEGAL_VEC  = 1 << 6;
+        const ILLEGAL_REG_ADDR  = 1 << 7;
+    }
+}
 
 bitflags::bitflags! {
     /// IA32_APIC_BASE MSR.
@@ -29,7 +61,7 @@ impl ApicBase {
     }
 }
 
-pub(super) struct LocalApic {
+pub struct LocalApic {
     inner: Arc<RwLock<dyn ApicControl>>,
     is_x2apic: bool,
 }
@@ -74,6 +106,77 @@ impl LocalApic {
             self.inner.read().id() >> 24
         }
     }
+
+    pub(super) fn is_x2apic(&self) -> bool {
+        self.is_x2apic
+    }
+
+    fn read_reg(&self, offset: u32) -> u32 {
+        if self.is_x2apic {
+            unsafe { x86::msr::rdmsr(X2APIC_MSR_BASE + (offset >> 4)) as u32 }
+        } else {
+            let vaddr = phys_to_virt(APIC_BASE) + offset as usize;
+            unsafe { core::ptr::read_volatile(vaddr as *const u32) }
+        }
+    }
+
+    fn write_reg(&self, offset: u32, value: u32) {
+        if self.is_x2apic {
+            unsafe { x86::msr::wrmsr(X2APIC_MSR_BASE + (offset >> 4), value as u64) }
+        } else {
+            let vaddr = phys_to_virt(APIC_BASE) + offset as usize;
+            unsafe { core::ptr::write_volatile(vaddr as *mut u32, value) }
+        }
+    }
+
+    /// Read and clear the Error Status Register.
+    fn read_esr(&self) -> ApicError {
+        // A write is required to update the ESR before reading it.
+        self.write_reg(APIC_ESR, 0);
+        ApicError::from_bits_truncate(self.read_reg(APIC_ESR))
+    }
+
+    pub(super) fn lvt_timer(&self) -> u32 {
+        self.read_reg(APIC_LVT_TIMER)
+    }
+
+    pub(super) fn set_lvt_timer(&self, value: u32) {
+        self.write_reg(APIC_LVT_TIMER, value)
+    }
+
+    /// Write `icr_low` to the ICR with the physical APIC ID `apic_id` as
+    /// destination.
+    fn send_ipi_raw(&self, apic_id: u32, icr_low: u32) {
+        // Hold the lock to not interleave with other ICR accesses.
+        let _inner = self.inner.write();
+        if self.is_x2apic {
+            let icr = (apic_id as u64) << 32 | icr_low as u64;
+            unsafe { x86::msr::wrmsr(X2APIC_MSR_BASE + (APIC_ICR_LOW >> 4), icr) }
+        } else {
+            self.write_reg(APIC_ICR_HIGH, apic_id << 24);
+            self.write_reg(APIC_ICR_LOW, icr_low);
+        }
+    }
+
+    /// Send a fixed IPI with `vector` to the physical APIC ID `apic_id`.
+    fn send_fixed_ipi(&self, apic_id: u32, vector: u8) {
+        self.send_ipi_raw(apic_id, vector as u32)
+    }
+
+    /// Send an NMI to the physical APIC ID `apic_id`.
+    pub(super) fn send_nmi_ipi(&self, apic_id: u32) {
+        self.send_ipi_raw(apic_id, APIC_ICR_DM_NMI)
+    }
+}
+
+impl LocalIrqChip for LocalApic {
+    fn id(&self) -> u32 {
+        LocalApic::id(self)
+    }
+
+    fn send_ipi(&self, dest: u32, vector: u8) {
+        self.send_fixed_ipi(dest, vector)
+    }
 }
 
 static LOCAL_APIC: Once<LocalApic> = Once::new();
@@ -91,6 +194,11 @@ pub(super) fn apic_to_cpu_id(apic_id: u32) -> u32 {
     }
 }
 
+/// Returns the APIC ID of the CPU `cpu_id`, if it entered the hypervisor.
+pub(super) fn cpu_to_apic_id(cpu_id: u32) -> Option<u32> {
+    (0..=MAX_APIC_ID).find(|&apic_id| apic_to_cpu_id(apic_id) == cpu_id)
+}
+
 pub(super) fn init() -> HvResult {
     let lapic = LocalApic::new()?;
     LOCAL_APIC.call_once(|| lapic);
@@ -106,21 +214,37 @@ pub(super) fn init_percpu(cpu_id: u32) -> HvResult {
     Ok(())
 }
 
-pub(super) unsafe fn start_ap(apic_id: u32, start_page_idx: u8) {
+pub(super) unsafe fn start_ap(apic_id: u32, start_page_idx: u8) -> HvResult {
     info!("Starting RT cpu {}...", apic_id);
-    let apic_id = if lapic().is_x2apic {
+    let lapic = lapic();
+    let dest = if lapic.is_x2apic {
         ApicId::X2Apic(apic_id)
     } else {
         ApicId::XApic(apic_id as u8)
     };
 
+    // Clear stale errors before sending IPIs.
+    lapic.read_esr();
+
     // INIT-SIPI-SIPI Sequence
-    let mut lapic = lapic().inner.write();
-    lapic.ipi_init(apic_id);
-    delay_us(10 * 1000); // 10ms
-    lapic.ipi_startup(apic_id, start_page_idx);
-    delay_us(200); // 200 us
-    lapic.ipi_startup(apic_id, start_page_idx);
+    {
+        let mut inner = lapic.inner.write();
+        inner.ipi_init(dest);
+        delay_us(10 * 1000); // 10ms
+        inner.ipi_startup(dest, start_page_idx);
+        delay_us(200); // 200 us
+        inner.ipi_startup(dest, start_page_idx);
+    }
+
+    let err = lapic.read_esr();
+    let send_err = ApicError::SEND_CHECKSUM | ApicError::SEND_ACCEPT | ApicError::SEND_ILLEGAL_VEC;
+    if err.intersects(send_err) {
+        return hv_result_err!(
+            EIO,
+            format!("APIC {} refused INIT-SIPI-SIPI: ESR={:?}", apic_id, err)
+        );
+    }
+    Ok(())
 }
 
 pub(super) unsafe fn shutdown_ap(apic_id: u32) {
@@ -1,7 +1,15 @@
+use alloc::vec::Vec;
 use core::slice;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-use super::{apic, cpu};
+use super::cpuid::cpuid;
+use super::{acpi, apic, cpu, rt_policy};
+use crate::cell::{self, CellState};
+use crate::config::{HvSystemConfig, MAX_RTOS_PCI_DEVICES};
 use crate::error::HvResult;
+use crate::hal::LocalIrqChip;
+use crate::lock::SpinLock;
+use crate::logging::RateLimit;
 use crate::memory::{addr::phys_to_virt, PhysAddr, PAGE_SIZE};
 use crate::percpu::PerCpu;
 
@@ -9,15 +17,334 @@ const START_PAGE_IDX: u8 = 6;
 const START_PAGE_COUNT: usize = 1;
 const START_PAGE_PADDR: usize = START_PAGE_IDX as usize * PAGE_SIZE;
 
+/// Offset of the MSR list (executed by the trampoline) in the start page.
+const RT_MSR_LIST_OFFSET: usize = 0xe00;
+/// Maximum number of entries of the MSR list.
+const MAX_RT_MSRS: usize = 16;
+
+/// Offset of the counter of RT CPUs which reached the trampoline in the start
+/// page.
+const RT_STARTED_COUNT_OFFSET: usize = 0xdf0;
+/// Offset of the boot information pointer (loaded into EBX by the trampoline)
+/// in the start page. Below the MSR list, out of reach of the temporary stack.
+const RT_BOOT_INFO_PTR_OFFSET: usize = 0xdf8;
+/// Passed in EAX to the RTOS, along with the boot information in EBX.
+const RT_BOOT_MAGIC: u32 = u32::from_le_bytes(*b"RVMB");
+const RT_BOOT_INFO_VERSION: u32 = 5;
+/// Maximum number of RT CPUs listed in the boot information.
+pub(super) const MAX_RT_BOOT_CPUS: usize = 32;
+
+/// `RtBootInfo::stop_flags`: the RTOS is asked to stop before its CPUs get
+/// INIT, and acknowledges with `stop_ack`.
+const RT_STOP_COOPERATIVE: u32 = 1 << 0;
+/// Time given to the RTOS to acknowledge a stop request.
+const RT_STOP_TIMEOUT_US: u64 = 100 * 1000; // 100ms
+
+/// Minimum time between two samples of the device counters of the RTOS.
+const RT_STATS_SAMPLE_US: u64 = 1000; // 1ms
+/// Minimum time between two warnings about deadline misses, which come at
+/// the rate of the RTOS.
+const DEADLINE_WARN_INTERVAL_MS: u64 = 1000;
+
+/// Number of INIT-SIPI-SIPI attempts for each RT CPU.
+const START_AP_ATTEMPTS: u32 = 3;
+/// Time to wait for an AP to appear after each attempt.
+const START_AP_TIMEOUT_US: u64 = 100 * 1000; // 100ms
+
+/// Startup result of a RT CPU.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RtCpuStatus {
+    NotStarted = 0,
+    Started = 1,
+    /// The AP did not appear within the timeout after all attempts.
+    Timeout = 2,
+    /// The local APIC reported a send error for the IPIs.
+    Refused = 3,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RtCpuInfo {
+    pub apic_id: u32,
+    pub cpu_id: u32,
+    pub status: RtCpuStatus,
+    pub attempts: u32,
+}
+
+/// Boot information of the RTOS, in the last page of RTOS memory. Mirrored
+/// by `rvm_rt_guest::boot::RtBootInfo`, keep them in sync.
+#[repr(C)]
+struct RtBootInfo {
+    magic: u32,
+    version: u32,
+    /// RTOS memory, without the boot information page.
+    rtos_mem_start: u64,
+    rtos_mem_size: u64,
+    tsc_mhz: u32,
+    /// I/O port of the UART shared with the hypervisor.
+    console_port: u16,
+    num_cpus: u16,
+    apic_ids: [u32; MAX_RT_BOOT_CPUS],
+    /// Set by the RTOS, see `RT_STOP_COOPERATIVE`.
+    stop_flags: AtomicU32,
+    /// Raised on the RT CPUs with a stop request if not 0, set by the RTOS.
+    stop_vector: AtomicU32,
+    /// Set to 1 by the hypervisor to ask the RTOS to stop.
+    stop_request: AtomicU32,
+    /// Set to 1 by the RTOS once stopped.
+    stop_ack: AtomicU32,
+    /// Device interrupts handled by each RT CPU, indexed like `apic_ids`.
+    /// Counted by the RTOS.
+    irq_counts: [AtomicU64; MAX_RT_BOOT_CPUS],
+    /// Bytes transferred by DMA of the RTOS devices, if its drivers count them.
+    dma_bytes: AtomicU64,
+    /// Deadline reports of each RT CPU, indexed like `apic_ids`.
+    deadlines: [RtDeadlines; MAX_RT_BOOT_CPUS],
+    num_pci_devices: u16,
+    /// 1 if interrupts are remapped, see `irq_remap`.
+    irq_remapping: u16,
+    /// PCI devices assigned to the RTOS, in the order of the configuration.
+    pci_bdfs: [u16; MAX_RTOS_PCI_DEVICES],
+}
+
+/// Periodic cycles of a RT CPU and their deadline misses, reported by the
+/// RTOS. Times are TSC values, the time base of the hypervisor.
+#[repr(C)]
+#[derive(Default)]
+struct RtDeadlines {
+    cycles: AtomicU64,
+    /// Incremented after the `last_miss_*` fields are written.
+    misses: AtomicU64,
+    last_miss_start: AtomicU64,
+    last_miss_deadline: AtomicU64,
+    last_miss_end: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// Deadline misses of each RT CPU already reported.
+static SEEN_MISSES: [AtomicU64; MAX_RT_BOOT_CPUS] = [ZERO; MAX_RT_BOOT_CPUS];
+
+static RT_CPUS: SpinLock<Vec<RtCpuInfo>> = SpinLock::new(Vec::new());
+
+/// Physical address of the boot information page at the end of RTOS memory,
+/// which `HvSystemConfig::check()` ensures is larger than a page.
+fn boot_info_paddr() -> PhysAddr {
+    let rtos_memory = &HvSystemConfig::get().rtos_memory;
+    (rtos_memory.phys_start + rtos_memory.size) as PhysAddr - PAGE_SIZE
+}
+
+/// Writes the boot information page, returns its address for EBX, or 0 if
+/// it is not reachable in 32-bit mode.
+unsafe fn write_boot_info() -> u32 {
+    let sys_config = HvSystemConfig::get();
+    let mut info = RtBootInfo {
+        magic: RT_BOOT_MAGIC,
+        version: RT_BOOT_INFO_VERSION,
+        rtos_mem_start: sys_config.rtos_memory.phys_start,
+        rtos_mem_size: sys_config.rtos_memory.size - PAGE_SIZE as u64,
+        tsc_mhz: cpu::frequency() as u32,
+        console_port: super::serial::io_port(),
+        num_cpus: 0,
+        apic_ids: [0; MAX_RT_BOOT_CPUS],
+        stop_flags: AtomicU32::new(0),
+        stop_vector: AtomicU32::new(0),
+        stop_request: AtomicU32::new(0),
+        stop_ack: AtomicU32::new(0),
+        irq_counts: Default::default(),
+        dma_bytes: AtomicU64::new(0),
+        deadlines: Default::default(),
+        num_pci_devices: 0,
+        irq_remapping: super::irq_remap::enabled() as u16,
+        pci_bdfs: [0; MAX_RTOS_PCI_DEVICES],
+    };
+    SEEN_MISSES
+        .iter()
+        .for_each(|seen| seen.store(0, Ordering::Relaxed));
+    for (slot, apic_id) in info.apic_ids.iter_mut().zip(sys_config.rtos_cpus.iter()) {
+        *slot = apic_id;
+        info.num_cpus += 1;
+    }
+    for (slot, dev) in info.pci_bdfs.iter_mut().zip(sys_config.rtos_pci_devices()) {
+        *slot = dev.bdf;
+        info.num_pci_devices += 1;
+    }
+    let paddr = boot_info_paddr();
+    let page = phys_to_virt(paddr) as *mut u8;
+    core::ptr::write_bytes(page, 0, PAGE_SIZE);
+    core::ptr::write(page as *mut RtBootInfo, info);
+    u32::try_from(paddr).unwrap_or(0)
+}
+
+/// Checks the CPU role map of the configuration against the MADT, and logs
+/// it. The role of each CPU is chosen by its APIC ID, as the root cell CPU
+/// set or the RTOS CPU set, not by its position: each CPU of either set must
+/// be an enabled or online capable processor of the MADT.
+pub(super) fn check_cpu_roles() -> HvResult {
+    let sys_config = HvSystemConfig::get();
+    let (root_cpus, rtos_cpus) = (
+        sys_config.root_cell.config().cpu_set(),
+        sys_config.rtos_cpus,
+    );
+    let madt_cpus = match acpi::madt_apic_ids() {
+        Some(ids) => ids,
+        None if sys_config.strict_smt() => {
+            return hv_result_err!(ENODEV, "No ACPI MADT, RTOS CPU siblings cannot be checked");
+        }
+        None => {
+            info!("No ACPI MADT, CPU roles are not checked.");
+            return Ok(());
+        }
+    };
+    if let Some(apic_id) = root_cpus
+        .iter()
+        .chain(rtos_cpus.iter())
+        .find(|apic_id| !madt_cpus.contains(apic_id))
+    {
+        return hv_result_err!(
+            ENODEV,
+            "Configured CPU with APIC ID {} is not in the MADT",
+            apic_id
+        );
+    }
+    for &apic_id in madt_cpus {
+        let role = if root_cpus.contains(apic_id) {
+            "root cell"
+        } else if rtos_cpus.contains(apic_id) {
+            "RTOS"
+        } else {
+            "unassigned"
+        };
+        info!("CPU role: APIC {} -> {}", apic_id, role);
+    }
+    check_smt_siblings(madt_cpus)
+}
+
+/// Number of low APIC ID bits selecting the SMT thread within a core, from
+/// the extended topology leaf, or `None` if the leaf is missing.
+fn smt_id_bits() -> Option<u32> {
+    if cpuid!(0).eax < 0xb {
+        return None;
+    }
+    let leaf = cpuid!(0xb, 0);
+    // ECX[15:8]: level type of subleaf 0, 1 for SMT.
+    if (leaf.ecx >> 8) & 0xff == 1 {
+        Some(leaf.eax & 0x1f)
+    } else {
+        None
+    }
+}
+
+/// Logs the cores of the RTOS CPUs, and checks that none of them is shared
+/// with a root cell CPU. Sharing, or a topology that cannot be determined,
+/// fails if `HvSystemFlags::STRICT_SMT` is set, and is only warned about
+/// otherwise.
+fn check_smt_siblings(madt_cpus: &[u32]) -> HvResult {
+    let sys_config = HvSystemConfig::get();
+    let (root_cpus, rtos_cpus) = (
+        sys_config.root_cell.config().cpu_set(),
+        sys_config.rtos_cpus,
+    );
+    let bits = match smt_id_bits() {
+        Some(0) => {
+            info!("No SMT, RTOS CPUs have no siblings.");
+            return Ok(());
+        }
+        Some(bits) => bits,
+        None if sys_config.strict_smt() => {
+            return hv_result_err!(
+                ENODEV,
+                "No SMT topology reported, RTOS CPU siblings cannot be checked"
+            );
+        }
+        None => {
+            warn!("No SMT topology reported, RTOS CPU siblings are not checked.");
+            return Ok(());
+        }
+    };
+    let mut shared = false;
+    for rt_cpu in rtos_cpus.iter() {
+        let core = rt_cpu >> bits;
+        let siblings: Vec<u32> = madt_cpus
+            .iter()
+            .copied()
+            .filter(|&id| id != rt_cpu && id >> bits == core)
+            .collect();
+        info!("RTOS CPU APIC {}: SMT siblings {:?}", rt_cpu, siblings);
+        for &sibling in siblings.iter().filter(|&&id| root_cpus.contains(id)) {
+            warn!(
+                "RTOS CPU APIC {} shares its core with root cell CPU APIC {}",
+                rt_cpu, sibling
+            );
+            shared = true;
+        }
+    }
+    if shared && sys_config.strict_smt() {
+        return hv_result_err!(EINVAL, "RTOS CPUs share cores with the root cell");
+    }
+    Ok(())
+}
+
+/// Returns the startup information of all RT CPUs of the last `start_rt_cpus()`.
+pub fn rt_cpu_info() -> Vec<RtCpuInfo> {
+    RT_CPUS.lock().clone()
+}
+
+/// Number of RT CPUs which reached the trampoline since it was copied.
+fn started_count() -> u32 {
+    let counter = phys_to_virt(START_PAGE_PADDR + RT_STARTED_COUNT_OFFSET) as *const AtomicU32;
+    unsafe { (*counter).load(Ordering::Acquire) }
+}
+
+fn wait_for_ap(started: u32) -> bool {
+    let cycle_end = cpu::current_cycle() + START_AP_TIMEOUT_US * cpu::frequency() as u64;
+    while started_count() <= started {
+        if cpu::current_cycle() >= cycle_end {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+unsafe fn start_one_ap(info: &mut RtCpuInfo, wakeup_vector: Option<PhysAddr>) {
+    while info.attempts < START_AP_ATTEMPTS {
+        info.attempts += 1;
+        let started = started_count();
+        let res = match wakeup_vector {
+            Some(vector) => acpi::mp_wakeup(info.apic_id, vector),
+            None => apic::start_ap(info.apic_id, START_PAGE_IDX),
+        };
+        if let Err(e) = res {
+            warn!("{:?}", e);
+            info.status = RtCpuStatus::Refused;
+            continue;
+        }
+        if wait_for_ap(started) {
+            info.status = RtCpuStatus::Started;
+            return;
+        }
+        warn!(
+            "RT cpu {} (APIC {}) did not respond, attempt {}/{}",
+            info.cpu_id, info.apic_id, info.attempts, START_AP_ATTEMPTS
+        );
+        info.status = RtCpuStatus::Timeout;
+    }
+}
+
 core::arch::global_asm!(
     include_str!("boot_rt.S"),
     start_page_paddr = const START_PAGE_PADDR,
+    started_count_offset = const RT_STARTED_COUNT_OFFSET,
+    boot_info_ptr_offset = const RT_BOOT_INFO_PTR_OFFSET,
+    rt_boot_magic = const RT_BOOT_MAGIC,
 );
 
 #[allow(clippy::uninit_assumed_init)]
 pub unsafe fn start_rt_cpus(entry_paddr: PhysAddr) -> HvResult {
     extern "C" {
         fn ap_start();
+        fn ap_start64();
         fn ap_end();
     }
     const U64_PER_PAGE: usize = PAGE_SIZE / 8;
@@ -33,36 +360,207 @@ pub unsafe fn start_rt_cpus(entry_paddr: PhysAddr) -> HvResult {
         (ap_end as usize - ap_start as usize) / 8,
     );
     start_page[U64_PER_PAGE - 1] = entry_paddr as _; // entry
+    start_page[RT_STARTED_COUNT_OFFSET / 8] = 0;
+    start_page[RT_BOOT_INFO_PTR_OFFSET / 8] = write_boot_info() as u64;
+
+    let msrs = rt_policy::rt_cpu_msrs();
+    if msrs.len() > MAX_RT_MSRS {
+        start_page.copy_from_slice(&backup);
+        return hv_result_err!(E2BIG, "Too many MSRs to program on RT CPUs");
+    }
+    let msr_list = &mut start_page[RT_MSR_LIST_OFFSET / 8..];
+    msr_list[0] = msrs.len() as u64;
+    for (i, &(msr, value)) in msrs.iter().enumerate() {
+        msr_list[1 + i * 2] = msr as u64;
+        msr_list[2 + i * 2] = value;
+    }
+
+    // Prefer the ACPI MP wakeup mailbox if the firmware provides one, as
+    // INIT-SIPI may be disallowed on such platforms.
+    let wakeup_vector = if acpi::has_mp_wakeup_mailbox() {
+        Some(START_PAGE_PADDR + (ap_start64 as usize - ap_start as usize))
+    } else {
+        None
+    };
 
     let max_cpus = crate::header::HvHeader::get().max_cpus;
     let mut new_cpu_id = PerCpu::entered_cpus();
-    for apic_id in 0..max_cpus {
+    // The list is published once the CPUs are started, so that the lock is
+    // not held across the INIT-SIPI delays.
+    RT_CPUS.lock().clear();
+    let mut rt_cpus = Vec::new();
+    let rtos_cpus = HvSystemConfig::get().rtos_cpus;
+    for apic_id in rtos_cpus.iter() {
         if apic::apic_to_cpu_id(apic_id) == u32::MAX {
             if new_cpu_id >= max_cpus {
                 break;
             }
-            let current_entered_cpus = PerCpu::entered_cpus();
             let stack_top = PerCpu::from_id_mut(new_cpu_id).stack_top();
             start_page[U64_PER_PAGE - 3] = stack_top as u64; // stack
-            apic::start_ap(apic_id, START_PAGE_IDX);
+            let mut info = RtCpuInfo {
+                apic_id,
+                cpu_id: new_cpu_id,
+                status: RtCpuStatus::NotStarted,
+                attempts: 0,
+            };
+            start_one_ap(&mut info, wakeup_vector);
+            rt_cpus.push(info);
             new_cpu_id += 1;
+        }
+    }
+    start_page.copy_from_slice(&backup);
+
+    for info in rt_cpus.iter() {
+        info!("RT cpu {}: {:?}", info.cpu_id, info);
+    }
+    let res = if rt_cpus.iter().any(|c| c.status == RtCpuStatus::Refused) {
+        hv_result_err!(EIO, "Some RT CPUs refused to start")
+    } else if rt_cpus.iter().any(|c| c.status == RtCpuStatus::Timeout) {
+        hv_result_err!(ETIMEDOUT, "Some RT CPUs did not respond")
+    } else {
+        Ok(())
+    };
+    *RT_CPUS.lock() = rt_cpus;
+    res
+}
 
-            // wait for max 100ms
-            let cycle_end = cpu::current_cycle() + 100 * 1000 * cpu::frequency() as u64;
-            while PerCpu::entered_cpus() <= current_entered_cpus && cpu::current_cycle() < cycle_end
-            {
-                core::hint::spin_loop();
+/// Asks the RTOS to stop, if it opted in, so that it can park its devices
+/// before its CPUs get INIT. Returns whether it acknowledged in time.
+unsafe fn request_rt_stop() -> bool {
+    let info = &*(phys_to_virt(boot_info_paddr()) as *const RtBootInfo);
+    if info.magic != RT_BOOT_MAGIC
+        || info.version != RT_BOOT_INFO_VERSION
+        || info.stop_flags.load(Ordering::Acquire) & RT_STOP_COOPERATIVE == 0
+    {
+        return false;
+    }
+    info!("Requesting the RTOS to stop...");
+    info.stop_ack.store(0, Ordering::Relaxed);
+    info.stop_request.store(1, Ordering::Release);
+    // The page is writable by the RTOS: only the vector is taken from it, the
+    // destinations are the RTOS CPUs of the configuration.
+    match info.stop_vector.load(Ordering::Relaxed) {
+        0 => {}
+        vector @ 0x20..=0xff => {
+            for apic_id in HvSystemConfig::get().rtos_cpus.iter() {
+                apic::lapic().send_ipi(apic_id, vector as u8);
             }
         }
+        vector => warn!("Invalid RTOS stop vector {:#x}, not raised", vector),
     }
-    start_page.copy_from_slice(&backup);
-    Ok(())
+
+    let cycle_end = cpu::current_cycle() + RT_STOP_TIMEOUT_US * cpu::frequency() as u64;
+    while info.stop_ack.load(Ordering::Acquire) == 0 {
+        if cpu::current_cycle() >= cycle_end {
+            warn!("RTOS did not acknowledge the stop request, resetting RT CPUs");
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    info!("RTOS stopped.");
+    true
 }
 
+/// Copies the device counters of the RTOS from its boot information page to
+/// the stats window, at most every `RT_STATS_SAMPLE_US`. The hypervisor does
+/// not see the interrupts and DMA of the devices assigned to the RTOS, so it
+/// relies on the RTOS to count them and publishes them apart, as reported.
+pub fn sample_rt_device_stats() {
+    static NEXT_SAMPLE: AtomicU64 = AtomicU64::new(0);
+    if !crate::stats::enabled() || cell::rtos_lifecycle().get() != CellState::Running {
+        return;
+    }
+    let now = cpu::current_cycle();
+    let next = NEXT_SAMPLE.load(Ordering::Relaxed);
+    let period = RT_STATS_SAMPLE_US * cpu::frequency() as u64;
+    if now < next
+        || NEXT_SAMPLE
+            .compare_exchange(next, now + period, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let info = unsafe { &*(phys_to_virt(boot_info_paddr()) as *const RtBootInfo) };
+    if info.magic != RT_BOOT_MAGIC || info.version != RT_BOOT_INFO_VERSION {
+        return; // the RTOS reused the page
+    }
+    let rt_cpus = match RT_CPUS.try_lock() {
+        Some(rt_cpus) => rt_cpus,
+        None => return,
+    };
+    let num_cpus = (info.num_cpus as usize).min(MAX_RT_BOOT_CPUS);
+    for (i, apic_id) in info.apic_ids[..num_cpus].iter().enumerate() {
+        if let Some(rt_cpu) = rt_cpus.iter().find(|c| c.apic_id == *apic_id) {
+            sample_deadlines(rt_cpu.cpu_id, &info.deadlines[i], &SEEN_MISSES[i]);
+        }
+    }
+    let irqs = info.apic_ids[..num_cpus]
+        .iter()
+        .zip(&info.irq_counts)
+        .map(|(&apic_id, count)| (apic_id, count.load(Ordering::Relaxed)));
+    crate::stats_window::set_rt_reported_stats(irqs, info.dma_bytes.load(Ordering::Relaxed));
+}
+
+/// Publishes the cycles and deadline misses of the RT CPU `cpu_id`. A new
+/// miss is correlated with the root cell VM exits during the missed cycle.
+fn sample_deadlines(cpu_id: u32, deadlines: &RtDeadlines, seen_misses: &AtomicU64) {
+    let cycles = deadlines.cycles.load(Ordering::Relaxed);
+    let misses = deadlines.misses.load(Ordering::Acquire);
+    crate::stats_window::update_cpu_stats(cpu_id, |stats| {
+        stats.rt_cycles.store(cycles, Ordering::Relaxed);
+        stats.deadline_misses.store(misses, Ordering::Relaxed);
+    });
+    if misses == seen_misses.swap(misses, Ordering::Relaxed) {
+        return;
+    }
+    let start = deadlines.last_miss_start.load(Ordering::Relaxed);
+    let deadline = deadlines.last_miss_deadline.load(Ordering::Relaxed);
+    let end = deadlines.last_miss_end.load(Ordering::Relaxed);
+    let overrun = end.saturating_sub(deadline);
+    let (exits, exit_cycles) = crate::stats_window::exits_between(start, end);
+    static WARNINGS: RateLimit = RateLimit::new(DEADLINE_WARN_INTERVAL_MS);
+    if let Some(suppressed) = WARNINGS.check() {
+        warn!(
+            "RT CPU {} missed its deadline by {}us, with {} root cell VM exits ({} cycles) \
+            during the cycle ({} warnings suppressed)",
+            cpu_id,
+            overrun / cpu::frequency() as u64,
+            exits,
+            exit_cycles,
+            suppressed
+        );
+    }
+    crate::stats_window::update_cpu_stats(cpu_id, |stats| {
+        stats.last_miss_tsc.store(start, Ordering::Relaxed);
+        stats.last_miss_overrun.store(overrun, Ordering::Relaxed);
+        stats.last_miss_exits.store(exits, Ordering::Relaxed);
+        stats
+            .last_miss_exit_cycles
+            .store(exit_cycles, Ordering::Relaxed);
+    });
+}
+
+/// Stops the RT CPUs: the RTOS is asked to stop first if it opted in, then the
+/// CPUs get INIT regardless.
 pub unsafe fn shutdown_rt_cpus() -> HvResult {
-    let header = crate::header::HvHeader::get();
-    for apic_id in header.vm_cpus()..header.max_cpus {
-        apic::shutdown_ap(apic_id);
+    request_rt_stop();
+    // Not held across the INIT delays.
+    let started: Vec<u32> = RT_CPUS.lock().iter().map(|c| c.apic_id).collect();
+    if started.is_empty() {
+        let rtos_cpus = HvSystemConfig::get().rtos_cpus;
+        for apic_id in rtos_cpus.iter() {
+            apic::shutdown_ap(apic_id);
+        }
+    } else {
+        for &apic_id in started.iter() {
+            apic::shutdown_ap(apic_id);
+        }
+        for info in RT_CPUS.lock().iter_mut() {
+            info.status = RtCpuStatus::NotStarted;
+        }
     }
+    // Do not send stop requests to a stopped RTOS.
+    let info = &mut *(phys_to_virt(boot_info_paddr()) as *mut RtBootInfo);
+    info.magic = 0;
     Ok(())
 }
@@ -5,6 +5,7 @@ use x86_64::{addr::PhysAddr, structures::paging::PhysFrame, structures::Descript
 
 use super::segmentation::Segment;
 use super::tables::{GdtStruct, IdtStruct};
+use super::timer::TimerState;
 
 const SAVED_LINUX_REGS: usize = 8;
 
@@ -42,6 +43,9 @@ pub struct LinuxContext {
     pub kernel_gsbase: u64,
     pub pat: u64,
     pub mtrr_def_type: u64,
+    /// LAPIC timer state, passed through but checked, see `timer`. Saved by
+    /// `save_timer()`.
+    pub timer: TimerState,
 }
 
 #[repr(C)]
@@ -65,6 +69,54 @@ pub struct GeneralRegisters {
     pub r15: u64,
 }
 
+impl GeneralRegisters {
+    /// Returns the value of the register numbered `idx` in instruction
+    /// encodings, or `None` for RSP, which is not saved here.
+    pub fn get(&self, idx: usize) -> Option<u64> {
+        Some(match idx {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => return None,
+        })
+    }
+
+    /// Returns the register numbered `idx` in instruction encodings, or
+    /// `None` for RSP, which is not saved here.
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut u64> {
+        Some(match idx {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            _ => return None,
+        })
+    }
+}
+
 macro_rules! save_regs_to_stack {
     () => {
         "
@@ -111,6 +163,7 @@ macro_rules! restore_regs_from_stack {
 
 impl LinuxContext {
     /// Load linux callee-saved registers from the stack, and other system registers.
+    /// Must run on the page table of Linux, which maps its stack and GDT.
     pub fn load_from(linux_sp: usize) -> Self {
         let regs = unsafe { core::slice::from_raw_parts(linux_sp as *const u64, SAVED_LINUX_REGS) };
         let gdt = GdtStruct::sgdt();
@@ -148,9 +201,32 @@ impl LinuxContext {
             kernel_gsbase: Msr::IA32_KERNEL_GSBASE.read(),
             pat: Msr::IA32_PAT.read(),
             mtrr_def_type: Msr::IA32_MTRR_DEF_TYPE.read(),
+            timer: TimerState::default(),
         }
     }
 
+    /// Saves the LAPIC timer state. Must run on the hypervisor page table,
+    /// which maps the xAPIC registers.
+    pub fn save_timer(&mut self) {
+        self.timer = TimerState::save();
+    }
+
+    /// Switches back to the page table of Linux, to return to Linux after a
+    /// failed enable.
+    pub fn restore_page_table(&self) {
+        unsafe {
+            Cr3::write(
+                PhysFrame::containing_address(PhysAddr::new(self.cr3)),
+                Cr3Flags::empty(),
+            )
+        };
+    }
+
+    /// Undoes any change of the LAPIC timer since `load_from()`.
+    pub fn check_timer(&self, cpu_id: u32) {
+        self.timer.check(cpu_id)
+    }
+
     /// Restore system registers.
     pub fn restore(&self) {
         unsafe {
@@ -193,6 +269,35 @@ impl LinuxContext {
         }
     }
 
+    /// Copies `guest_regs`, followed by the return frame, below the Linux
+    /// stack pointer, for `return_staged()`. Returns the address of the copy.
+    #[cfg(feature = "scrub-percpu")]
+    pub fn stage_return(&self, guest_regs: &GeneralRegisters) -> usize {
+        let ret_addr = self.rsp as usize - 8;
+        let regs = ret_addr - 8 - core::mem::size_of::<GeneralRegisters>();
+        unsafe {
+            (ret_addr as *mut u64).write(self.rip);
+            ((ret_addr - 8) as *mut u64).write(ret_addr as u64);
+            core::ptr::copy_nonoverlapping(guest_regs, regs as *mut GeneralRegisters, 1);
+        }
+        regs
+    }
+
+    /// Returns to Linux with the registers staged at `regs` by
+    /// `stage_return()`, reading nothing from hypervisor memory.
+    #[cfg(feature = "scrub-percpu")]
+    pub unsafe fn return_staged(gs_base: u64, regs: usize) -> ! {
+        Msr::IA32_GS_BASE.write(gs_base);
+        core::arch::asm!(
+            "mov rsp, {regs}",
+            restore_regs_from_stack!(),
+            "pop rsp",
+            "ret",
+            regs = in(reg) regs,
+            options(noreturn),
+        );
+    }
+
     /// Restore linux general-purpose registers and stack, then return back to linux.
     pub fn return_to_linux(&self, guest_regs: &GeneralRegisters) -> ! {
         unsafe {
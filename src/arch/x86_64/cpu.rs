@@ -23,12 +23,35 @@ pub fn current_time_nanos() -> u64 {
     current_cycle() * 1000 / frequency() as u64
 }
 
+/// Disables local interrupts, and returns whether they were enabled.
+pub fn local_irq_save() -> bool {
+    let enabled = x86_64::instructions::interrupts::are_enabled();
+    if enabled {
+        x86_64::instructions::interrupts::disable();
+    }
+    enabled
+}
+
+/// Re-enables local interrupts if `enabled`, as returned by
+/// `local_irq_save()`.
+pub fn local_irq_restore(enabled: bool) {
+    if enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+}
+
 pub fn thread_pointer() -> usize {
     let ret;
     unsafe { core::arch::asm!("mov {0}, gs:0", out(reg) ret, options(nostack)) }; // PerCpu::self_vaddr
     ret
 }
 
+/// Returns the thread pointer register without dereferencing it, which is
+/// still the one of Linux on CPUs not entered into the hypervisor.
+pub fn thread_pointer_base() -> usize {
+    unsafe { Msr::IA32_GS_BASE.read() as usize }
+}
+
 pub fn set_thread_pointer(tp: usize) {
     unsafe { Msr::IA32_GS_BASE.write(tp as u64) };
 }
@@ -13,6 +13,7 @@ pub(super) enum CpuIdEax {
     HypervisorInfo = 0x4000_0000,
     HypervisorFeatures = 0x4000_0001,
     AmdFeatureInfo = 0x8000_0001,
+    AddressSizes = 0x8000_0008,
 }
 
 bitflags! {
@@ -187,6 +188,14 @@ impl CpuFeatures {
         }
     }
 
+    pub fn has_tsc_deadline(&self) -> bool {
+        if let Some(info) = self.cpuid.get_feature_info() {
+            info.has_tsc_deadline()
+        } else {
+            false
+        }
+    }
+
     pub fn has_rdtscp(&self) -> bool {
         if let Some(info) = self.cpuid.get_extended_processor_and_feature_identifiers() {
             info.has_rdtscp()
@@ -210,4 +219,9 @@ impl CpuFeatures {
             false
         }
     }
+
+    /// IBRS and IBPB, i.e. the IA32_SPEC_CTRL MSR is present.
+    pub fn has_spec_ctrl(&self) -> bool {
+        self.cpuid.get_extended_feature_info().is_some() && cpuid!(7, 0).edx & (1 << 26) != 0
+    }
 }
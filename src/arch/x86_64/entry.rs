@@ -1,3 +1,4 @@
+use crate::consts::EFI_STACK_SIZE;
 use crate::percpu::PerCpu;
 
 use libvmm::msr::Msr;
@@ -59,3 +60,49 @@ pub unsafe extern "C" fn arch_entry() -> i32 {
         options(noreturn),
     );
 }
+
+#[repr(align(4096))]
+struct EfiStack([u8; EFI_STACK_SIZE]);
+
+/// Stack of `efi_entry()`, only used before Linux boots.
+static mut EFI_STACK: EfiStack = EfiStack([0; EFI_STACK_SIZE]);
+
+/// Entry from an EFI driver, called once on the boot CPU with the hypervisor
+/// memory mapped at `HV_BASE`, see `header`. Takes no argument and saves the
+/// registers callee-saved in both the System V and the Microsoft x64 ABIs.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn efi_entry() -> i32 {
+    core::arch::asm!("
+        push rbp
+        push rbx
+        push rdi
+        push rsi
+        push r12
+        push r13
+        push r14
+        push r15
+
+        mov rcx, rsp
+        lea rsp, [rip + {stack} + {stack_size}]
+        push rcx
+        push rcx    // keep the stack 16-byte aligned
+        call {0}
+        pop rcx
+        pop rsp
+
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rsi
+        pop rdi
+        pop rbx
+        pop rbp
+        ret",
+        sym crate::efi_main,
+        stack = sym EFI_STACK,
+        stack_size = const EFI_STACK_SIZE,
+        options(noreturn),
+    );
+}
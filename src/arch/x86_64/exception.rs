@@ -53,10 +53,14 @@ pub struct TrapFrame {
     pub ss: usize,
 }
 
-fn exception_handler(frame: &TrapFrame) {
+fn exception_handler(frame: &mut TrapFrame) {
     trace!("Exception or interrupt #{:#x}", frame.num);
+    #[cfg(feature = "ist-selftest")]
+    if super::ist_test::handle(frame) {
+        return;
+    }
     match frame.num as u8 {
-        ExceptionType::NonMaskableInterrupt => handle_nmi(),
+        ExceptionType::NonMaskableInterrupt => handle_nmi(frame),
         ExceptionType::PageFault => handle_page_fault(frame),
         ExceptionType::IrqStart..=ExceptionType::IrqEnd => {
             warn!("Unhandled IRQ #{:#x?}", frame.num);
@@ -68,8 +72,10 @@ fn exception_handler(frame: &TrapFrame) {
     }
 }
 
-fn handle_nmi() {
-    warn!("Unhandled exception: NMI");
+fn handle_nmi(frame: &TrapFrame) {
+    if !super::watchdog::handle_nmi(frame) && !super::nested_tlb::take_kick() {
+        warn!("Unhandled exception: NMI");
+    }
 }
 
 fn handle_page_fault(frame: &TrapFrame) {
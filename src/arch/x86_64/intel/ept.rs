@@ -2,10 +2,15 @@ use core::{convert::TryFrom, fmt};
 
 use bit_field::BitField;
 use bitflags::bitflags;
+use libvmm::msr::Msr;
 use numeric_enum_macro::numeric_enum;
 
+use crate::arch::mem_encrypt::phys_addr_mask;
+use crate::arch::nested_tlb;
+use crate::error::HvResult;
+use crate::hal::NestedPaging;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
-use crate::memory::{GenericPTE, Level4PageTable, MemFlags, PagingInstr};
+use crate::memory::{GenericPTE, GenericPageTableImmut, Level4PageTable, MemFlags, PagingInstr};
 
 bitflags! {
     struct EPTFlags: u64 {
@@ -87,10 +92,18 @@ impl EPTMemType {
 
 impl GenericPTE for EPTEntry {
     fn addr(&self) -> HostPhysAddr {
-        (self.0.get_bits(12..52) << 12) as usize
+        (self.0 & phys_addr_mask()) as usize
     }
     fn flags(&self) -> MemFlags {
-        self.ept_flags().into()
+        let mut flags: MemFlags = self.ept_flags().into();
+        if self.is_present() {
+            match self.memory_type() {
+                Ok(EPTMemType::Uncached) => flags |= MemFlags::IO,
+                Ok(EPTMemType::WriteCombining) => flags |= MemFlags::IO | MemFlags::WRITE_COMBINE,
+                _ => {}
+            }
+        }
+        flags
     }
     fn is_unused(&self) -> bool {
         self.0 == 0
@@ -103,14 +116,25 @@ impl GenericPTE for EPTEntry {
     }
 
     fn set_addr(&mut self, paddr: HostPhysAddr) {
-        self.0.set_bits(12..52, paddr as u64 >> 12);
+        self.0 = (self.0 & !phys_addr_mask()) | (paddr as u64 & phys_addr_mask());
     }
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) {
+        // The EPT memory type replaces the MTRRs, and is combined with the
+        // guest PAT.
+        // (Intel SDM Volume 3, Section 28.2.7.2, Memory Type Used for
+        // Translated Guest-Physical Addresses)
+        let mem_type = if flags.contains(MemFlags::WRITE_COMBINE) {
+            EPTMemType::WriteCombining
+        } else if flags.contains(MemFlags::IO) {
+            EPTMemType::Uncached
+        } else {
+            EPTMemType::WriteBack
+        };
         let mut flags = flags.into();
         if is_huge {
             flags |= EPTFlags::HUGE_PAGE;
         }
-        self.set_flags_and_mem_type(flags, EPTMemType::WriteBack);
+        self.set_flags_and_mem_type(flags, mem_type);
     }
     fn set_table(&mut self, paddr: HostPhysAddr) {
         self.set_addr(paddr);
@@ -122,9 +146,62 @@ impl GenericPTE for EPTEntry {
     fn clear(&mut self) {
         self.0 = 0
     }
+    fn test_and_clear_accessed(&mut self) -> bool {
+        let accessed = self.ept_flags().contains(EPTFlags::ACCESSED);
+        self.0 &= !EPTFlags::ACCESSED.bits();
+        accessed
+    }
 }
 
 impl EPTEntry {
+    /// Returns why the entry at `level` (0 for the root table) causes an EPT
+    /// misconfiguration, if it does.
+    /// (Intel SDM Volume 3, Section 29.3.3.1, EPT Misconfigurations)
+    pub fn misconfig_reason(&self, level: usize) -> Option<&'static str> {
+        if !self.is_present() {
+            return None;
+        }
+        let caps = Msr::IA32_VMX_EPT_VPID_CAP.read();
+        let flags = self.ept_flags();
+        if flags.contains(EPTFlags::WRITE) && !flags.contains(EPTFlags::READ) {
+            return Some("writable but not readable");
+        }
+        if flags & (EPTFlags::READ | EPTFlags::WRITE | EPTFlags::EXECUTE) == EPTFlags::EXECUTE
+            && !caps.get_bit(0)
+        {
+            return Some("execute-only not supported");
+        }
+        // Address bits beyond the physical address width.
+        if self.0.get_bits(12..52) & !(phys_addr_mask() >> 12) != 0 {
+            return Some("reserved address bits set");
+        }
+        let is_leaf = level == 3 || self.is_huge();
+        match level {
+            0 if self.is_huge() => return Some("huge page in the root table"),
+            1 if self.is_huge() && !caps.get_bit(17) => return Some("1GB pages not supported"),
+            _ => {}
+        }
+        if !is_leaf {
+            if self.0.get_bits(3..8) != 0 {
+                return Some("reserved bits 7:3 set in a table entry");
+            }
+            return None;
+        }
+        // Address bits below the size of a huge page.
+        let offset_bits = match level {
+            1 if self.is_huge() => Some(12..30),
+            2 if self.is_huge() => Some(12..21),
+            _ => None,
+        };
+        if offset_bits.map_or(false, |bits| self.0.get_bits(bits) != 0) {
+            return Some("huge page address not aligned");
+        }
+        match self.memory_type() {
+            Ok(_) => None,
+            Err(_) => Some("invalid memory type"),
+        }
+    }
+
     fn ept_flags(&self) -> EPTFlags {
         EPTFlags::from_bits_truncate(self.0)
     }
@@ -155,9 +232,42 @@ impl PagingInstr for EPTInstr {
         libvmm::vmx::Vmcs::set_ept_pointer(root_paddr).expect("Failed to set EPT_POINTER");
     }
 
+    /// INVEPT only invalidates the translations of the executing CPU, so
+    /// each CPU does it when it resumes its guest, see `nested_tlb`.
     fn flush(_vaddr: Option<usize>) {
-        // do nothing
+        nested_tlb::flush();
+    }
+
+    fn flush_generation() -> u64 {
+        nested_tlb::generation()
+    }
+
+    fn is_flushed(generation: u64) -> bool {
+        nested_tlb::is_flushed(generation)
+    }
+
+    fn supports_1g_pages() -> bool {
+        // (Intel SDM Volume 3, Appendix A.10, VPID and EPT Capabilities)
+        Msr::IA32_VMX_EPT_VPID_CAP.read() & (1 << 17) != 0
     }
 }
 
+/// Invalidates the EPT translations cached by the current CPU if mappings were
+/// changed since `seen_generation`. Called right before resuming the guest.
+pub(super) fn sync_flush(seen_generation: &mut u64) -> HvResult {
+    use libvmm::vmx::{flags::InvEptType, vmcs::VmcsField64Control};
+    let cpu_id = crate::percpu::PerCpu::current().id;
+    nested_tlb::resume_guest(cpu_id, seen_generation, || {
+        let eptp = VmcsField64Control::EPT_POINTER.read()?;
+        unsafe { libvmm::vmx::invept(InvEptType::SingleContext, eptp)? };
+        Ok(())
+    })
+}
+
 pub type ExtendedPageTable = Level4PageTable<GuestPhysAddr, EPTEntry, EPTInstr>;
+
+impl NestedPaging for ExtendedPageTable {
+    fn nested_root(&self) -> u64 {
+        libvmm::vmx::flags::EPTPointer::from_table_phys(self.root_paddr()).bits()
+    }
+}
@@ -3,32 +3,59 @@ mod structs;
 mod vcpu;
 mod vmexit;
 
+use libvmm::msr::Msr;
 use libvmm::vmx::Vmcs;
 use x86::vmx::VmFail;
 
 use crate::arch::cpuid::CpuFeatures;
+use crate::arch::vmm::InterceptFlags;
+use crate::config::{HvSystemConfig, InterceptProfile};
 use crate::error::{HvError, HvResult};
 
+#[cfg(test)]
+pub use ept::EPTEntry as NestedPTE;
 pub use ept::ExtendedPageTable as NestedPageTable;
 pub use vcpu::Vcpu;
 
 impl From<VmFail> for HvError {
     fn from(err: VmFail) -> Self {
         match err {
-            VmFail::VmFailValid => hv_err!(
-                EIO,
-                format!("{:?}: {:x?}", err, Vmcs::instruction_error().unwrap())
-            ),
-            _ => hv_err!(EIO, format!("{:?}", err)),
+            VmFail::VmFailValid => {
+                hv_err!(EIO, "{:?}: {:x?}", err, Vmcs::instruction_error().unwrap())
+            }
+            _ => hv_err!(EIO, "{:?}", err),
         }
     }
 }
 
 pub fn check_hypervisor_feature() -> HvResult {
-    if CpuFeatures::new().has_vmx() {
-        Ok(())
-    } else {
+    if !CpuFeatures::new().has_vmx() {
         warn!("Feature VMX not supported!");
-        hv_result_err!(ENODEV, "VMX feature checks failed!")
+        return hv_result_err!(ENODEV, "VMX feature checks failed!");
+    }
+    // The EPT is always a 4-level table.
+    if Msr::IA32_VMX_EPT_VPID_CAP.read() & (1 << 6) == 0 {
+        return hv_result_err!(ENODEV, "4-level EPT page walk not supported!");
+    }
+    Ok(())
+}
+
+/// Intercepts of the configured profile. CPUID exiting can not be disabled
+/// on VMX, while the `LowLatency` profile only intercepts the MSR writes
+/// mediated for the RT CPU policy. Legacy PIC ports are
+/// always intercepted, as well as PCI configuration ports if PCI devices are
+/// assigned to the RTOS or interrupts are remapped.
+pub fn intercepts() -> InterceptFlags {
+    let config = HvSystemConfig::get();
+    let flags = match config.intercept_profile() {
+        InterceptProfile::Default => InterceptFlags::all(),
+        InterceptProfile::LowLatency => {
+            InterceptFlags::CPUID | InterceptFlags::LEGACY_IRQ | InterceptFlags::RESET
+        }
+    };
+    if config.rtos_pci_devices().is_empty() && !config.irq_remapping() {
+        flags
+    } else {
+        flags | InterceptFlags::PCI_CONFIG
     }
 }
@@ -1,4 +1,5 @@
 use bit_field::BitField;
+use libvmm::msr::Msr;
 
 use crate::error::HvResult;
 use crate::memory::{addr::virt_to_phys, AlignedPage, Frame, PhysAddr};
@@ -21,38 +22,141 @@ impl VmxRegion {
     pub fn paddr(&self) -> PhysAddr {
         self.frame.start_paddr()
     }
+
+    /// The VMX-abort indicator of a VMCS region, set by the processor before
+    /// it shuts down on a failed VM exit. (Intel SDM Volume 3, Section 27.7)
+    pub fn abort_indicator(&self) -> u32 {
+        unsafe { core::ptr::read_volatile((self.frame.as_ptr() as *const u32).add(1)) }
+    }
+}
+
+/// An entry of the VM-entry/VM-exit MSR load/store areas.
+/// (Intel SDM Volume 3, Section 24.7.2, VM-Exit Controls for MSRs)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct MsrAreaEntry {
+    index: u32,
+    _reserved: u32,
+    data: u64,
+}
+
+/// A page of MSR entries, used as a VM-entry/VM-exit MSR load/store area.
+pub(super) struct MsrArea {
+    frame: Frame,
+    count: usize,
+}
+
+impl MsrArea {
+    /// Max number of entries in one page.
+    const CAPACITY: usize = crate::memory::PAGE_SIZE / core::mem::size_of::<MsrAreaEntry>();
+
+    pub fn new() -> HvResult<Self> {
+        Ok(Self {
+            frame: Frame::new_zero()?,
+            count: 0,
+        })
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        self.frame.start_paddr()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    fn entries(&self) -> &[MsrAreaEntry] {
+        unsafe { core::slice::from_raw_parts(self.frame.as_ptr() as _, self.count) }
+    }
+
+    fn entries_mut(&mut self) -> &mut [MsrAreaEntry] {
+        unsafe { core::slice::from_raw_parts_mut(self.frame.as_mut_ptr() as _, Self::CAPACITY) }
+    }
+
+    /// Get the value of `msr` in this area.
+    pub fn get(&self, msr: Msr) -> Option<u64> {
+        self.entries()
+            .iter()
+            .find(|e| e.index == msr as u32)
+            .map(|e| e.data)
+    }
+
+    /// Set the value of `msr` in this area, add a new entry if not exists.
+    pub fn set(&mut self, msr: Msr, data: u64) -> HvResult {
+        let count = self.count;
+        if let Some(e) = self.entries_mut()[..count]
+            .iter_mut()
+            .find(|e| e.index == msr as u32)
+        {
+            e.data = data;
+            return Ok(());
+        }
+        if count >= Self::CAPACITY {
+            return hv_result_err!(ENOMEM, "MSR area is full");
+        }
+        self.entries_mut()[count] = MsrAreaEntry {
+            index: msr as u32,
+            _reserved: 0,
+            data,
+        };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Iterate over all (MSR index, value) pairs in this area.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.entries().iter().map(|e| (e.index, e.data))
+    }
 }
 
 pub(super) struct MsrBitmap(AlignedPage);
 
 impl MsrBitmap {
+    /// A bitmap intercepting no MSR.
+    pub fn empty() -> Self {
+        Self(AlignedPage::new())
+    }
+
     fn mask_range(&mut self, msr_range: core::ops::RangeInclusive<u32>, is_write: bool) {
         for msr in msr_range {
             self.mask(msr, is_write);
         }
     }
 
-    fn mask(&mut self, msr: u32, is_write: bool) {
+    /// Returns the byte containing the bit of `msr` and the bit index.
+    fn bit_of(&mut self, msr: u32, is_write: bool) -> (&mut u8, u8) {
         // (Intel SDM Volume 3, Section 24.6.9, MSR-Bitmap Address)
         // There are four contiguous MSR bitmaps, which are each 1-KByte in size:
         // 1. Read bitmap for low MSRs (0x0000_0000..0x0000_1FFF)
         // 2. Read bitmap for high MSRs (0xC000_0000..0xC000_1FFF)
         // 3. Write bitmap for low MSRs (0x0000_0000..0x0000_1FFF)
         // 4. Write bitmap for high MSRs (0xC000_0000..0xC000_1FFF)
-        let mut ptr = self.0.as_mut_ptr();
+        let mut offset = 0;
         let msr_low = msr & 0x1fff;
-        let msr_byte = (msr_low / 8) as usize;
-        let msr_bit = (msr_low % 8) as u8;
+        if msr >= 0xc000_0000 {
+            offset += 1 << 10;
+        }
+        if is_write {
+            offset += 2 << 10;
+        }
+        (
+            &mut self.0[offset + (msr_low / 8) as usize],
+            (msr_low % 8) as u8,
+        )
+    }
 
-        unsafe {
-            if msr >= 0xc000_0000 {
-                ptr = ptr.add(1 << 10);
-            }
-            if is_write {
-                ptr = ptr.add(2 << 10);
-            }
-            core::slice::from_raw_parts_mut(ptr, 1024)[msr_byte] &= 1 << msr_bit;
+    fn mask(&mut self, msr: u32, is_write: bool) {
+        let (byte, bit) = self.bit_of(msr, is_write);
+        *byte &= 1 << bit;
+    }
+
+    /// Intercept writes to `msrs`.
+    pub fn intercept_writes(mut self, msrs: &[u32]) -> Self {
+        for &msr in msrs {
+            let (byte, bit) = self.bit_of(msr, true);
+            *byte |= 1 << bit;
         }
+        self
     }
 
     pub fn paddr(&self) -> usize {
@@ -60,9 +164,34 @@ impl MsrBitmap {
     }
 }
 
+/// I/O bitmaps A (ports 0x0000..0x7FFF) and B (ports 0x8000..0xFFFF).
+/// (Intel SDM Volume 3, Section 24.6.4, I/O-Bitmap Addresses)
+pub(super) struct IoBitmap([AlignedPage; 2]);
+
+impl IoBitmap {
+    /// Bitmaps intercepting `ports`.
+    pub fn new(ports: impl Iterator<Item = u16>) -> Self {
+        let mut map = Self([AlignedPage::new(), AlignedPage::new()]);
+        for port in ports {
+            let page = &mut map.0[(port >> 15) as usize];
+            let port_low = (port & 0x7fff) as usize;
+            page[port_low / 8] |= 1 << (port_low % 8);
+        }
+        map
+    }
+
+    pub fn paddr_a(&self) -> usize {
+        virt_to_phys(self.0[0].as_ptr() as usize)
+    }
+
+    pub fn paddr_b(&self) -> usize {
+        virt_to_phys(self.0[1].as_ptr() as usize)
+    }
+}
+
 impl Default for MsrBitmap {
     fn default() -> Self {
-        let mut map = Self(AlignedPage::new());
+        let mut map = Self::empty();
         // read
         map.mask(0x277, false); // IA32_PAT
         map.mask(0x2FF, false); // IA32_MTRR_DEF_TYPE
@@ -13,16 +13,19 @@ use libvmm::vmx::{
 use x86::segmentation::SegmentSelector;
 use x86_64::addr::VirtAddr;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
 use x86_64::registers::rflags::RFlags;
 
-use super::structs::{MsrBitmap, VmxRegion};
+use super::structs::{IoBitmap, MsrArea, MsrBitmap, VmxRegion};
 use crate::arch::cpuid::CpuFeatures;
 use crate::arch::segmentation::{Segment, SegmentAccessRights};
 use crate::arch::tables::{GdtStruct, IdtStruct};
-use crate::arch::vmm::VcpuAccessGuestState;
+use crate::arch::vmm::{efer_after_cr0_write, GuestMsr, GuestSegment};
+use crate::arch::vmm::{InterceptFlags, VcpuAccessGuestState};
 use crate::arch::{GeneralRegisters, GuestPageTableImmut, LinuxContext};
 use crate::cell::Cell;
 use crate::error::HvResult;
+use crate::hal::Vcpu as HalVcpu;
 use crate::percpu::PerCpu;
 
 #[repr(C)]
@@ -35,10 +38,21 @@ pub struct Vcpu {
     vmxon_region: VmxRegion,
     /// VMCS of this CPU, required by VMX
     vmcs_region: VmxRegion,
+    /// Guest-owned MSRs, loaded on VM entry and stored on VM exit.
+    guest_msrs: MsrArea,
+    /// Host values of guest-owned MSRs, loaded on VM exit.
+    host_msrs: MsrArea,
+    /// EPT generation whose translations are in the TLB of this CPU.
+    ept_generation: u64,
 }
 
 lazy_static! {
-    static ref MSR_BITMAP: MsrBitmap = MsrBitmap::default();
+    static ref MSR_BITMAP: MsrBitmap =
+        MsrBitmap::default().intercept_writes(crate::arch::rt_policy::mediated_msrs());
+    static ref MIN_MSR_BITMAP: MsrBitmap =
+        MsrBitmap::empty().intercept_writes(crate::arch::rt_policy::mediated_msrs());
+    static ref IO_BITMAP: IoBitmap =
+        IoBitmap::new(crate::arch::pio::intercepted_ports(super::intercepts()));
 }
 
 macro_rules! set_guest_segment {
@@ -53,8 +67,24 @@ macro_rules! set_guest_segment {
     }};
 }
 
-impl Vcpu {
-    pub fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self> {
+macro_rules! guest_segment {
+    ($reg: ident) => {{
+        use VmcsField16Guest::*;
+        use VmcsField32Guest::*;
+        use VmcsField64Guest::*;
+        Segment {
+            selector: SegmentSelector::from_raw(concat_idents!($reg, _SELECTOR).read()?),
+            base: concat_idents!($reg, _BASE).read()?,
+            limit: concat_idents!($reg, _LIMIT).read()?,
+            access_rights: SegmentAccessRights::from_bits_truncate(
+                concat_idents!($reg, _AR_BYTES).read()?,
+            ),
+        }
+    }};
+}
+
+impl HalVcpu for Vcpu {
+    fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self> {
         super::check_hypervisor_feature()?;
 
         // make sure all perf counters are off
@@ -107,13 +137,17 @@ impl Vcpu {
             host_stack_top: PerCpu::current().stack_top() as _,
             vmxon_region,
             vmcs_region,
+            guest_msrs: MsrArea::new()?,
+            host_msrs: MsrArea::new()?,
+            ept_generation: 0,
         };
         ret.vmcs_setup(linux, cell)?;
 
         Ok(ret)
     }
 
-    pub fn enter(&mut self, linux: &LinuxContext) -> HvResult {
+    fn enter(&mut self, linux: &LinuxContext) -> HvResult {
+        self.sync_nested_tlb()?;
         let regs = self.regs_mut();
         regs.rax = 0;
         regs.rbx = linux.rbx;
@@ -127,51 +161,104 @@ impl Vcpu {
                 "mov rsp, {0}",
                 restore_regs_from_stack!(),
                 "vmlaunch",
+                // VMLAUNCH failed, RSP points to Vcpu::host_stack_top.
+                "mov rsp, [rsp]",
+                "call {1}",
                 in(reg) regs as * const _ as usize,
+                sym vmlaunch_failed,
+                options(noreturn),
             );
         }
-        // Never return if successful
-        error!(
-            "Activate hypervisor failed: {:?}",
-            Vmcs::instruction_error()
-        );
-        hv_result_err!(EIO)
     }
 
-    pub fn exit(&self, linux: &mut LinuxContext) -> HvResult {
+    fn exit(&self, linux: &mut LinuxContext) -> HvResult {
+        crate::arch::nested_tlb::leave_guest(PerCpu::current().id);
         self.load_vmcs_guest(linux)?;
+        // Hand the last guest values of guest-owned MSRs back to Linux.
+        for (msr, val) in self.guest_msrs.iter() {
+            unsafe { x86::msr::wrmsr(msr, val) };
+        }
         Vmcs::clear(self.vmcs_region.paddr())?;
         unsafe { vmx::vmxoff()? };
         info!("successed to turn off VMX.");
         Ok(())
     }
 
-    pub fn inject_fault(&mut self) -> HvResult {
+    fn inject_fault(&mut self) -> HvResult {
         Vmcs::inject_interrupt(crate::arch::ExceptionType::GeneralProtectionFault, Some(0))?;
         Ok(())
     }
 
-    pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
-        VmcsField64Guest::RIP.write(VmcsField64Guest::RIP.read()? + instr_len as u64)?;
-        Ok(())
-    }
-
-    pub fn guest_is_privileged(&self) -> bool {
+    fn guest_is_privileged(&self) -> bool {
         SegmentAccessRights::from_bits_truncate(VmcsField32Guest::CS_AR_BYTES.read().unwrap()).dpl()
             == 0
     }
 
-    pub fn in_hypercall(&self) -> bool {
+    fn in_hypercall(&self) -> bool {
         matches!(Vmcs::exit_reason(), Ok(VmxExitReason::VMCALL))
     }
 
+    fn abort_indicator(&self) -> Option<u32> {
+        match self.vmcs_region.abort_indicator() {
+            0 => None,
+            reason => Some(reason),
+        }
+    }
+
+    fn num_frames(&self) -> usize {
+        4 // VMXON region, VMCS, guest and host MSR areas
+    }
+}
+
+impl Vcpu {
+    pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
+        VmcsField64Guest::RIP.write(VmcsField64Guest::RIP.read()? + instr_len as u64)?;
+        Ok(())
+    }
+
+    /// Invalidates the stale EPT translations of this CPU, right before
+    /// resuming the guest.
+    pub fn sync_nested_tlb(&mut self) -> HvResult {
+        super::ept::sync_flush(&mut self.ept_generation)
+    }
+
     pub fn guest_page_table(&self) -> GuestPageTableImmut {
         use crate::memory::{addr::align_down, GenericPageTableImmut};
         unsafe { GuestPageTableImmut::from_root(align_down(self.cr(3) as _)) }
     }
+
+    /// Declare `msr` as owned by the guest: `guest_val` is loaded on the next
+    /// VM entry, the guest value is saved on each VM exit, and the current host
+    /// value is restored on VM exit.
+    ///
+    /// Must be called on the CPU owning this VMCS.
+    pub fn set_guest_owned_msr(&mut self, msr: Msr, guest_val: u64) -> HvResult {
+        self.guest_msrs.set(msr, guest_val)?;
+        self.host_msrs.set(msr, msr.read())?;
+        self.update_msr_areas()
+    }
+
+    /// Get the guest value of a guest-owned MSR saved on the last VM exit.
+    pub fn guest_owned_msr(&self, msr: Msr) -> Option<u64> {
+        self.guest_msrs.get(msr)
+    }
 }
 
 impl Vcpu {
+    /// Writes the guest EFER, keeping the IA-32e mode guest entry control
+    /// consistent with EFER.LMA.
+    fn set_guest_efer(efer: u64) -> HvResult {
+        use vmx::flags::VmEntryControls as EntryCtrl;
+        VmcsField64Guest::IA32_EFER.write(efer)?;
+        let mut ctrl = EntryCtrl::from_bits_truncate(VmcsField32Control::VM_ENTRY_CONTROLS.read()?);
+        ctrl.set(
+            EntryCtrl::IA32E_MODE,
+            efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0,
+        );
+        VmcsField32Control::VM_ENTRY_CONTROLS.write(ctrl.bits())?;
+        Ok(())
+    }
+
     fn vmcs_setup(&mut self, linux: &LinuxContext, cell: &Cell) -> HvResult {
         let paddr = self.vmcs_region.paddr();
         Vmcs::clear(paddr)?;
@@ -179,6 +266,29 @@ impl Vcpu {
         self.setup_vmcs_host()?;
         self.setup_vmcs_guest(linux)?;
         self.setup_vmcs_control(cell)?;
+        self.setup_guest_owned_msrs()?;
+        Ok(())
+    }
+
+    /// MSRs that Linux and the hypervisor may program differently, so they are
+    /// switched on VM entry and VM exit.
+    fn setup_guest_owned_msrs(&mut self) -> HvResult {
+        if CpuFeatures::new().has_spec_ctrl() {
+            self.set_guest_owned_msr(Msr::IA32_SPEC_CTRL, Msr::IA32_SPEC_CTRL.read())?;
+        }
+        Ok(())
+    }
+
+    fn update_msr_areas(&self) -> HvResult {
+        // The exit-store area and the entry-load area are the same, so the
+        // guest values saved on VM exit are loaded again on the next VM entry.
+        let guest_count = self.guest_msrs.count() as u32;
+        VmcsField64Control::VM_EXIT_MSR_STORE_ADDR.write(self.guest_msrs.paddr() as _)?;
+        VmcsField32Control::VM_EXIT_MSR_STORE_COUNT.write(guest_count)?;
+        VmcsField64Control::VM_ENTRY_MSR_LOAD_ADDR.write(self.guest_msrs.paddr() as _)?;
+        VmcsField32Control::VM_ENTRY_MSR_LOAD_COUNT.write(guest_count)?;
+        VmcsField64Control::VM_EXIT_MSR_LOAD_ADDR.write(self.host_msrs.paddr() as _)?;
+        VmcsField32Control::VM_EXIT_MSR_LOAD_COUNT.write(self.host_msrs.count() as u32)?;
         Ok(())
     }
 
@@ -199,7 +309,9 @@ impl Vcpu {
         VmcsField16Host::TR_SELECTOR.write(GdtStruct::TSS_SELECTOR.bits())?;
         VmcsField64Host::FS_BASE.write(0)?;
         VmcsField64Host::GS_BASE.write(Msr::IA32_GS_BASE.read())?;
-        VmcsField64Host::TR_BASE.write(0)?;
+        // The IST stacks are found through the TSS after VM exits.
+        let tss = Segment::from_selector(GdtStruct::TSS_SELECTOR, &GdtStruct::sgdt());
+        VmcsField64Host::TR_BASE.write(tss.base)?;
 
         VmcsField64Host::GDTR_BASE.write(GdtStruct::sgdt().base.as_u64())?;
         VmcsField64Host::IDTR_BASE.write(IdtStruct::sidt().base.as_u64())?;
@@ -218,8 +330,8 @@ impl Vcpu {
         VmcsField64Guest::IA32_PAT.write(linux.pat)?;
         VmcsField64Guest::IA32_EFER.write(linux.efer)?;
 
-        self.set_cr(0, linux.cr0.bits());
         self.set_cr(4, linux.cr4.bits());
+        self.set_cr(0, linux.cr0.bits());
         self.set_cr(3, linux.cr3);
 
         set_guest_segment!(linux.es, ES);
@@ -289,21 +401,30 @@ impl Vcpu {
     }
 
     fn setup_vmcs_control(&mut self, cell: &Cell) -> HvResult {
+        let intercepts = super::intercepts();
         use vmx::flags::PinVmExecControls as PinCtrl;
+        let (set, clear) = if intercepts.contains(InterceptFlags::NMI) {
+            (PinCtrl::NMI_EXITING, PinCtrl::empty())
+        } else {
+            (
+                PinCtrl::empty(),
+                PinCtrl::NMI_EXITING | PinCtrl::VIRTUAL_NMIS,
+            )
+        };
         Vmcs::set_control(
             VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PINBASED_CTLS.read(),
             // NO INTR_EXITING to pass-through interrupts
-            PinCtrl::NMI_EXITING.bits(),
-            0,
+            set.bits(),
+            clear.bits(),
         )?;
 
         use vmx::flags::PrimaryVmExecControls as CpuCtrl;
         Vmcs::set_control(
             VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PROCBASED_CTLS.read(),
-            // NO UNCOND_IO_EXITING to pass-through PIO
-            (CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SEC_CONTROLS).bits(),
+            // NO UNCOND_IO_EXITING to pass-through PIO not in the I/O bitmaps
+            (CpuCtrl::USE_IO_BITMAPS | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SEC_CONTROLS).bits(),
             (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
         )?;
 
@@ -347,16 +468,21 @@ impl Vcpu {
             0,
         )?;
 
-        VmcsField32Control::VM_EXIT_MSR_STORE_COUNT.write(0)?;
-        VmcsField32Control::VM_EXIT_MSR_LOAD_COUNT.write(0)?;
-        VmcsField32Control::VM_ENTRY_MSR_LOAD_COUNT.write(0)?;
+        self.update_msr_areas()?;
 
         VmcsField64Control::CR4_GUEST_HOST_MASK.write(0)?;
         VmcsField32Control::CR3_TARGET_COUNT.write(0)?;
 
-        unsafe { cell.gpm.activate() }; // Set EPT_POINTER
+        unsafe { cell.gpm.read().activate() }; // Set EPT_POINTER
 
-        VmcsField64Control::MSR_BITMAP.write(MSR_BITMAP.paddr() as _)?;
+        let msr_bitmap = if intercepts.contains(InterceptFlags::MSR) {
+            &*MSR_BITMAP
+        } else {
+            &*MIN_MSR_BITMAP
+        };
+        VmcsField64Control::MSR_BITMAP.write(msr_bitmap.paddr() as _)?;
+        VmcsField64Control::IO_BITMAP_A.write(IO_BITMAP.paddr_a() as _)?;
+        VmcsField64Control::IO_BITMAP_B.write(IO_BITMAP.paddr_b() as _)?;
         VmcsField32Control::EXCEPTION_BITMAP.write(0)?;
 
         Ok(())
@@ -416,6 +542,15 @@ impl VcpuAccessGuestState for Vcpu {
         (|| -> HvResult {
             match cr_idx {
                 0 => {
+                    // Keep EFER.LMA and the IA-32e mode guest entry control
+                    // consistent with CR0.PG. A write raising #GP leaves CR0
+                    // unchanged.
+                    let efer = VmcsField64Guest::IA32_EFER.read()?;
+                    let efer = match efer_after_cr0_write(val, self.cr(4), efer) {
+                        Some(efer) => efer,
+                        None => return self.inject_fault(),
+                    };
+
                     // Retrieve/validate restrictions on CR0
                     //
                     // In addition to what the VMX MSRs tell us, make sure that
@@ -431,6 +566,7 @@ impl VcpuAccessGuestState for Vcpu {
                     VmcsField64Guest::CR0.write((val & must0) | must1)?;
                     VmcsField64Control::CR0_READ_SHADOW.write(val)?;
                     VmcsField64Control::CR0_GUEST_HOST_MASK.write(must1 | !must0)?;
+                    Self::set_guest_efer(efer)?;
                 }
                 3 => VmcsField64Guest::CR3.write(val)?,
                 4 => {
@@ -448,28 +584,72 @@ impl VcpuAccessGuestState for Vcpu {
         })()
         .expect("Failed to write guest control register")
     }
+
+    fn segment(&self, seg: GuestSegment) -> Segment {
+        (|| -> HvResult<Segment> {
+            Ok(match seg {
+                GuestSegment::Es => guest_segment!(ES),
+                GuestSegment::Cs => guest_segment!(CS),
+                GuestSegment::Ss => guest_segment!(SS),
+                GuestSegment::Ds => guest_segment!(DS),
+                GuestSegment::Fs => guest_segment!(FS),
+                GuestSegment::Gs => guest_segment!(GS),
+                GuestSegment::Ldtr => guest_segment!(LDTR),
+                GuestSegment::Tr => guest_segment!(TR),
+                GuestSegment::Gdtr => Segment {
+                    base: VmcsField64Guest::GDTR_BASE.read()?,
+                    limit: VmcsField32Guest::GDTR_LIMIT.read()?,
+                    ..Segment::invalid()
+                },
+                GuestSegment::Idtr => Segment {
+                    base: VmcsField64Guest::IDTR_BASE.read()?,
+                    limit: VmcsField32Guest::IDTR_LIMIT.read()?,
+                    ..Segment::invalid()
+                },
+            })
+        })()
+        .expect("Failed to read guest segment")
+    }
+
+    fn msr(&self, msr: GuestMsr) -> u64 {
+        (|| -> HvResult<u64> {
+            Ok(match msr {
+                GuestMsr::SysenterCs => VmcsField32Guest::SYSENTER_CS.read()? as _,
+                GuestMsr::SysenterEsp => VmcsField64Guest::SYSENTER_ESP.read()?,
+                GuestMsr::SysenterEip => VmcsField64Guest::SYSENTER_EIP.read()?,
+                GuestMsr::DebugCtl => VmcsField64Guest::IA32_DEBUGCTL.read()?,
+                GuestMsr::Pat => VmcsField64Guest::IA32_PAT.read()?,
+                GuestMsr::Efer => VmcsField64Guest::IA32_EFER.read()?,
+                GuestMsr::FsBase => VmcsField64Guest::FS_BASE.read()?,
+                GuestMsr::GsBase => VmcsField64Guest::GS_BASE.read()?,
+                // Not switched on VM exits unless guest-owned, so the live
+                // value is the guest one.
+                _ => self
+                    .guest_owned_msr(msr.msr())
+                    .unwrap_or_else(|| msr.msr().read()),
+            })
+        })()
+        .expect("Failed to read guest MSR")
+    }
 }
 
 impl Debug for Vcpu {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        (|| -> HvResult<Result> {
-            Ok(f.debug_struct("Vcpu")
-                .field("guest_regs", &self.guest_regs)
-                .field("rip", &self.instr_pointer())
-                .field("rsp", &self.stack_pointer())
-                .field("rflags", unsafe {
-                    &RFlags::from_bits_unchecked(self.rflags())
-                })
-                .field("cr0", unsafe { &Cr0Flags::from_bits_unchecked(self.cr(0)) })
-                .field("cr3", &self.cr(3))
-                .field("cr4", unsafe { &Cr4Flags::from_bits_unchecked(self.cr(4)) })
-                .field("cs", &VmcsField16Guest::CS_SELECTOR.read()?)
-                .field("fs_base", &VmcsField64Guest::FS_BASE.read()?)
-                .field("gs_base", &VmcsField64Guest::GS_BASE.read()?)
-                .field("tss", &VmcsField16Guest::TR_SELECTOR.read()?)
-                .finish())
-        })()
-        .unwrap()
+        f.debug_struct("Vcpu")
+            .field("guest_regs", &self.guest_regs)
+            .field("rip", &self.instr_pointer())
+            .field("rsp", &self.stack_pointer())
+            .field("rflags", unsafe {
+                &RFlags::from_bits_unchecked(self.rflags())
+            })
+            .field("cr0", unsafe { &Cr0Flags::from_bits_unchecked(self.cr(0)) })
+            .field("cr3", &self.cr(3))
+            .field("cr4", unsafe { &Cr4Flags::from_bits_unchecked(self.cr(4)) })
+            .field("cs", &self.segment(GuestSegment::Cs).selector)
+            .field("fs_base", &self.fs_base())
+            .field("gs_base", &self.gs_base())
+            .field("tss", &self.segment(GuestSegment::Tr).selector)
+            .finish()
     }
 }
 
@@ -491,6 +671,12 @@ unsafe extern "sysv64" fn vmx_exit() -> ! {
     );
 }
 
+fn vmlaunch_failed() -> ! {
+    let err = hv_err!(ENOEXEC, "VMLAUNCH failed: {:?}", Vmcs::instruction_error());
+    PerCpu::current_mut().abort_entry(err)
+}
+
 fn vmresume_failed() -> ! {
-    panic!("VM resume failed: {:?}", Vmcs::instruction_error());
+    let err = hv_err!(ENOEXEC, "VMRESUME failed: {:?}", Vmcs::instruction_error());
+    PerCpu::current_mut().abort_entry(err)
 }
@@ -1,9 +1,63 @@
-use libvmm::vmx::vmcs::{EptViolationInfo, ExitInterruptInfo, VmExitInfo};
+use libvmm::vmx::flags::VmxBasic;
+use libvmm::vmx::vmcs::{EptViolationInfo, ExitInterruptInfo, IoExitInfo, VmExitInfo};
 use libvmm::vmx::VmxExitReason;
 
+use crate::arch::pio::IoInstrInfo;
 use crate::arch::vmm::VmExit;
 use crate::arch::ExceptionType;
 use crate::error::HvResult;
+use crate::hal::Vcpu;
+
+/// Whether VM exits of `reason` are caused by the execution of an
+/// instruction, and report its length (SDM Vol. 3C, section 28.2.5).
+fn is_instruction_exit(reason: VmxExitReason) -> bool {
+    use VmxExitReason::*;
+    matches!(
+        reason,
+        CPUID
+            | GETSEC
+            | HLT
+            | INVD
+            | INVLPG
+            | RDPMC
+            | RDTSC
+            | RSM
+            | VMCALL
+            | VMCLEAR
+            | VMLAUNCH
+            | VMPTRLD
+            | VMPTRST
+            | VMREAD
+            | VMRESUME
+            | VMWRITE
+            | VMOFF
+            | VMON
+            | CR_ACCESS
+            | DR_ACCESS
+            | IO_INSTRUCTION
+            | MSR_READ
+            | MSR_WRITE
+            | MWAIT_INSTRUCTION
+            | MONITOR_INSTRUCTION
+            | PAUSE_INSTRUCTION
+            | GDTR_IDTR
+            | LDTR_TR
+            | INVEPT
+            | RDTSCP
+            | INVVPID
+            | WBINVD
+            | XSETBV
+            | RDRAND
+            | INVPCID
+            | VMFUNC
+            | ENCLS
+            | RDSEED
+            | XSAVES
+            | XRSTORS
+            | UMWAIT
+            | TPAUSE
+    )
+}
 
 impl VmExit<'_> {
     fn handle_exception_nmi(&mut self, exit_info: &VmExitInfo) -> HvResult {
@@ -23,6 +77,17 @@ impl VmExit<'_> {
 
     fn handle_ept_violation(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let ept_vio_info = EptViolationInfo::new()?;
+        if crate::integrity::check_violation(
+            ept_vio_info.guest_paddr,
+            ept_vio_info.write,
+            ept_vio_info.instruction,
+            exit_info.guest_rip,
+        ) {
+            return hv_result_err!(EPERM, "Access to monitored range");
+        }
+        if let Some(res) = self.handle_mmio(ept_vio_info.guest_paddr) {
+            return res;
+        }
         warn!(
             "VM exit: EPT violation @ {:#x} RIP({:#x}, {}): {:#x?}",
             ept_vio_info.guest_paddr,
@@ -33,12 +98,51 @@ impl VmExit<'_> {
         hv_result_err!(ENOSYS)
     }
 
+    /// Diagnoses an EPT misconfiguration, which is a hypervisor bug: prints the
+    /// entries translating the faulting address with the malformed one, and
+    /// the memory region which mapped it.
+    fn handle_ept_misconfig(&mut self) -> HvResult {
+        use libvmm::vmx::vmcs::VmcsField64ReadOnly;
+        let gpaddr = VmcsField64ReadOnly::GUEST_PHYSICAL_ADDRESS.read()? as usize;
+        error!("VM exit: EPT misconfiguration @ {:#x}", gpaddr);
+        let gpm = crate::cell::root_cell().gpm.read();
+        for (level, entry) in gpm.page_table().entry_chain(gpaddr).iter().enumerate() {
+            match entry.misconfig_reason(level) {
+                Some(reason) => error!("  level {}: {:#x?} <= {}", level, entry, reason),
+                None => error!("  level {}: {:#x?}", level, entry),
+            }
+        }
+        match gpm.find_region(gpaddr) {
+            Some(region) => error!("  mapped by {:#x?}", region),
+            None => error!("  not mapped by any memory region"),
+        }
+        hv_result_err!(EIO, "EPT misconfiguration")
+    }
+
+    fn handle_io_instruction(&mut self, exit_info: &VmExitInfo) -> HvResult {
+        let io_info = IoExitInfo::new(VmxBasic::read().io_exit_info)?;
+        self.handle_io(&IoInstrInfo {
+            port: io_info.port,
+            size: io_info.size,
+            is_in: io_info.is_in,
+            is_string: io_info.is_string,
+            is_rep: io_info.is_rep,
+            // The root cell always runs in long mode.
+            addr_size: io_info.addr_size.unwrap_or(8),
+            instr_len: exit_info.exit_instruction_length as u8,
+        })
+    }
+
     pub fn handle_exit(&mut self) -> HvResult {
         let exit_info = VmExitInfo::new()?;
+        self.exit_reason = exit_info.exit_reason as u32;
+        crate::logging::note_exit_reason(self.exit_reason);
         trace!("VM exit: {:#x?}", exit_info);
 
         if exit_info.entry_failure {
-            panic!("VM entry failed: {:#x?}", exit_info);
+            error!("VM entry failed: {:#x?}", exit_info);
+            let err = hv_err!(ENOEXEC, "VM entry failed: {:#x}", self.exit_reason);
+            self.cpu_data.abort_entry(err);
         }
         // self.test_read_guest_memory(
         //     exit_info.guest_rip as _,
@@ -51,16 +155,27 @@ impl VmExit<'_> {
             VmxExitReason::VMCALL => self.handle_hypercall(),
             VmxExitReason::MSR_READ => self.handle_msr_read(),
             VmxExitReason::MSR_WRITE => self.handle_msr_write(),
+            VmxExitReason::IO_INSTRUCTION => self.handle_io_instruction(&exit_info),
             VmxExitReason::EPT_VIOLATION => self.handle_ept_violation(&exit_info),
+            VmxExitReason::EPT_MISCONFIG => self.handle_ept_misconfig(),
             VmxExitReason::TRIPLE_FAULT => {
                 error!("Triple fault: {:#x?}", exit_info);
                 self.cpu_data.vcpu.inject_fault()?;
                 Ok(())
             }
-            _ => hv_result_err!(ENOSYS),
+            reason => {
+                // The instruction length is only valid for exits caused by
+                // an instruction.
+                let instr_len = if is_instruction_exit(reason) {
+                    exit_info.exit_instruction_length as u8
+                } else {
+                    0
+                };
+                self.handle_unhandled(instr_len)
+            }
         };
 
-        if res.is_err() {
+        if res.is_err() && crate::logging::is_boosted() {
             warn!(
                 "VM exit handler for reason {:?} returned {:?}:\n\
                 {:#x?}\n\n\
@@ -68,6 +183,11 @@ impl VmExit<'_> {
                 {:#x?}",
                 exit_info.exit_reason, res, exit_info, self.cpu_data.vcpu,
             );
+        } else if res.is_err() {
+            warn!(
+                "VM exit handler for reason {:?} returned {:?}",
+                exit_info.exit_reason, res
+            );
         }
         res
     }
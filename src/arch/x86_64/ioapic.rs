@@ -0,0 +1,211 @@
+//! I/O APIC mediation, with interrupt remapping.
+//!
+//! The register pages of the I/O APICs of the MADT are unmapped from the root
+//! cell and emulated: the redirection table entries (RTEs) written by Linux
+//! are shadowed, and the I/O APICs are programmed with their remappable
+//! format (see `irq_remap`). Reads of the RTEs return the shadowed values,
+//! with the delivery status and remote IRR of the hardware. Other registers
+//! and the EOI register are passed through.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::acpi;
+use super::irq_remap::{self, IrqMessage, IrqSource};
+use crate::cell;
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::addr::{phys_to_virt, PhysAddr};
+use crate::memory::{hv_page_table, MemFlags, MemoryRegion, PAGE_SIZE};
+use crate::mmio::MmioDevice;
+
+/// Register select and window offsets.
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+/// Indirect registers: version, and the first redirection table entry.
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_RTE0: u32 = 0x10;
+/// RTE bits read from the hardware: delivery status and remote IRR.
+const RTE_HW_STATUS: u32 = 1 << 12 | 1 << 14;
+
+struct IoApic {
+    id: u8,
+    base: PhysAddr,
+    /// Hypervisor mapping of the registers.
+    regs: usize,
+    /// Requester ID of the I/O APIC, from the DMAR.
+    sid: Option<u16>,
+    /// Last value written to the register select by the guest.
+    select: u32,
+    /// Redirection table entries, as programmed by the guest.
+    rtes: Vec<u64>,
+}
+
+static IOAPICS: SpinLock<Vec<IoApic>> = SpinLock::new(Vec::new());
+
+impl IoApic {
+    fn read_reg(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.regs + IOAPIC_REGSEL) as *mut u32).write_volatile(reg);
+            ((self.regs + IOAPIC_WINDOW) as *const u32).read_volatile()
+        }
+    }
+
+    fn write_reg(&self, reg: u32, value: u32) {
+        unsafe {
+            ((self.regs + IOAPIC_REGSEL) as *mut u32).write_volatile(reg);
+            ((self.regs + IOAPIC_WINDOW) as *mut u32).write_volatile(value);
+        }
+    }
+
+    /// Returns the pin and half of the RTE register `reg`.
+    fn rte_of(&self, reg: u32) -> Option<(usize, bool)> {
+        let index = reg.checked_sub(IOAPIC_REG_RTE0)? as usize;
+        (index / 2 < self.rtes.len()).then(|| (index / 2, index % 2 == 1))
+    }
+
+    /// Programs the RTE of `pin` with its shadow, in remappable format if
+    /// `remap`.
+    fn program(&self, pin: usize, remap: bool) -> HvResult {
+        let shadow = self.rtes[pin];
+        let rte = if remap {
+            let source = IrqSource::IoApic(self.id, pin as u8);
+            let msg = IrqMessage::from_rte(shadow);
+            let handle = irq_remap::remap_root(source, self.sid, &msg)?;
+            irq_remap::rte_remappable(handle, shadow)
+        } else {
+            shadow
+        };
+        // Masked while the halves are not consistent.
+        let reg = IOAPIC_REG_RTE0 + pin as u32 * 2;
+        self.write_reg(reg, rte as u32 | 1 << 16);
+        self.write_reg(reg + 1, (rte >> 32) as u32);
+        self.write_reg(reg, rte as u32);
+        Ok(())
+    }
+}
+
+/// The register page of an I/O APIC, indexed in `IOAPICS`.
+struct IoApicDevice(usize);
+
+impl MmioDevice for IoApicDevice {
+    fn name(&self) -> &str {
+        "I/O APIC"
+    }
+
+    fn read(&self, offset: usize, _size: u8) -> u64 {
+        let ioapics = IOAPICS.lock();
+        let ioapic = &ioapics[self.0];
+        let value = match offset {
+            IOAPIC_REGSEL => ioapic.select,
+            IOAPIC_WINDOW => match ioapic.rte_of(ioapic.select) {
+                Some((pin, false)) => {
+                    let hw = ioapic.read_reg(ioapic.select);
+                    (ioapic.rtes[pin] as u32 & !RTE_HW_STATUS) | (hw & RTE_HW_STATUS)
+                }
+                Some((pin, true)) => (ioapic.rtes[pin] >> 32) as u32,
+                None => ioapic.read_reg(ioapic.select),
+            },
+            _ => unsafe { ((ioapic.regs + offset) as *const u32).read_volatile() },
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: usize, _size: u8, value: u64) {
+        let mut ioapics = IOAPICS.lock();
+        let ioapic = &mut ioapics[self.0];
+        let value = value as u32;
+        match offset {
+            IOAPIC_REGSEL => ioapic.select = value & 0xff,
+            IOAPIC_WINDOW => match ioapic.rte_of(ioapic.select) {
+                Some((pin, high)) => {
+                    let rte = &mut ioapic.rtes[pin];
+                    *rte = if high {
+                        (*rte & 0xffff_ffff) | (value as u64) << 32
+                    } else {
+                        (*rte & !0xffff_ffff) | value as u64
+                    };
+                    if let Err(e) = ioapic.program(pin, true) {
+                        warn!(
+                            "Failed to remap pin {} of I/O APIC {}: {:?}",
+                            pin, ioapic.id, e
+                        );
+                    }
+                }
+                None => ioapic.write_reg(ioapic.select, value),
+            },
+            // EOI register, the vector of remappable RTEs is unchanged.
+            _ => unsafe { ((ioapic.regs + offset) as *mut u32).write_volatile(value) },
+        }
+    }
+}
+
+/// Translates the RTEs of the I/O APICs into remappable format, and
+/// emulates their registers. Called once interrupt remapping is enabled.
+pub(super) fn init() -> HvResult {
+    let cell = cell::root_cell();
+    for info in acpi::madt_ioapics() {
+        let regs = phys_to_virt(info.base);
+        hv_page_table()
+            .write()
+            .insert(MemoryRegion::new_with_offset_mapper(
+                regs,
+                info.base,
+                PAGE_SIZE,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+            ))?;
+        let sid = acpi::dmar_units()
+            .iter()
+            .flat_map(|unit| unit.ioapics.iter())
+            .find(|&&(id, _)| id == info.id)
+            .map(|&(_, sid)| sid);
+        if sid.is_none() {
+            warn!(
+                "I/O APIC {} is not in the DMAR, its source is not verified",
+                info.id
+            );
+        }
+        let mut ioapic = IoApic {
+            id: info.id,
+            base: info.base,
+            regs,
+            sid,
+            select: 0,
+            rtes: Vec::new(),
+        };
+        let num_pins = ((ioapic.read_reg(IOAPIC_REG_VERSION) >> 16) & 0xff) as usize + 1;
+        for pin in 0..num_pins {
+            let reg = IOAPIC_REG_RTE0 + pin as u32 * 2;
+            let rte = ioapic.read_reg(reg) as u64 | (ioapic.read_reg(reg + 1) as u64) << 32;
+            ioapic.rtes.push(rte);
+        }
+        for pin in 0..num_pins {
+            ioapic.program(pin, true)?;
+        }
+
+        let mut ioapics = IOAPICS.lock();
+        ioapics.push(ioapic);
+        let index = ioapics.len() - 1;
+        drop(ioapics);
+        cell.gpm
+            .write()
+            .unmap_phys(info.base..info.base + PAGE_SIZE)?;
+        cell.register_mmio_device(info.base, PAGE_SIZE, Box::new(IoApicDevice(index)))?;
+        info!(
+            "I/O APIC {} @ {:#x}: {} pins remapped",
+            info.id, info.base, num_pins
+        );
+    }
+    Ok(())
+}
+
+/// Programs the I/O APICs with their RTEs in compatibility format again,
+/// before interrupt remapping is disabled.
+pub(super) fn restore() {
+    for ioapic in IOAPICS.lock().iter() {
+        for pin in 0..ioapic.rtes.len() {
+            ioapic.program(pin, false).ok();
+        }
+        debug!("I/O APIC {} @ {:#x} restored", ioapic.id, ioapic.base);
+    }
+}
@@ -0,0 +1,471 @@
+//! DMA remapping with Intel VT-d.
+//!
+//! The remapping units of the ACPI DMAR translate the DMA of the PCI devices
+//! through second-level page tables, one per domain:
+//!
+//! - the root cell domain maps the root cell memory regions flagged `DMA`,
+//!   except hypervisor and RTOS memory, so that devices of Linux can neither
+//!   corrupt the hypervisor nor the RTOS;
+//! - each PCI device assigned to the RTOS gets a domain of its own, which
+//!   identity maps the RTOS memory, so that the IOTLB entries of a VF are
+//!   never shared with another.
+//!
+//! The tables mirror the memory maps of the configuration and are not changed
+//! afterwards: hypervisor memory released to the root cell later is not made
+//! DMA-able. The register sets of the enabled units are hidden from the root
+//! cell. Units already enabled by Linux are left alone, Linux must be booted
+//! with `intel_iommu=off` for its DMA to be isolated.
+//!
+//! Interrupt remapping can be enabled on top, see `irq_remap`. Translation
+//! is disabled again on every path leaving the hypervisor.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+
+use super::acpi::{self, DmarUnit};
+use super::cpu;
+use super::mem_encrypt::phys_addr_mask;
+use crate::cell;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::addr::{phys_to_virt, GuestPhysAddr, HostPhysAddr, PhysAddr};
+use crate::memory::{
+    hv_page_table, Frame, GenericPTE, GenericPageTableImmut, Level4PageTable, MemFlags,
+    MemoryRegion, MemorySet, PagingInstr,
+};
+
+/// Remapping unit registers (VT-d 4.1, section 10.4).
+const VTD_CAP: usize = 0x08;
+const VTD_ECAP: usize = 0x10;
+const VTD_GCMD: usize = 0x18;
+const VTD_GSTS: usize = 0x1c;
+const VTD_RTADDR: usize = 0x20;
+const VTD_CCMD: usize = 0x28;
+
+/// CAP: 4-level second-level tables supported (SAGAW bit 2).
+const CAP_SAGAW_4LEVEL: u64 = 1 << 10;
+/// CAP: second-level 2 MB and 1 GB pages supported.
+const CAP_SLLPS_2M: u64 = 1 << 34;
+const CAP_SLLPS_1G: u64 = 1 << 35;
+/// ECAP: page walks snoop the processor caches.
+pub(super) const ECAP_COHERENT: u64 = 1 << 0;
+
+/// CCMD: global context-cache invalidation.
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_CIRG_GLOBAL: u64 = 1 << 61;
+/// IOTLB invalidate register: global IOTLB invalidation.
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_IIRG_GLOBAL: u64 = 1 << 60;
+
+/// GSTS bits which are not one-shot, to be kept when writing GCMD.
+const GSTS_PERSISTENT_MASK: u32 = 0x96ff_ffff;
+
+/// Time to wait for a unit to complete a command.
+const COMMAND_TIMEOUT_US: u64 = 10 * 1000; // 10ms
+
+/// Domain IDs; 0 is reserved with caching mode. The RTOS devices get the
+/// IDs from `RTOS_DOMAIN_ID` on, in configuration order.
+const ROOT_DOMAIN_ID: u64 = 1;
+const RTOS_DOMAIN_ID: u64 = 2;
+/// Context entry address width of 4-level tables (48 bits).
+const CONTEXT_AW_48: u64 = 2;
+
+bitflags! {
+    /// Global command and status register bits.
+    pub(super) struct GlobalCmd: u32 {
+        /// Translation enable.
+        const TE = 1 << 31;
+        /// Set root table pointer.
+        const SRTP = 1 << 30;
+        /// Queued invalidation enable.
+        const QIE = 1 << 26;
+        /// Interrupt remapping enable.
+        const IRE = 1 << 25;
+        /// Set interrupt remapping table pointer.
+        const SIRTP = 1 << 24;
+        /// Compatibility format interrupts, passed through if set.
+        const CFI = 1 << 23;
+    }
+}
+
+bitflags! {
+    struct DmaPTEFlags: u64 {
+        const READ =        1 << 0;
+        const WRITE =       1 << 1;
+        const SUPER_PAGE =  1 << 7;
+    }
+}
+
+/// Whether all the enabled units support 1 GB pages.
+static SUPPORTS_1G: AtomicBool = AtomicBool::new(false);
+
+/// Second-level page table entry.
+#[derive(Clone, Debug)]
+pub struct DmaPTE(u64);
+
+impl DmaPTE {
+    fn dma_flags(&self) -> DmaPTEFlags {
+        DmaPTEFlags::from_bits_truncate(self.0)
+    }
+}
+
+impl GenericPTE for DmaPTE {
+    fn addr(&self) -> HostPhysAddr {
+        (self.0 & phys_addr_mask() & !0xfff) as _
+    }
+    fn flags(&self) -> MemFlags {
+        let mut flags = MemFlags::empty();
+        if self.dma_flags().contains(DmaPTEFlags::READ) {
+            flags |= MemFlags::READ;
+        }
+        if self.dma_flags().contains(DmaPTEFlags::WRITE) {
+            flags |= MemFlags::WRITE;
+        }
+        flags
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        self.dma_flags()
+            .intersects(DmaPTEFlags::READ | DmaPTEFlags::WRITE)
+    }
+    fn is_huge(&self) -> bool {
+        self.dma_flags().contains(DmaPTEFlags::SUPER_PAGE)
+    }
+    fn set_addr(&mut self, paddr: HostPhysAddr) {
+        let mask = phys_addr_mask() & !0xfff;
+        self.0 = (self.0 & !mask) | (paddr as u64 & mask);
+    }
+    fn set_flags(&mut self, flags: MemFlags, is_huge: bool) {
+        let mut dma_flags = DmaPTEFlags::empty();
+        if flags.contains(MemFlags::READ) {
+            dma_flags |= DmaPTEFlags::READ;
+        }
+        if flags.contains(MemFlags::WRITE) {
+            dma_flags |= DmaPTEFlags::WRITE;
+        }
+        if is_huge {
+            dma_flags |= DmaPTEFlags::SUPER_PAGE;
+        }
+        self.0 = (self.0 & !0xfff) | dma_flags.bits();
+    }
+    fn set_table(&mut self, paddr: HostPhysAddr) {
+        self.set_addr(paddr);
+        self.0 = (self.0 & !0xfff) | (DmaPTEFlags::READ | DmaPTEFlags::WRITE).bits();
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+pub struct DmaInstr;
+
+impl PagingInstr for DmaInstr {
+    unsafe fn activate(_root_paddr: HostPhysAddr) {}
+    /// The IOTLBs are invalidated when translation is enabled, the tables
+    /// are not changed afterwards.
+    fn flush(_vaddr: Option<usize>) {}
+    fn supports_1g_pages() -> bool {
+        SUPPORTS_1G.load(Ordering::Relaxed)
+    }
+}
+
+pub type DmaPageTable = Level4PageTable<GuestPhysAddr, DmaPTE, DmaInstr>;
+
+/// A remapping unit whose registers are mapped.
+#[derive(Clone)]
+pub(super) struct RemappingUnit {
+    pub reg_base: PhysAddr,
+    reg_size: usize,
+    regs: usize,
+    cap: u64,
+    pub ecap: u64,
+}
+
+impl RemappingUnit {
+    pub(super) fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.regs + offset) as *const u32).read_volatile() }
+    }
+
+    pub(super) fn read64(&self, offset: usize) -> u64 {
+        unsafe { ((self.regs + offset) as *const u64).read_volatile() }
+    }
+
+    pub(super) fn write32(&self, offset: usize, value: u32) {
+        unsafe { ((self.regs + offset) as *mut u32).write_volatile(value) }
+    }
+
+    pub(super) fn write64(&self, offset: usize, value: u64) {
+        unsafe { ((self.regs + offset) as *mut u64).write_volatile(value) }
+    }
+
+    fn status(&self) -> GlobalCmd {
+        GlobalCmd::from_bits_truncate(self.read32(VTD_GSTS))
+    }
+
+    /// Waits until `done` returns true.
+    pub(super) fn wait(&self, what: &str, done: impl Fn(&Self) -> bool) -> HvResult {
+        let cycle_end = cpu::current_cycle() + COMMAND_TIMEOUT_US * cpu::frequency() as u64;
+        while !done(self) {
+            if cpu::current_cycle() >= cycle_end {
+                return hv_result_err!(
+                    EIO,
+                    format!(
+                        "DMA remapping unit {:#x}: {} timed out",
+                        self.reg_base, what
+                    )
+                );
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Sets or clears the global command `cmd`, and waits for its status.
+    pub(super) fn global_command(&self, cmd: GlobalCmd, set: bool) -> HvResult {
+        let mut value = self.read32(VTD_GSTS) & GSTS_PERSISTENT_MASK;
+        if set {
+            value |= cmd.bits();
+        } else {
+            value &= !cmd.bits();
+        }
+        self.write32(VTD_GCMD, value);
+        self.wait("global command", |unit| unit.status().contains(cmd) == set)
+    }
+
+    /// Invalidates the context caches and IOTLBs of all domains.
+    fn invalidate_all(&self) -> HvResult {
+        self.write64(VTD_CCMD, CCMD_ICC | CCMD_CIRG_GLOBAL);
+        self.wait("context invalidation", |unit| {
+            unit.read64(VTD_CCMD) & CCMD_ICC == 0
+        })?;
+        let iotlb = ((self.ecap >> 8) & 0x3ff) as usize * 16 + 8;
+        self.write64(iotlb, IOTLB_IVT | IOTLB_IIRG_GLOBAL);
+        self.wait("IOTLB invalidation", |unit| {
+            unit.read64(iotlb) & IOTLB_IVT == 0
+        })
+    }
+
+    fn enable(&self, root_table: PhysAddr) -> HvResult {
+        self.write64(VTD_RTADDR, root_table as u64);
+        self.global_command(GlobalCmd::SRTP, true)?;
+        self.invalidate_all()?;
+        self.global_command(GlobalCmd::TE, true)
+    }
+}
+
+/// Enabled remapping units, with the tables they walk.
+struct DmaRemapping {
+    units: Vec<RemappingUnit>,
+    _root_table: Frame,
+    _context_tables: Vec<Frame>,
+    _domains: Vec<MemorySet<DmaPageTable>>,
+}
+
+static REMAPPING: SpinLock<Option<DmaRemapping>> = SpinLock::new(None);
+
+/// Maps the registers of `unit` and checks whether it can be used.
+fn probe(unit: &DmarUnit) -> HvResult<Option<RemappingUnit>> {
+    if unit.segment != 0 {
+        warn!(
+            "DMA remapping unit {:#x} of PCI segment {} is not supported",
+            unit.reg_base, unit.segment
+        );
+        return Ok(None);
+    }
+    let regs = phys_to_virt(unit.reg_base);
+    hv_page_table()
+        .write()
+        .insert(MemoryRegion::new_with_offset_mapper(
+            regs,
+            unit.reg_base,
+            unit.reg_size,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))?;
+    let mut unit = RemappingUnit {
+        reg_base: unit.reg_base,
+        reg_size: unit.reg_size,
+        regs,
+        cap: 0,
+        ecap: 0,
+    };
+    unit.cap = unit.read64(VTD_CAP);
+    unit.ecap = unit.read64(VTD_ECAP);
+    if unit.status().contains(GlobalCmd::TE) {
+        warn!(
+            "DMA remapping unit {:#x} is in use by Linux, its DMA is not isolated",
+            unit.reg_base
+        );
+        Ok(None)
+    } else if unit.cap & CAP_SAGAW_4LEVEL == 0 {
+        warn!(
+            "DMA remapping unit {:#x} does not support 4-level tables",
+            unit.reg_base
+        );
+        Ok(None)
+    } else {
+        Ok(Some(unit))
+    }
+}
+
+/// Builds the second-level tables of the root cell domain, then of the
+/// domain of each RTOS device.
+fn build_domains(flags: MemFlags) -> HvResult<Vec<MemorySet<DmaPageTable>>> {
+    let sys_config = HvSystemConfig::get();
+    let hv_start = sys_config.hypervisor_memory.phys_start as HostPhysAddr;
+    let hv_range = hv_start..hv_start + sys_config.hypervisor_memory.size as usize;
+    let rtos_start = sys_config.rtos_memory.phys_start as HostPhysAddr;
+    let rtos_size = sys_config.rtos_memory.size as usize;
+
+    let mut root = MemorySet::new();
+    let root_config = sys_config.root_cell.config();
+    for region in root_config.mem_regions() {
+        if region.flags.contains(MemFlags::DMA) {
+            root.insert(MemoryRegion::new_with_offset_mapper(
+                region.virt_start as GuestPhysAddr,
+                region.phys_start as HostPhysAddr,
+                region.size as usize,
+                region.flags | flags,
+            ))?;
+        }
+    }
+    root.unmap_phys(hv_range)?;
+    root.unmap_phys(rtos_start..rtos_start + rtos_size)?;
+
+    let mut domains = vec![root];
+    for _ in sys_config.rtos_pci_devices() {
+        let mut rtos = MemorySet::new();
+        rtos.insert(MemoryRegion::new_with_offset_mapper(
+            rtos_start,
+            rtos_start,
+            rtos_size,
+            MemFlags::READ | MemFlags::WRITE | flags,
+        ))?;
+        domains.push(rtos);
+    }
+    Ok(domains)
+}
+
+/// Fills the 256 context entries of `table` with `domain`.
+fn fill_context_table(table: &Frame, domain: &MemorySet<DmaPageTable>, id: u64) {
+    let entries = table.as_mut_ptr() as *mut u64;
+    let lo = domain.page_table().root_paddr() as u64 | 1;
+    let hi = CONTEXT_AW_48 | id << 8;
+    for devfn in 0..256 {
+        unsafe {
+            entries.add(devfn * 2).write(lo);
+            entries.add(devfn * 2 + 1).write(hi);
+        }
+    }
+}
+
+/// Builds the tables and enables DMA remapping on the units of the DMAR.
+pub(super) fn init() -> HvResult {
+    let mut units = Vec::new();
+    for unit in acpi::dmar_units() {
+        if let Some(unit) = probe(unit)? {
+            units.push(unit);
+        }
+    }
+    if units.is_empty() {
+        info!("No DMA remapping unit enabled, DMA is not isolated.");
+        return Ok(());
+    }
+
+    let flags = if units.iter().all(|unit| unit.cap & CAP_SLLPS_2M != 0) {
+        MemFlags::empty()
+    } else {
+        MemFlags::NO_HUGEPAGES
+    };
+    let supports_1g = units.iter().all(|unit| unit.cap & CAP_SLLPS_1G != 0);
+    SUPPORTS_1G.store(supports_1g, Ordering::Relaxed);
+    let domains = build_domains(flags)?;
+
+    // All buses share the context table of the root cell domain, but those
+    // of the devices assigned to the RTOS.
+    let shared = Frame::new_zero()?;
+    fill_context_table(&shared, &domains[0], ROOT_DOMAIN_ID);
+    let mut context_tables = Vec::new();
+    let root_table = Frame::new_zero()?;
+    let root_entries = root_table.as_mut_ptr() as *mut u64;
+    for bus in 0..256 {
+        unsafe {
+            root_entries
+                .add(bus * 2)
+                .write(shared.start_paddr() as u64 | 1)
+        };
+    }
+    let rtos_devices = HvSystemConfig::get().rtos_pci_devices();
+    for (dev, (domain, id)) in rtos_devices
+        .iter()
+        .zip(domains[1..].iter().zip(RTOS_DOMAIN_ID..))
+    {
+        let (bus, devfn) = ((dev.bdf >> 8) as usize, (dev.bdf & 0xff) as usize);
+        let root_entry = unsafe { root_entries.add(bus * 2) };
+        if unsafe { root_entry.read() } == shared.start_paddr() as u64 | 1 {
+            let mut table = Frame::new()?;
+            table.as_slice_mut().copy_from_slice(shared.as_slice());
+            unsafe { root_entry.write(table.start_paddr() as u64 | 1) };
+            context_tables.push(table);
+        }
+        let table = phys_to_virt(unsafe { root_entry.read() } as usize & !0xfff) as *mut u64;
+        unsafe {
+            table
+                .add(devfn * 2)
+                .write(domain.page_table().root_paddr() as u64 | 1);
+            table.add(devfn * 2 + 1).write(CONTEXT_AW_48 | id << 8);
+        }
+        info!(
+            "PCI {:04x} DMA confined to the RTOS memory, domain {}",
+            dev.bdf, id
+        );
+    }
+    context_tables.push(shared);
+
+    if units.iter().any(|unit| unit.ecap & ECAP_COHERENT == 0) {
+        // Page walks of the unit do not snoop the caches.
+        unsafe { core::arch::asm!("wbinvd") };
+    }
+    let mut gpm = cell::root_cell().gpm.write();
+    for unit in &units {
+        unit.enable(root_table.start_paddr())?;
+        if gpm.find_region(unit.reg_base).is_some() {
+            gpm.protect(unit.reg_base, unit.reg_size, MemFlags::empty())?;
+        }
+        info!("DMA remapping enabled on unit {:#x}", unit.reg_base);
+    }
+    drop(gpm);
+    if HvSystemConfig::get().irq_remapping() {
+        if units.len() == acpi::dmar_units().len() {
+            super::irq_remap::init(&units)?;
+        } else {
+            warn!("Interrupt remapping needs all the DMA remapping units, not enabled");
+        }
+    }
+    *REMAPPING.lock() = Some(DmaRemapping {
+        units,
+        _root_table: root_table,
+        _context_tables: context_tables,
+        _domains: domains,
+    });
+    Ok(())
+}
+
+/// Disables DMA remapping, before the hypervisor memory is given back to
+/// Linux. Called on every CPU leaving the hypervisor: disabled, aborted,
+/// panicked or failing to enable.
+pub fn disable() {
+    super::irq_remap::disable();
+    if let Some(remapping) = REMAPPING.lock().take() {
+        for unit in &remapping.units {
+            match unit.global_command(GlobalCmd::TE, false) {
+                Ok(()) => info!("DMA remapping disabled on unit {:#x}", unit.reg_base),
+                Err(e) => error!("{:?}", e),
+            }
+        }
+    }
+}
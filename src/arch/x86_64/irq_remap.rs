@@ -0,0 +1,451 @@
+//! Interrupt remapping with Intel VT-d.
+//!
+//! Once enabled, interrupts in compatibility format are blocked: every MSI and
+//! I/O APIC interrupt must name an entry of the interrupt remapping table
+//! (IRT), which holds its destination and vector. They are only blocked once
+//! all the interrupt sources of the root cell are converted, none is lost in
+//! between. The table is only written by the hypervisor:
+//!
+//! - the first entries are reserved for the PCI devices assigned to the RTOS:
+//!   for the device with index `d` in the configuration and the RT CPU with
+//!   index `c` in the boot information, entry `(d * num_rt_cpus + c) * 256 +
+//!   v` delivers vector `v` (from 0x10) to that CPU. The RTOS programs its
+//!   MSIs in remappable format with these handles;
+//! - the other entries are allocated for the interrupt sources of the root
+//!   cell, whose MSIs, MSI-X entries (see `pci`) and I/O APIC redirection
+//!   entries (see `ioapic`) are intercepted and translated.
+//!
+//! Each entry only accepts requests from the source it was made for, so that
+//! a device can not use the entries of another one. Devices behind a PCI
+//! bridge, whose requests carry the ID of the bridge, are blocked. A root
+//! cell interrupt targeting a single CPU is rejected if the CPU is not in the
+//! root cell, while interrupts targeting several CPUs, with a logical
+//! destination or a broadcast, are redirected to the first CPU of the root
+//! cell.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::iommu::{GlobalCmd, RemappingUnit, ECAP_COHERENT};
+use crate::cell;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::{Frame, PAGE_SIZE};
+
+/// Remapping unit registers of queued invalidation and interrupt remapping.
+const VTD_IQT: usize = 0x88;
+const VTD_IQA: usize = 0x90;
+const VTD_IRTA: usize = 0xb8;
+
+/// ECAP: queued invalidation, interrupt remapping and x2APIC destinations
+/// supported.
+const ECAP_QI: u64 = 1 << 1;
+const ECAP_IR: u64 = 1 << 3;
+const ECAP_EIM: u64 = 1 << 4;
+/// IRTA: 32-bit x2APIC destination IDs.
+const IRTA_EIME: u64 = 1 << 11;
+
+/// Interrupt remapping table entry (IRTE) bits.
+const IRTE_PRESENT: u64 = 1 << 0;
+const IRTE_LEVEL: u64 = 1 << 4;
+/// Only accept requests whose source ID is the one of the entry.
+const IRTE_SVT_VERIFY_SID: u64 = 1 << 18;
+
+/// Invalidation descriptors: interrupt entry cache, by index, and wait with a
+/// status write.
+const INV_IEC: u64 = 0x4;
+const INV_IEC_INDEX: u64 = 1 << 4;
+const INV_WAIT: u64 = 0x5;
+const INV_WAIT_SW: u64 = 1 << 5;
+/// 128-bit descriptors in a single page queue.
+const INV_QUEUE_LEN: usize = PAGE_SIZE / 16;
+
+/// Lowest vector of the entries reserved for the RTOS.
+const FIRST_RTOS_VECTOR: usize = 0x10;
+/// Number of entries for the interrupt sources of the root cell.
+const ROOT_IRTES: usize = 2048;
+/// Max number of IRT entries.
+const MAX_IRTES: usize = 1 << 16;
+
+/// Delivery modes accepted for the root cell.
+const DELIVERY_FIXED: u8 = 0b000;
+const DELIVERY_LOWEST_PRIO: u8 = 0b001;
+const DELIVERY_NMI: u8 = 0b100;
+
+/// Compatibility format MSI address: logical destination mode.
+const MSI_ADDR_DEST_LOGICAL: u32 = 1 << 2;
+/// Remappable format MSI address: interrupt format and handle present.
+const MSI_ADDR_BASE: u32 = 0xfee0_0000;
+const MSI_ADDR_REMAPPABLE: u32 = 1 << 4;
+/// MSI data: level triggered.
+const MSI_DATA_LEVEL: u32 = 1 << 15;
+
+/// Redirection table entry bits kept in remappable format: vector, polarity,
+/// trigger mode and mask.
+const RTE_KEPT_BITS: u64 = 0xff | 1 << 13 | 1 << 15 | 1 << 16;
+const RTE_DEST_LOGICAL: u64 = 1 << 11;
+const RTE_LEVEL: u64 = 1 << 15;
+const RTE_MASKED: u64 = 1 << 16;
+const RTE_REMAPPABLE: u64 = 1 << 48;
+
+/// A source of interrupts of the root cell, with its own IRT entry.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub(super) enum IrqSource {
+    /// MSI of the PCI device.
+    Msi(u16),
+    /// MSI-X table entry of the PCI device.
+    MsiX(u16, u16),
+    /// Pin of the I/O APIC with this ID.
+    IoApic(u8, u8),
+}
+
+/// An interrupt in compatibility format, as programmed by the root cell.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct IrqMessage {
+    vector: u8,
+    delivery_mode: u8,
+    logical: bool,
+    level: bool,
+    masked: bool,
+    dest: u32,
+}
+
+impl IrqMessage {
+    /// Decodes a compatibility format MSI, `None` if `addr` is not in the
+    /// interrupt address range, e.g. not programmed yet.
+    pub fn from_msi(addr: u32, data: u32) -> Option<Self> {
+        if addr & 0xfff0_0000 != MSI_ADDR_BASE {
+            return None;
+        }
+        Some(Self {
+            vector: data as u8,
+            delivery_mode: ((data >> 8) & 0b111) as u8,
+            logical: addr & MSI_ADDR_DEST_LOGICAL != 0,
+            level: data & MSI_DATA_LEVEL != 0,
+            masked: false,
+            dest: (addr >> 12) & 0xff,
+        })
+    }
+
+    /// Decodes a compatibility format I/O APIC redirection table entry.
+    pub fn from_rte(rte: u64) -> Self {
+        Self {
+            vector: rte as u8,
+            delivery_mode: ((rte >> 8) & 0b111) as u8,
+            logical: rte & RTE_DEST_LOGICAL != 0,
+            level: rte & RTE_LEVEL != 0,
+            masked: rte & RTE_MASKED != 0,
+            dest: (rte >> 56) as u32,
+        }
+    }
+}
+
+/// Returns the remappable format MSI address and data of `handle`.
+pub(super) fn msi_remappable(handle: u16) -> (u32, u32) {
+    let handle = handle as u32;
+    let addr = MSI_ADDR_BASE | (handle & 0x7fff) << 5 | MSI_ADDR_REMAPPABLE | (handle >> 15) << 2;
+    (addr, 0)
+}
+
+/// Returns the remappable format of the redirection table entry `rte`, with
+/// `handle`. The vector must be kept for the EOIs of level triggered
+/// interrupts.
+pub(super) fn rte_remappable(handle: u16, rte: u64) -> u64 {
+    let handle = handle as u64;
+    (rte & RTE_KEPT_BITS) | RTE_REMAPPABLE | (handle & 0x7fff) << 49 | (handle >> 15) << 11
+}
+
+/// Invalidation queue of a remapping unit.
+struct InvQueue {
+    unit: RemappingUnit,
+    queue: Frame,
+    tail: usize,
+}
+
+struct IrqRemapping {
+    queues: Vec<InvQueue>,
+    table: Frame,
+    /// Status written by the wait descriptors, one dword per unit.
+    status: Frame,
+    /// Whether all the units snoop the caches when reading the table.
+    coherent: bool,
+    x2apic: bool,
+    first_root_irte: usize,
+    num_irtes: usize,
+    root_handles: BTreeMap<IrqSource, u16>,
+}
+
+static IRQ_REMAPPING: SpinLock<Option<IrqRemapping>> = SpinLock::new(None);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether interrupts are remapped.
+pub(super) fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Number of RT CPUs in the handles of the RTOS devices.
+fn num_rt_cpus() -> usize {
+    let count = HvSystemConfig::get().rtos_cpus.count() as usize;
+    count.min(super::boot_rt::MAX_RT_BOOT_CPUS)
+}
+
+impl InvQueue {
+    /// Queues `desc` followed by a wait descriptor writing 1 to `status`, and
+    /// waits for it.
+    fn submit(&mut self, desc: [u64; 2], status: (*mut u32, u64)) -> HvResult {
+        let (status_ptr, status_paddr) = status;
+        unsafe { status_ptr.write_volatile(0) };
+        let wait = [INV_WAIT | INV_WAIT_SW | 1 << 32, status_paddr];
+        let entries = self.queue.as_mut_ptr() as *mut [u64; 2];
+        for desc in [desc, wait] {
+            unsafe { entries.add(self.tail).write_volatile(desc) };
+            self.tail = (self.tail + 1) % INV_QUEUE_LEN;
+        }
+        self.unit.write64(VTD_IQT, (self.tail as u64) << 4);
+        self.unit.wait("interrupt entry invalidation", |_| unsafe {
+            status_ptr.read_volatile() == 1
+        })
+    }
+}
+
+impl IrqRemapping {
+    fn irte_ptr(&self, index: usize) -> *mut u64 {
+        unsafe { (self.table.as_mut_ptr() as *mut u64).add(index * 2) }
+    }
+
+    /// Invalidates the cached entry `index` (all entries if `None`) on all
+    /// units.
+    fn invalidate(&mut self, index: Option<usize>) -> HvResult {
+        let desc = match index {
+            Some(index) => INV_IEC | INV_IEC_INDEX | (index as u64) << 32,
+            None => INV_IEC,
+        };
+        let (status_vaddr, status_paddr) = (self.status.as_mut_ptr(), self.status.start_paddr());
+        for (i, queue) in self.queues.iter_mut().enumerate() {
+            let status_ptr = unsafe { (status_vaddr as *mut u32).add(i) };
+            queue.submit([desc, 0], (status_ptr, (status_paddr + i * 4) as u64))?;
+        }
+        Ok(())
+    }
+
+    /// Writes entry `index`, and invalidates it if `flush`. The source ID
+    /// never changes for an entry, the low half with the present bit is
+    /// written last in one store.
+    fn set_irte(&mut self, index: usize, lo: u64, hi: u64, flush: bool) -> HvResult {
+        let ptr = self.irte_ptr(index);
+        unsafe {
+            ptr.add(1).write_volatile(hi);
+            ptr.write_volatile(lo);
+            if !self.coherent {
+                core::arch::asm!("clflush [{}]", in(reg) ptr);
+            }
+        }
+        if flush {
+            self.invalidate(Some(index))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Destination field of an entry for the APIC ID `apic_id`.
+    fn irte_dest(&self, apic_id: u32) -> u64 {
+        if self.x2apic {
+            (apic_id as u64) << 32
+        } else {
+            ((apic_id & 0xff) as u64) << 40
+        }
+    }
+
+    /// Fills the entries reserved for the RTOS devices.
+    fn fill_rtos_irtes(&mut self) -> HvResult {
+        let sys_config = HvSystemConfig::get();
+        let rt_cpus: Vec<u32> = sys_config.rtos_cpus.iter().take(num_rt_cpus()).collect();
+        for (d, dev) in sys_config.rtos_pci_devices().iter().enumerate() {
+            let hi = IRTE_SVT_VERIFY_SID | dev.bdf as u64;
+            for (c, &apic_id) in rt_cpus.iter().enumerate() {
+                let block = (d * rt_cpus.len() + c) * 256;
+                for vector in FIRST_RTOS_VECTOR..256 {
+                    let lo = self.irte_dest(apic_id) | (vector as u64) << 16 | IRTE_PRESENT;
+                    self.set_irte(block + vector, lo, hi, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the low half of the entry delivering `msg` to the root cell,
+    /// or `None` if it targets a CPU outside of the root cell or has an
+    /// unsupported delivery mode.
+    fn root_irte(&self, msg: &IrqMessage) -> Option<u64> {
+        let root_cpus = &cell::root_cell().cpu_set;
+        let broadcast = msg.dest == 0xff;
+        let dest = if msg.logical || broadcast {
+            root_cpus.iter().next()?
+        } else if root_cpus.contains(msg.dest) {
+            msg.dest
+        } else {
+            return None;
+        };
+        let delivery_mode = match msg.delivery_mode {
+            DELIVERY_FIXED | DELIVERY_LOWEST_PRIO | DELIVERY_NMI => msg.delivery_mode,
+            _ => return None,
+        };
+        let mut lo = self.irte_dest(dest)
+            | (msg.vector as u64) << 16
+            | (delivery_mode as u64) << 5
+            | IRTE_PRESENT;
+        if msg.level {
+            lo |= IRTE_LEVEL;
+        }
+        Some(lo)
+    }
+
+    /// Returns the handle of `source`, allocating its entry if needed.
+    fn root_handle(&mut self, source: IrqSource) -> HvResult<u16> {
+        if let Some(&handle) = self.root_handles.get(&source) {
+            return Ok(handle);
+        }
+        let index = self.first_root_irte + self.root_handles.len();
+        if index >= self.num_irtes {
+            return hv_result_err!(ENOMEM, "Interrupt remapping table is full");
+        }
+        self.root_handles.insert(source, index as u16);
+        Ok(index as u16)
+    }
+}
+
+/// Points the entry of `source` of the root cell, with the source ID `sid`
+/// (`None` if unknown), to `msg`, and returns its handle. The entry of a
+/// masked or rejected `msg` is not present: the interrupt is blocked and
+/// reported as a fault by the remapping unit.
+pub(super) fn remap_root(source: IrqSource, sid: Option<u16>, msg: &IrqMessage) -> HvResult<u16> {
+    let mut guard = IRQ_REMAPPING.lock();
+    let remapping = guard
+        .as_mut()
+        .ok_or_else(|| hv_err!(ENODEV, "Interrupt remapping is disabled"))?;
+    let handle = remapping.root_handle(source)?;
+    let lo = match remapping.root_irte(msg) {
+        _ if msg.masked => 0,
+        Some(lo) => lo,
+        None => {
+            warn!("Blocked interrupt of {:x?}: {:x?}", source, msg);
+            0
+        }
+    };
+    let hi = match sid {
+        Some(sid) => IRTE_SVT_VERIFY_SID | sid as u64,
+        None => 0,
+    };
+    remapping.set_irte(handle as usize, lo, hi, true)?;
+    Ok(handle)
+}
+
+/// Enables queued invalidation and interrupt remapping on `units`, all the
+/// units of the DMAR.
+pub(super) fn init(units: &[RemappingUnit]) -> HvResult {
+    let required = ECAP_QI | ECAP_IR;
+    if let Some(unit) = units.iter().find(|unit| unit.ecap & required != required) {
+        warn!(
+            "DMA remapping unit {:#x} does not support interrupt remapping, not enabled",
+            unit.reg_base
+        );
+        return Ok(());
+    }
+    let x2apic = super::apic::lapic().is_x2apic();
+    if x2apic && units.iter().any(|unit| unit.ecap & ECAP_EIM == 0) {
+        warn!("Interrupt remapping does not support x2APIC destinations, not enabled");
+        return Ok(());
+    }
+
+    let sys_config = HvSystemConfig::get();
+    let first_root_irte = sys_config.rtos_pci_devices().len() * num_rt_cpus() * 256;
+    let num_irtes = (first_root_irte + ROOT_IRTES).next_power_of_two();
+    if num_irtes > MAX_IRTES {
+        return hv_result_err!(EINVAL, "Too many interrupt remapping table entries");
+    }
+    let table_frames = num_irtes * 16 / PAGE_SIZE;
+    let mut table = Frame::new_contiguous(table_frames, table_frames.trailing_zeros() as usize)?;
+    table.zero();
+    let mut queues = Vec::new();
+    for unit in units {
+        queues.push(InvQueue {
+            unit: unit.clone(),
+            queue: Frame::new_zero()?,
+            tail: 0,
+        });
+    }
+    let mut remapping = IrqRemapping {
+        queues,
+        table,
+        status: Frame::new_zero()?,
+        coherent: units.iter().all(|unit| unit.ecap & ECAP_COHERENT != 0),
+        x2apic,
+        first_root_irte,
+        num_irtes,
+        root_handles: BTreeMap::new(),
+    };
+    remapping.fill_rtos_irtes()?;
+    if !remapping.coherent {
+        // Table reads of the units do not snoop the caches.
+        unsafe { core::arch::asm!("wbinvd") };
+    }
+
+    let mut irta = remapping.table.start_paddr() as u64 | (num_irtes.trailing_zeros() - 1) as u64;
+    if x2apic {
+        irta |= IRTA_EIME;
+    }
+    for queue in &remapping.queues {
+        let unit = &queue.unit;
+        unit.write64(VTD_IQT, 0);
+        unit.write64(VTD_IQA, queue.queue.start_paddr() as u64);
+        unit.global_command(GlobalCmd::QIE, true)?;
+        unit.write64(VTD_IRTA, irta);
+        unit.global_command(GlobalCmd::SIRTP, true)?;
+    }
+    remapping.invalidate(None)?;
+    // Compatibility format interrupts keep passing through until all the
+    // sources of the root cell are converted, so that none is lost.
+    for unit in units {
+        unit.global_command(GlobalCmd::CFI, true)?;
+        unit.global_command(GlobalCmd::IRE, true)?;
+    }
+    info!(
+        "Interrupt remapping table: {} entries, {} reserved for the RTOS",
+        num_irtes, first_root_irte
+    );
+    *IRQ_REMAPPING.lock() = Some(remapping);
+    ENABLED.store(true, Ordering::Release);
+
+    super::ioapic::init()?;
+    super::pci::remap_msis()?;
+    for unit in units {
+        unit.global_command(GlobalCmd::CFI, false)?;
+        info!("Interrupt remapping enabled on unit {:#x}", unit.reg_base);
+    }
+    Ok(())
+}
+
+/// Disables interrupt remapping. Called on every CPU disabling the
+/// hypervisor, before DMA remapping is disabled. The interrupts of the root
+/// cell are given back their compatibility format programming first.
+pub(super) fn disable() {
+    if !ENABLED.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    super::ioapic::restore();
+    super::pci::restore_msis();
+    if let Some(remapping) = IRQ_REMAPPING.lock().take() {
+        for queue in &remapping.queues {
+            let unit = &queue.unit;
+            let res = unit
+                .global_command(GlobalCmd::IRE, false)
+                .and_then(|_| unit.global_command(GlobalCmd::QIE, false));
+            match res {
+                Ok(()) => info!("Interrupt remapping disabled on unit {:#x}", unit.reg_base),
+                Err(e) => error!("{:?}", e),
+            }
+        }
+    }
+}
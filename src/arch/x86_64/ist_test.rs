@@ -0,0 +1,127 @@
+//! Self-test of the exceptions delivered on interrupt stacks (IST).
+//!
+//! Built with `IST_SELFTEST=on`, each CPU raises every exception of
+//! `IST_VECTORS` once while entering the hypervisor, before any guest runs,
+//! and checks that its handler runs on the expected interrupt stack. A CPU
+//! failing the test does not enter the hypervisor. With the `amd` feature,
+//! `IST_VECTORS` is empty and the test checks nothing.
+//!
+//! NMIs and machine checks are raised with `int n`, which goes through the
+//! same gate and stack switch. A double fault is raised for real: with a
+//! scratch IDT whose #GP gate is not present, the #GP of a non-canonical
+//! access escalates to #NP and then to #DF. The saved RIP of a double fault
+//! is undefined, so the handler resumes at a recovery point recorded before
+//! the access.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::exception::{ExceptionType, TrapFrame};
+use super::tables::{IdtStruct, TssStruct, IST_VECTORS};
+use crate::consts::MAX_CPUS;
+use crate::error::HvResult;
+use crate::percpu::PerCpu;
+
+/// Address raising a #GP when accessed.
+const NON_CANONICAL_ADDR: u64 = 0x8000_0000_0000_0000;
+/// Value of `EXPECTED` while no test runs.
+const NO_TEST: usize = usize::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE: AtomicUsize = AtomicUsize::new(NO_TEST);
+
+/// Vector raised by the test running on each CPU.
+static EXPECTED: [AtomicUsize; MAX_CPUS] = [NONE; MAX_CPUS];
+/// Address of the trap frame of the expected exception on each CPU, 0 until
+/// it is taken.
+static HIT_FRAME: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+/// Where each CPU resumes after its double fault.
+static RESUME_RIP: [AtomicUsize; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+lazy_static! {
+    /// The hypervisor IDT, with the #GP gate not present.
+    static ref SCRATCH_IDT: IdtStruct = {
+        let mut idt = IdtStruct::alloc().expect("Failed to allocate the scratch IDT");
+        idt.init();
+        idt.set_present(ExceptionType::GeneralProtectionFault, false);
+        idt
+    };
+}
+
+/// Raises each IST-backed exception on the current CPU, whose TSS is `tss`.
+pub(super) fn run(cpu_id: u32, tss: &TssStruct) -> HvResult {
+    for &(vector, index) in IST_VECTORS.iter() {
+        let stack = tss.ist_stack(index);
+        let frame = raise(cpu_id as usize, vector);
+        if frame == 0 {
+            return hv_result_err!(EIO, "CPU {}: exception #{} not taken", cpu_id, vector);
+        }
+        if !stack.contains(&frame) {
+            return hv_result_err!(
+                EIO,
+                "CPU {}: exception #{} handled @ {:#x}, outside of IST stack {} {:#x?}",
+                cpu_id,
+                vector,
+                frame,
+                index + 1,
+                stack
+            );
+        }
+        info!(
+            "CPU {}: exception #{} handled on IST stack {}",
+            cpu_id,
+            vector,
+            index + 1
+        );
+    }
+    Ok(())
+}
+
+/// Raises `vector` on the current CPU `id`, and returns the address of its
+/// trap frame, 0 if the exception was not taken.
+fn raise(id: usize, vector: u8) -> usize {
+    HIT_FRAME[id].store(0, Ordering::Release);
+    EXPECTED[id].store(vector as usize, Ordering::Release);
+    match vector {
+        ExceptionType::NonMaskableInterrupt => unsafe { asm!("int 2") },
+        ExceptionType::MachineCheck => unsafe { asm!("int 18") },
+        ExceptionType::DoubleFault => raise_double_fault(id),
+        _ => unreachable!(),
+    }
+    EXPECTED[id].store(NO_TEST, Ordering::Release);
+    HIT_FRAME[id].load(Ordering::Acquire)
+}
+
+fn raise_double_fault(id: usize) {
+    let hv_idt = IdtStruct::sidt();
+    SCRATCH_IDT.load();
+    unsafe {
+        asm!(
+            "lea {tmp}, [rip + 2f]",
+            "mov [{resume}], {tmp}",
+            "mov {tmp}, {addr}",
+            "mov {tmp}, [{tmp}]",
+            "2:",
+            tmp = out(reg) _,
+            resume = in(reg) &RESUME_RIP[id] as *const AtomicUsize,
+            addr = const NON_CANONICAL_ADDR,
+        );
+    }
+    IdtStruct::lidt(&hv_idt);
+}
+
+/// Records the exception described by `frame` if it was raised by the test
+/// running on the current CPU. Returns whether it was.
+pub(super) fn handle(frame: &mut TrapFrame) -> bool {
+    let id = PerCpu::current().id as usize;
+    if EXPECTED[id].load(Ordering::Acquire) != frame.num {
+        return false;
+    }
+    HIT_FRAME[id].store(frame as *const _ as usize, Ordering::Release);
+    if frame.num == ExceptionType::DoubleFault as usize {
+        frame.rip = RESUME_RIP[id].load(Ordering::Acquire);
+    }
+    true
+}
@@ -0,0 +1,113 @@
+//! Legacy PIC (8259) shielding.
+//!
+//! Both PICs are kept fully masked, so that legacy interrupts can not be
+//! delivered to any CPU (including RT CPUs by a misrouted ExtINT). The PIC
+//! ports Linux still accesses, e.g. on suspend/resume, are virtualized:
+//! writes to the interrupt mask are kept in a shadow register and read back
+//! from it, initialization sequences are passed through, then the PIC is
+//! masked again.
+//!
+//! The PIT (8254) stays with the root cell, its ports are passed through: its
+//! interrupt then only reaches the CPUs through the I/O APIC, which Linux
+//! programs for its own CPUs.
+
+use x86::io::outb;
+
+use crate::lock::SpinLock;
+
+const PIC_MASTER_CMD: u16 = 0x20;
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_CMD: u16 = 0xa0;
+const PIC_SLAVE_DATA: u16 = 0xa1;
+
+const PIC_ICW1_INIT: u8 = 1 << 4;
+const PIC_ICW1_SNGL: u8 = 1 << 1;
+const PIC_ICW1_IC4: u8 = 1 << 0;
+
+/// Ports virtualized by this module.
+pub const PORTS: [u16; 4] = [
+    PIC_MASTER_CMD,
+    PIC_MASTER_DATA,
+    PIC_SLAVE_CMD,
+    PIC_SLAVE_DATA,
+];
+
+#[derive(Debug)]
+struct Pic {
+    data_port: u16,
+    /// Interrupt mask written by the guest.
+    shadow_imr: u8,
+    /// Number of ICWs left in the current initialization sequence.
+    pending_icws: u8,
+}
+
+static PICS: SpinLock<[Pic; 2]> = SpinLock::new([
+    Pic {
+        data_port: PIC_MASTER_DATA,
+        shadow_imr: 0xff,
+        pending_icws: 0,
+    },
+    Pic {
+        data_port: PIC_SLAVE_DATA,
+        shadow_imr: 0xff,
+        pending_icws: 0,
+    },
+]);
+
+impl Pic {
+    fn write_cmd(&mut self, value: u8) {
+        unsafe { outb(self.data_port - 1, value) };
+        if value & PIC_ICW1_INIT != 0 {
+            // ICW2, plus ICW3 in cascade mode, plus ICW4 if requested.
+            self.pending_icws =
+                1 + (value & PIC_ICW1_SNGL == 0) as u8 + (value & PIC_ICW1_IC4 != 0) as u8;
+            // Initialization clears the mask.
+            self.shadow_imr = 0;
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        if self.pending_icws > 0 {
+            unsafe { outb(self.data_port, value) };
+            self.pending_icws -= 1;
+            if self.pending_icws == 0 {
+                unsafe { outb(self.data_port, 0xff) };
+            }
+        } else {
+            self.shadow_imr = value;
+        }
+    }
+}
+
+/// Mask both PICs.
+pub fn init() {
+    unsafe {
+        outb(PIC_MASTER_DATA, 0xff);
+        outb(PIC_SLAVE_DATA, 0xff);
+    }
+}
+
+/// Emulate a guest read from `port`, or returns `None` if it is passed
+/// through.
+pub fn port_read(port: u16) -> Option<u8> {
+    match port {
+        PIC_MASTER_DATA | PIC_SLAVE_DATA => {
+            Some(PICS.lock()[(port == PIC_SLAVE_DATA) as usize].shadow_imr)
+        }
+        _ => None,
+    }
+}
+
+/// Emulate a guest write to `port`. Returns whether it has been handled.
+pub fn port_write(port: u16, value: u8) -> bool {
+    match port {
+        PIC_MASTER_CMD | PIC_SLAVE_CMD => {
+            PICS.lock()[(port == PIC_SLAVE_CMD) as usize].write_cmd(value)
+        }
+        PIC_MASTER_DATA | PIC_SLAVE_DATA => {
+            PICS.lock()[(port == PIC_SLAVE_DATA) as usize].write_data(value)
+        }
+        _ => return false,
+    }
+    true
+}
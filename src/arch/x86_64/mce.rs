@@ -0,0 +1,117 @@
+//! Thermal and machine-check monitoring on behalf of RT CPUs.
+//!
+//! Once RT CPUs are carved out, Linux no longer handles their thermal and
+//! machine-check events. The root CPUs poll the package thermal status and
+//! the machine-check banks visible to them (i.e. shared by the package,
+//! including those of RT cores), and forward new events to the root cell
+//! through the event channel. Banks private to a RT core can only be read on
+//! that core and are not covered.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use libvmm::msr::Msr;
+
+use super::cpu;
+use super::cpuid::CpuId;
+use crate::event::{self, HvEventType};
+use crate::lock::SpinLock;
+
+/// Interval of polling.
+const POLL_INTERVAL_US: u64 = 1000 * 1000; // 1s
+
+const MAX_MC_BANKS: usize = 32;
+
+/// IA32_MCi_STATUS: the error is valid.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+/// IA32_MCi_STATUS: IA32_MCi_ADDR is valid.
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+
+/// IA32_PACKAGE_THERM_STATUS bits that indicate an alert.
+const PKG_THERM_ALERT_MASK: u64 = (1 << 0) // thermal status
+    | (1 << 2) // PROCHOT
+    | (1 << 4) // critical temperature
+    | (1 << 10); // power limitation
+
+struct McePoller {
+    has_pkg_therm: bool,
+    num_banks: usize,
+    last_pkg_therm: u64,
+    last_bank_status: [u64; MAX_MC_BANKS],
+}
+
+static NEXT_POLL_CYCLE: AtomicU64 = AtomicU64::new(0);
+static POLLER: SpinLock<Option<McePoller>> = SpinLock::new(None);
+
+impl McePoller {
+    fn new() -> Self {
+        let cpuid = CpuId::new();
+        let has_pkg_therm = cpuid
+            .get_thermal_power_info()
+            .map_or(false, |t| t.has_ptm());
+        let has_mca = cpuid
+            .get_feature_info()
+            .map_or(false, |f| f.has_mce() && f.has_mca());
+        let num_banks = if has_mca {
+            (Msr::IA32_MCG_CAP.read() as usize & 0xff).min(MAX_MC_BANKS)
+        } else {
+            0
+        };
+        info!(
+            "MCE monitor: package thermal = {}, {} MC banks",
+            has_pkg_therm, num_banks
+        );
+        Self {
+            has_pkg_therm,
+            num_banks,
+            last_pkg_therm: 0,
+            last_bank_status: [0; MAX_MC_BANKS],
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.has_pkg_therm {
+            let status = Msr::IA32_PACKAGE_THERM_STATUS.read() & PKG_THERM_ALERT_MASK;
+            if status != self.last_pkg_therm {
+                if status & !self.last_pkg_therm != 0 {
+                    warn!("Package thermal alert: {:#x}", status);
+                }
+                event::send(HvEventType::ThermalAlert, [status, 0, 0]);
+                self.last_pkg_therm = status;
+            }
+        }
+        for bank in 0..self.num_banks {
+            let msr_base = Msr::IA32_MC0_CTL as u32 + bank as u32 * 4;
+            let status = unsafe { x86::msr::rdmsr(msr_base + 1) };
+            if status & MCI_STATUS_VAL != 0 && status != self.last_bank_status[bank] {
+                let addr = if status & MCI_STATUS_ADDRV != 0 {
+                    unsafe { x86::msr::rdmsr(msr_base + 2) }
+                } else {
+                    0
+                };
+                warn!(
+                    "Machine check in bank {}: status={:#x}, addr={:#x}",
+                    bank, status, addr
+                );
+                event::send(HvEventType::MachineCheck, [bank as u64, status, addr]);
+            }
+            self.last_bank_status[bank] = status;
+        }
+    }
+}
+
+/// Polls thermal and machine-check status if the interval expired. Called on
+/// VM exits of root CPUs.
+pub(super) fn poll() {
+    let now = cpu::current_cycle();
+    if now < NEXT_POLL_CYCLE.load(Ordering::Relaxed) {
+        return;
+    }
+    // Only one CPU polls at a time.
+    if let Some(mut poller) = POLLER.try_lock() {
+        NEXT_POLL_CYCLE.store(
+            now + POLL_INTERVAL_US * cpu::frequency() as u64,
+            Ordering::Relaxed,
+        );
+        poller.get_or_insert_with(McePoller::new).poll();
+    }
+}
@@ -0,0 +1,133 @@
+//! Memory encryption awareness (Intel TME/MKTME, AMD SME/SEV) and SGX audit.
+//!
+//! With MKTME, the upper bits of physical addresses are used as KeyIDs; with
+//! SME, one bit (the C-bit) marks encrypted pages and some physical address
+//! bits are reserved. Page table entries built by the hypervisor must keep
+//! physical addresses out of these bits, and set the C-bit for RAM if SME is
+//! enabled, since all memory written by Linux is encrypted then.
+
+use bitflags::bitflags;
+use libvmm::msr::Msr;
+use spin::Once;
+
+use super::cpuid::cpuid;
+
+/// IA32_TME_ACTIVATE: lock bit.
+const TME_ACTIVATE_LOCKED: u64 = 1 << 0;
+/// IA32_TME_ACTIVATE: TME enable bit.
+const TME_ACTIVATE_ENABLED: u64 = 1 << 1;
+/// SYSCFG: MemEncryptionModeEn (SME enabled).
+const SYSCFG_MEM_ENCRYPT_EN: u64 = 1 << 23;
+
+bitflags! {
+    pub struct MemEncryptFeatures: u64 {
+        /// Intel TME is enabled.
+        const TME   = 1 << 0;
+        /// Intel MKTME KeyIDs are taken from physical address bits.
+        const MKTME = 1 << 1;
+        /// AMD SME is enabled.
+        const SME   = 1 << 2;
+        /// AMD SEV is supported (not available to cells).
+        const SEV   = 1 << 3;
+        /// Intel SGX is supported.
+        const SGX   = 1 << 4;
+    }
+}
+
+#[derive(Debug)]
+pub struct MemEncryptInfo {
+    pub features: MemEncryptFeatures,
+    /// Usable physical address width, excluding KeyID or reserved bits.
+    pub phys_addr_bits: u8,
+    /// Number of MKTME KeyID bits.
+    pub keyid_bits: u8,
+    /// Position of the SME C-bit.
+    pub c_bit: Option<u8>,
+}
+
+fn max_extended_leaf() -> u32 {
+    cpuid!(0x8000_0000).eax
+}
+
+fn detect() -> MemEncryptInfo {
+    if cfg!(test) {
+        // Host tests run in user mode, where MSRs are not readable. Report the
+        // architectural maximum, so that host addresses fit in page tables.
+        return MemEncryptInfo {
+            features: MemEncryptFeatures::empty(),
+            phys_addr_bits: 52,
+            keyid_bits: 0,
+            c_bit: None,
+        };
+    }
+    let mut features = MemEncryptFeatures::empty();
+    let mut phys_addr_bits = if max_extended_leaf() >= 0x8000_0008 {
+        cpuid!(0x8000_0008).eax as u8
+    } else {
+        36
+    };
+    let mut keyid_bits = 0;
+    let mut c_bit = None;
+
+    let leaf7 = cpuid!(7, 0);
+    if leaf7.ebx & (1 << 2) != 0 {
+        features |= MemEncryptFeatures::SGX;
+    }
+    if leaf7.ecx & (1 << 13) != 0 {
+        let activate = Msr::IA32_TME_ACTIVATE.read();
+        let enabled = TME_ACTIVATE_LOCKED | TME_ACTIVATE_ENABLED;
+        if activate & enabled == enabled {
+            features |= MemEncryptFeatures::TME;
+            keyid_bits = ((activate >> 32) & 0xf) as u8;
+            if keyid_bits != 0 {
+                features |= MemEncryptFeatures::MKTME;
+                phys_addr_bits -= keyid_bits;
+            }
+        }
+    }
+
+    if max_extended_leaf() >= 0x8000_001f {
+        let leaf = cpuid!(0x8000_001f);
+        if leaf.eax & (1 << 1) != 0 {
+            features |= MemEncryptFeatures::SEV;
+        }
+        if leaf.eax & (1 << 0) != 0 && Msr::SYSCFG.read() & SYSCFG_MEM_ENCRYPT_EN != 0 {
+            features |= MemEncryptFeatures::SME;
+            c_bit = Some((leaf.ebx & 0x3f) as u8);
+            phys_addr_bits -= ((leaf.ebx >> 6) & 0x3f) as u8;
+        }
+    }
+
+    MemEncryptInfo {
+        features,
+        phys_addr_bits,
+        keyid_bits,
+        c_bit,
+    }
+}
+
+pub fn info() -> &'static MemEncryptInfo {
+    static INFO: Once<MemEncryptInfo> = Once::new();
+    INFO.call_once(detect)
+}
+
+/// Mask of the physical address bits (12..phys_addr_bits) in page table entries.
+pub fn phys_addr_mask() -> u64 {
+    ((1u64 << info().phys_addr_bits) - 1) & !0xfff
+}
+
+/// The C-bit to set in page table entries of RAM, or 0 if SME is disabled.
+pub fn encrypt_mask() -> u64 {
+    info().c_bit.map_or(0, |bit| 1 << bit)
+}
+
+pub(super) fn audit() {
+    let info = info();
+    info!("Memory encryption: {:#x?}", info);
+    if info.features.contains(MemEncryptFeatures::SEV) {
+        warn!("SEV is supported but cannot be used by cells.");
+    }
+    if info.features.contains(MemEncryptFeatures::SGX) {
+        warn!("SGX is supported, EPC pages must not be assigned to RT cells.");
+    }
+}
@@ -0,0 +1,254 @@
+//! MMIO instruction emulation.
+//!
+//! Accesses to the emulated MMIO devices of a cell trap as nested page faults.
+//! The faulting instruction is fetched from guest memory and decoded to get
+//! the access size and the register or immediate operand; the guest physical
+//! address comes from the exit information. Only the MOV forms used for
+//! device register accesses are supported:
+//!
+//! - `88`/`89`: MOV r/m, reg (write)
+//! - `8A`/`8B`: MOV reg, r/m (read)
+//! - `C6`/`C7`: MOV r/m, imm (write)
+//! - `0F B6`/`0F B7`: MOVZX reg, r/m8/r/m16 (read)
+//!
+//! RSP can not be used as the register operand.
+
+use super::vmm::{Vcpu, VcpuAccessGuestState};
+use crate::cell;
+use crate::error::HvResult;
+use crate::hal::Vcpu as HalVcpu;
+use crate::memory::{GuestPhysAddr, HostVirtAddr, MemFlags, PAGE_SIZE};
+use crate::mmio::MmioAccess;
+
+const MAX_INSTR_LEN: usize = 15;
+
+#[derive(Debug)]
+enum MmioOperand {
+    /// General register numbered `idx`, or bits 8..16 of it (AH, CH, DH, BH).
+    Reg {
+        idx: usize,
+        high_byte: bool,
+    },
+    Imm(u64),
+}
+
+/// Decoded MMIO access instruction.
+#[derive(Debug)]
+struct MmioInstr {
+    len: u8,
+    /// Access size in bytes: 1, 2, 4 or 8.
+    size: u8,
+    is_write: bool,
+    operand: MmioOperand,
+    /// Size of the destination register of reads.
+    dst_size: u8,
+}
+
+struct InstrBytes<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl InstrBytes<'_> {
+    fn next(&mut self) -> HvResult<u8> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| hv_err!(EINVAL, "Truncated MMIO instruction"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn skip(&mut self, n: usize) -> HvResult {
+        for _ in 0..n {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    fn imm(&mut self, size: u8) -> HvResult<u64> {
+        let mut val = 0;
+        for i in 0..size {
+            val |= (self.next()? as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+}
+
+fn size_mask(size: u8) -> u64 {
+    match size {
+        8 => u64::MAX,
+        _ => (1 << (size * 8)) - 1,
+    }
+}
+
+fn decode(bytes: &[u8]) -> HvResult<MmioInstr> {
+    let mut instr = InstrBytes { bytes, pos: 0 };
+    let mut opsize16 = false;
+    let mut op = instr.next()?;
+    loop {
+        match op {
+            0x66 => opsize16 = true,
+            // Segment overrides and address size do not change the access.
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x67 => {}
+            _ => break,
+        }
+        op = instr.next()?;
+    }
+    let rex = if op & 0xf0 == 0x40 {
+        let rex = op;
+        op = instr.next()?;
+        rex
+    } else {
+        0
+    };
+    let opsize = if rex & 0x8 != 0 {
+        8
+    } else if opsize16 {
+        2
+    } else {
+        4
+    };
+    let opcode = if op == 0x0f {
+        0x0f00 | instr.next()? as u16
+    } else {
+        op as u16
+    };
+    let (size, is_write, dst_size) = match opcode {
+        0x88 | 0xc6 => (1, true, 1),
+        0x89 | 0xc7 => (opsize, true, opsize),
+        0x8a => (1, false, 1),
+        0x8b => (opsize, false, opsize),
+        0x0fb6 => (1, false, opsize),
+        0x0fb7 => (2, false, opsize),
+        _ => return hv_result_err!(ENOSYS, "Unsupported MMIO instruction: {:02x?}", bytes),
+    };
+
+    let modrm = instr.next()?;
+    let (md, rm) = (modrm >> 6, modrm & 7);
+    let reg = ((modrm >> 3) & 7) as usize | ((rex as usize & 0x4) << 1);
+    if md == 3 {
+        return hv_result_err!(EINVAL, "MMIO instruction without memory operand");
+    }
+    if rm == 4 {
+        let sib = instr.next()?;
+        if md == 0 && sib & 7 == 5 {
+            instr.skip(4)?;
+        }
+    }
+    match md {
+        0 if rm == 5 => instr.skip(4)?, // RIP-relative
+        1 => instr.skip(1)?,
+        2 => instr.skip(4)?,
+        _ => {}
+    }
+
+    let operand = if opcode == 0xc6 || opcode == 0xc7 {
+        if reg & 7 != 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let imm = instr.imm(size.min(4))?;
+        // imm32 is sign-extended for 64-bit operands.
+        MmioOperand::Imm(if size == 8 {
+            imm as i32 as i64 as u64
+        } else {
+            imm
+        })
+    } else {
+        let reg_size = if is_write { size } else { dst_size };
+        let high_byte = reg_size == 1 && rex == 0 && (4..8).contains(&reg);
+        MmioOperand::Reg {
+            idx: if high_byte { reg - 4 } else { reg },
+            high_byte,
+        }
+    };
+    Ok(MmioInstr {
+        len: instr.pos as u8,
+        size,
+        is_write,
+        operand,
+        dst_size,
+    })
+}
+
+/// Translate `len` bytes of guest code at linear address `gvaddr`, which the
+/// guest must be allowed to execute, as the MMU would check the fetch: the
+/// guest page must be executable, and user accessible from user mode, and the
+/// cell must have the guest physical page mapped executable.
+fn code_ptr(vcpu: &Vcpu, gvaddr: usize, len: usize) -> HvResult<HostVirtAddr> {
+    let (gpaddr, flags, _) = vcpu.guest_page_table().query_effective(gvaddr)?;
+    if !flags.contains(MemFlags::EXECUTE) {
+        return hv_result_err!(EFAULT, "Guest code at {:#x} is not executable", gvaddr);
+    }
+    if !vcpu.guest_is_privileged() && !flags.contains(MemFlags::USER) {
+        return hv_result_err!(EFAULT, "Guest code at {:#x} is not user accessible", gvaddr);
+    }
+    cell::root_cell().guest_ram_to_hv(gpaddr, len, MemFlags::READ | MemFlags::EXECUTE)
+}
+
+/// Copy up to `MAX_INSTR_LEN` bytes at the guest RIP into `buf`, stopping at
+/// an inaccessible page. Returns the number of bytes copied.
+fn fetch_instr(vcpu: &Vcpu, buf: &mut [u8; MAX_INSTR_LEN]) -> HvResult<usize> {
+    let rip = vcpu.instr_pointer() as usize;
+    let mut copied = 0;
+    while copied < MAX_INSTR_LEN {
+        let gvaddr = rip + copied;
+        let len = (PAGE_SIZE - gvaddr % PAGE_SIZE).min(MAX_INSTR_LEN - copied);
+        let ptr = code_ptr(vcpu, gvaddr, len);
+        match ptr {
+            Ok(ptr) => unsafe {
+                core::ptr::copy_nonoverlapping(ptr as *const u8, buf[copied..].as_mut_ptr(), len)
+            },
+            // The instruction may end before the next page.
+            Err(_) if copied > 0 => break,
+            Err(err) => return Err(err),
+        }
+        copied += len;
+    }
+    Ok(copied)
+}
+
+fn reg_mut(vcpu: &mut Vcpu, idx: usize) -> HvResult<&mut u64> {
+    vcpu.regs_mut()
+        .get_mut(idx)
+        .ok_or_else(|| hv_err!(EINVAL, "RSP as MMIO operand"))
+}
+
+/// Emulate the instruction accessing the MMIO device at `gpaddr`.
+pub(super) fn handle_mmio_access(vcpu: &mut Vcpu, gpaddr: GuestPhysAddr) -> HvResult {
+    let mut buf = [0; MAX_INSTR_LEN];
+    let len = fetch_instr(vcpu, &mut buf)?;
+    let instr = decode(&buf[..len])?;
+    trace!("VM exit: MMIO access @ {:#x}: {:#x?}", gpaddr, instr);
+
+    let mask = size_mask(instr.size);
+    let value = match instr.operand {
+        _ if !instr.is_write => 0,
+        MmioOperand::Imm(imm) => imm & mask,
+        MmioOperand::Reg { idx, high_byte } => {
+            let shift = if high_byte { 8 } else { 0 };
+            (*reg_mut(vcpu, idx)? >> shift) & mask
+        }
+    };
+    let access = MmioAccess {
+        gpaddr,
+        size: instr.size,
+        is_write: instr.is_write,
+        value,
+        rip: vcpu.instr_pointer(),
+    };
+    let value = cell::root_cell().mmio.read().dispatch(&access)? & mask;
+
+    if let MmioOperand::Reg { idx, high_byte } = instr.operand {
+        if !instr.is_write {
+            let reg = reg_mut(vcpu, idx)?;
+            *reg = match instr.dst_size {
+                1 if high_byte => (*reg & !0xff00) | (value << 8),
+                1 => (*reg & !0xff) | value,
+                2 => (*reg & !0xffff) | value,
+                _ => value, // zero-extended in 64-bit mode
+            };
+        }
+    }
+    vcpu.advance_rip(instr.len)
+}
@@ -1,28 +1,63 @@
 #[macro_use]
 mod context;
+mod acpi;
 mod apic;
 mod boot_rt;
 mod cpuid;
 mod entry;
 mod exception;
+mod ioapic;
+mod irq_remap;
+#[cfg(feature = "ist-selftest")]
+mod ist_test;
+mod legacy_irq;
+mod mce;
+mod mmio;
+mod nested_tlb;
 mod page_table;
+mod pci;
 mod percpu;
+mod pio;
+mod reset;
+mod rt_policy;
 mod segmentation;
+mod smi;
 mod tables;
+mod timer;
+mod watchdog;
 
 pub mod cpu;
+pub mod iommu;
+pub mod mem_encrypt;
 pub mod serial;
 pub mod vmm;
 
-pub use boot_rt::{shutdown_rt_cpus, start_rt_cpus};
+pub use boot_rt::{rt_cpu_info, shutdown_rt_cpus, start_rt_cpus, RtCpuStatus};
 pub use context::{GeneralRegisters, LinuxContext};
 pub use exception::ExceptionType;
+pub use nested_tlb::flush_sync as flush_nested_tlbs;
 pub use page_table::PageTable as HostPageTable;
 pub use page_table::PageTable as GuestPageTable;
 pub use page_table::PageTableImmut as GuestPageTableImmut;
 pub use percpu::ArchPerCpu;
 pub use vmm::NestedPageTable;
+pub use vmm::Vcpu as ArchVcpu;
+pub use watchdog::set_timeout as set_watchdog_timeout;
+
+/// Returns the local APIC of the current CPU.
+pub fn local_irq_chip<'a>() -> &'a impl crate::hal::LocalIrqChip {
+    apic::lapic()
+}
 
 pub fn init_early() -> crate::error::HvResult {
-    apic::init()
+    mem_encrypt::audit();
+    smi::report();
+    apic::init()?;
+    acpi::init()?;
+    boot_rt::check_cpu_roles()?;
+    if vmm::intercepts().contains(vmm::InterceptFlags::LEGACY_IRQ) {
+        legacy_irq::init();
+    }
+    pci::init()?;
+    iommu::init()
 }
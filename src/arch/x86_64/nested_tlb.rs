@@ -0,0 +1,121 @@
+//! Invalidation of the nested paging translations cached by the CPUs.
+//!
+//! Changing the nested page table only bumps `GENERATION` (`flush()`), the
+//! other CPUs may still use the old translations while they run their guest.
+//! Each CPU invalidates them when it resumes its guest, if the generation
+//! changed since it last did (`resume_guest()`). `GUEST_GENERATION` tells
+//! which generation each CPU runs its guest with, so that the frames of
+//! unmapped tables are only freed once no guest can walk them anymore
+//! (`is_flushed()`).
+//!
+//! Callers relying on a change right away, e.g. a removed write permission,
+//! use `flush_sync()`: the other CPUs running their guest get an NMI to leave
+//! it, and are waited for. Without NMI interception, an NMI would be taken by
+//! the guest, so the next VM exits of these CPUs are waited for instead, up
+//! to `SYNC_TIMEOUT_US`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::vmm::{intercepts, InterceptFlags};
+use super::{apic, cpu};
+use crate::consts::MAX_CPUS;
+use crate::error::HvResult;
+use crate::percpu::PerCpu;
+
+/// Time `flush_sync()` waits for the other CPUs.
+const SYNC_TIMEOUT_US: u64 = 10 * 1000; // 10ms
+
+/// `GUEST_GENERATION` of a CPU in the hypervisor or disabled, which uses no
+/// guest translations.
+const NOT_IN_GUEST: u64 = u64::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const OUT: AtomicU64 = AtomicU64::new(NOT_IN_GUEST);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+/// Incremented whenever nested paging mappings are changed.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+/// Generation whose translations each CPU runs its guest with.
+static GUEST_GENERATION: [AtomicU64; MAX_CPUS] = [OUT; MAX_CPUS];
+/// Whether an NMI was sent to each CPU by `flush_sync()`.
+static KICKED: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+
+/// Invalidates the translations of all CPUs, lazily.
+pub(super) fn flush() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns the generation of the last `flush()`.
+pub(super) fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Returns whether no CPU runs its guest with translations older than
+/// `generation`.
+pub(super) fn is_flushed(generation: u64) -> bool {
+    GUEST_GENERATION[..PerCpu::entered_cpus() as usize]
+        .iter()
+        .all(|guest_generation| guest_generation.load(Ordering::SeqCst) >= generation)
+}
+
+/// Invalidates the translations of all CPUs, and waits until no CPU runs its
+/// guest with the old ones. Must not be called with a lock the VM exit
+/// handlers may take.
+pub fn flush_sync() -> HvResult {
+    flush();
+    let generation = generation();
+    let entered_cpus = PerCpu::entered_cpus() as usize;
+    if intercepts().contains(InterceptFlags::NMI) {
+        for (id, guest_generation) in GUEST_GENERATION[..entered_cpus].iter().enumerate() {
+            if guest_generation.load(Ordering::SeqCst) >= generation {
+                continue;
+            }
+            if let Some(apic_id) = apic::cpu_to_apic_id(id as u32) {
+                KICKED[id].store(true, Ordering::Release);
+                apic::lapic().send_nmi_ipi(apic_id);
+            }
+        }
+    }
+    let cycle_end = cpu::current_cycle() + SYNC_TIMEOUT_US * cpu::frequency() as u64;
+    while !is_flushed(generation) {
+        if cpu::current_cycle() >= cycle_end {
+            return hv_result_err!(ETIMEDOUT, "CPUs still run their guest with stale mappings");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Returns whether the NMI taken by the current CPU was sent by
+/// `flush_sync()`, the VM exit it caused is all it was for.
+pub(super) fn take_kick() -> bool {
+    KICKED[PerCpu::current().id as usize].swap(false, Ordering::AcqRel)
+}
+
+/// Called when the CPU `cpu_id` leaves its guest, on VM exits and when the
+/// hypervisor is disabled.
+pub(super) fn leave_guest(cpu_id: u32) {
+    GUEST_GENERATION[cpu_id as usize].store(NOT_IN_GUEST, Ordering::SeqCst);
+}
+
+/// Called right before the CPU `cpu_id` resumes its guest, whose cached
+/// translations are those of generation `synced`. They are invalidated by
+/// `invalidate` if mappings changed since.
+pub(super) fn resume_guest(
+    cpu_id: u32,
+    synced: &mut u64,
+    invalidate: impl FnOnce() -> HvResult,
+) -> HvResult {
+    let guest_generation = &GUEST_GENERATION[cpu_id as usize];
+    // Published before `GENERATION` is read: a concurrent `flush()` is either
+    // seen below, or sees this CPU in its guest with the old translations.
+    guest_generation.store(*synced, Ordering::SeqCst);
+    let generation = GENERATION.load(Ordering::SeqCst);
+    if *synced != generation {
+        invalidate()?;
+        *synced = generation;
+        guest_generation.store(generation, Ordering::SeqCst);
+    }
+    Ok(())
+}
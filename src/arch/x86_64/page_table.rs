@@ -8,6 +8,7 @@ use x86_64::{
     structures::paging::PhysFrame,
 };
 
+use super::mem_encrypt::{encrypt_mask, phys_addr_mask};
 use crate::memory::{GenericPTE, MemFlags, PagingInstr, PhysAddr, VirtAddr};
 use crate::memory::{Level4PageTable, Level4PageTableImmut};
 
@@ -55,14 +56,12 @@ impl From<PTF> for MemFlags {
     }
 }
 
-const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // 12..52
-
 #[derive(Clone)]
 pub struct PTEntry(u64);
 
 impl GenericPTE for PTEntry {
     fn addr(&self) -> PhysAddr {
-        (self.0 & PHYS_ADDR_MASK) as _
+        (self.0 & phys_addr_mask()) as _
     }
     fn flags(&self) -> MemFlags {
         PTF::from_bits_truncate(self.0).into()
@@ -78,22 +77,32 @@ impl GenericPTE for PTEntry {
     }
 
     fn set_addr(&mut self, paddr: PhysAddr) {
-        self.0 = (self.0 & !PHYS_ADDR_MASK) | (paddr as u64 & PHYS_ADDR_MASK);
+        self.0 = (self.0 & !phys_addr_mask()) | (paddr as u64 & phys_addr_mask());
     }
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) {
+        let is_ram = !flags.is_empty() && !flags.contains(MemFlags::IO);
         let mut flags: PTF = flags.into();
         if is_huge {
             flags |= PTF::HUGE_PAGE;
         }
         self.0 = self.addr() as u64 | flags.bits();
+        if is_ram {
+            self.0 |= encrypt_mask();
+        }
     }
     fn set_table(&mut self, paddr: PhysAddr) {
-        self.0 = (paddr as u64 & PHYS_ADDR_MASK)
+        self.0 = (paddr as u64 & phys_addr_mask())
+            | encrypt_mask()
             | (PTF::PRESENT | PTF::WRITABLE | PTF::USER_ACCESSIBLE).bits();
     }
     fn clear(&mut self) {
         self.0 = 0
     }
+    fn test_and_clear_accessed(&mut self) -> bool {
+        let accessed = PTF::from_bits_truncate(self.0).contains(PTF::ACCESSED);
+        self.0 &= !PTF::ACCESSED.bits();
+        accessed
+    }
 }
 
 impl Debug for PTEntry {
@@ -110,8 +119,9 @@ pub struct X86PagingInstr;
 
 impl PagingInstr for X86PagingInstr {
     unsafe fn activate(root_paddr: PhysAddr) {
+        let root_paddr = root_paddr as u64 | encrypt_mask();
         Cr3::write(
-            PhysFrame::containing_address(X86PhysAddr::new(root_paddr as u64)),
+            PhysFrame::containing_address(X86PhysAddr::new(root_paddr)),
             Cr3Flags::empty(),
         );
     }
@@ -0,0 +1,736 @@
+//! PCI configuration space mediation.
+//!
+//! Configuration mechanism #1 (ports `0xCF8`-`0xCFF`) is intercepted and
+//! passed through, but for the devices below. BARs are not tracked: the
+//! ranges the hypervisor emulates or protects stay where they were when it
+//! was enabled, Linux must not move them.
+//!
+//! PCI devices, typically SR-IOV virtual functions, can also be assigned to
+//! the RTOS while Linux keeps the physical function. Their BARs are unmapped
+//! from the root cell and Linux writes to their configuration space are
+//! dropped. A VF has no BARs of its own: they are found in the SR-IOV
+//! capability of its physical function, read through the memory mapped
+//! configuration space of the ACPI MCFG. The MSI-X table must be in the
+//! assigned BARs, so that the RTOS owns it and routes the vectors to the RT
+//! CPUs itself (through the entries of `irq_remap` reserved for the device
+//! with interrupt remapping). With VT-d, the DMA of each assigned device is
+//! confined to the RTOS memory in a domain of its own, see `iommu`.
+//!
+//! With interrupt remapping (see `irq_remap`), the MSIs of the other devices
+//! are translated: writes to the MSI capability and to the MSI-X tables are
+//! shadowed, and the devices are programmed with the remappable format of
+//! the interrupts. Reads return the shadowed values. Only the devices present
+//! when the hypervisor is enabled are handled, MSI-X tables do not follow
+//! their BAR, and multiple message MSI only gets its first vector.
+//!
+//! Accesses through the memory mapped configuration space (ECAM) are not
+//! intercepted. Linux uses configuration mechanism #1 for the first 256
+//! bytes, with the MSI capability, when it is available.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use x86::io::{inl, outl};
+
+use super::acpi;
+use super::irq_remap::{self, IrqMessage, IrqSource};
+use crate::cell;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::addr::{align_down, align_up, phys_to_virt, PhysAddr};
+use crate::memory::{
+    hv_page_table, GenericPageTableImmut, GuestPhysAddr, MemFlags, MemoryRegion, PAGE_SIZE,
+};
+use crate::mmio::MmioDevice;
+
+pub const PCI_CONFIG_ADDR_PORT: u16 = 0xcf8;
+pub const PCI_CONFIG_DATA_PORT: u16 = 0xcfc;
+
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+const PCI_COMMAND: u32 = 0x04;
+const PCI_COMMAND_MEMORY: u32 = 1 << 1;
+const PCI_BAR0: u32 = 0x10;
+const PCI_BAR_MEM_TYPE_64: u32 = 0b10 << 1;
+/// Header type dword, and its multi-function bit.
+const PCI_HEADER_TYPE: u32 = 0x0c;
+const PCI_HEADER_MULTI_FUNCTION: u32 = 1 << 23;
+/// Status bit (in the command dword): capability list present.
+const PCI_STATUS_CAP_LIST: u32 = 1 << 20;
+const PCI_CAP_PTR: u32 = 0x34;
+const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+/// MSI message control (in the capability dword): 64-bit address.
+const PCI_MSI_64BIT: u32 = 1 << 23;
+/// Start of the extended capabilities, only reachable through ECAM.
+const PCI_EXT_CAP_START: u32 = 0x100;
+const PCI_EXT_CAP_ID_SRIOV: u16 = 0x10;
+/// SR-IOV capability registers (PCIe 5.0, section 9.3.3), and the VF
+/// Enable and VF MSE control bits.
+const SRIOV_CTRL: u32 = 0x08;
+const SRIOV_CTRL_VF_ENABLE: u32 = 1 << 0;
+const SRIOV_CTRL_VF_MEMORY: u32 = 1 << 3;
+const SRIOV_NUM_VFS: u32 = 0x10;
+/// First VF Offset in the low half, VF Stride in the upper half.
+const SRIOV_VF_OFFSET: u32 = 0x14;
+const SRIOV_VF_BAR0: u32 = 0x24;
+
+/// The MSI capability of a device of the root cell, with interrupt
+/// remapping.
+#[derive(Debug)]
+struct MsiCap {
+    bdf: u16,
+    /// Offset of the capability in the configuration space.
+    cap: u32,
+    is_64: bool,
+    /// Address, upper address and data dwords, as programmed by the guest.
+    shadow: [u32; 3],
+}
+
+struct PciMediator {
+    /// Last value written to `PCI_CONFIG_ADDR_PORT` by the guest.
+    config_addr: u32,
+    msis: Vec<MsiCap>,
+}
+
+static MEDIATOR: SpinLock<PciMediator> = SpinLock::new(PciMediator {
+    config_addr: 0,
+    msis: Vec::new(),
+});
+
+/// An MSI-X table of a device of the root cell, with interrupt remapping.
+struct MsixTable {
+    bdf: u16,
+    /// Hypervisor mapping of the emulated pages, and offset of the table in
+    /// them.
+    vaddr: usize,
+    offset: usize,
+    /// Address, upper address and data dwords of each entry, as programmed
+    /// by the guest. The vector control dword is passed through.
+    shadow: Vec<[u32; 3]>,
+}
+
+static MSIX_TABLES: SpinLock<Vec<MsixTable>> = SpinLock::new(Vec::new());
+
+/// Read the configuration dword at `reg` of device `bdf`. The caller must
+/// restore the address port.
+unsafe fn read_config(bdf: u16, reg: u32) -> u32 {
+    outl(
+        PCI_CONFIG_ADDR_PORT,
+        PCI_CONFIG_ENABLE | (bdf as u32) << 8 | (reg & 0xfc),
+    );
+    inl(PCI_CONFIG_DATA_PORT)
+}
+
+/// Write `value` to the configuration dword at `reg` of device `bdf`. The
+/// caller must restore the address port.
+unsafe fn write_config(bdf: u16, reg: u32, value: u32) {
+    outl(
+        PCI_CONFIG_ADDR_PORT,
+        PCI_CONFIG_ENABLE | (bdf as u32) << 8 | (reg & 0xfc),
+    );
+    outl(PCI_CONFIG_DATA_PORT, value)
+}
+
+/// Returns the offset of the first capability `id` of device `bdf`. The
+/// caller must restore the address port.
+unsafe fn find_capability(bdf: u16, id: u8) -> Option<u32> {
+    if read_config(bdf, PCI_COMMAND) & PCI_STATUS_CAP_LIST == 0 {
+        return None;
+    }
+    let mut cap = read_config(bdf, PCI_CAP_PTR) & 0xfc;
+    // Bounded, in case of a looping list.
+    for _ in 0..48 {
+        if cap < 0x40 {
+            break;
+        }
+        let header = read_config(bdf, cap);
+        if header as u8 == id {
+            return Some(cap);
+        }
+        cap = (header >> 8) & 0xfc;
+    }
+    None
+}
+
+/// The 4 KB configuration space of a device, mapped from the memory mapped
+/// configuration space (ECAM).
+struct EcamConfig(usize);
+
+impl EcamConfig {
+    /// Maps the configuration space of device `bdf`, or returns `None` if no
+    /// range of the MCFG covers it.
+    fn map(bdf: u16) -> HvResult<Option<Self>> {
+        let bus = (bdf >> 8) as u8;
+        let range = match acpi::ecam_ranges()
+            .iter()
+            .find(|range| range.segment == 0 && (range.start_bus..=range.end_bus).contains(&bus))
+        {
+            Some(range) => range,
+            None => return Ok(None),
+        };
+        let paddr = range.base + (bdf as PhysAddr) * PAGE_SIZE;
+        let vaddr = phys_to_virt(paddr);
+        let mut hv_pt = hv_page_table().write();
+        if hv_pt.page_table().query(vaddr).is_err() {
+            hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+                vaddr,
+                paddr,
+                PAGE_SIZE,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+            ))?;
+        }
+        Ok(Some(Self(vaddr)))
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe { ((self.0 + (reg & 0xffc) as usize) as *const u32).read_volatile() }
+    }
+
+    /// Returns the offset of the first extended capability `id`.
+    fn find_ext_capability(&self, id: u16) -> Option<u32> {
+        let mut cap = PCI_EXT_CAP_START;
+        // Bounded, in case of a looping list.
+        for _ in 0..960 {
+            let header = self.read(cap);
+            if header == 0 || header == u32::MAX {
+                break;
+            }
+            if header as u16 == id {
+                return Some(cap);
+            }
+            cap = (header >> 20) & 0xffc;
+            if cap < PCI_EXT_CAP_START {
+                break;
+            }
+        }
+        None
+    }
+}
+
+/// An enabled SR-IOV virtual function.
+struct VirtFn {
+    /// Configuration space of the physical function, and offset of its
+    /// SR-IOV capability.
+    pf_config: EcamConfig,
+    cap: u32,
+    /// Index of the VF among those of the physical function.
+    index: u64,
+}
+
+impl VirtFn {
+    /// Finds the physical function of `bdf` among `functions`, if `bdf` is
+    /// an enabled virtual function.
+    fn find(bdf: u16, functions: &[u16]) -> HvResult<Option<Self>> {
+        for &pf in functions {
+            let pf_config = match EcamConfig::map(pf)? {
+                Some(config) => config,
+                None => continue,
+            };
+            let cap = match pf_config.find_ext_capability(PCI_EXT_CAP_ID_SRIOV) {
+                Some(cap) => cap,
+                None => continue,
+            };
+            if pf_config.read(cap + SRIOV_CTRL) & SRIOV_CTRL_VF_ENABLE == 0 {
+                continue;
+            }
+            let num_vfs = pf_config.read(cap + SRIOV_NUM_VFS) as u16;
+            let offsets = pf_config.read(cap + SRIOV_VF_OFFSET);
+            let (first, stride) = (offsets as u16, (offsets >> 16) as u16);
+            let index = (0..num_vfs)
+                .find(|&n| pf.wrapping_add(first).wrapping_add(n.wrapping_mul(stride)) == bdf);
+            if let Some(index) = index {
+                return Ok(Some(Self {
+                    pf_config,
+                    cap,
+                    index: index as u64,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the base address of `bar` of the VF, whose BARs are `size`
+    /// bytes, or `None` if the memory space of the VFs is disabled.
+    fn bar_base(&self, bar: u8, size: u64) -> Option<GuestPhysAddr> {
+        if self.pf_config.read(self.cap + SRIOV_CTRL) & SRIOV_CTRL_VF_MEMORY == 0 {
+            return None;
+        }
+        let reg = self.cap + SRIOV_VF_BAR0 + bar as u32 * 4;
+        let low = self.pf_config.read(reg);
+        let mut base = (low & !0xf) as u64;
+        if low & (0b11 << 1) == PCI_BAR_MEM_TYPE_64 {
+            base |= (self.pf_config.read(reg + 4) as u64) << 32;
+        }
+        Some((base + self.index * size) as _)
+    }
+}
+
+/// Returns the functions present on the PCI buses. The caller must restore
+/// the address port.
+unsafe fn scan_functions() -> Vec<u16> {
+    let mut functions = Vec::new();
+    for bus in 0..256u16 {
+        for dev in 0..32u16 {
+            for func in 0..8u16 {
+                let bdf = bus << 8 | dev << 3 | func;
+                if read_config(bdf, 0) as u16 == 0xffff {
+                    if func == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                functions.push(bdf);
+                if func == 0 && read_config(bdf, PCI_HEADER_TYPE) & PCI_HEADER_MULTI_FUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    functions
+}
+
+/// Returns `old` with the `size` bytes at byte `offset` replaced by `value`.
+fn merge_bytes(old: u32, offset: usize, size: u8, value: u32) -> u32 {
+    if size >= 4 {
+        return value;
+    }
+    let shift = offset * 8;
+    let mask = ((1u32 << (size * 8)) - 1) << shift;
+    (old & !mask) | ((value << shift) & mask)
+}
+
+/// Returns the remappable format of the MSI `shadow` (address, upper address
+/// and data dwords) of `source`, or `shadow` itself if it is not an
+/// interrupt message.
+fn remap_msi(source: IrqSource, bdf: u16, shadow: [u32; 3]) -> HvResult<[u32; 3]> {
+    let [addr, _, data] = shadow;
+    match IrqMessage::from_msi(addr, data) {
+        Some(msg) => {
+            let handle = irq_remap::remap_root(source, Some(bdf), &msg)?;
+            let (addr, data) = irq_remap::msi_remappable(handle);
+            Ok([addr, 0, data])
+        }
+        None => Ok(shadow),
+    }
+}
+
+impl MsiCap {
+    /// Reads the MSI capability at `cap` of device `bdf`. The caller must
+    /// restore the address port.
+    unsafe fn read(bdf: u16, cap: u32) -> Self {
+        let mut msi = Self {
+            bdf,
+            cap,
+            is_64: read_config(bdf, cap) & PCI_MSI_64BIT != 0,
+            shadow: [0; 3],
+        };
+        for i in 0..3 {
+            if let Some(reg) = msi.reg(i) {
+                msi.shadow[i] = read_config(bdf, reg);
+            }
+        }
+        msi
+    }
+
+    /// Configuration dword of the shadowed dword `i`.
+    fn reg(&self, i: usize) -> Option<u32> {
+        match i {
+            0 => Some(self.cap + 4),
+            1 => self.is_64.then(|| self.cap + 8),
+            _ => Some(self.cap + if self.is_64 { 12 } else { 8 }),
+        }
+    }
+
+    fn shadow_index(&self, reg: u32) -> Option<usize> {
+        (0..3).find(|&i| self.reg(i) == Some(reg))
+    }
+
+    /// Programs the device with the shadowed MSI, in remappable format if
+    /// `remap`. The extended message data in the upper half of the data
+    /// dword is kept. The caller must restore the address port.
+    unsafe fn program(&self, remap: bool) -> HvResult {
+        let values = if remap {
+            remap_msi(IrqSource::Msi(self.bdf), self.bdf, self.shadow)?
+        } else {
+            self.shadow
+        };
+        for i in 0..3 {
+            if let Some(reg) = self.reg(i) {
+                let value = match i {
+                    2 => (self.shadow[2] & 0xffff_0000) | (values[2] & 0xffff),
+                    _ => values[i],
+                };
+                write_config(self.bdf, reg, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MsixTable {
+    fn entry_ptr(&self, entry: usize) -> *mut u32 {
+        (self.vaddr + self.offset + entry * 16) as *mut u32
+    }
+
+    /// Returns the entry and dword of the shadowed dword at `offset` in the
+    /// emulated pages.
+    fn shadowed_dword(&self, offset: usize) -> Option<(usize, usize)> {
+        let offset = offset.checked_sub(self.offset)?;
+        let (entry, dword) = (offset / 16, offset % 16 / 4);
+        (entry < self.shadow.len() && dword < 3).then(|| (entry, dword))
+    }
+
+    /// Programs `entry` with its shadow, in remappable format if `remap`.
+    fn program(&self, entry: usize, remap: bool) -> HvResult {
+        let values = if remap {
+            let source = IrqSource::MsiX(self.bdf, entry as u16);
+            remap_msi(source, self.bdf, self.shadow[entry])?
+        } else {
+            self.shadow[entry]
+        };
+        let ptr = self.entry_ptr(entry);
+        for (i, &value) in values.iter().enumerate() {
+            unsafe { ptr.add(i).write_volatile(value) };
+        }
+        Ok(())
+    }
+
+    fn read_dword(&self, offset: usize, size: u8) -> u64 {
+        match self.shadowed_dword(offset & !3) {
+            Some((entry, dword)) => {
+                let value = self.shadow[entry][dword] >> (offset % 4 * 8);
+                (value as u64) & ((1u64 << (size * 8)) - 1)
+            }
+            None => unsafe {
+                let ptr = self.vaddr + offset;
+                match size {
+                    1 => (ptr as *const u8).read_volatile() as u64,
+                    2 => (ptr as *const u16).read_volatile() as u64,
+                    _ => (ptr as *const u32).read_volatile() as u64,
+                }
+            },
+        }
+    }
+
+    fn write_dword(&mut self, offset: usize, size: u8, value: u32) {
+        match self.shadowed_dword(offset & !3) {
+            Some((entry, dword)) => {
+                let old = self.shadow[entry][dword];
+                self.shadow[entry][dword] = merge_bytes(old, offset % 4, size, value);
+                if let Err(e) = self.program(entry, true) {
+                    warn!(
+                        "Failed to remap MSI-X entry {} of PCI {:04x}: {:?}",
+                        entry, self.bdf, e
+                    );
+                }
+            }
+            None => unsafe {
+                let ptr = self.vaddr + offset;
+                match size {
+                    1 => (ptr as *mut u8).write_volatile(value as u8),
+                    2 => (ptr as *mut u16).write_volatile(value as u16),
+                    _ => (ptr as *mut u32).write_volatile(value),
+                }
+            },
+        }
+    }
+}
+
+/// The pages of an MSI-X table, emulated with interrupt remapping. Accesses
+/// outside of the table, e.g. to the pending bit array, are passed through.
+struct MsixDevice(usize);
+
+impl MmioDevice for MsixDevice {
+    fn name(&self) -> &str {
+        "MSI-X table"
+    }
+
+    fn read(&self, offset: usize, size: u8) -> u64 {
+        let tables = MSIX_TABLES.lock();
+        let table = &tables[self.0];
+        match size {
+            8 => table.read_dword(offset, 4) | table.read_dword(offset + 4, 4) << 32,
+            _ => table.read_dword(offset, size),
+        }
+    }
+
+    fn write(&mut self, offset: usize, size: u8, value: u64) {
+        let mut tables = MSIX_TABLES.lock();
+        let table = &mut tables[self.0];
+        match size {
+            8 => {
+                table.write_dword(offset, 4, value as u32);
+                table.write_dword(offset + 4, 4, (value >> 32) as u32);
+            }
+            _ => table.write_dword(offset, size, value as u32),
+        }
+    }
+}
+
+/// Translates the MSI-X table of device `bdf` whose capability is at `cap`,
+/// and emulates its pages. The caller must restore the address port.
+unsafe fn emulate_msix_table(bdf: u16, cap: u32) -> HvResult {
+    let num_entries = ((read_config(bdf, cap) >> 16) & 0x7ff) as usize + 1;
+    let table_reg = read_config(bdf, cap + 4);
+    let (bir, table_offset) = ((table_reg & 0x7) as u8, (table_reg & !0x7) as usize);
+    let base = read_bar_base(bdf, bir)
+        .ok_or_else(|| hv_err!(ENODEV, "PCI {:04x} memory decoding is disabled", bdf))?;
+    let table_paddr: PhysAddr = base + table_offset;
+    let start = align_down(table_paddr);
+    let size = align_up(table_paddr + num_entries * 16) - start;
+    let vaddr = phys_to_virt(start);
+    hv_page_table()
+        .write()
+        .insert(MemoryRegion::new_with_offset_mapper(
+            vaddr,
+            start,
+            size,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))?;
+    let mut table = MsixTable {
+        bdf,
+        vaddr,
+        offset: table_paddr - start,
+        shadow: Vec::with_capacity(num_entries),
+    };
+    for entry in 0..num_entries {
+        let ptr = table.entry_ptr(entry);
+        table
+            .shadow
+            .push([0, 1, 2].map(|i| ptr.add(i).read_volatile()));
+    }
+    for entry in 0..num_entries {
+        table.program(entry, true)?;
+    }
+    let mut tables = MSIX_TABLES.lock();
+    tables.push(table);
+    let index = tables.len() - 1;
+    drop(tables);
+
+    let cell = cell::root_cell();
+    cell.gpm.write().unmap_phys(start..start + size)?;
+    info!(
+        "PCI {:04x} MSI-X table of {} entries remapped",
+        bdf, num_entries
+    );
+    cell.register_mmio_device(start, size, Box::new(MsixDevice(index)))
+}
+
+/// Translates the MSIs and MSI-X tables of the devices of the root cell into
+/// remappable format, and tracks them. Called once interrupt remapping is
+/// enabled.
+pub(super) fn remap_msis() -> HvResult {
+    let mut mediator = MEDIATOR.lock();
+    let functions = unsafe { scan_functions() };
+    let res = functions
+        .into_iter()
+        .filter(|&bdf| !is_rtos_device(bdf))
+        .try_for_each(|bdf| unsafe {
+            if let Some(cap) = find_capability(bdf, PCI_CAP_ID_MSI) {
+                let msi = MsiCap::read(bdf, cap);
+                msi.program(true)?;
+                mediator.msis.push(msi);
+            }
+            if let Some(cap) = find_capability(bdf, PCI_CAP_ID_MSIX) {
+                if let Err(e) = emulate_msix_table(bdf, cap) {
+                    warn!("PCI {:04x} MSI-X interrupts are blocked: {:?}", bdf, e);
+                }
+            }
+            Ok(())
+        });
+    unsafe { outl(PCI_CONFIG_ADDR_PORT, mediator.config_addr) };
+    res
+}
+
+/// Programs the devices of the root cell with their MSIs in compatibility
+/// format again, before interrupt remapping is disabled.
+pub(super) fn restore_msis() {
+    let mediator = MEDIATOR.lock();
+    for msi in &mediator.msis {
+        unsafe { msi.program(false).ok() };
+    }
+    unsafe { outl(PCI_CONFIG_ADDR_PORT, mediator.config_addr) };
+    for table in MSIX_TABLES.lock().iter() {
+        for entry in 0..table.shadow.len() {
+            table.program(entry, false).ok();
+        }
+    }
+}
+
+/// Read the base address programmed in `bar` of device `bdf`, or `None` if
+/// memory decoding is disabled.
+unsafe fn read_bar_base(bdf: u16, bar: u8) -> Option<GuestPhysAddr> {
+    if read_config(bdf, PCI_COMMAND) & PCI_COMMAND_MEMORY == 0 {
+        return None;
+    }
+    let reg = PCI_BAR0 + bar as u32 * 4;
+    let low = read_config(bdf, reg);
+    let mut base = (low & !0xf) as u64;
+    if low & (0b11 << 1) == PCI_BAR_MEM_TYPE_64 {
+        base |= (read_config(bdf, reg + 4) as u64) << 32;
+    }
+    Some(base as _)
+}
+
+impl PciMediator {
+    /// Shadow a guest write of `value` at byte `offset` of the configuration
+    /// dword `reg` of `bdf`, if it belongs to a remapped MSI, and program the
+    /// device again.
+    fn update_msi(&mut self, bdf: u16, reg: u32, offset: usize, size: u8, value: u32) {
+        let msi = match self.msis.iter_mut().find(|msi| msi.bdf == bdf) {
+            Some(msi) => msi,
+            None => return,
+        };
+        if let Some(i) = msi.shadow_index(reg) {
+            msi.shadow[i] = merge_bytes(msi.shadow[i], offset, size, value);
+            if let Err(e) = unsafe { msi.program(true) } {
+                warn!("Failed to remap the MSI of PCI {:04x}: {:?}", bdf, e);
+            }
+            unsafe { outl(PCI_CONFIG_ADDR_PORT, self.config_addr) };
+        }
+    }
+}
+
+/// Whether `bdf` is assigned to the RTOS.
+fn is_rtos_device(bdf: u16) -> bool {
+    HvSystemConfig::get()
+        .rtos_pci_devices()
+        .iter()
+        .any(|dev| dev.bdf == bdf)
+}
+
+/// Check that the MSI-X table of device `bdf`, if any, is in the BARs
+/// assigned to the RTOS. The caller must restore the address port.
+unsafe fn check_rtos_msix(bdf: u16, bar_sizes: &[u64; 6]) -> HvResult {
+    let cap = match find_capability(bdf, PCI_CAP_ID_MSIX) {
+        Some(cap) => cap,
+        None => return Ok(()),
+    };
+    let num_entries = ((read_config(bdf, cap) >> 16) & 0x7ff) as u64 + 1;
+    let table_reg = read_config(bdf, cap + 4);
+    let (bir, table_offset) = ((table_reg & 0x7) as usize, (table_reg & !0x7) as u64);
+    match bar_sizes.get(bir) {
+        Some(&size) if table_offset + num_entries * 16 <= size => Ok(()),
+        _ => hv_result_err!(
+            EINVAL,
+            "PCI {:04x} MSI-X table is outside of the BARs assigned to the RTOS",
+            bdf
+        ),
+    }
+}
+
+/// Hide the BARs of the PCI devices assigned to the RTOS from the root cell.
+pub fn init() -> HvResult {
+    let mediator = MEDIATOR.lock();
+    let mut gpm = cell::root_cell().gpm.write();
+    let mut functions = None;
+    let res = HvSystemConfig::get()
+        .rtos_pci_devices()
+        .iter()
+        .try_for_each(|dev| unsafe {
+            let (bdf, bar_sizes) = (dev.bdf, dev.bar_sizes);
+            // Virtual functions read as absent through their vendor ID.
+            let virt_fn = if read_config(bdf, 0) as u16 == 0xffff {
+                let functions = functions.get_or_insert_with(|| scan_functions());
+                let virt_fn = VirtFn::find(bdf, functions)?.ok_or_else(|| {
+                    hv_err!(
+                        ENODEV,
+                        "PCI {:04x} is neither present nor an enabled VF",
+                        bdf
+                    )
+                })?;
+                Some(virt_fn)
+            } else {
+                None
+            };
+            check_rtos_msix(bdf, &bar_sizes)?;
+            for (bar, &size) in bar_sizes.iter().enumerate().filter(|(_, &s)| s != 0) {
+                let base = match &virt_fn {
+                    Some(virt_fn) => virt_fn.bar_base(bar as u8, size),
+                    None => read_bar_base(bdf, bar as u8),
+                }
+                .ok_or_else(|| hv_err!(ENODEV, "PCI {:04x} memory decoding is disabled", bdf))?;
+                info!(
+                    "PCI {:04x} BAR{} @ [{:#x}, {:#x}) assigned to the RTOS",
+                    bdf,
+                    bar,
+                    base,
+                    base + size as usize
+                );
+                if gpm.find_region(base).is_some() {
+                    gpm.protect(base, size as usize, MemFlags::empty())?;
+                }
+            }
+            Ok(())
+        });
+    unsafe { outl(PCI_CONFIG_ADDR_PORT, mediator.config_addr) };
+    res
+}
+
+/// Whether a guest write to `port` may be passed through. Writes to the
+/// configuration space of the devices assigned to the RTOS are dropped.
+pub fn config_write_allowed(port: u16) -> bool {
+    let addr = MEDIATOR.lock().config_addr;
+    port < PCI_CONFIG_DATA_PORT
+        || addr & PCI_CONFIG_ENABLE == 0
+        || !is_rtos_device((addr >> 8) as u16)
+}
+
+/// Whether `port` is used by configuration mechanism #1.
+pub fn is_config_port(port: u16) -> bool {
+    (PCI_CONFIG_ADDR_PORT..PCI_CONFIG_DATA_PORT + 4).contains(&port)
+}
+
+/// Returns the value of a guest read from `port`, `value` as read from the
+/// device but for the shadowed MSI registers.
+pub fn config_read(port: u16, size: u8, value: u32) -> u32 {
+    if port < PCI_CONFIG_DATA_PORT || !irq_remap::enabled() {
+        return value;
+    }
+    let mediator = MEDIATOR.lock();
+    let addr = mediator.config_addr;
+    if addr & PCI_CONFIG_ENABLE == 0 {
+        return value;
+    }
+    let (bdf, reg) = ((addr >> 8) as u16, addr & 0xfc);
+    let shadow = mediator
+        .msis
+        .iter()
+        .find(|msi| msi.bdf == bdf)
+        .and_then(|msi| msi.shadow_index(reg).map(|i| msi.shadow[i]));
+    match shadow {
+        Some(shadow) if size < 4 => {
+            let shift = (port - PCI_CONFIG_DATA_PORT) as u32 * 8;
+            (shadow >> shift) & ((1 << (size * 8)) - 1)
+        }
+        Some(shadow) => shadow,
+        None => value,
+    }
+}
+
+/// Track a guest write of `value` to `port`, after it has been passed through.
+pub fn config_write(port: u16, size: u8, value: u32) {
+    let mut mediator = MEDIATOR.lock();
+    if port == PCI_CONFIG_ADDR_PORT {
+        if size == 4 {
+            mediator.config_addr = value;
+        }
+        return;
+    }
+    let addr = mediator.config_addr;
+    if addr & PCI_CONFIG_ENABLE == 0 || port < PCI_CONFIG_DATA_PORT {
+        return;
+    }
+    let bdf = (addr >> 8) as u16;
+    let reg = addr & 0xfc;
+    if irq_remap::enabled() {
+        mediator.update_msi(
+            bdf,
+            reg,
+            (port - PCI_CONFIG_DATA_PORT) as usize,
+            size,
+            value,
+        );
+    }
+}
@@ -11,9 +11,9 @@ pub struct ArchPerCpu {
 
 impl ArchPerCpu {
     pub fn init(&mut self, cpu_id: u32) -> HvResult {
-        self.tss = TssStruct::alloc();
+        self.tss = TssStruct::alloc()?;
 
-        self.gdt = GdtStruct::alloc();
+        self.gdt = GdtStruct::alloc()?;
         self.gdt.init(&self.tss);
 
         // Setup new GDT, IDT, CS, TSS
@@ -26,6 +26,8 @@ impl ArchPerCpu {
         }
         IDT.lock().load();
         self.gdt.load_tss(GdtStruct::TSS_SELECTOR);
+        #[cfg(feature = "ist-selftest")]
+        super::ist_test::run(cpu_id, &self.tss)?;
 
         // PAT0: WB, PAT1: WC, PAT2: UC
         unsafe { Msr::IA32_PAT.write(0x070106) };
@@ -0,0 +1,228 @@
+//! Port I/O instruction emulation.
+//!
+//! Intercepted port accesses are forwarded to `port_read()`/`port_write()`,
+//! which pass them through except for the ports virtualized by `legacy_irq`,
+//! PCI configuration writes to devices assigned to the RTOS and the MSI
+//! registers shadowed by `pci` with interrupt remapping. Resets and
+//! power-offs are detected by `reset` before being passed through.
+//! String instructions (INS/OUTS, optionally REP-prefixed) are emulated element
+//! by element through guest memory. At most `MAX_STRING_IO_CHUNK` elements are
+//! handled per VM exit: if the REP count is not exhausted, RIP is not advanced
+//! and the guest re-executes the instruction for the next chunk.
+//!
+//! Guest memory is accessed with the permissions of the guest page table: a
+//! denied access fails the VM exit, which injects #GP instead of #PF.
+//!
+//! Segment overrides are not supported: the root cell runs in long mode, so
+//! the segment bases of ES (INS) and DS (OUTS) are zero.
+
+use x86::io::{inb, inl, inw, outb, outl, outw};
+use x86_64::registers::control::Cr0Flags;
+use x86_64::registers::rflags::RFlags;
+
+use super::vmm::{self, InterceptFlags, Vcpu, VcpuAccessGuestState};
+use super::{legacy_irq, pci, reset};
+use crate::cell;
+use crate::error::HvResult;
+use crate::hal::Vcpu as HalVcpu;
+use crate::memory::{MemFlags, PAGE_SIZE};
+use crate::stats_window::{trace_io, IoTraceKind};
+
+/// Max number of elements of a string instruction emulated in one VM exit.
+const MAX_STRING_IO_CHUNK: u64 = 64;
+
+/// Decoded information of an I/O instruction VM exit.
+#[derive(Debug)]
+pub struct IoInstrInfo {
+    pub port: u16,
+    /// Access size in bytes: 1, 2 or 4.
+    pub size: u8,
+    pub is_in: bool,
+    pub is_string: bool,
+    pub is_rep: bool,
+    /// Address size in bytes of string instructions: 2, 4 or 8.
+    pub addr_size: u8,
+    pub instr_len: u8,
+}
+
+/// Ports intercepted with `flags`.
+pub(super) fn intercepted_ports(flags: InterceptFlags) -> impl Iterator<Item = u16> {
+    let pci = flags
+        .contains(InterceptFlags::PCI_CONFIG)
+        .then(|| pci::PCI_CONFIG_ADDR_PORT..pci::PCI_CONFIG_DATA_PORT + 4);
+    let legacy = flags
+        .contains(InterceptFlags::LEGACY_IRQ)
+        .then(|| legacy_irq::PORTS);
+    let reset = flags.contains(InterceptFlags::RESET).then(reset::ports);
+    pci.into_iter()
+        .flatten()
+        .chain(legacy.into_iter().flatten())
+        .chain(reset.into_iter().flatten())
+}
+
+fn port_read(vcpu: &Vcpu, port: u16, size: u8) -> HvResult<u32> {
+    let emulated = match size {
+        1 => legacy_irq::port_read(port),
+        _ => None,
+    };
+    let value = match emulated {
+        Some(value) => value as u32,
+        None => unsafe {
+            match size {
+                1 => inb(port) as u32,
+                2 => inw(port) as u32,
+                4 => inl(port),
+                _ => return hv_result_err!(EINVAL),
+            }
+        },
+    };
+    let value = if pci::is_config_port(port) {
+        pci::config_read(port, size, value)
+    } else {
+        value
+    };
+    trace_io(
+        IoTraceKind::PioRead,
+        port as _,
+        size,
+        value as _,
+        vcpu.instr_pointer(),
+    );
+    Ok(value)
+}
+
+fn port_write(vcpu: &Vcpu, port: u16, size: u8, value: u32) -> HvResult {
+    trace_io(
+        IoTraceKind::PioWrite,
+        port as _,
+        size,
+        value as _,
+        vcpu.instr_pointer(),
+    );
+    if pci::is_config_port(port) && !pci::config_write_allowed(port) {
+        debug!("Dropped PCI config write to a device assigned to the RTOS");
+        return Ok(());
+    }
+    if size == 1 && legacy_irq::port_write(port, value as u8) {
+        return Ok(());
+    }
+    if vmm::intercepts().contains(InterceptFlags::RESET) {
+        reset::port_write(port, size, value);
+    }
+    unsafe {
+        match size {
+            1 => outb(port, value as u8),
+            2 => outw(port, value as u16),
+            4 => outl(port, value),
+            _ => return hv_result_err!(EINVAL),
+        }
+    }
+    if pci::is_config_port(port) {
+        pci::config_write(port, size, value);
+    }
+    Ok(())
+}
+
+/// Translate `len` bytes of guest memory at linear address `gvaddr`, checking
+/// the guest page table permissions as the MMU would for the access (a write
+/// if `write`): user mode needs `USER`, and writes need `WRITE` unless
+/// supervisor writes ignore it (CR0.WP clear). The cell must have the guest
+/// physical page mapped for the access too.
+fn guest_mem_ptr(vcpu: &Vcpu, gvaddr: usize, len: usize, write: bool) -> HvResult<*mut u8> {
+    if gvaddr / PAGE_SIZE != (gvaddr + len - 1) / PAGE_SIZE {
+        return hv_result_err!(EINVAL, "String I/O element crosses a page boundary");
+    }
+    let (gpaddr, flags, _) = vcpu.guest_page_table().query_effective(gvaddr)?;
+    let user = !vcpu.guest_is_privileged();
+    if user && !flags.contains(MemFlags::USER) {
+        return hv_result_err!(EFAULT, "String I/O at {:#x} is not user accessible", gvaddr);
+    }
+    let write_protect = Cr0Flags::from_bits_truncate(vcpu.cr(0)).contains(Cr0Flags::WRITE_PROTECT);
+    if write && (user || write_protect) && !flags.contains(MemFlags::WRITE) {
+        return hv_result_err!(EFAULT, "String I/O at {:#x} is not writable", gvaddr);
+    }
+    let gpm_flags = if write {
+        MemFlags::WRITE
+    } else {
+        MemFlags::READ
+    };
+    Ok(cell::root_cell().guest_ram_to_hv(gpaddr, len, gpm_flags)? as *mut u8)
+}
+
+/// Write `val` to a register used as a string address or counter, following
+/// the update rules of the address size.
+fn set_addr_reg(reg: &mut u64, val: u64, addr_size: u8) {
+    *reg = match addr_size {
+        2 => (*reg & !0xffff) | (val & 0xffff),
+        4 => val & 0xffff_ffff, // zero-extended in 64-bit mode
+        _ => val,
+    };
+}
+
+/// Returns whether the instruction has been completed.
+fn emulate_string_io(vcpu: &mut Vcpu, io: &IoInstrInfo) -> HvResult<bool> {
+    let addr_mask = match io.addr_size {
+        2 => 0xffff,
+        4 => 0xffff_ffff,
+        _ => u64::MAX,
+    };
+    let step = if RFlags::from_bits_truncate(vcpu.rflags()).contains(RFlags::DIRECTION_FLAG) {
+        (io.size as u64).wrapping_neg()
+    } else {
+        io.size as u64
+    };
+    let size = io.size as usize;
+    let mut count = if io.is_rep {
+        vcpu.regs().rcx & addr_mask
+    } else {
+        1
+    };
+
+    for _ in 0..count.min(MAX_STRING_IO_CHUNK) {
+        let regs = vcpu.regs();
+        let addr = if io.is_in { regs.rdi } else { regs.rsi } & addr_mask;
+        let ptr = guest_mem_ptr(vcpu, addr as usize, size, io.is_in)?;
+        if io.is_in {
+            let val = port_read(vcpu, io.port, io.size)?;
+            unsafe { core::ptr::copy_nonoverlapping(val.to_le_bytes().as_ptr(), ptr, size) };
+        } else {
+            let mut buf = [0u8; 4];
+            unsafe { core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), size) };
+            port_write(vcpu, io.port, io.size, u32::from_le_bytes(buf))?;
+        }
+
+        let regs = vcpu.regs_mut();
+        let addr_reg = if io.is_in {
+            &mut regs.rdi
+        } else {
+            &mut regs.rsi
+        };
+        set_addr_reg(addr_reg, addr.wrapping_add(step), io.addr_size);
+        count -= 1;
+        if io.is_rep {
+            set_addr_reg(&mut regs.rcx, count, io.addr_size);
+        }
+    }
+    Ok(count == 0)
+}
+
+/// Emulate an intercepted IN, OUT, INS or OUTS instruction.
+pub(super) fn handle_io_instruction(vcpu: &mut Vcpu, io: &IoInstrInfo) -> HvResult {
+    trace!("VM exit: I/O instruction {:#x?}", io);
+    if io.is_string {
+        if !emulate_string_io(vcpu, io)? {
+            return Ok(()); // re-execute for the next chunk
+        }
+    } else if io.is_in {
+        let val = port_read(vcpu, io.port, io.size)? as u64;
+        let rax = &mut vcpu.regs_mut().rax;
+        *rax = match io.size {
+            1 => (*rax & !0xff) | val,
+            2 => (*rax & !0xffff) | val,
+            _ => val, // zero-extended in 64-bit mode
+        };
+    } else {
+        port_write(vcpu, io.port, io.size, vcpu.regs().rax as u32)?;
+    }
+    vcpu.advance_rip(io.instr_len)
+}
@@ -0,0 +1,106 @@
+//! Root cell reset and power-off interception.
+//!
+//! Writes resetting or powering off the machine are intercepted, so that the
+//! RT CPUs are stopped and the diagnostics (log ring, crash data) are written
+//! back to RAM before the write is passed through:
+//!
+//! - port 0xCF9 (reset control register) with RST_CPU set;
+//! - the reset command 0xFE to the keyboard controller (port 0x64);
+//! - the reset value to the ACPI reset register, if in the I/O space;
+//! - SLP_EN to the ACPI PM1 control registers, for S5 (and other sleep
+//!   states, which the RTOS can not survive either).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::acpi;
+use crate::cell::{self, CellState};
+
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+const KBC_COMMAND_PORT: u16 = 0x64;
+
+/// Reset control register: reset the CPUs, with the reset type in bit 1.
+const RESET_CONTROL_RST_CPU: u8 = 1 << 2;
+/// Keyboard controller command pulsing the reset line.
+const KBC_RESET: u8 = 0xfe;
+/// PM1 control register: enter the sleep state of SLP_TYP.
+const PM1_CNT_SLP_EN: u32 = 1 << 13;
+
+static PREPARED: AtomicBool = AtomicBool::new(false);
+
+/// Ports intercepted to detect a reset or power-off, including both bytes of
+/// the 16-bit PM1 control registers.
+pub fn ports() -> impl Iterator<Item = u16> {
+    let power = acpi::power_ports();
+    let pm1_cnt = power
+        .into_iter()
+        .flat_map(|p| p.pm1_cnt)
+        .filter(|&port| port != 0)
+        .flat_map(|port| [port, port + 1]);
+    let acpi_reset = power.and_then(|p| p.reset).map(|(port, _)| port);
+    [RESET_CONTROL_PORT, KBC_COMMAND_PORT]
+        .into_iter()
+        .chain(acpi_reset)
+        .chain(pm1_cnt)
+}
+
+/// Whether writing `value` of `size` bytes to `port` resets or powers off the
+/// machine.
+fn is_reset_write(port: u16, size: u8, value: u32) -> bool {
+    let byte = value as u8;
+    if size == 1 && port == RESET_CONTROL_PORT {
+        return byte & RESET_CONTROL_RST_CPU != 0;
+    }
+    if size == 1 && port == KBC_COMMAND_PORT {
+        return byte == KBC_RESET;
+    }
+    let power = match acpi::power_ports() {
+        Some(power) => power,
+        None => return false,
+    };
+    if power.reset == Some((port, byte)) {
+        return true;
+    }
+    power.pm1_cnt.iter().any(|&cnt| {
+        cnt != 0
+            && match port.wrapping_sub(cnt) {
+                0 => size >= 2 && value & PM1_CNT_SLP_EN != 0,
+                1 => (value << 8) & PM1_CNT_SLP_EN != 0,
+                _ => false,
+            }
+    })
+}
+
+/// Stops the RT CPUs and writes back the caches, once.
+fn prepare(port: u16, value: u32) {
+    if PREPARED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    warn!(
+        "Root cell resets or powers off (port {:#x}, value {:#x}), stopping RT CPUs",
+        port, value
+    );
+    if cell::rtos_lifecycle().get() == CellState::Running {
+        match unsafe { super::shutdown_rt_cpus() } {
+            Ok(_) => {
+                crate::attest::clear_rtos_measurement();
+                cell::rtos_lifecycle().transition(CellState::ShutDown).ok();
+            }
+            Err(e) => error!("Failed to stop RT CPUs before reset: {:?}", e),
+        }
+    }
+    crate::event::shutdown();
+    crate::stats_window::log_notify_shutdown();
+    cell::root_cell()
+        .lifecycle
+        .transition(CellState::ShutDown)
+        .ok();
+    // A warm reset keeps RAM: make the log ring and crash data visible there.
+    unsafe { core::arch::asm!("wbinvd") };
+}
+
+/// Called before a root cell write to an intercepted port is passed through.
+pub fn port_write(port: u16, size: u8, value: u32) {
+    if is_reset_write(port, size, value) {
+        prepare(port, value);
+    }
+}
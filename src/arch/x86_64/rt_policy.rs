@@ -0,0 +1,169 @@
+//! Frequency/thermal/idle policy of RT CPUs.
+//!
+//! RT CPUs never run the hypervisor, so the policy is translated into a list
+//! of MSR writes executed by the AP trampoline before jumping to the RTOS.
+//!
+//! Some power management MSRs are shared by the whole package, so the root
+//! cell could override the P-state policy of RT CPUs. With a P-state policy,
+//! root cell writes to them are intercepted (on Intel only) and mediated by
+//! `mediate_msr_write()`.
+
+use alloc::vec::Vec;
+
+use libvmm::msr::Msr;
+
+use super::cpuid::CpuId;
+use crate::config::{HvSystemConfig, RtCpuPolicyFlags};
+
+/// IA32_PERF_CTL: IDA (turbo) engage disable.
+const PERF_CTL_TURBO_DISENGAGE: u64 = 1 << 32;
+/// IA32_PM_ENABLE: HWP enabled.
+const PM_ENABLE_HWP: u64 = 1 << 0;
+/// MSR_PKG_CST_CONFIG_CONTROL: package C-state limit.
+const CST_CONFIG_LIMIT_MASK: u64 = 0xf;
+/// MSR_PKG_CST_CONFIG_CONTROL: CFG lock, the register is read-only if set.
+const CST_CONFIG_LOCK: u64 = 1 << 15;
+/// IA32_MISC_ENABLE: ENABLE MONITOR FSM.
+const MISC_ENABLE_MWAIT: u64 = 1 << 18;
+/// IA32_MISC_ENABLE: IDA (turbo) disable, package scoped.
+const MISC_ENABLE_TURBO_DISABLE: u64 = 1 << 38;
+
+/// Power management MSRs shared with RT CPUs in the same package.
+const MEDIATED_MSRS: [u32; 5] = [
+    Msr::IA32_PM_ENABLE as u32,
+    Msr::IA32_HWP_REQUEST_PKG as u32,
+    Msr::IA32_ENERGY_PERF_BIAS as u32,
+    Msr::MSR_TURBO_RATIO_LIMIT as u32,
+    Msr::IA32_MISC_ENABLE as u32,
+];
+
+fn pstate_policy() -> RtCpuPolicyFlags {
+    HvSystemConfig::get().rtos_cpu_policy.flags
+        & (RtCpuPolicyFlags::FIXED_PSTATE | RtCpuPolicyFlags::DISABLE_TURBO)
+}
+
+/// MSRs whose writes by the root cell must be passed to
+/// `mediate_msr_write()`.
+pub(super) fn mediated_msrs() -> &'static [u32] {
+    if pstate_policy().is_empty() {
+        &[]
+    } else {
+        &MEDIATED_MSRS
+    }
+}
+
+/// Emulate a root cell write of `value` to `msr` without affecting the
+/// P-state policy of RT CPUs. Returns `false` if `msr` is not mediated.
+pub(super) fn mediate_msr_write(msr: u32, value: u64) -> bool {
+    let policy = pstate_policy();
+    if policy.is_empty() || !MEDIATED_MSRS.contains(&msr) {
+        return false;
+    }
+    let value = if msr == Msr::IA32_MISC_ENABLE as u32 {
+        if policy.contains(RtCpuPolicyFlags::DISABLE_TURBO) {
+            Some(value | MISC_ENABLE_TURBO_DISABLE)
+        } else {
+            Some(value)
+        }
+    } else {
+        // Enabling HWP makes IA32_PERF_CTL of RT CPUs ineffective, and the
+        // others change the frequency of RT CPUs in the package.
+        None
+    };
+    match value {
+        Some(value) => unsafe { x86::msr::wrmsr(msr, value) },
+        None => warn!(
+            "Dropped MSR write conflicting with the RT CPU policy: {:#x} <- {:#x}",
+            msr, value
+        ),
+    }
+    true
+}
+
+/// Returns the MSRs to program on each RT CPU, as `(msr, value)` pairs.
+pub(super) fn rt_cpu_msrs() -> Vec<(u32, u64)> {
+    let policy = &HvSystemConfig::get().rtos_cpu_policy;
+    let flags = policy.flags;
+    let mut msrs = Vec::new();
+    if flags.is_empty() {
+        return msrs;
+    }
+
+    let cpuid = CpuId::new();
+    let has_eist = cpuid.get_feature_info().map_or(false, |f| f.has_eist());
+    let has_acpi = cpuid.get_feature_info().map_or(false, |f| f.has_acpi());
+    let has_mwait = cpuid
+        .get_feature_info()
+        .map_or(false, |f| f.has_monitor_mwait());
+    let has_hwp = cpuid
+        .get_thermal_power_info()
+        .map_or(false, |t| t.has_hwp())
+        && Msr::IA32_PM_ENABLE.read() & PM_ENABLE_HWP != 0;
+
+    if flags.intersects(RtCpuPolicyFlags::FIXED_PSTATE | RtCpuPolicyFlags::DISABLE_TURBO) {
+        if has_hwp || has_eist {
+            let ratio = if flags.contains(RtCpuPolicyFlags::FIXED_PSTATE) {
+                policy.perf_ratio as u64 & 0xff
+            } else {
+                // Maximum non-turbo ratio.
+                (Msr::MSR_PLATFORM_INFO.read() >> 8) & 0xff
+            };
+            if has_hwp {
+                // IA32_PERF_CTL is ignored once HWP is enabled, request
+                // min = max = desired = ratio and EPP = 0 (performance) instead.
+                msrs.push((
+                    Msr::IA32_HWP_REQUEST as u32,
+                    ratio | ratio << 8 | ratio << 16,
+                ));
+            } else {
+                let mut perf_ctl = ratio << 8;
+                if flags.contains(RtCpuPolicyFlags::DISABLE_TURBO) {
+                    perf_ctl |= PERF_CTL_TURBO_DISENGAGE;
+                }
+                msrs.push((Msr::IA32_PERF_CTL as u32, perf_ctl));
+            }
+        } else {
+            warn!("Neither HWP nor EIST is supported, ignore RT CPU P-state policy.");
+        }
+    }
+
+    if flags.contains(RtCpuPolicyFlags::MASK_THERMAL_INT) {
+        if has_acpi {
+            msrs.push((Msr::IA32_THERM_INTERRUPT as u32, 0));
+        }
+        if has_hwp {
+            msrs.push((Msr::IA32_HWP_INTERRUPT as u32, 0));
+        }
+    }
+
+    if flags.contains(RtCpuPolicyFlags::LIMIT_CSTATE) {
+        // The register is core scoped, all cores are assumed to be configured
+        // alike by the firmware.
+        let cst_config = if cfg!(feature = "intel") {
+            Some(Msr::MSR_PKG_CST_CONFIG_CONTROL.read())
+        } else {
+            None
+        };
+        match cst_config {
+            Some(val) if val & CST_CONFIG_LOCK == 0 => msrs.push((
+                Msr::MSR_PKG_CST_CONFIG_CONTROL as u32,
+                (val & !CST_CONFIG_LIMIT_MASK)
+                    | (policy.cstate_limit as u64 & CST_CONFIG_LIMIT_MASK),
+            )),
+            Some(_) => {
+                warn!("C-state configuration is locked by firmware, ignore RT CPU C-state limit.")
+            }
+            None => warn!("C-state limit is not supported, ignore RT CPU C-state limit."),
+        }
+    }
+
+    if flags.contains(RtCpuPolicyFlags::DISABLE_MWAIT) && has_mwait {
+        msrs.push((
+            Msr::IA32_MISC_ENABLE as u32,
+            Msr::IA32_MISC_ENABLE.read() & !MISC_ENABLE_MWAIT,
+        ));
+    }
+
+    info!("RT CPU policy {:?} => MSRs {:#x?}", flags, msrs);
+    msrs
+}
@@ -70,9 +70,19 @@ impl SegmentAccessRights {
         let bits = self.bits() as u16;
         (bits & 0xff) | ((bits & 0xf000) >> 4)
     }
+
+    #[cfg(feature = "amd")]
+    pub fn from_svm_segment_attributes(attr: u16) -> Self {
+        let rights = Self::from_bits_truncate(((attr & 0xff) | ((attr & 0xf00) << 4)) as u32);
+        if rights.contains(Self::PRESENT) {
+            rights
+        } else {
+            rights | Self::UNUSABLE
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Segment {
     pub selector: SegmentSelector,
     pub base: u64,
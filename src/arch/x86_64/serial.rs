@@ -1,46 +1,62 @@
-use core::fmt::{Arguments, Result, Write};
+use core::fmt::{Arguments, Write};
 
-use spin::Mutex;
 use uart_16550::{BaudRate, SerialPort};
+use x86::io::outb;
 
-const SERIAL_IO_PORT: u16 = 0x3F8;
+use crate::config::{ConsoleType, HvSystemConfig};
+use crate::console::{Console, CrLfWriter};
+use crate::lock::SpinLock;
 
-struct ByteConvertor<T: Write> {
-    inner: T,
-}
+const DEFAULT_IO_PORT: u16 = 0x3F8;
 
-impl<T: Write> ByteConvertor<T> {
-    pub fn new(inner: T) -> Self {
-        Self { inner }
+/// I/O port of the legacy UART, from the configuration if it selects one.
+pub(super) fn io_port() -> u16 {
+    let console = &HvSystemConfig::get().console;
+    match (console.console_type(), console.address) {
+        (ConsoleType::LegacyUart, port) if port != 0 => port as u16,
+        _ => DEFAULT_IO_PORT,
     }
 }
 
-impl<T: Write> Write for ByteConvertor<T> {
-    fn write_str(&mut self, s: &str) -> Result {
-        for byte in s.bytes() {
-            match byte {
-                b'\n' => {
-                    self.inner.write_char('\r')?;
-                    self.inner.write_char('\n')?;
-                }
-                _ => self.inner.write_char(byte as char)?,
-            }
-        }
-        Ok(())
+/// Reprograms the divisor latch and the line control register of the UART
+/// at `port`, which `SerialPort::init()` sets for a 1.8432 MHz clock and 8N1.
+fn set_line_params(port: u16) {
+    const LCR_DLAB: u8 = 1 << 7;
+    let console = &HvSystemConfig::get().console;
+    let divisor = console.uart_divisor();
+    if divisor == 0 {
+        // Keep 115200 baud to report the invalid configuration.
+        return;
+    }
+    unsafe {
+        outb(port + 3, LCR_DLAB);
+        outb(port, divisor as u8);
+        outb(port + 1, (divisor >> 8) as u8);
+        outb(port + 3, console.uart_line_control());
     }
 }
 
 lazy_static! {
-    static ref SERIAL1: Mutex<ByteConvertor<SerialPort>> = {
-        let mut serial_port = unsafe { SerialPort::new(SERIAL_IO_PORT) };
+    static ref SERIAL1: SpinLock<CrLfWriter<SerialPort>> = {
+        let port = io_port();
+        let mut serial_port = unsafe { SerialPort::new(port) };
         serial_port.init(BaudRate::Baud115200);
-        Mutex::new(ByteConvertor::new(serial_port))
+        set_line_params(port);
+        SpinLock::new(CrLfWriter::new(serial_port))
     };
 }
 
-pub fn putfmt(fmt: Arguments) {
-    SERIAL1
-        .lock()
-        .write_fmt(fmt)
-        .expect("Printing to serial failed");
+impl Console for SpinLock<CrLfWriter<SerialPort>> {
+    fn write_fmt(&self, args: Arguments) {
+        // Also called from NMI context and on panics, possibly interrupting a
+        // print on the same CPU.
+        self.lock_reentrant()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    }
+}
+
+/// The 16550 UART at an I/O port, the console until `console::init()`.
+pub fn legacy_uart() -> &'static dyn Console {
+    &*SERIAL1
 }
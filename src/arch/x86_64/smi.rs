@@ -0,0 +1,50 @@
+//! SMI accounting.
+//!
+//! SMIs stall the CPUs while the firmware runs in SMM, invisibly to both the
+//! hypervisor and the RTOS, and show up as unexplained latency. On the Intel
+//! CPUs having it, `MSR_SMI_COUNT` is sampled on the VM exits of each CPU and
+//! published in the stats window along with the sample TSC, so a reader can
+//! derive SMI rates from two samples. RT CPUs are not sampled while running the RTOS, but SMIs
+//! are usually broadcast to all CPUs, so the counts of the root cell CPUs also
+//! cover them.
+//!
+//! The SMI sources (e.g. legacy USB emulation, TCO watchdog, power management
+//! events) are enabled by the chipset and firmware configuration, which the
+//! hypervisor can neither inspect nor lock portably. They have to be disabled
+//! in the firmware setup; the counts here quantify what is left.
+
+use bit_field::BitField;
+use libvmm::msr::Msr;
+use spin::Once;
+
+use super::cpuid::cpuid;
+
+/// Whether the CPU has `MSR_SMI_COUNT`: Intel family 6 since Nehalem, except
+/// the first Atoms (Bonnell and Saltwell). Reading it elsewhere raises #GP.
+fn has_smi_count() -> bool {
+    let eax = cpuid!(1).eax;
+    let family = eax.get_bits(8..12);
+    let model = eax.get_bits(4..8) | eax.get_bits(16..20) << 4;
+    cfg!(feature = "intel")
+        && family == 6
+        && model >= 0x1a
+        && !matches!(model, 0x1c | 0x26 | 0x27 | 0x35 | 0x36)
+}
+
+/// Number of SMIs since reset, or `None` if the CPU does not count them.
+pub fn count() -> Option<u64> {
+    static SUPPORTED: Once<bool> = Once::new();
+    if *SUPPORTED.call_once(has_smi_count) {
+        Some(Msr::MSR_SMI_COUNT.read() & 0xffff_ffff)
+    } else {
+        None
+    }
+}
+
+/// Log the SMIs seen before the hypervisor is enabled.
+pub fn report() {
+    match count() {
+        Some(count) => info!("SMIs since reset: {}", count),
+        None => info!("SMI count not available"),
+    }
+}
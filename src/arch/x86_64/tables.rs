@@ -1,34 +1,80 @@
-use alloc::boxed::Box;
 use core::fmt::{Debug, Formatter, Result};
 use core::mem::size_of;
+use core::ops::Range;
 
-use spin::Mutex;
 use x86::{segmentation::SegmentSelector, task, Ring};
 use x86_64::addr::VirtAddr;
 use x86_64::instructions::tables::{lgdt, lidt, sidt};
 use x86_64::structures::gdt::{Descriptor, DescriptorFlags};
-use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
+use x86_64::structures::idt::{Entry, EntryOptions, HandlerFunc, InterruptDescriptorTable};
 use x86_64::structures::{tss::TaskStateSegment, DescriptorTablePointer};
 
+#[cfg(not(feature = "amd"))]
+use super::exception::ExceptionType;
 use super::segmentation::SegmentAccessRights;
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::{Frame, PAGE_SIZE};
 
 lazy_static! {
-    pub(super) static ref IDT: Mutex<IdtStruct> = {
-        let mut idt = IdtStruct::alloc();
+    pub(super) static ref IDT: SpinLock<IdtStruct> = {
+        let mut idt = IdtStruct::alloc().expect("Failed to allocate the IDT");
         idt.init();
-        Mutex::new(idt)
+        SpinLock::new(idt)
     };
 }
 
+/// Size of each interrupt stack of a CPU.
+const IST_STACK_SIZE: usize = 4 * PAGE_SIZE; // 16 KB
+
+/// Exceptions delivered on an interrupt stack of their own, as they may occur
+/// while the current stack is unusable, with their index in the IST.
+#[cfg(not(feature = "amd"))]
+pub(super) const IST_VECTORS: &[(u8, usize)] = &[
+    (ExceptionType::DoubleFault, 0),
+    (ExceptionType::NonMaskableInterrupt, 1),
+    (ExceptionType::MachineCheck, 2),
+];
+
+/// No interrupt stack on AMD: `vmload` loads the TR of the guest, so until
+/// the next VMRUN the IST in use would be the one of the guest's TSS.
+#[cfg(feature = "amd")]
+pub(super) const IST_VECTORS: &[(u8, usize)] = &[];
+
+/// Allocates a descriptor table on its own zeroed pages, so that it never
+/// shares a page with other hypervisor data. The pages are never freed.
+fn alloc_table_pages<T>() -> HvResult<*mut T> {
+    let frame_count = (size_of::<T>() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut frame = Frame::new_contiguous(frame_count, 0)?;
+    frame.zero();
+    let ptr = frame.as_mut_ptr() as *mut T;
+    core::mem::forget(frame);
+    Ok(ptr)
+}
+
 pub(super) struct TssStruct {
     inner: &'static mut TaskStateSegment,
 }
 
 impl TssStruct {
-    pub fn alloc() -> Self {
-        Self {
-            inner: Box::leak(Box::new(TaskStateSegment::new())),
+    pub fn alloc() -> HvResult<Self> {
+        let ptr = alloc_table_pages::<TaskStateSegment>()?;
+        unsafe { ptr.write(TaskStateSegment::new()) };
+        let inner = unsafe { &mut *ptr };
+        for &(_, index) in IST_VECTORS.iter() {
+            let stack = Frame::new_contiguous(IST_STACK_SIZE / PAGE_SIZE, 0)?;
+            let top = stack.as_mut_ptr() as usize + IST_STACK_SIZE;
+            inner.interrupt_stack_table[index] = VirtAddr::new(top as u64);
+            core::mem::forget(stack);
         }
+        Ok(Self { inner })
+    }
+
+    /// Returns the address range of the interrupt stack `index` of the IST.
+    #[allow(dead_code)]
+    pub fn ist_stack(&self, index: usize) -> Range<usize> {
+        let top = self.inner.interrupt_stack_table[index].as_u64() as usize;
+        top - IST_STACK_SIZE..top
     }
 }
 
@@ -42,10 +88,11 @@ impl GdtStruct {
     pub const KDATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, Ring::Ring0);
     pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(3, Ring::Ring0);
 
-    pub fn alloc() -> Self {
-        Self {
-            table: Box::leak(Box::new([0u64; 16])),
-        }
+    pub fn alloc() -> HvResult<Self> {
+        let ptr = alloc_table_pages::<[u64; 16]>()?;
+        Ok(Self {
+            table: unsafe { &mut *ptr },
+        })
     }
 
     pub fn init(&mut self, tss: &TssStruct) {
@@ -131,13 +178,26 @@ pub(super) struct IdtStruct {
 }
 
 impl IdtStruct {
-    pub fn alloc() -> Self {
-        Self {
-            table: Box::leak(Box::new(InterruptDescriptorTable::new())),
-        }
+    pub fn alloc() -> HvResult<Self> {
+        let ptr = alloc_table_pages::<InterruptDescriptorTable>()?;
+        unsafe { ptr.write(InterruptDescriptorTable::new()) };
+        Ok(Self {
+            table: unsafe { &mut *ptr },
+        })
     }
 
     pub fn init(&mut self) {
+        for vector in 0..=255 {
+            self.set_entry(vector);
+        }
+        for &(vector, index) in IST_VECTORS.iter() {
+            unsafe { self.set_entry(vector).set_stack_index(index as u16) };
+        }
+    }
+
+    /// Points the gate of `vector` to its entry in `exception.S`, and returns
+    /// the options of the gate.
+    fn set_entry(&mut self, vector: u8) -> &mut EntryOptions {
         extern "C" {
             #[link_name = "exception_entries"]
             static ENTRIES: [extern "C" fn(); 256];
@@ -145,9 +205,14 @@ impl IdtStruct {
         let entries = unsafe {
             core::slice::from_raw_parts_mut(self.table as *mut _ as *mut Entry<HandlerFunc>, 256)
         };
-        for i in 0..256 {
-            entries[i].set_handler_fn(unsafe { core::mem::transmute(ENTRIES[i]) });
-        }
+        let handler = unsafe { core::mem::transmute(ENTRIES[vector as usize]) };
+        entries[vector as usize].set_handler_fn(handler)
+    }
+
+    /// Marks the gate of `vector` present or not present.
+    #[allow(dead_code)]
+    pub fn set_present(&mut self, vector: u8, present: bool) {
+        self.set_entry(vector).set_present(present);
     }
 
     pub fn pointer(&self) -> DescriptorTablePointer {
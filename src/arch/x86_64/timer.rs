@@ -0,0 +1,75 @@
+//! LAPIC timer of the root cell.
+//!
+//! The timer is passed through to Linux, which keeps programming its
+//! clockevent device without VM exits:
+//!
+//! - IA32_TSC_DEADLINE and the timer LVT, initial count, current count and
+//!   divide configuration registers are not intercepted, neither as MSRs nor
+//!   in the xAPIC page;
+//! - the guest TSC is the host TSC (no offsetting, scaling or RDTSC exiting),
+//!   so deadlines keep their meaning;
+//! - the hypervisor never programs the LAPIC timer: its delays busy-wait on
+//!   the TSC, and its watchdog uses NMIs sent by other CPUs.
+//!
+//! Intercepting the timer would cost a VM exit per clockevent reprogramming,
+//! which happens thousands of times per second on tickless kernels, with
+//! nothing to protect: RT CPUs have their own LAPICs. The price is that a
+//! timer interrupt firing during a VM exit is only delivered on the next VM
+//! entry, e.g. after the INIT-SIPI delays of `RtStart`.
+//!
+//! The timer state of Linux is saved when a CPU enters the hypervisor and
+//! checked once the CPU is set up, to catch (and undo) any change by the
+//! hypervisor code in between.
+
+use libvmm::msr::Msr;
+
+use super::apic::lapic;
+use super::cpu;
+use super::cpuid::CpuFeatures;
+
+#[derive(Debug, Default)]
+pub struct TimerState {
+    lvt_timer: u32,
+    /// 0 if the TSC-deadline mode is not supported or not armed.
+    tsc_deadline: u64,
+}
+
+impl TimerState {
+    pub fn save() -> Self {
+        let tsc_deadline = if CpuFeatures::new().has_tsc_deadline() {
+            unsafe { Msr::IA32_TSC_DEADLINE.read() }
+        } else {
+            0
+        };
+        Self {
+            lvt_timer: lapic().lvt_timer(),
+            tsc_deadline,
+        }
+    }
+
+    /// Restores the saved state if it was modified since `save()`. An armed
+    /// deadline may only have expired in the meantime, which clears it.
+    pub fn check(&self, cpu_id: u32) {
+        let lvt_timer = lapic().lvt_timer();
+        if lvt_timer != self.lvt_timer {
+            error!(
+                "CPU {}: LAPIC timer LVT changed to {:#x} during init, restored to {:#x}",
+                cpu_id, lvt_timer, self.lvt_timer
+            );
+            lapic().set_lvt_timer(self.lvt_timer);
+        }
+        if !CpuFeatures::new().has_tsc_deadline() {
+            return;
+        }
+        let tsc_deadline = unsafe { Msr::IA32_TSC_DEADLINE.read() };
+        let expired = tsc_deadline == 0 && cpu::current_cycle() >= self.tsc_deadline;
+        if tsc_deadline != self.tsc_deadline && !expired {
+            error!(
+                "CPU {}: TSC deadline changed to {:#x} during init, restored to {:#x}",
+                cpu_id, tsc_deadline, self.tsc_deadline
+            );
+            // A deadline in the past fires immediately, as it would have.
+            unsafe { Msr::IA32_TSC_DEADLINE.write(self.tsc_deadline) };
+        }
+    }
+}
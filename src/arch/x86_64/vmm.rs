@@ -6,13 +6,187 @@ mod vendor;
 #[path = "amd/mod.rs"]
 mod vendor;
 
+use core::sync::atomic::Ordering;
+
+use bitflags::bitflags;
+use libvmm::msr::Msr;
+use numeric_enum_macro::numeric_enum;
 use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
+
+use super::segmentation::Segment;
+use super::{mem_encrypt, GeneralRegisters};
+use crate::config::{ExitPolicy, HvSystemConfig, MAX_GUEST_PHYS_ADDR_BITS, MIN_PHYS_ADDR_BITS};
+use crate::fault_inject::{self, FaultPoint};
+use crate::logging::RateLimit;
+use crate::{error::HvResult, memory::GuestPhysAddr, percpu::PerCpu};
+
+/// Min interval between the warnings of skipped VM exits, see `exit_policy`.
+const SKIP_WARN_INTERVAL_MS: u64 = 1000;
 
-use super::GeneralRegisters;
-use crate::{error::HvResult, percpu::PerCpu};
+#[cfg(all(test, feature = "intel"))]
+pub use vendor::NestedPTE;
+pub use vendor::{check_hypervisor_feature, intercepts, NestedPageTable, Vcpu};
+
+bitflags! {
+    /// Guest operations intercepted by the hypervisor, depending on the
+    /// `InterceptProfile` and the hardware.
+    pub struct InterceptFlags: u64 {
+        /// CPUID, always intercepted with VMX, where CPUID unconditionally
+        /// exits. With SVM, the guest may execute it natively if the cell
+        /// hides the hypervisor from CPUID.
+        const CPUID = 1 << 0;
+        /// NMIs, reflected to the host.
+        const NMI   = 1 << 1;
+        /// Accesses to the MSRs selected by the MSR bitmap.
+        const MSR   = 1 << 2;
+        /// PCI configuration space accesses through ports, to shadow MSIs and
+        /// protect the devices assigned to the RTOS.
+        const PCI_CONFIG = 1 << 3;
+        /// Legacy PIC ports, to keep them masked.
+        const LEGACY_IRQ = 1 << 4;
+        /// Reset and power-off ports, to stop the RT CPUs first.
+        const RESET = 1 << 5;
+    }
+}
 
-pub use vendor::{check_hypervisor_feature, NestedPageTable, Vcpu};
+numeric_enum! {
+    /// Guest segment and descriptor table registers. GDTR and IDTR only have
+    /// a base and a limit.
+    #[repr(u8)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum GuestSegment {
+        Es = 0,
+        Cs = 1,
+        Ss = 2,
+        Ds = 3,
+        Fs = 4,
+        Gs = 5,
+        Ldtr = 6,
+        Tr = 7,
+        Gdtr = 8,
+        Idtr = 9,
+    }
+}
+
+pub const NUM_GUEST_SEGMENTS: usize = 10;
+
+impl GuestSegment {
+    pub const ALL: [Self; NUM_GUEST_SEGMENTS] = [
+        Self::Es,
+        Self::Cs,
+        Self::Ss,
+        Self::Ds,
+        Self::Fs,
+        Self::Gs,
+        Self::Ldtr,
+        Self::Tr,
+        Self::Gdtr,
+        Self::Idtr,
+    ];
+}
 
+numeric_enum! {
+    /// MSR-backed guest state, numbered by the MSR index.
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum GuestMsr {
+        SysenterCs = 0x174,
+        SysenterEsp = 0x175,
+        SysenterEip = 0x176,
+        DebugCtl = 0x1d9,
+        Pat = 0x277,
+        Efer = 0xc000_0080,
+        Star = 0xc000_0081,
+        Lstar = 0xc000_0082,
+        Cstar = 0xc000_0083,
+        Sfmask = 0xc000_0084,
+        FsBase = 0xc000_0100,
+        GsBase = 0xc000_0101,
+        KernelGsBase = 0xc000_0102,
+    }
+}
+
+pub const NUM_GUEST_MSRS: usize = 13;
+
+impl GuestMsr {
+    /// All guest MSRs, in the order of [`GuestState::msrs`].
+    pub const ALL: [Self; NUM_GUEST_MSRS] = [
+        Self::SysenterCs,
+        Self::SysenterEsp,
+        Self::SysenterEip,
+        Self::DebugCtl,
+        Self::Pat,
+        Self::Efer,
+        Self::Star,
+        Self::Lstar,
+        Self::Cstar,
+        Self::Sfmask,
+        Self::FsBase,
+        Self::GsBase,
+        Self::KernelGsBase,
+    ];
+
+    pub fn msr(self) -> Msr {
+        match self {
+            Self::SysenterCs => Msr::IA32_SYSENTER_CS,
+            Self::SysenterEsp => Msr::IA32_SYSENTER_ESP,
+            Self::SysenterEip => Msr::IA32_SYSENTER_EIP,
+            Self::DebugCtl => Msr::IA32_DEBUGCTL,
+            Self::Pat => Msr::IA32_PAT,
+            Self::Efer => Msr::IA32_EFER,
+            Self::Star => Msr::IA32_STAR,
+            Self::Lstar => Msr::IA32_LSTAR,
+            Self::Cstar => Msr::IA32_CSTAR,
+            Self::Sfmask => Msr::IA32_FMASK,
+            Self::FsBase => Msr::IA32_FS_BASE,
+            Self::GsBase => Msr::IA32_GS_BASE,
+            Self::KernelGsBase => Msr::IA32_KERNEL_GSBASE,
+        }
+    }
+}
+
+/// A segment register in [`GuestState`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GuestSegmentState {
+    pub base: u64,
+    pub limit: u32,
+    /// In the VMX format of `SegmentAccessRights`, on both vendors.
+    pub access_rights: u32,
+    pub selector: u16,
+}
+
+impl From<&Segment> for GuestSegmentState {
+    fn from(seg: &Segment) -> Self {
+        Self {
+            base: seg.base,
+            limit: seg.limit,
+            access_rights: seg.access_rights.bits(),
+            selector: seg.selector.bits(),
+        }
+    }
+}
+
+/// The complete register state of a guest vCPU, saved in one go for the
+/// diagnostics.
+#[derive(Debug, Default, Clone)]
+pub struct GuestState {
+    /// In the order of instruction encodings, RSP included.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+    /// Control registers as seen by the guest.
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    /// Indexed by [`GuestSegment`].
+    pub segments: [GuestSegmentState; NUM_GUEST_SEGMENTS],
+    /// In the order of [`GuestMsr::ALL`].
+    pub msrs: [u64; NUM_GUEST_MSRS],
+}
+
+/// The one accessor layer for the guest register state of a vCPU, hiding
+/// whether it lives in the VMCS, the VMCB, the saved registers or the MSRs.
 pub trait VcpuAccessGuestState {
     // Architecture independent methods:
     fn regs(&self) -> &GeneralRegisters;
@@ -33,6 +207,38 @@ pub trait VcpuAccessGuestState {
     fn gs_base(&self) -> u64;
     fn cr(&self, cr_idx: usize) -> u64;
     fn set_cr(&mut self, cr_idx: usize, val: u64);
+    fn segment(&self, seg: GuestSegment) -> Segment;
+    fn msr(&self, msr: GuestMsr) -> u64;
+
+    /// Returns the general register numbered `idx` in instruction encodings,
+    /// RSP included.
+    fn gpr(&self, idx: usize) -> u64 {
+        match idx {
+            4 => self.stack_pointer(),
+            _ => self.regs().get(idx).expect("Invalid register index"),
+        }
+    }
+
+    fn save_state(&self) -> GuestState {
+        let mut state = GuestState {
+            rip: self.instr_pointer(),
+            rflags: self.rflags(),
+            cr0: self.cr(0),
+            cr3: self.cr(3),
+            cr4: self.cr(4),
+            ..Default::default()
+        };
+        for (idx, gpr) in state.gprs.iter_mut().enumerate() {
+            *gpr = self.gpr(idx);
+        }
+        for seg in GuestSegment::ALL {
+            state.segments[seg as usize] = (&self.segment(seg)).into();
+        }
+        for (val, msr) in state.msrs.iter_mut().zip(GuestMsr::ALL) {
+            *val = self.msr(msr);
+        }
+        state
+    }
 }
 
 const VM_EXIT_LEN_CPUID: u8 = 2;
@@ -40,6 +246,15 @@ const VM_EXIT_LEN_RDMSR: u8 = 2;
 const VM_EXIT_LEN_WRMSR: u8 = 2;
 const VM_EXIT_LEN_HYPERCALL: u8 = 3;
 
+/// Synthetic MSR of the hypercall fast path, in the range reserved for
+/// software: a WRMSR to it with the code in EAX and the arguments in RDI and
+/// RSI issues the hypercall, only for `HyperCallCode::is_fast()` codes.
+pub const FAST_HYPERCALL_MSR: u32 = 0x4000_0080;
+/// Set in EAX of the `HypervisorFeatures` CPUID leaf if the fast path is
+/// available: with VMX, where the MSRs beyond the MSR bitmap always exit,
+/// while SVM does not intercept MSR accesses.
+const FEATURE_FAST_HYPERCALL: u64 = 1 << 0;
+
 const HOST_CR0: Cr0Flags = Cr0Flags::from_bits_truncate(
     Cr0Flags::PAGING.bits()
         | Cr0Flags::WRITE_PROTECT.bits()
@@ -50,14 +265,105 @@ const HOST_CR0: Cr0Flags = Cr0Flags::from_bits_truncate(
 );
 const HOST_CR4: Cr4Flags = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION;
 
+/// Checks that the regions of `config` fit in the physical address width of
+/// the CPU, and the guest addresses of the root cell in its own width, as
+/// page table entries would silently truncate the addresses beyond.
+pub fn check_phys_addr_bits(config: &HvSystemConfig) -> HvResult {
+    let host_bits = mem_encrypt::info().phys_addr_bits;
+    let cell = config.root_cell.config();
+    // The EPT/NPT is a 4-level table.
+    let max_guest_bits = host_bits.min(MAX_GUEST_PHYS_ADDR_BITS);
+    let guest_bits = match cell.phys_addr_bits() {
+        0 => max_guest_bits,
+        bits if !(MIN_PHYS_ADDR_BITS..=max_guest_bits).contains(&bits) => {
+            return hv_result_err!(
+                EINVAL,
+                "Invalid guest physical address width {} (CPU: {}, EPT/NPT walk: {})",
+                bits,
+                host_bits,
+                MAX_GUEST_PHYS_ADDR_BITS
+            );
+        }
+        bits => bits,
+    };
+    let beyond = |start: u64, size: u64, bits: u8| {
+        start.checked_add(size).map_or(true, |end| end > 1 << bits)
+    };
+    let regions = cell.mem_regions();
+    let host_regions = regions
+        .iter()
+        .chain([&config.hypervisor_memory, &config.rtos_memory]);
+    for r in host_regions {
+        let (start, size) = (r.phys_start, r.size);
+        if beyond(start, size, host_bits) {
+            return hv_result_err!(
+                EINVAL,
+                "Region [{:#x}, {:#x}) beyond the {}-bit physical address width",
+                start,
+                start.wrapping_add(size),
+                host_bits
+            );
+        }
+    }
+    let guest_region = regions
+        .iter()
+        .find(|r| beyond(r.virt_start, r.size, guest_bits));
+    if let Some(r) = guest_region {
+        let (start, size) = (r.virt_start, r.size);
+        return hv_result_err!(
+            EINVAL,
+            "Guest region [{:#x}, {:#x}) beyond the {}-bit guest physical address width",
+            start,
+            start.wrapping_add(size),
+            guest_bits
+        );
+    }
+    Ok(())
+}
+
+/// Returns the guest EFER once CR0 becomes `cr0`, with `LONG_MODE_ACTIVE`
+/// following CR0.PG and EFER.LME, or `None` if the write raises #GP: paging
+/// enabled with LME set but CR4.PAE clear, or long mode entered without
+/// protected mode.
+///
+/// With unrestricted guests, the processor switches long mode itself when the
+/// guest writes CR0; this is for the CR0 values set by the hypervisor.
+pub fn efer_after_cr0_write(cr0: u64, cr4: u64, efer: u64) -> Option<u64> {
+    let cr0 = Cr0Flags::from_bits_truncate(cr0);
+    let cr4 = Cr4Flags::from_bits_truncate(cr4);
+    let efer = EferFlags::from_bits_truncate(efer);
+    if !cr0.contains(Cr0Flags::PAGING) {
+        return Some((efer - EferFlags::LONG_MODE_ACTIVE).bits());
+    }
+    if !efer.contains(EferFlags::LONG_MODE_ENABLE) {
+        return Some(efer.bits());
+    }
+    if !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+        || !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE)
+    {
+        return None;
+    }
+    Some((efer | EferFlags::LONG_MODE_ACTIVE).bits())
+}
+
 pub(super) struct VmExit<'a> {
     pub cpu_data: &'a mut PerCpu,
+    /// Raw exit reason, set by the vendor exit handler.
+    pub exit_reason: u32,
+    /// Hypercall code, if the exit is a hypercall.
+    pub hypercall: Option<u32>,
+    /// Whether the hypercall came through `FAST_HYPERCALL_MSR`, then the
+    /// periodic polls are left to the next VM exit.
+    pub fast_hypercall: bool,
 }
 
 impl VmExit<'_> {
     pub fn new() -> Self {
         Self {
             cpu_data: PerCpu::current_mut(),
+            exit_reason: 0,
+            hypercall: None,
+            fast_hypercall: false,
         }
     }
 
@@ -76,6 +382,12 @@ impl VmExit<'_> {
         let guest_regs = self.cpu_data.vcpu.regs();
         let id = guest_regs.rcx;
         let value = guest_regs.rax | (guest_regs.rdx << 32);
+        if id as u32 == FAST_HYPERCALL_MSR {
+            return self.handle_fast_hypercall();
+        }
+        if super::rt_policy::mediate_msr_write(id as u32, value) {
+            return self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR);
+        }
         warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
         // TODO
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR)?;
@@ -84,17 +396,26 @@ impl VmExit<'_> {
 
     pub fn handle_cpuid(&mut self) -> HvResult {
         use super::cpuid::{cpuid, CpuIdEax, FeatureInfoFlags};
+        use crate::config::CpuidPolicyFlags;
         let signature = unsafe { &*("RVMRVMRVMRVM".as_ptr() as *const [u32; 3]) };
+        let policy = crate::cell::root_cell().config.cpuid_policy();
+        let phys_addr_bits = crate::cell::root_cell().config.phys_addr_bits();
         let cr4_flags = Cr4Flags::from_bits_truncate(self.cpu_data.vcpu.cr(4));
         let guest_regs = self.cpu_data.vcpu.regs_mut();
         let function = guest_regs.rax as u32;
-        if function == CpuIdEax::HypervisorInfo as _ {
+        // Without the hypervisor leaves, they return the same as on bare metal.
+        let hide_leaves = policy.contains(CpuidPolicyFlags::HIDE_LEAVES);
+        if function == CpuIdEax::HypervisorInfo as _ && !hide_leaves {
             guest_regs.rax = CpuIdEax::HypervisorFeatures as u32 as _;
             guest_regs.rbx = signature[0] as _;
             guest_regs.rcx = signature[1] as _;
             guest_regs.rdx = signature[2] as _;
-        } else if function == CpuIdEax::HypervisorFeatures as _ {
-            guest_regs.rax = 0;
+        } else if function == CpuIdEax::HypervisorFeatures as _ && !hide_leaves {
+            guest_regs.rax = if cfg!(feature = "intel") {
+                FEATURE_FAST_HYPERCALL
+            } else {
+                0
+            };
             guest_regs.rbx = 0;
             guest_regs.rcx = 0;
             guest_regs.rdx = 0;
@@ -110,27 +431,93 @@ impl VmExit<'_> {
                     flags.insert(FeatureInfoFlags::OSXSAVE);
                 }
                 flags.remove(FeatureInfoFlags::VMX);
-                flags.insert(FeatureInfoFlags::HYPERVISOR);
+                flags.set(
+                    FeatureInfoFlags::HYPERVISOR,
+                    !policy.contains(CpuidPolicyFlags::HIDE_PRESENT_BIT),
+                );
                 guest_regs.rcx = flags.bits();
             } else if function == CpuIdEax::AmdFeatureInfo as _ {
                 let mut flags = FeatureInfoFlags::from_bits_truncate(guest_regs.rcx as _);
                 flags.remove(FeatureInfoFlags::SVM);
                 guest_regs.rcx = flags.bits();
+            } else if function == CpuIdEax::AddressSizes as _ && phys_addr_bits != 0 {
+                // EAX[7:0]: physical address width, EAX[23:16]: guest physical
+                // address width on AMD, where 0 means the same.
+                let eax = guest_regs.rax as u32 & !0xff_00ff;
+                guest_regs.rax = (eax | phys_addr_bits as u32) as _;
             }
         }
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_CPUID)?;
         Ok(())
     }
 
+    pub fn handle_io(&mut self, io: &super::pio::IoInstrInfo) -> HvResult {
+        super::pio::handle_io_instruction(&mut self.cpu_data.vcpu, io)
+    }
+
+    /// Emulates the access to an MMIO device at `gpaddr`, or returns `None` if
+    /// no device covers it.
+    pub fn handle_mmio(&mut self, gpaddr: GuestPhysAddr) -> Option<HvResult> {
+        if !crate::cell::root_cell().mmio.read().contains(gpaddr) {
+            return None;
+        }
+        Some(super::mmio::handle_mmio_access(
+            &mut self.cpu_data.vcpu,
+            gpaddr,
+        ))
+    }
+
+    /// Handles an exit reason without handler as its `ExitPolicy` says, see
+    /// `exit_policy`. `instr_len` is the length of the instruction which
+    /// caused the exit, 0 if none.
+    pub fn handle_unhandled(&mut self, instr_len: u8) -> HvResult {
+        let rip = self.cpu_data.vcpu.instr_pointer();
+        match crate::exit_policy::get(self.exit_reason) {
+            ExitPolicy::Fault => hv_result_err!(ENOSYS),
+            ExitPolicy::Skip => {
+                static WARNINGS: RateLimit = RateLimit::new(SKIP_WARN_INTERVAL_MS);
+                if let Some(suppressed) = WARNINGS.check() {
+                    warn!(
+                        "Unhandled VM exit {:#x} @ RIP {:#x}, {} bytes skipped ({} warnings suppressed)",
+                        self.exit_reason, rip, instr_len, suppressed
+                    );
+                }
+                self.cpu_data.vcpu.advance_rip(instr_len)
+            }
+            ExitPolicy::Panic => panic!(
+                "Unhandled VM exit {:#x} @ RIP {:#x}: {:#x?}",
+                self.exit_reason,
+                rip,
+                self.cpu_data.vcpu.save_state()
+            ),
+        }
+    }
+
     pub fn handle_hypercall(&mut self) -> HvResult {
         use crate::hypercall::HyperCall;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_HYPERCALL)?;
         let guest_regs = self.cpu_data.vcpu.regs();
         let (code, arg0, arg1) = (guest_regs.rax, guest_regs.rdi, guest_regs.rsi);
+        self.hypercall = Some(code as u32);
         HyperCall::new(self.cpu_data).hypercall(code as _, arg0, arg1)?;
         Ok(())
     }
 
+    fn handle_fast_hypercall(&mut self) -> HvResult {
+        use crate::hypercall::HyperCall;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let (code, arg0, arg1) = (guest_regs.rax as u32, guest_regs.rdi, guest_regs.rsi);
+        self.hypercall = Some(code);
+        self.fast_hypercall = true;
+        let mut hypercall = HyperCall::new(self.cpu_data);
+        hypercall.fast_hypercall(code, arg0, arg1)?;
+        // The injected #GP must point at the WRMSR, as it would on bare metal.
+        if !hypercall.faulted() {
+            self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR)?;
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn test_read_guest_memory(&self, gvaddr: usize, size: usize) -> HvResult {
         use crate::cell;
@@ -138,7 +525,7 @@ impl VmExit<'_> {
 
         let pt = self.cpu_data.vcpu.guest_page_table();
         let (gpaddr, _, _) = pt.query(gvaddr)?;
-        let (hpaddr, _, _) = cell::root_cell().gpm.page_table().query(gpaddr)?;
+        let (hpaddr, _, _) = cell::root_cell().gpm.read().page_table().query(gpaddr)?;
         println!(
             "GVA({:#x?}) -> GPA({:#x?}) -> HPA({:#x?}):",
             gvaddr, gpaddr, hpaddr
@@ -150,8 +537,17 @@ impl VmExit<'_> {
 }
 
 pub(super) fn vmexit_handler() {
+    let start_cycle = super::cpu::current_cycle();
     let mut vmexit = VmExit::new();
-    let res = vmexit.handle_exit();
+    super::nested_tlb::leave_guest(vmexit.cpu_data.id);
+    super::watchdog::enter(vmexit.cpu_data.id);
+    let guest_rip = crate::rip_latency::active().then(|| vmexit.cpu_data.vcpu.instr_pointer());
+    fault_inject::begin_exit(vmexit.cpu_data.id);
+    let res = if fault_inject::should_fail(FaultPoint::ExitHandler) {
+        hv_result_err!(EIO, "Injected fault")
+    } else {
+        vmexit.handle_exit()
+    };
     if let Err(err) = res {
         error!(
             "Failed to handle VM exit, inject fault to guest...\n{:?}",
@@ -159,4 +555,88 @@ pub(super) fn vmexit_handler() {
         );
         vmexit.cpu_data.fault().unwrap();
     }
+    fault_inject::end_exit(vmexit.cpu_data.id);
+    vmexit.cpu_data.guest_ran = true;
+    if !vmexit.fast_hypercall {
+        super::mce::poll();
+        super::boot_rt::sample_rt_device_stats();
+        super::watchdog::poll(vmexit.cpu_data.id);
+        crate::mem_heat::poll();
+        crate::hypercall::async_op::poll();
+        crate::irq_storm::poll();
+        crate::mirror::poll();
+        crate::stats_window::poll_log_notify();
+    }
+
+    let end_cycle = super::cpu::current_cycle();
+    crate::stats_window::update_cpu_stats(vmexit.cpu_data.id, |stats| {
+        let cycles = end_cycle - start_cycle;
+        stats.vm_exits.fetch_add(1, Ordering::Relaxed);
+        stats.exit_cycles.fetch_add(cycles, Ordering::Relaxed);
+        if vmexit.fast_hypercall {
+            stats.fast_hypercalls.fetch_add(1, Ordering::Relaxed);
+            stats
+                .fast_hypercall_cycles
+                .fetch_add(cycles, Ordering::Relaxed);
+        } else if vmexit.hypercall.is_some() {
+            stats.hypercall_cycles.fetch_add(cycles, Ordering::Relaxed);
+        }
+        if let Some(count) = super::smi::count() {
+            stats.smi_count.store(count, Ordering::Relaxed);
+            stats.smi_sample_tsc.store(end_cycle, Ordering::Relaxed);
+        }
+    });
+    if let Some(rip) = guest_rip {
+        crate::rip_latency::record(rip, end_cycle - start_cycle);
+    }
+    crate::stats_window::trace_exit(
+        vmexit.cpu_data.id,
+        start_cycle,
+        end_cycle - start_cycle,
+        vmexit.exit_reason,
+        vmexit.hypercall,
+    );
+    crate::logging::unboost();
+    super::watchdog::leave(vmexit.cpu_data.id);
+    vmexit
+        .cpu_data
+        .vcpu
+        .sync_nested_tlb()
+        .expect("Failed to invalidate the nested paging translations");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PE: u64 = Cr0Flags::PROTECTED_MODE_ENABLE.bits();
+    const PG: u64 = Cr0Flags::PAGING.bits();
+    const PAE: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+    const LME: u64 = EferFlags::LONG_MODE_ENABLE.bits();
+    const LMA: u64 = EferFlags::LONG_MODE_ACTIVE.bits();
+
+    #[test]
+    fn test_boot_16_32_64() {
+        // Real mode to protected mode, paging off.
+        assert_eq!(efer_after_cr0_write(PE, 0, 0), Some(0));
+        // 32-bit paging.
+        assert_eq!(efer_after_cr0_write(PE | PG, 0, 0), Some(0));
+        // Back to protected mode, then PAE and LME, then paging on.
+        assert_eq!(efer_after_cr0_write(PE, PAE, LME), Some(LME));
+        assert_eq!(efer_after_cr0_write(PE | PG, PAE, LME), Some(LME | LMA));
+    }
+
+    #[test]
+    fn test_leave_long_mode() {
+        assert_eq!(efer_after_cr0_write(PE, PAE, LME | LMA), Some(LME));
+        assert_eq!(efer_after_cr0_write(0, 0, LME | LMA), Some(LME));
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        // Long mode requires PAE.
+        assert_eq!(efer_after_cr0_write(PE | PG, 0, LME), None);
+        // Paging requires protected mode.
+        assert_eq!(efer_after_cr0_write(PG, PAE, LME), None);
+    }
 }
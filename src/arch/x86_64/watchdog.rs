@@ -0,0 +1,166 @@
+//! Watchdog for stuck VM exit handlers.
+//!
+//! Each root CPU records when it entered its VM exit handler. While polling
+//! on their own VM exits, the other root CPUs check these timestamps, and
+//! send an NMI to a CPU which has stayed in the hypervisor for longer than
+//! `HvSystemConfig::watchdog_timeout_ms`. The NMI handler of the stuck CPU
+//! then records the interrupted RIP, registers and stack, which the next
+//! polling CPU logs, instead of an infinite loop in the hypervisor silently
+//! freezing the core.
+//! Nothing is logged in NMI context, where the logger lock may be held by
+//! the interrupted code. One dump is recorded at a time.
+//!
+//! The performance counters, the local APIC timer and the HPET all belong to
+//! Linux, so the NMI is sent by another CPU rather than by a timer of the
+//! stuck CPU. A hang of all root CPUs at once is not detected.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use super::apic;
+use super::cpu;
+use super::exception::TrapFrame;
+use super::GeneralRegisters;
+use crate::consts::MAX_CPUS;
+use crate::header::HvHeader;
+use crate::percpu::PerCpu;
+
+const DEFAULT_TIMEOUT_US: u64 = 2 * 1000 * 1000; // 2s
+/// Number of stack words dumped.
+const STACK_DUMP_WORDS: usize = 32;
+/// Words of a dump: RIP, RSP, the general registers, the number of stack
+/// words and the stack.
+const REGS_WORDS: usize = size_of::<GeneralRegisters>() / 8;
+const DUMP_WORDS: usize = 3 + REGS_WORDS + STACK_DUMP_WORDS;
+/// `DUMP_CPU` when no dump is recorded.
+const NO_CPU: u32 = u32::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+/// Time a CPU may stay in its VM exit handler.
+static TIMEOUT_US: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_US);
+
+/// Cycle at which each CPU entered its VM exit handler, 0 if in the guest.
+static EXIT_START_CYCLE: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+/// Whether a watchdog NMI has been sent to each CPU for its current exit.
+static NMI_SENT: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+/// CPU whose context is recorded in `DUMP`, and whether it is complete.
+static DUMP_CPU: AtomicU32 = AtomicU32::new(NO_CPU);
+static DUMP_READY: AtomicBool = AtomicBool::new(false);
+static DUMP: [AtomicU64; DUMP_WORDS] = [ZERO; DUMP_WORDS];
+
+/// Sets the time a CPU may stay in its VM exit handler, 0 for the default.
+pub fn set_timeout(ms: u32) {
+    let us = match ms {
+        0 => DEFAULT_TIMEOUT_US,
+        ms => ms as u64 * 1000,
+    };
+    TIMEOUT_US.store(us, Ordering::Relaxed);
+}
+
+/// Called when the CPU `cpu_id` enters its VM exit handler.
+pub(super) fn enter(cpu_id: u32) {
+    EXIT_START_CYCLE[cpu_id as usize].store(cpu::current_cycle(), Ordering::Release);
+}
+
+/// Called when the CPU `cpu_id` leaves its VM exit handler.
+pub(super) fn leave(cpu_id: u32) {
+    EXIT_START_CYCLE[cpu_id as usize].store(0, Ordering::Release);
+    NMI_SENT[cpu_id as usize].store(false, Ordering::Release);
+}
+
+/// Sends an NMI to the CPUs stuck in their VM exit handler. Called on VM
+/// exits of root CPUs.
+pub(super) fn poll(cpu_id: u32) {
+    let now = cpu::current_cycle();
+    let threshold = TIMEOUT_US.load(Ordering::Relaxed) * cpu::frequency() as u64;
+    let num_cpus = (HvHeader::get().max_cpus as usize).min(MAX_CPUS);
+    for id in (0..num_cpus).filter(|&id| id != cpu_id as usize) {
+        let start = EXIT_START_CYCLE[id].load(Ordering::Acquire);
+        if start == 0 || now.saturating_sub(start) < threshold {
+            continue;
+        }
+        if NMI_SENT[id].swap(true, Ordering::AcqRel) {
+            continue;
+        }
+        error!(
+            "Watchdog: CPU {} stuck in the VM exit handler for {} ms",
+            id,
+            (now - start) / cpu::frequency() as u64 / 1000
+        );
+        if let Some((owner, location)) = crate::lock::waiting_on(id as u32) {
+            error!(
+                "Watchdog: CPU {} is waiting for a lock held by CPU {} since {}",
+                id, owner, location
+            );
+        }
+        match apic::cpu_to_apic_id(id as u32) {
+            Some(apic_id) => apic::lapic().send_nmi_ipi(apic_id),
+            None => warn!("Watchdog: CPU {} is stuck, but has no APIC ID", id),
+        }
+    }
+    report_dump();
+}
+
+/// Logs the context recorded by `handle_nmi()`, if any, and frees the dump.
+fn report_dump() {
+    if !DUMP_READY.load(Ordering::Acquire) {
+        return;
+    }
+    let word = |i: usize| DUMP[i].load(Ordering::Relaxed);
+    let mut regs = GeneralRegisters::default();
+    let regs_words =
+        unsafe { core::slice::from_raw_parts_mut(&mut regs as *mut _ as *mut u64, REGS_WORDS) };
+    for (i, reg) in regs_words.iter_mut().enumerate() {
+        *reg = word(3 + i);
+    }
+    let stack_len = (word(2) as usize).min(STACK_DUMP_WORDS);
+    let stack_start = 3 + REGS_WORDS;
+    // Not on the heap, whose lock the stuck CPU may hold.
+    let mut stack = [0u64; STACK_DUMP_WORDS];
+    for (i, value) in stack[..stack_len].iter_mut().enumerate() {
+        *value = word(stack_start + i);
+    }
+    error!(
+        "Watchdog: CPU {} interrupted @ RIP {:#x}, RSP {:#x}",
+        DUMP_CPU.load(Ordering::Relaxed),
+        word(0),
+        word(1)
+    );
+    error!("{:#x?}", regs);
+    error!("Stack: {:#x?}", &stack[..stack_len]);
+    DUMP_READY.store(false, Ordering::Relaxed);
+    DUMP_CPU.store(NO_CPU, Ordering::Release);
+}
+
+/// Records the context of the current CPU if the NMI described by `frame`
+/// was sent by the watchdog, for `report_dump()`. Returns whether it was.
+pub(super) fn handle_nmi(frame: &TrapFrame) -> bool {
+    let cpu_data = PerCpu::current();
+    let id = cpu_data.id as usize;
+    let start = EXIT_START_CYCLE[id].load(Ordering::Acquire);
+    if start == 0 || !NMI_SENT[id].load(Ordering::Acquire) {
+        return false;
+    }
+    if DUMP_CPU
+        .compare_exchange(NO_CPU, id as u32, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        // Another dump is not reported yet.
+        return true;
+    }
+    let stack_end = cpu_data.stack_top().min(frame.rsp + STACK_DUMP_WORDS * 8);
+    let stack_len = stack_end.saturating_sub(frame.rsp) / 8;
+    let regs =
+        unsafe { core::slice::from_raw_parts(&frame.regs as *const _ as *const u64, REGS_WORDS) };
+    let stack = unsafe { core::slice::from_raw_parts(frame.rsp as *const u64, stack_len) };
+    let header = [frame.rip as u64, frame.rsp as u64, stack_len as u64];
+    for (slot, &value) in DUMP.iter().zip(header.iter().chain(regs).chain(stack)) {
+        slot.store(value, Ordering::Relaxed);
+    }
+    DUMP_READY.store(true, Ordering::Release);
+    true
+}
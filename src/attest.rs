@@ -0,0 +1,110 @@
+//! Measurement of the partitioning stack and attestation reports.
+//!
+//! The hypervisor measures its own code and read-only data, the system
+//! configuration, and the RTOS image when it is started. A report with all
+//! the measurements and a caller-provided nonce can be requested by the root
+//! cell, which may then have the report digest quoted by its TPM.
+
+use core::mem::size_of;
+
+use sha2::{Digest, Sha256};
+
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::addr::{phys_to_virt, GuestPhysAddr, PhysAddr};
+use crate::memory::MemFlags;
+
+pub const ATTEST_REPORT_VERSION: u32 = 2;
+
+pub type HashValue = [u8; 32];
+
+/// The attestation report written into the root cell memory.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct HvAttestReport {
+    pub version: u32,
+    /// Whether the RTOS has been measured.
+    pub rtos_measured: u32,
+    pub nonce: u64,
+    /// SHA-256 of the hypervisor code and read-only data.
+    pub hv_hash: HashValue,
+    /// Identifier of the hypervisor build, see `build_info`.
+    pub build_id: HashValue,
+    /// SHA-256 of the system configuration.
+    pub config_hash: HashValue,
+    /// SHA-256 of the RTOS image, all zero if not measured.
+    pub rtos_hash: HashValue,
+    /// SHA-256 of all fields above, to be used as TPM quote qualifying data.
+    pub digest: HashValue,
+}
+
+static RTOS_HASH: SpinLock<Option<HashValue>> = SpinLock::new(None);
+
+pub fn sha256(data: &[u8]) -> HashValue {
+    Sha256::digest(data).into()
+}
+
+fn hv_hash() -> HashValue {
+    let range = crate::consts::hv_text_rodata_range();
+    sha256(unsafe { core::slice::from_raw_parts(range.start as *const u8, range.len()) })
+}
+
+fn config_hash() -> HashValue {
+    let config = HvSystemConfig::get();
+    sha256(unsafe { core::slice::from_raw_parts(config as *const _ as *const u8, config.size()) })
+}
+
+/// Measures the RTOS image `[start, start + size)` before it is started.
+pub fn measure_rtos(start: PhysAddr, size: usize) {
+    let image = unsafe { core::slice::from_raw_parts(phys_to_virt(start) as *const u8, size) };
+    let hash = sha256(image);
+    info!("RTOS image measured: {:02x?}", hash);
+    *RTOS_HASH.lock() = Some(hash);
+}
+
+/// Returns the RTOS measurement taken when it was started.
+pub fn rtos_measurement() -> Option<HashValue> {
+    *RTOS_HASH.lock()
+}
+
+/// Clears the RTOS measurement when it is shut down.
+pub fn clear_rtos_measurement() {
+    RTOS_HASH.lock().take();
+}
+
+/// Generates an attestation report with `nonce` into the root cell memory at
+/// `report_gpaddr`.
+pub fn attest(report_gpaddr: GuestPhysAddr, nonce: u64) -> HvResult {
+    let vaddr = crate::cell::root_cell().guest_ram_to_hv(
+        report_gpaddr,
+        size_of::<HvAttestReport>(),
+        MemFlags::WRITE,
+    )?;
+    if vaddr % core::mem::align_of::<HvAttestReport>() != 0 {
+        return hv_result_err!(EINVAL, "Attestation report is not aligned");
+    }
+    let rtos_hash = *RTOS_HASH.lock();
+    let mut report = HvAttestReport {
+        version: ATTEST_REPORT_VERSION,
+        rtos_measured: rtos_hash.is_some() as u32,
+        nonce,
+        hv_hash: hv_hash(),
+        build_id: crate::build_info::BUILD_ID,
+        config_hash: config_hash(),
+        rtos_hash: rtos_hash.unwrap_or_default(),
+        digest: [0; 32],
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(report.version.to_le_bytes());
+    hasher.update(report.rtos_measured.to_le_bytes());
+    hasher.update(report.nonce.to_le_bytes());
+    hasher.update(report.hv_hash);
+    hasher.update(report.build_id);
+    hasher.update(report.config_hash);
+    hasher.update(report.rtos_hash);
+    report.digest = hasher.finalize().into();
+
+    unsafe { core::ptr::write(vaddr as *mut HvAttestReport, report) };
+    Ok(())
+}
@@ -0,0 +1,10 @@
+//! Build identification.
+//!
+//! The build behavior is only selected by Cargo features, never by
+//! environment variables, and `BUILD_ID` is a hash of the sources, the
+//! toolchain, the target and the enabled features, generated by `build.rs`.
+//! Two builds with the same identifier are expected to be bit-identical,
+//! which can be checked against the `hv_hash` of attestation reports. The
+//! identifier is also exported in the image header for the driver.
+
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
@@ -1,48 +1,443 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::RwLock;
+
 use crate::arch::NestedPageTable;
 use crate::config::{CellConfig, HvSystemConfig};
+use crate::cpuset::CpuSet;
 use crate::error::HvResult;
-use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
-use crate::memory::{MemFlags, MemoryRegion, MemorySet};
+use crate::hal::Vcpu;
+use crate::hypercall::limit::HypercallLimiter;
+use crate::lock::SpinLock;
+use crate::memory::addr::{
+    align_down, is_aligned, phys_to_virt, GuestPhysAddr, HostPhysAddr, HostVirtAddr,
+};
+use crate::memory::{
+    empty_page_paddr, GenericPageTable, MemFlags, MemoryRegion, MemorySet, PAGE_SIZE,
+};
+use crate::mirror::Mirrored;
+use crate::mmio::{MmioDevice, MmioRegistry};
+use crate::percpu::{CpuState, PerCpu};
+use crate::vector::VectorAllocator;
+
+/// Frames allocated by the hypervisor on behalf of a cell.
+#[derive(Debug)]
+pub struct CellMemUsage {
+    /// Frames of the nested page table.
+    pub page_table_frames: usize,
+    /// Frames of the hardware structures of the vCPUs.
+    pub vcpu_frames: usize,
+}
+
+/// Lifecycle state of a cell.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CellState {
+    /// Described by the system configuration, nothing set up yet.
+    Configured = 0,
+    /// Memory set up (root cell), or image measured (RTOS).
+    Loaded = 1,
+    /// CPUs running the cell.
+    Running = 2,
+    /// Setup or startup failed, the CPUs may be in any state.
+    Failed = 3,
+    /// CPUs stopped or given back to Linux.
+    ShutDown = 4,
+}
+
+impl CellState {
+    fn from_u32(value: u32) -> Self {
+        match value {
+            0 => Self::Configured,
+            1 => Self::Loaded,
+            2 => Self::Running,
+            3 => Self::Failed,
+            _ => Self::ShutDown,
+        }
+    }
+
+    /// Whether a cell in state `self` may go to state `to`. Failed and shut
+    /// down cells may be loaded again.
+    fn can_become(self, to: Self) -> bool {
+        use CellState::*;
+        matches!(
+            (self, to),
+            (Configured, Loaded | Failed | ShutDown)
+                | (Loaded, Running | Failed | ShutDown)
+                | (Running, Failed | ShutDown)
+                | (Failed, Loaded | ShutDown)
+                | (ShutDown, Loaded)
+        )
+    }
+}
+
+/// The state of a cell, only changed by valid transitions.
+#[derive(Debug)]
+pub struct CellLifecycle {
+    name: &'static str,
+    state: AtomicU32,
+}
+
+impl CellLifecycle {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: AtomicU32::new(CellState::Configured as u32),
+        }
+    }
+
+    pub fn get(&self) -> CellState {
+        CellState::from_u32(self.state.load(Ordering::Acquire))
+    }
+
+    /// Moves the cell to state `to`. Staying in the current state is allowed
+    /// and does nothing.
+    pub fn transition(&self, to: CellState) -> HvResult {
+        let mut from = self.get();
+        loop {
+            if from == to {
+                return Ok(());
+            }
+            if !from.can_become(to) {
+                return hv_result_err!(
+                    EINVAL,
+                    "{} cell cannot go from {:?} to {:?}",
+                    self.name,
+                    from,
+                    to
+                );
+            }
+            match self.state.compare_exchange(
+                from as u32,
+                to as u32,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(state) => from = CellState::from_u32(state),
+            }
+        }
+        info!("{} cell: {:?} -> {:?}", self.name, from, to);
+        Ok(())
+    }
+}
+
+/// The RTOS partition. It runs natively and has no `Cell`, but follows the
+/// same lifecycle: loaded and started by `RtStart`, shut down by `RtShutdown`.
+static RTOS_LIFECYCLE: CellLifecycle = CellLifecycle::new("RTOS");
+
+pub fn rtos_lifecycle() -> &'static CellLifecycle {
+    &RTOS_LIFECYCLE
+}
 
 #[derive(Debug)]
 pub struct Cell<'a> {
     /// Cell configuration.
     pub config: CellConfig<'a>,
+    /// Hardware IDs of the CPUs running the cell.
+    pub cpu_set: CpuSet,
     /// Guest physical memory set.
-    pub gpm: MemorySet<NestedPageTable>,
+    pub gpm: RwLock<MemorySet<NestedPageTable>>,
+    /// Emulated MMIO devices.
+    pub mmio: RwLock<MmioRegistry>,
+    /// Hypercall rate limit and anomaly counters.
+    pub hypercall_limiter: HypercallLimiter,
+    /// Loaded once the memory set is audited, running once all CPUs are
+    /// about to enter it, shut down by `HypervisorDisable`.
+    pub lifecycle: CellLifecycle,
+    /// Interrupt vectors of the cell allocated by the hypervisor.
+    pub vectors: VectorAllocator,
+    /// Parts of the regions mapping RTOS memory, unmapped while the RTOS is
+    /// started.
+    hidden_rtos_memory: SpinLock<Vec<MemoryRegion<GuestPhysAddr>>>,
 }
 
 impl Cell<'_> {
     fn new_root() -> HvResult<Self> {
         let sys_config = HvSystemConfig::get();
         let cell_config = sys_config.root_cell.config();
-        let hv_phys_start = sys_config.hypervisor_memory.phys_start as usize;
-        let hv_phys_size = sys_config.hypervisor_memory.size as usize;
-
-        let mut gpm = MemorySet::new();
-
-        // Map hypervisor memory to the empty page.
-        gpm.insert(MemoryRegion::new_with_empty_mapper(
-            hv_phys_start,
-            hv_phys_size,
-            MemFlags::READ | MemFlags::NO_HUGEPAGES,
-        ))?;
-        // Map all physical memory regions.
-        for region in cell_config.mem_regions() {
-            gpm.insert(MemoryRegion::new_with_offset_mapper(
-                region.virt_start as GuestPhysAddr,
-                region.phys_start as HostPhysAddr,
-                region.size as usize,
-                region.flags,
-            ))?;
-        }
+        let gpm = root_gpm(sys_config)?;
         trace!("Guest phyiscal memory set: {:#x?}", gpm);
 
         Ok(Self {
+            cpu_set: cell_config.cpu_set(),
             config: cell_config,
-            gpm,
+            gpm: RwLock::new(gpm),
+            mmio: RwLock::new(MmioRegistry::new()),
+            hypercall_limiter: HypercallLimiter::new(),
+            lifecycle: CellLifecycle::new("Root"),
+            vectors: VectorAllocator::new(sys_config.root_cell.config().vector_pool()),
+            hidden_rtos_memory: SpinLock::new(Vec::new()),
+        })
+    }
+
+    /// Checks that no hypervisor memory is reachable from the cell, except the
+    /// pages deliberately shared read-only: the header page, the empty page
+    /// and the stats window.
+    fn audit_hv_isolation(&self) -> HvResult {
+        let sys_config = HvSystemConfig::get();
+        let hv_start = sys_config.hypervisor_memory.phys_start as HostPhysAddr;
+        let hv_end = hv_start + sys_config.hypervisor_memory.size as usize;
+        let shared = hv_shared_ranges();
+
+        for region in self.gpm.read().regions() {
+            let range = region.phys_range();
+            if range.end <= hv_start || range.start >= hv_end {
+                continue;
+            }
+            let allowed = shared
+                .iter()
+                .any(|r| r.start <= range.start && range.end <= r.end);
+            if !allowed || region.flags.contains(MemFlags::WRITE) {
+                return hv_result_err!(
+                    EPERM,
+                    format!(
+                        "Hypervisor memory [{:#x}, {:#x}) is reachable from guest {:#x} ({:?})",
+                        range.start, range.end, region.start, region.flags
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the nested page table for pages reaching memory the cell must
+    /// not access: hypervisor memory not released to it, but the pages shared
+    /// read-only, and RTOS memory once the RTOS is started. Unlike
+    /// `audit_hv_isolation()`, it checks what the hardware translates, huge
+    /// pages included. Returns the number of such pages, each logged.
+    pub fn audit_isolation(&self) -> usize {
+        let sys_config = HvSystemConfig::get();
+        let hv_start = sys_config.hypervisor_memory.phys_start as HostPhysAddr;
+        let mut protected = [hv_start..crate::memory::managed_end(), 0..0];
+        if !matches!(
+            rtos_lifecycle().get(),
+            CellState::Configured | CellState::ShutDown
+        ) {
+            let rt_start = sys_config.rtos_memory.phys_start as HostPhysAddr;
+            protected[1] = rt_start..rt_start + sys_config.rtos_memory.size as usize;
+        }
+        let shared = hv_shared_ranges();
+
+        let mut violations = 0;
+        let gpm = self.gpm.read();
+        gpm.page_table()
+            .for_each_page(&mut |gpaddr, size, paddr, flags| {
+                let end = paddr + size as usize;
+                let reached = protected.iter().any(|r| paddr < r.end && r.start < end);
+                let allowed = !flags.contains(MemFlags::WRITE)
+                    && shared.iter().any(|r| r.start <= paddr && end <= r.end);
+                if reached && !allowed {
+                    warn!(
+                        "Guest page {:#x} ({:?}, {:?}) reaches protected memory {:#x}",
+                        gpaddr, size, flags, paddr
+                    );
+                    violations += 1;
+                }
+            });
+        violations
+    }
+
+    /// Unmaps the RTOS memory from the cell when the RTOS is started, so that
+    /// the measured image can no longer be modified, neither by the cell nor
+    /// through the hypervisor (see `guest_ram_to_hv()`). Regions and huge
+    /// pages covering it are split.
+    pub fn hide_rtos_memory(&self) -> HvResult {
+        let rtos_memory = &HvSystemConfig::get().rtos_memory;
+        let start = rtos_memory.phys_start as HostPhysAddr;
+        let range = start..start + rtos_memory.size as usize;
+        let mut hidden = self.hidden_rtos_memory.lock();
+        let mut gpm = self.gpm.write();
+        let parts = crate::mirror::update(Mirrored::NptRoot, || gpm.unmap_phys(range))?;
+        hidden.extend(parts);
+        Ok(())
+    }
+
+    /// Maps the RTOS memory hidden by `hide_rtos_memory()` again once the RTOS
+    /// is shut down, for the driver to load the next image.
+    pub fn restore_rtos_memory(&self) -> HvResult {
+        let mut hidden = self.hidden_rtos_memory.lock();
+        let mut gpm = self.gpm.write();
+        crate::mirror::update(Mirrored::NptRoot, || {
+            let mut tx = gpm.transaction();
+            while let Some(part) = hidden.pop() {
+                tx.insert(part)?;
+            }
+            tx.commit();
+            Ok(())
+        })
+    }
+
+    /// Returns the hypervisor virtual address of guest RAM `[gpaddr, gpaddr + size)`,
+    /// which must be mapped in the cell with at least `flags`, to contiguous
+    /// host memory directly accessible by the hypervisor (i.e. with the `DMA`
+    /// flag). Hidden and protected memory is refused as to the cell itself.
+    pub fn guest_ram_to_hv(
+        &self,
+        gpaddr: GuestPhysAddr,
+        size: usize,
+        flags: MemFlags,
+    ) -> HvResult<HostVirtAddr> {
+        let gpaddr_end = match gpaddr.checked_add(size) {
+            Some(end) => end,
+            None => return hv_result_err!(EFAULT, "Guest RAM range overflowed"),
+        };
+        match check_mapped_in(&self.gpm.read(), gpaddr, size, flags | MemFlags::DMA)? {
+            Some(hpaddr) => Ok(phys_to_virt(hpaddr)),
+            None => hv_result_err!(
+                EFAULT,
+                "Guest RAM [{:#x}, {:#x}) is not accessible",
+                gpaddr,
+                gpaddr_end
+            ),
+        }
+    }
+
+    /// Emulates `device` on guest physical `[start, start + size)`, which must
+    /// not be mapped in the guest physical memory set.
+    pub fn register_mmio_device(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        device: Box<dyn MmioDevice>,
+    ) -> HvResult {
+        let gpm = self.gpm.read();
+        let mapped = (align_down(start)..start + size)
+            .step_by(PAGE_SIZE)
+            .any(|gpaddr| gpm.find_region(gpaddr).is_some());
+        if mapped {
+            return hv_result_err!(EINVAL, "MMIO device range is mapped");
+        }
+        self.mmio.write().register(start, size, device)
+    }
+
+    /// Returns the frames used for this cell. All CPUs with the hypervisor
+    /// enabled run the root cell.
+    pub fn mem_usage(&self) -> CellMemUsage {
+        let vcpu_frames = (0..PerCpu::entered_cpus())
+            .map(|cpu_id| unsafe { PerCpu::from_id(cpu_id) })
+            .filter(|cpu_data| cpu_data.state == CpuState::HvEnabled)
+            .map(|cpu_data| cpu_data.vcpu.num_frames())
+            .sum();
+        CellMemUsage {
+            page_table_frames: self.gpm.read().page_table().table_frames(),
+            vcpu_frames,
+        }
+    }
+
+    /// Maps hypervisor memory `[paddr, paddr + size)`, released by the frame
+    /// allocator, into the cell instead of the empty page. The range must be
+    /// at the end of the hidden part of hypervisor memory.
+    pub fn map_released_hv_memory(&self, paddr: HostPhysAddr, size: usize) -> HvResult {
+        let mut gpm = self.gpm.write();
+        crate::mirror::update(Mirrored::NptRoot, || {
+            let mut tx = gpm.transaction();
+            let hidden = match tx.find_region(paddr) {
+                Some(region) if region.start + region.size == paddr + size => region.clone(),
+                _ => return hv_result_err!(EINVAL, "Range is not at the end of hypervisor memory"),
+            };
+            tx.delete(hidden.start)?;
+            tx.insert(MemoryRegion::new_with_empty_mapper(
+                hidden.start,
+                paddr - hidden.start,
+                hidden.flags,
+            ))?;
+            tx.insert(MemoryRegion::new_with_offset_mapper(
+                paddr,
+                paddr,
+                size,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+            ))?;
+            tx.commit();
+            Ok(())
         })
     }
+
+    /// Removes `WRITE` and/or `EXECUTE` permissions of guest RAM
+    /// `[gpaddr, gpaddr + size)`, a non-empty page aligned range. Permissions
+    /// can never be restored.
+    pub fn protect_range(&self, gpaddr: GuestPhysAddr, size: usize, remove: MemFlags) -> HvResult {
+        if !(MemFlags::WRITE | MemFlags::EXECUTE).contains(remove) {
+            return hv_result_err!(EINVAL);
+        }
+        if size == 0 || !is_aligned(gpaddr) || !is_aligned(size) {
+            return hv_result_err!(EINVAL, "Range is empty or not page aligned");
+        }
+        let end = gpaddr
+            .checked_add(size)
+            .ok_or_else(|| hv_err!(EINVAL, "Range overflowed"))?;
+        let in_ram = self.config.mem_regions().iter().any(|region| {
+            let start = region.virt_start as GuestPhysAddr;
+            let region_end = start + region.size as usize;
+            !region.flags.contains(MemFlags::IO) && start <= gpaddr && end <= region_end
+        });
+        if !in_ram {
+            return hv_result_err!(EFAULT, "Range is not in guest RAM");
+        }
+
+        let mut gpm = self.gpm.write();
+        let flags = match gpm.find_region(gpaddr) {
+            Some(region) => region.flags - remove,
+            None => return hv_result_err!(EFAULT),
+        };
+        crate::mirror::update(Mirrored::NptRoot, || gpm.protect(gpaddr, size, flags))?;
+        drop(gpm);
+        // The other CPUs could still write through their cached translations.
+        crate::arch::flush_nested_tlbs()?;
+        info!(
+            "Guest RAM [{:#x}, {:#x}) protected: {:?}",
+            gpaddr, end, flags
+        );
+        Ok(())
+    }
+
+    /// Checks that all of `[gpaddr, gpaddr + size)` is mapped with at least
+    /// `flags`, which excludes hidden hypervisor memory and protected ranges.
+    pub fn check_mapped(&self, gpaddr: GuestPhysAddr, size: usize, flags: MemFlags) -> HvResult {
+        check_mapped_in(&self.gpm.read(), gpaddr, size, flags).map(|_| ())
+    }
+}
+
+/// Checks that all of `[gpaddr, gpaddr + size)` is mapped in `gpm` with at
+/// least `flags`. Returns the host physical address of `gpaddr` if the range
+/// maps contiguous host memory, not the empty page.
+fn check_mapped_in<PT>(
+    gpm: &MemorySet<PT>,
+    gpaddr: GuestPhysAddr,
+    size: usize,
+    flags: MemFlags,
+) -> HvResult<Option<HostPhysAddr>>
+where
+    PT: GenericPageTable<VA = GuestPhysAddr>,
+{
+    let end = gpaddr
+        .checked_add(size)
+        .ok_or_else(|| hv_err!(EFAULT, "Guest memory range overflowed"))?;
+    let mut hpaddr = gpm.find_region(gpaddr).and_then(|r| r.translate(gpaddr));
+    let mut addr = gpaddr;
+    while addr < end {
+        match gpm.find_region(addr) {
+            Some(region) if region.flags.contains(flags) => {
+                if region.translate(addr) != hpaddr.map(|paddr| paddr + (addr - gpaddr)) {
+                    hpaddr = None;
+                }
+                addr = region.start + region.size;
+            }
+            Some(region) => {
+                return hv_result_err!(
+                    EPERM,
+                    "Guest memory at {:#x} is mapped {:?}",
+                    addr,
+                    region.flags
+                )
+            }
+            None => return hv_result_err!(EFAULT, "No guest memory at {:#x}", addr),
+        }
+    }
+    Ok(hpaddr)
 }
 
 static ROOT_CELL: spin::Once<Cell> = spin::Once::new();
@@ -51,13 +446,338 @@ pub fn root_cell<'a>() -> &'a Cell<'a> {
     ROOT_CELL.get().expect("Uninitialized root cell!")
 }
 
+/// Returns the root cell if initialized, for the failure paths.
+pub fn try_root_cell<'a>() -> Option<&'a Cell<'a>> {
+    ROOT_CELL.get()
+}
+
+/// Builds the guest physical memory set of the root cell described by
+/// `sys_config`.
+fn root_gpm<PT>(sys_config: &HvSystemConfig) -> HvResult<MemorySet<PT>>
+where
+    PT: GenericPageTable<VA = GuestPhysAddr>,
+{
+    let cell_config = sys_config.root_cell.config();
+    let hv_phys_start = sys_config.hypervisor_memory.phys_start as usize;
+    let hv_phys_size = sys_config.hypervisor_memory.size as usize;
+
+    let mut gpm = MemorySet::new();
+
+    // Map all physical memory regions.
+    for region in cell_config.mem_regions() {
+        gpm.insert(MemoryRegion::new_with_offset_mapper(
+            region.virt_start as GuestPhysAddr,
+            region.phys_start as HostPhysAddr,
+            region.size as usize,
+            region.flags,
+        ))?;
+    }
+    // A region may cover hypervisor memory too (e.g. all RAM, mapped with
+    // huge pages): unmap it, splitting the region and its pages around.
+    let hv_range = hv_phys_start..hv_phys_start + hv_phys_size;
+    for part in gpm.unmap_phys(hv_range)? {
+        warn!(
+            "Hypervisor memory unmapped from root cell region [{:#x}, {:#x})",
+            part.start,
+            part.start + part.size
+        );
+    }
+    // Keep the header page readable for the driver to get the handshake
+    // results, and map the rest of hypervisor memory to the empty page.
+    gpm.insert(MemoryRegion::new_with_offset_mapper(
+        hv_phys_start,
+        hv_phys_start,
+        PAGE_SIZE,
+        MemFlags::READ | MemFlags::NO_HUGEPAGES,
+    ))?;
+    gpm.insert(MemoryRegion::new_with_empty_mapper(
+        hv_phys_start + PAGE_SIZE,
+        hv_phys_size - PAGE_SIZE,
+        MemFlags::READ | MemFlags::NO_HUGEPAGES,
+    ))?;
+    // Map the stats window read-only.
+    if let Some((paddr, size)) = crate::stats_window::window_region() {
+        gpm.insert(MemoryRegion::new_with_offset_mapper(
+            sys_config.stats_window_gpa as GuestPhysAddr,
+            paddr,
+            size,
+            MemFlags::READ,
+        ))?;
+    }
+    Ok(gpm)
+}
+
+/// Physical memory of the hypervisor the cell may read: the header page, the
+/// empty page and the stats window.
+fn hv_shared_ranges() -> [Range<HostPhysAddr>; 3] {
+    let hv_start = HvSystemConfig::get().hypervisor_memory.phys_start as HostPhysAddr;
+    let empty_page = empty_page_paddr();
+    let mut shared = [
+        hv_start..hv_start + PAGE_SIZE,
+        empty_page..empty_page + PAGE_SIZE,
+        0..0,
+    ];
+    if let Some((paddr, size)) = crate::stats_window::window_region() {
+        shared[2] = paddr..paddr + size;
+    }
+    shared
+}
+
 pub fn init() -> HvResult {
     crate::arch::vmm::check_hypervisor_feature()?;
 
     let root_cell = Cell::new_root()?;
+    root_cell.audit_hv_isolation()?;
+    if root_cell.audit_isolation() != 0 {
+        return hv_result_err!(EPERM, "Hypervisor memory is reachable from the root cell");
+    }
+    root_cell.lifecycle.transition(CellState::Loaded)?;
     info!("Root cell init end.");
     debug!("{:#x?}", root_cell);
 
     ROOT_CELL.call_once(|| root_cell);
     Ok(())
 }
+
+/// Replays the construction of the root cell EPT from configurations, and
+/// compares the resulting mappings with golden ones.
+#[cfg(all(test, feature = "intel"))]
+mod tests {
+    use rvm_config::{CellBuilder, MemFlags as ConfigFlags, SystemConfigBuilder};
+
+    use super::*;
+    use crate::arch::vmm::NestedPTE;
+    use crate::error::HvErrorNum;
+    use crate::memory::{Level4PageTable, PagingInstr, PhysAddr};
+
+    /// Host address of the pages mapped to the empty page.
+    const EMPTY: usize = usize::MAX;
+
+    /// Paging instructions of the host tests: nothing to activate or flush.
+    struct HostInstr;
+
+    impl PagingInstr for HostInstr {
+        unsafe fn activate(_root_paddr: PhysAddr) {}
+        fn flush(_vaddr: Option<usize>) {}
+    }
+
+    type ReplayPageTable = Level4PageTable<GuestPhysAddr, NestedPTE, HostInstr>;
+
+    /// Pages of the same size and flags, mapping contiguous guest and host
+    /// memory.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Mapping {
+        gpa: usize,
+        size: usize,
+        hpa: usize,
+        page_size: usize,
+        flags: MemFlags,
+    }
+
+    fn mapping(gpa: usize, size: usize, hpa: usize, page_size: usize, flags: u64) -> Mapping {
+        Mapping {
+            gpa,
+            size,
+            hpa,
+            page_size,
+            flags: MemFlags::from_bits_truncate(flags),
+        }
+    }
+
+    /// Builds the nested page table of the root cell from a configuration
+    /// blob, as when enabling the hypervisor, and returns its mappings.
+    fn replay(blob: &[u8]) -> Vec<Mapping> {
+        crate::memory::init_test_frame_allocator();
+        let sys_config = unsafe { &*(blob.as_ptr() as *const HvSystemConfig) };
+        let gpm = root_gpm::<ReplayPageTable>(sys_config).unwrap();
+        let empty_page = empty_page_paddr();
+        let mut mappings: Vec<Mapping> = Vec::new();
+        gpm.page_table()
+            .for_each_page(&mut |gpa, page_size, hpa, flags| {
+                let size = page_size as usize;
+                let hpa = if hpa == empty_page { EMPTY } else { hpa };
+                if let Some(last) = mappings.last_mut() {
+                    let contiguous = if hpa == EMPTY {
+                        last.hpa == EMPTY
+                    } else {
+                        last.hpa != EMPTY && last.hpa + last.size == hpa
+                    };
+                    if contiguous
+                        && last.gpa + last.size == gpa
+                        && last.page_size == size
+                        && last.flags == flags
+                    {
+                        last.size += size;
+                        return;
+                    }
+                }
+                mappings.push(Mapping {
+                    gpa,
+                    size,
+                    hpa,
+                    page_size: size,
+                    flags,
+                });
+            });
+        mappings
+    }
+
+    #[test]
+    fn test_replay_root_cell() {
+        let ram = ConfigFlags::READ | ConfigFlags::WRITE | ConfigFlags::EXECUTE | ConfigFlags::DMA;
+        let cell = CellBuilder::new("root")
+            .cpus(&[0, 1])
+            .mem_region(0, 0, 0x8000_0000, ram)
+            // All high RAM, including the hypervisor and the RTOS memory.
+            .mem_region(0x1_0000_0000, 0x1_0000_0000, 0x8000_0000, ram)
+            // Guest addresses 1G aligned, host addresses only 1M aligned.
+            .mem_region(
+                0x2_0010_0000,
+                0x8000_0000,
+                0x40_0000,
+                ConfigFlags::READ | ConfigFlags::WRITE,
+            )
+            // One 2M page, then 4K pages.
+            .mem_region(0x2_1000_0000, 0x9000_0000, 0x30_1000, ConfigFlags::READ)
+            .mem_region(
+                0xfed0_0000,
+                0xfed0_0000,
+                0x1000,
+                ConfigFlags::READ | ConfigFlags::WRITE | ConfigFlags::IO,
+            );
+        let blob = SystemConfigBuilder::new(0x1_0000_0000, 0x400_0000, 0x1_0400_0000, 0x400_0000)
+            .rtos_cpus(&[2, 3])
+            .root_cell(cell)
+            .build()
+            .unwrap();
+
+        const R: u64 = MemFlags::READ.bits();
+        const RW: u64 = R | MemFlags::WRITE.bits();
+        const RWX: u64 = RW | MemFlags::EXECUTE.bits();
+        const IO: u64 = MemFlags::IO.bits();
+        let golden = [
+            mapping(0, 0x8000_0000, 0, 0x4000_0000, RWX),
+            mapping(0x8000_0000, 0x40_0000, 0x2_0010_0000, 0x1000, RW),
+            mapping(0x9000_0000, 0x20_0000, 0x2_1000_0000, 0x20_0000, R),
+            mapping(0x9020_0000, 0x10_1000, 0x2_1020_0000, 0x1000, R),
+            mapping(0xfed0_0000, 0x1000, 0xfed0_0000, 0x1000, RW | IO),
+            // The header page, then the rest of the hypervisor memory.
+            mapping(0x1_0000_0000, 0x1000, 0x1_0000_0000, 0x1000, R),
+            mapping(0x1_0000_1000, 0x3ff_f000, EMPTY, 0x1000, R),
+            // The rest of the 1G page split around the hypervisor memory.
+            mapping(0x1_0400_0000, 0x3c00_0000, 0x1_0400_0000, 0x20_0000, RWX),
+            mapping(0x1_4000_0000, 0x4000_0000, 0x1_4000_0000, 0x4000_0000, RWX),
+        ];
+        assert_eq!(replay(&blob), golden);
+    }
+
+    /// The root cell memory set of all high RAM, hypervisor and RTOS memory
+    /// included, and of RAM at guest 2G mapped with an offset.
+    fn access_gpm() -> MemorySet<ReplayPageTable> {
+        let ram = ConfigFlags::READ | ConfigFlags::WRITE | ConfigFlags::DMA;
+        let cell = CellBuilder::new("root")
+            .cpus(&[0, 1])
+            .mem_region(0x1_0000_0000, 0x1_0000_0000, 0x8000_0000, ram)
+            .mem_region(0x2_0010_0000, 0x8000_0000, 0x40_0000, ram);
+        let blob = SystemConfigBuilder::new(0x1_0000_0000, 0x400_0000, 0x1_0400_0000, 0x400_0000)
+            .rtos_cpus(&[2, 3])
+            .root_cell(cell)
+            .build()
+            .unwrap();
+        crate::memory::init_test_frame_allocator();
+        let sys_config = unsafe { &*(blob.as_ptr() as *const HvSystemConfig) };
+        root_gpm(sys_config).unwrap()
+    }
+
+    #[test]
+    fn test_guest_ram_access() {
+        let mut gpm = access_gpm();
+        let read = MemFlags::READ | MemFlags::DMA;
+        let write = MemFlags::WRITE | MemFlags::DMA;
+        // Translated with the offset of the region.
+        assert_eq!(
+            check_mapped_in(&gpm, 0x8000_1000, 0x10, read).unwrap(),
+            Some(0x2_0010_1000)
+        );
+        // Hypervisor memory, the header page included, is not accessible.
+        assert!(check_mapped_in(&gpm, 0x1_0000_0000, 0x10, read).is_err());
+        assert!(check_mapped_in(&gpm, 0x1_0000_2000, 0x10, read).is_err());
+        assert!(check_mapped_in(&gpm, 0x1_03ff_f000, 0x2000, read).is_err());
+        // Read-only once protected, and still contiguous across the split.
+        gpm.protect(0x8000_2000, 0x1000, MemFlags::READ | MemFlags::DMA)
+            .unwrap();
+        assert!(check_mapped_in(&gpm, 0x8000_2000, 0x10, write).is_err());
+        assert_eq!(
+            check_mapped_in(&gpm, 0x8000_1ff8, 0x10, read).unwrap(),
+            Some(0x2_0010_1ff8)
+        );
+    }
+
+    #[test]
+    fn test_string_io_buffer() {
+        let mut gpm = access_gpm();
+        // INS writes its buffer, OUTS reads it.
+        let (ins, outs) = (
+            MemFlags::WRITE | MemFlags::DMA,
+            MemFlags::READ | MemFlags::DMA,
+        );
+        gpm.protect(0x8000_3000, 0x1000, MemFlags::READ | MemFlags::DMA)
+            .unwrap();
+        assert!(check_mapped_in(&gpm, 0x8000_3000, 1, ins).is_err());
+        assert!(check_mapped_in(&gpm, 0x8000_3000, 1, outs).is_ok());
+        assert!(check_mapped_in(&gpm, 0x8000_4000, 4, ins).is_ok());
+        // Unmapped, or mapped to the empty page.
+        assert!(check_mapped_in(&gpm, 0x7000_0000, 1, outs).is_err());
+        assert!(check_mapped_in(&gpm, 0x1_0000_1000, 1, outs).is_err());
+        assert!(check_mapped_in(&gpm, 0x1_0000_1000, 1, ins).is_err());
+    }
+
+    #[test]
+    fn test_hidden_memory() {
+        let mut gpm = access_gpm();
+        let rw = MemFlags::READ | MemFlags::WRITE | MemFlags::DMA;
+        let rtos = 0x1_0400_0000..0x1_0800_0000;
+        assert_eq!(
+            check_mapped_in(&gpm, 0x1_0400_0000, PAGE_SIZE, rw).unwrap(),
+            Some(0x1_0400_0000)
+        );
+        // Hidden as by `hide_rtos_memory()`.
+        let parts = gpm.unmap_phys(rtos).unwrap();
+        for addr in [0x1_0400_0000, 0x1_0600_0000, 0x1_07ff_f000] {
+            assert!(check_mapped_in(&gpm, addr, PAGE_SIZE, rw).is_err());
+            assert!(check_mapped_in(&gpm, addr, 1, MemFlags::READ).is_err());
+        }
+        // Ranges overlapping the hidden memory are refused too.
+        assert!(check_mapped_in(&gpm, 0x1_03ff_f000, 0x2000, MemFlags::READ).is_err());
+        assert!(check_mapped_in(&gpm, 0x1_07ff_f000, 0x2000, rw).is_err());
+        assert!(check_mapped_in(&gpm, 0x1_0800_0000, PAGE_SIZE, rw).is_ok());
+        // And accessible again once restored.
+        for part in parts {
+            gpm.insert(part).unwrap();
+        }
+        assert!(check_mapped_in(&gpm, 0x1_0600_0000, PAGE_SIZE, rw).is_ok());
+    }
+
+    #[test]
+    fn test_check_mapped_overflow() {
+        let gpm = access_gpm();
+        let err = check_mapped_in(&gpm, 0x8000_1000, usize::MAX - 0xfff, MemFlags::READ);
+        assert!(matches!(err, Err(e) if e.num() == HvErrorNum::EFAULT));
+    }
+
+    #[test]
+    fn test_protect_overflow() {
+        let mut gpm = access_gpm();
+        let flags = MemFlags::READ | MemFlags::DMA;
+        for size in [usize::MAX - 0xfff, usize::MAX, 0, 0x800] {
+            assert!(gpm.protect(0x8000_1000, size, flags).is_err());
+        }
+        assert!(gpm.protect(0x8000_1800, 0x1000, flags).is_err());
+        // The region map is left intact.
+        assert_eq!(
+            check_mapped_in(&gpm, 0x8000_0000, 0x40_0000, flags | MemFlags::WRITE).unwrap(),
+            Some(0x2_0010_0000)
+        );
+        assert!(gpm.protect(0x8000_1000, 0x1000, flags).is_ok());
+    }
+}
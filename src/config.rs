@@ -1,50 +1,16 @@
 use core::fmt::{Debug, Formatter, Result};
 use core::{mem::size_of, slice};
 
+use crate::consts::MAX_CELLS;
+use crate::cpuset::CpuSet;
 use crate::error::HvResult;
-use crate::memory::MemFlags;
+use crate::memory::{MemFlags, PAGE_SIZE};
+use crate::mirror::Mirrored;
 
-const CONFIG_SIGNATURE: [u8; 6] = *b"RVMSYS";
-const CONFIG_REVISION: u16 = 13;
+#[path = "../crates/rvm-config/src/layout.rs"]
+mod layout;
 
-const HV_CELL_NAME_MAXLEN: usize = 31;
-
-/// The jailhouse cell configuration.
-///
-/// @note Keep Config._HEADER_FORMAT in jailhouse-cell-linux in sync with this
-/// structure.
-#[derive(Debug)]
-#[repr(C, packed)]
-pub struct HvCellDesc {
-    signature: [u8; 6],
-    revision: u16,
-    name: [u8; HV_CELL_NAME_MAXLEN + 1],
-    id: u32, // set by the driver
-    num_memory_regions: u32,
-}
-
-#[derive(Debug)]
-#[repr(C, packed)]
-pub struct HvMemoryRegion {
-    pub phys_start: u64,
-    pub virt_start: u64,
-    pub size: u64,
-    pub flags: MemFlags,
-}
-
-/// General descriptor of the system.
-#[derive(Debug)]
-#[repr(C, packed)]
-pub struct HvSystemConfig {
-    pub signature: [u8; 6],
-    pub revision: u16,
-    /// RVM location in memory
-    pub hypervisor_memory: HvMemoryRegion,
-    /// RTOS location in memory
-    pub rtos_memory: HvMemoryRegion,
-    pub root_cell: HvCellDesc,
-    // CellConfigLayout placed here.
-}
+pub use layout::*;
 
 /// A dummy layout with all variant-size fields empty.
 #[derive(Debug)]
@@ -67,6 +33,68 @@ impl HvCellDesc {
     }
 }
 
+impl HvConsole {
+    pub fn console_type(&self) -> ConsoleType {
+        ConsoleType::try_from(self.console_type).unwrap_or(ConsoleType::LegacyUart)
+    }
+
+    /// Divisor latch value of the 16550 UART for the baud rate, 0 if out of
+    /// range.
+    pub fn uart_divisor(&self) -> u16 {
+        let baud_rate = match self.baud_rate {
+            0 => DEFAULT_UART_BAUD_RATE,
+            rate => rate,
+        };
+        let clock_hz = match self.clock_hz {
+            0 => DEFAULT_UART_CLOCK_HZ,
+            hz => hz,
+        };
+        u16::try_from(clock_hz / 16 / baud_rate).unwrap_or(0)
+    }
+
+    /// Line control register value of the 16550 UART: word length, stop bits
+    /// and parity.
+    pub fn uart_line_control(&self) -> u8 {
+        let word_length = match self.data_bits {
+            bits @ 5..=8 => bits - 5,
+            _ => 3,
+        };
+        let stop_bits = (self.stop_bits == 2) as u8;
+        let parity = match ConsoleParity::try_from(self.parity) {
+            Ok(ConsoleParity::Odd) => 0b001,
+            Ok(ConsoleParity::Even) => 0b011,
+            _ => 0,
+        };
+        word_length | stop_bits << 2 | parity << 3
+    }
+
+    fn check(&self) -> HvResult {
+        match ConsoleType::try_from(self.console_type) {
+            Ok(ConsoleType::MmioUart) if self.address == 0 => {
+                return hv_result_err!(EINVAL, "MMIO UART console without address!");
+            }
+            Ok(_) => {}
+            Err(_) => return hv_result_err!(EINVAL, "Invalid console type!"),
+        }
+        let (baud_rate, clock_hz) = (self.baud_rate, self.clock_hz);
+        if self.uart_divisor() == 0 {
+            return hv_result_err!(
+                EINVAL,
+                "Console baud rate {} not reachable with a {} Hz UART clock!",
+                baud_rate,
+                clock_hz
+            );
+        }
+        if ConsoleParity::try_from(self.parity).is_err()
+            || !matches!(self.data_bits, 0 | 5..=8)
+            || self.stop_bits > 2
+        {
+            return hv_result_err!(EINVAL, "Invalid console line parameters!");
+        }
+        Ok(())
+    }
+}
+
 impl HvSystemConfig {
     pub fn get<'a>() -> &'a Self {
         unsafe { &*crate::consts::hv_config_ptr() }
@@ -76,6 +104,76 @@ impl HvSystemConfig {
         size_of::<Self>() + self.root_cell.config_size()
     }
 
+    pub fn intercept_profile(&self) -> InterceptProfile {
+        InterceptProfile::try_from(self.intercept_profile).unwrap_or(InterceptProfile::Default)
+    }
+
+    pub fn lockdown(&self) -> bool {
+        let flags = self.flags;
+        flags.contains(HvSystemFlags::LOCKDOWN)
+    }
+
+    pub fn user_hypercalls(&self) -> bool {
+        let flags = self.flags;
+        flags.contains(HvSystemFlags::USER_HYPERCALLS)
+    }
+
+    pub fn strict_smt(&self) -> bool {
+        let flags = self.flags;
+        flags.contains(HvSystemFlags::STRICT_SMT)
+    }
+
+    pub fn irq_remapping(&self) -> bool {
+        let flags = self.flags;
+        flags.contains(HvSystemFlags::IRQ_REMAPPING)
+    }
+
+    pub fn exit_policies(&self) -> &[HvExitPolicy] {
+        let num = (self.num_exit_policies as usize).min(MAX_EXIT_POLICIES);
+        &self.exit_policies[..num]
+    }
+
+    pub fn rtos_pci_devices(&self) -> &[HvPciDevice] {
+        let num = (self.num_rtos_pci_devices as usize).min(MAX_RTOS_PCI_DEVICES);
+        &self.rtos_pci_devices[..num]
+    }
+
+    /// Checks that the I/O regions of the root cell (e.g. large device BARs)
+    /// do not collide with RAM, the hypervisor or the RTOS memory.
+    fn check_io_regions(&self) -> HvResult {
+        let overlapped = |a: &HvMemoryRegion, b: &HvMemoryRegion| {
+            let (a_start, b_start) = (a.virt_start, b.virt_start);
+            let (a_end, b_end) = (a_start + a.size, b_start + b.size);
+            a_start < b_end && b_start < a_end
+        };
+        let regions = self.root_cell.config().mem_regions();
+        for io in regions.iter().filter(|r| r.flags.contains(MemFlags::IO)) {
+            let collided = regions
+                .iter()
+                .filter(|r| !r.flags.contains(MemFlags::IO))
+                .chain([&self.hypervisor_memory, &self.rtos_memory])
+                .any(|r| overlapped(io, r));
+            if collided {
+                let (start, size) = (io.virt_start, io.size);
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "I/O region [{:#x}, {:#x}) collides with RAM!",
+                        start,
+                        start + size
+                    )
+                );
+            }
+        }
+        if regions
+            .iter()
+            .any(|r| r.flags.contains(MemFlags::WRITE_COMBINE) && !r.flags.contains(MemFlags::IO))
+        {
+            return hv_result_err!(EINVAL, "Write-combining region is not I/O!");
+        }
+        Ok(())
+    }
+
     pub fn check(&self) -> HvResult {
         if self.signature != CONFIG_SIGNATURE {
             return hv_result_err!(EINVAL, "HvSystemConfig signature not matched!");
@@ -83,10 +181,135 @@ impl HvSystemConfig {
         if self.revision != CONFIG_REVISION {
             return hv_result_err!(EINVAL, "HvSystemConfig revision not matched!");
         }
+        if InterceptProfile::try_from(self.intercept_profile).is_err() {
+            return hv_result_err!(EINVAL, "Invalid intercept profile!");
+        }
+        self.check_io_regions()?;
+        // The last page of the RTOS memory holds its boot information.
+        let rtos_size = self.rtos_memory.size;
+        if rtos_size <= PAGE_SIZE as u64 {
+            return hv_result_err!(
+                EINVAL,
+                "RTOS memory of {:#x} bytes has no room for the boot information page!",
+                rtos_size
+            );
+        }
+        crate::arch::vmm::check_phys_addr_bits(self)?;
+        if self.num_rtos_pci_devices as usize > MAX_RTOS_PCI_DEVICES {
+            return hv_result_err!(EINVAL, "Too many RTOS PCI devices!");
+        }
+        let (rate, burst) = (self.hypercall_limit.rate, self.hypercall_limit.burst);
+        if rate != 0 && burst == 0 {
+            return hv_result_err!(EINVAL, "Hypercall limit without burst!");
+        }
+        if self.log_level > MAX_LOG_LEVEL {
+            return hv_result_err!(EINVAL, "Invalid log level!");
+        }
+        if self.num_exit_policies as usize > MAX_EXIT_POLICIES {
+            return hv_result_err!(EINVAL, "Too many exit policies!");
+        }
+        if self
+            .exit_policies()
+            .iter()
+            .any(|entry| ExitPolicy::try_from(entry.policy).is_err())
+        {
+            return hv_result_err!(EINVAL, "Invalid exit policy!");
+        }
+        self.console.check()?;
+        let cpuid_policy = self.root_cell.config().cpuid_policy();
+        if cpuid_policy.contains(CpuidPolicyFlags::HIDE_LEAVES)
+            && !cpuid_policy.contains(CpuidPolicyFlags::HIDE_PRESENT_BIT)
+        {
+            return hv_result_err!(
+                EINVAL,
+                "Hypervisor CPUID leaves hidden without the present bit!"
+            );
+        }
+        let (start, size) = self.root_cell.config().vector_pool();
+        if size != 0
+            && (start < 32 || size > MAX_VECTOR_POOL_SIZE || start.checked_add(size - 1).is_none())
+        {
+            return hv_result_err!(EINVAL, "Invalid vector pool!");
+        }
+        let (root_cpus, rtos_cpus) = (self.root_cell.config().cpu_set(), self.rtos_cpus);
+        if root_cpus.is_empty() {
+            return hv_result_err!(EINVAL, "No CPU assigned to the root cell!");
+        }
+        if root_cpus.overlaps(&rtos_cpus) {
+            return hv_result_err!(EINVAL, "Root cell and RTOS CPUs overlapped!");
+        }
+        let num_cells = 1 + !rtos_cpus.is_empty() as usize;
+        if num_cells > MAX_CELLS {
+            return hv_result_err!(
+                EINVAL,
+                "{} cells configured, the maximum is {}",
+                num_cells,
+                MAX_CELLS
+            );
+        }
+        let header = crate::header::HvHeader::get();
+        if root_cpus.count() != header.vm_cpus()
+            || root_cpus.count() + rtos_cpus.count() > header.max_cpus
+        {
+            return hv_result_err!(EINVAL, "CPU sets not matched with the hypervisor header!");
+        }
         Ok(())
     }
 }
 
+impl HvSystemConfig {
+    /// Applies the log level, the watchdog timeout and the exit policies.
+    pub fn apply_runtime_params(&self) {
+        crate::logging::set_level(self.log_level);
+        crate::arch::set_watchdog_timeout(self.watchdog_timeout_ms);
+        crate::exit_policy::load(self);
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.size()) }
+    }
+
+    /// Copies the reloadable parameters of `other`.
+    fn copy_reloadable(&mut self, other: &Self) {
+        let limit = &other.hypercall_limit;
+        self.hypercall_limit = HvHypercallLimit {
+            rate: limit.rate,
+            burst: limit.burst,
+        };
+        self.log_level = other.log_level;
+        self.watchdog_timeout_ms = other.watchdog_timeout_ms;
+        self.num_exit_policies = other.num_exit_policies;
+        self.exit_policies = other.exit_policies;
+    }
+}
+
+/// Applies the reloadable parameters (hypercall limit, log level, watchdog
+/// timeout, exit policies) of `blob`, a configuration whose other fields must
+/// be the same as the current one.
+pub fn reload(blob: &[u8]) -> HvResult {
+    let current = HvSystemConfig::get();
+    if blob.len() != current.size() {
+        return hv_result_err!(EINVAL, "Configuration size changed");
+    }
+    // Compare all but the reloadable fields and the cell ID set by the driver.
+    let mut structural = blob.to_vec();
+    let new = unsafe { &mut *(structural.as_mut_ptr() as *mut HvSystemConfig) };
+    new.copy_reloadable(current);
+    new.root_cell.id = current.root_cell.id;
+    if structural != current.as_bytes() {
+        return hv_result_err!(EINVAL, "Structural configuration changed");
+    }
+    let new = unsafe { &*(blob.as_ptr() as *const HvSystemConfig) };
+    new.check()?;
+
+    let config = unsafe { &mut *(crate::consts::hv_config_ptr() as *mut HvSystemConfig) };
+    crate::mirror::update(Mirrored::Config, || config.copy_reloadable(new));
+    config.apply_runtime_params();
+    crate::cell::root_cell().hypercall_limiter.reload();
+    info!("Configuration reloaded");
+    Ok(())
+}
+
 impl<'a> CellConfig<'a> {
     const fn from(desc: &'a HvCellDesc) -> Self {
         Self { desc }
@@ -100,6 +323,30 @@ impl<'a> CellConfig<'a> {
         self.desc.config_size()
     }
 
+    pub fn cpu_set(&self) -> CpuSet {
+        self.desc.cpu_set
+    }
+
+    /// Whether the hypercall numbered `nr` is allowed by the hypercall mask.
+    pub fn hypercall_allowed(&self, nr: u32) -> bool {
+        let mask = self.desc.hypercall_mask;
+        mask == 0 || (nr < 64 && mask & (1 << nr) != 0)
+    }
+
+    pub fn cpuid_policy(&self) -> CpuidPolicyFlags {
+        self.desc.cpuid_policy
+    }
+
+    /// Physical address width reported to the cell, 0 for the one of the CPU.
+    pub fn phys_addr_bits(&self) -> u8 {
+        self.desc.phys_addr_bits
+    }
+
+    /// First vector and size of the vector pool of the cell, see `vector`.
+    pub fn vector_pool(&self) -> (u8, u8) {
+        (self.desc.vector_pool_start, self.desc.vector_pool_size)
+    }
+
     pub fn mem_regions(&self) -> &[HvMemoryRegion] {
         // XXX: data may unaligned, which cause panic on debug mode. Same below.
         // See: https://doc.rust-lang.org/src/core/slice/mod.rs.html#6435-6443
@@ -120,6 +367,11 @@ impl Debug for CellConfig<'_> {
         f.debug_struct("CellConfig")
             .field("name", &core::str::from_utf8(&name[..len]))
             .field("size", &self.size())
+            .field("cpu_set", &self.cpu_set())
+            .field("hypercall_mask", &{ self.desc.hypercall_mask })
+            .field("cpuid_policy", &self.cpuid_policy())
+            .field("phys_addr_bits", &self.phys_addr_bits())
+            .field("vector_pool", &self.vector_pool())
             .field("mem_regions", &self.mem_regions())
             .finish()
     }
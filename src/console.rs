@@ -0,0 +1,161 @@
+//! Hypervisor console.
+//!
+//! `print!()` and the logger write to a `Console` backend selected by
+//! `HvSystemConfig::console`. The legacy UART is used until `init()`, which
+//! is called once all CPUs run on the hypervisor page table (the MMIO UART is
+//! only mapped there). Both UARTs are programmed with the baud rate and line
+//! parameters of the configuration. The log ring of the stats window gets the
+//! log records with any backend.
+
+use core::fmt::{self, Arguments, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Once;
+
+use crate::config::{ConsoleType, HvConsole, HvSystemConfig};
+use crate::lock::SpinLock;
+use crate::memory::addr::{phys_to_virt, HostPhysAddr};
+
+/// A sink of the console output.
+pub trait Console: Sync {
+    /// Writes `args`. Also called on panics and from NMI context.
+    fn write_fmt(&self, args: Arguments);
+}
+
+/// Converts `\n` to `\r\n` for terminals.
+pub struct CrLfWriter<T: Write> {
+    inner: T,
+}
+
+impl<T: Write> CrLfWriter<T> {
+    pub const fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: Write> Write for CrLfWriter<T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                b'\n' => {
+                    self.inner.write_char('\r')?;
+                    self.inner.write_char('\n')?;
+                }
+                _ => self.inner.write_char(byte as char)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 16550 UART with 32-bit registers, as found in SoCs without legacy I/O
+/// ports. Programmed for 115200 8N1, assuming the usual 1.8432 MHz clock.
+pub struct MmioUart {
+    base: usize,
+}
+
+impl MmioUart {
+    const THR: usize = 0;
+    const IER: usize = 1;
+    const FCR: usize = 2;
+    const LCR: usize = 3;
+    const MCR: usize = 4;
+    const LSR: usize = 5;
+    const LSR_THR_EMPTY: u32 = 1 << 5;
+    const LCR_DLAB: u32 = 1 << 7;
+
+    /// # Safety
+    ///
+    /// The UART registers at `config.address` must be mapped in the
+    /// hypervisor page table.
+    unsafe fn new(config: &HvConsole) -> Self {
+        let mut uart = Self {
+            base: phys_to_virt(config.address as HostPhysAddr),
+        };
+        let divisor = config.uart_divisor() as u32;
+        uart.write_reg(Self::IER, 0);
+        uart.write_reg(Self::LCR, Self::LCR_DLAB);
+        uart.write_reg(Self::THR, divisor & 0xff); // divisor low byte
+        uart.write_reg(Self::IER, divisor >> 8); // divisor high byte
+        uart.write_reg(Self::LCR, config.uart_line_control() as u32);
+        uart.write_reg(Self::FCR, 0xc7); // enable and clear the FIFOs
+        uart.write_reg(Self::MCR, 0x0b); // DTR, RTS, OUT2
+        uart
+    }
+
+    fn read_reg(&self, reg: usize) -> u32 {
+        unsafe { ((self.base + reg * 4) as *const u32).read_volatile() }
+    }
+
+    fn write_reg(&mut self, reg: usize, value: u32) {
+        unsafe { ((self.base + reg * 4) as *mut u32).write_volatile(value) }
+    }
+}
+
+impl Write for MmioUart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            while self.read_reg(Self::LSR) & Self::LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.write_reg(Self::THR, byte as u32);
+        }
+        Ok(())
+    }
+}
+
+impl Console for SpinLock<CrLfWriter<MmioUart>> {
+    fn write_fmt(&self, args: Arguments) {
+        // Interleaving with an interrupted print is better than a deadlock.
+        self.lock_reentrant().write_fmt(args).ok();
+    }
+}
+
+/// Writes to the log ring of the stats window only.
+struct MemoryRing;
+
+impl Console for MemoryRing {
+    fn write_fmt(&self, args: Arguments) {
+        crate::stats_window::log_write(args);
+    }
+}
+
+struct NullConsole;
+
+impl Console for NullConsole {
+    fn write_fmt(&self, _args: Arguments) {}
+}
+
+static CONSOLE_TYPE: AtomicU32 = AtomicU32::new(ConsoleType::LegacyUart as u32);
+static MMIO_UART: Once<SpinLock<CrLfWriter<MmioUart>>> = Once::new();
+
+fn console() -> &'static dyn Console {
+    match ConsoleType::try_from(CONSOLE_TYPE.load(Ordering::Acquire)) {
+        Ok(ConsoleType::MmioUart) => MMIO_UART.get().unwrap(),
+        Ok(ConsoleType::MemoryRing) => &MemoryRing,
+        Ok(ConsoleType::Null) => &NullConsole,
+        _ => crate::arch::serial::legacy_uart(),
+    }
+}
+
+/// Switches to the backend of the configuration.
+pub fn init() {
+    let config = &HvSystemConfig::get().console;
+    let console_type = config.console_type();
+    if console_type == ConsoleType::MmioUart {
+        let uart = unsafe { MmioUart::new(config) };
+        MMIO_UART.call_once(|| SpinLock::new(CrLfWriter::new(uart)));
+    }
+    info!("Console: {:?}", console_type);
+    CONSOLE_TYPE.store(console_type as u32, Ordering::Release);
+}
+
+/// Whether the console output goes to the log ring of the stats window, where
+/// the logger writes its records already.
+pub fn is_log_ring() -> bool {
+    CONSOLE_TYPE.load(Ordering::Acquire) == ConsoleType::MemoryRing as u32
+}
+
+pub fn print(args: Arguments) {
+    console().write_fmt(args);
+}
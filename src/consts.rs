@@ -1,3 +1,5 @@
+use core::ops::Range;
+
 use crate::config::HvSystemConfig;
 use crate::header::HvHeader;
 use crate::memory::addr::{align_up, VirtAddr};
@@ -5,12 +7,31 @@ use crate::percpu::PerCpu;
 
 pub use crate::memory::PAGE_SIZE;
 
+/// Max number of CPUs (`HvHeader::max_cpus`), sizing the static tables
+/// indexed by CPU ID. Checked when each CPU enters the hypervisor.
+pub const MAX_CPUS: usize = 256;
+
+/// Max number of cells: the root cell and the RTOS partition. Set to 1 to
+/// reject configurations with RTOS CPUs.
+pub const MAX_CELLS: usize = 2;
+
 /// Size of the hypervisor heap.
 pub const HV_HEAP_SIZE: usize = 32 * 1024 * 1024; // 32 MB
 
+/// Size of the per-CPU emergency heap, used when the main heap is exhausted
+/// or its lock cannot be taken.
+pub const EMERGENCY_HEAP_SIZE: usize = 16 * 1024; // 16 KB
+
+/// Number of formatted error messages each CPU can hold without allocating
+/// from the heap.
+pub const ERROR_MSG_POOL_SIZE: usize = 16;
+
 /// Size of the per-CPU data (stack and other CPU-local data).
 pub const PER_CPU_SIZE: usize = 512 * 1024; // 512 KB
 
+/// Size of the stack used by `arch::efi_entry`.
+pub const EFI_STACK_SIZE: usize = 64 * 1024; // 64 KB
+
 /// Start virtual address of the hypervisor memory.
 pub const HV_BASE: usize = 0xffff_ff00_0000_0000;
 
@@ -30,6 +51,12 @@ pub fn free_memory_start() -> VirtAddr {
     align_up(hv_config_ptr() as usize + HvSystemConfig::get().size())
 }
 
+/// Virtual address range of the hypervisor code and read-only data, which
+/// are never modified after loading.
+pub fn hv_text_rodata_range() -> Range<VirtAddr> {
+    __text_start as usize..__rodata_end as usize
+}
+
 /// End virtual address of the hypervisor memory.
 pub fn hv_end() -> VirtAddr {
     HV_BASE + HvSystemConfig::get().hypervisor_memory.size as usize
@@ -37,5 +64,7 @@ pub fn hv_end() -> VirtAddr {
 
 extern "C" {
     fn __header_start();
+    fn __text_start();
+    fn __rodata_end();
     fn __core_end();
 }
@@ -0,0 +1,76 @@
+//! Sets of physical CPUs.
+//!
+//! CPUs are indexed by their hardware ID (the local APIC ID on x86_64), as the
+//! logical CPU IDs of the hypervisor depend on the order CPUs enter it.
+
+use core::fmt::{Debug, Formatter, Result};
+
+const CPU_SET_WORDS: usize = 4;
+
+/// A bitmap of hardware CPU IDs, also used as is in the configuration.
+#[repr(transparent)]
+#[derive(Clone, Copy, Default, Eq, PartialEq)]
+pub struct CpuSet {
+    bits: [u64; CPU_SET_WORDS],
+}
+
+impl CpuSet {
+    /// Max number of CPUs in a set, hardware IDs must be less than it.
+    pub const CAPACITY: usize = CPU_SET_WORDS * 64;
+
+    pub const fn new() -> Self {
+        Self {
+            bits: [0; CPU_SET_WORDS],
+        }
+    }
+
+    pub fn contains(&self, cpu: u32) -> bool {
+        let cpu = cpu as usize;
+        cpu < Self::CAPACITY && self.bits[cpu / 64] & (1 << (cpu % 64)) != 0
+    }
+
+    /// Adds `cpu` to the set. Returns `false` if `cpu` is out of range.
+    pub fn insert(&mut self, cpu: u32) -> bool {
+        let cpu = cpu as usize;
+        if cpu >= Self::CAPACITY {
+            return false;
+        }
+        self.bits[cpu / 64] |= 1 << (cpu % 64);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&w| w == 0)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.bits.iter().map(|w| w.count_ones()).sum()
+    }
+
+    /// Whether all CPUs of this set are also in `other`.
+    pub fn is_subset_of(&self, other: &Self) -> bool {
+        self.bits
+            .iter()
+            .zip(other.bits.iter())
+            .all(|(a, b)| a & !b == 0)
+    }
+
+    /// Whether this set and `other` have any CPU in common.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.bits
+            .iter()
+            .zip(other.bits.iter())
+            .any(|(a, b)| a & b != 0)
+    }
+
+    /// Iterates over the CPUs in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..Self::CAPACITY as u32).filter(move |&cpu| self.contains(cpu))
+    }
+}
+
+impl Debug for CpuSet {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
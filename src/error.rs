@@ -1,5 +1,9 @@
 use alloc::string::String;
-use core::fmt::{Debug, Formatter, Result};
+use core::fmt::{self, Debug, Display, Formatter, Result, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory::PoolBox;
+use crate::percpu::PerCpu;
 
 /// POSIX errno
 #[repr(u32)]
@@ -10,6 +14,8 @@ pub enum HvErrorNum {
     ENOENT = 2,
     EIO = 5,
     E2BIG = 7,
+    ENOEXEC = 8,
+    EAGAIN = 11,
     ENOMEM = 12,
     EFAULT = 14,
     EBUSY = 16,
@@ -18,14 +24,60 @@ pub enum HvErrorNum {
     EINVAL = 22,
     ERANGE = 34,
     ENOSYS = 38,
+    ETIMEDOUT = 110,
+}
+
+/// Subsystems where errors come from, classified by the module path.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HvErrorSubsystem {
+    Other = 0,
+    Memory = 1,
+    Vmm = 2,
+    Apic = 3,
+    Config = 4,
+    Cell = 5,
+    Hypercall = 6,
 }
 
+/// `Hypercall` is the last variant.
+pub const NUM_ERROR_SUBSYSTEMS: usize = HvErrorSubsystem::Hypercall as usize + 1;
+
+/// Number of errors constructed in each subsystem.
+static ERROR_COUNTS: [AtomicU64; NUM_ERROR_SUBSYSTEMS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; NUM_ERROR_SUBSYSTEMS]
+};
+
 pub struct HvError {
     num: HvErrorNum,
+    subsystem: HvErrorSubsystem,
     loc_line: u32,
     loc_col: u32,
     loc_file: &'static str,
-    msg: Option<String>,
+    msg: Option<ErrorMsg>,
+}
+
+/// Length of error messages formatted into a per-CPU pool buffer. Longer
+/// messages are truncated.
+pub const MSG_BUF_LEN: usize = 120;
+
+/// A formatted error message that does not live on the heap.
+pub struct MsgBuf {
+    len: usize,
+    buf: [u8; MSG_BUF_LEN],
+}
+
+/// The message attached to an `HvError`.
+///
+/// Formatted messages come from the per-CPU message pool, so that errors
+/// returned on the VM exit path never take the heap lock. The heap is only
+/// used before the CPU enters the hypervisor or when the pool is exhausted.
+pub enum ErrorMsg {
+    Static(&'static str),
+    Pooled(PoolBox<MsgBuf>),
+    Heap(String),
 }
 
 pub type HvResult<T = ()> = core::result::Result<T, HvError>;
@@ -38,6 +90,8 @@ impl HvErrorNum {
             ENOENT => "No such file or directory",
             EIO => "I/O error",
             E2BIG => "Argument list too long",
+            ENOEXEC => "Exec format error",
+            EAGAIN => "Try again",
             ENOMEM => "Out of memory",
             EFAULT => "Bad address",
             EBUSY => "Device or resource busy",
@@ -46,20 +100,116 @@ impl HvErrorNum {
             EINVAL => "Invalid argument",
             ERANGE => "Math result not representable",
             ENOSYS => "Function not implemented",
+            ETIMEDOUT => "Connection timed out",
+        }
+    }
+}
+
+impl HvErrorSubsystem {
+    fn from_module_path(path: &str) -> Self {
+        // Skip the crate name.
+        let path = path.split_once("::").map_or("", |(_, p)| p);
+        if path.starts_with("memory") {
+            Self::Memory
+        } else if path.starts_with("arch::vmm") {
+            Self::Vmm
+        } else if path.starts_with("arch::apic") {
+            Self::Apic
+        } else if path.starts_with("config") {
+            Self::Config
+        } else if path.starts_with("cell") {
+            Self::Cell
+        } else if path.starts_with("hypercall") {
+            Self::Hypercall
+        } else {
+            Self::Other
+        }
+    }
+}
+
+impl MsgBuf {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            buf: [0; MSG_BUF_LEN],
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // `write_str` only cuts at character boundaries.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+impl Write for MsgBuf {
+    fn write_str(&mut self, s: &str) -> Result {
+        let mut n = s.len().min(MSG_BUF_LEN - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
         }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
     }
 }
 
+impl ErrorMsg {
+    pub fn format(args: fmt::Arguments) -> Self {
+        if let Some(cpu) = PerCpu::try_current_mut() {
+            if let Ok(mut buf) = cpu.msg_pool.alloc(MsgBuf::new()) {
+                let _ = buf.write_fmt(args);
+                return Self::Pooled(buf);
+            }
+        }
+        Self::Heap(alloc::fmt::format(args))
+    }
+}
+
+impl From<&'static str> for ErrorMsg {
+    fn from(s: &'static str) -> Self {
+        Self::Static(s)
+    }
+}
+
+impl From<String> for ErrorMsg {
+    fn from(s: String) -> Self {
+        Self::Heap(s)
+    }
+}
+
+impl Display for ErrorMsg {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Self::Static(s) => f.write_str(s),
+            Self::Pooled(buf) => f.write_str(buf.as_str()),
+            Self::Heap(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Number of errors constructed in the subsystem indexed by `subsystem`.
+pub fn error_count(subsystem: usize) -> Option<u64> {
+    ERROR_COUNTS
+        .get(subsystem)
+        .map(|c| c.load(Ordering::Relaxed))
+}
+
 impl HvError {
     pub fn new(
         num: HvErrorNum,
+        loc_module: &'static str,
         loc_file: &'static str,
         loc_line: u32,
         loc_col: u32,
-        msg: Option<String>,
+        msg: Option<ErrorMsg>,
     ) -> Self {
+        let subsystem = HvErrorSubsystem::from_module_path(loc_module);
+        let count = ERROR_COUNTS[subsystem as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        crate::stats_window::update_error_count(subsystem, count);
+        crate::logging::note_error_path(loc_file, loc_line);
         Self {
             num,
+            subsystem,
             loc_file,
             loc_line,
             loc_col,
@@ -67,9 +217,17 @@ impl HvError {
         }
     }
 
+    pub fn num(&self) -> HvErrorNum {
+        self.num
+    }
+
     pub fn code(&self) -> i32 {
         -(self.num as u32 as i32)
     }
+
+    pub fn subsystem(&self) -> HvErrorSubsystem {
+        self.subsystem
+    }
 }
 
 impl Debug for HvError {
@@ -91,18 +249,39 @@ impl Debug for HvError {
 
 #[macro_export]
 macro_rules! hv_err {
+    ($num: ident, $fmt: literal, $($arg: tt)+) => {{
+        use crate::error::{ErrorMsg, HvError, HvErrorNum::*};
+        HvError::new(
+            $num,
+            module_path!(),
+            file!(),
+            line!(),
+            column!(),
+            Some(ErrorMsg::format(format_args!($fmt, $($arg)+))),
+        )
+    }};
     ($num: ident) => {{
         use crate::error::{HvError, HvErrorNum::*};
-        HvError::new($num, file!(), line!(), column!(), None)
+        HvError::new($num, module_path!(), file!(), line!(), column!(), None)
     }};
     ($num: ident, $msg: expr) => {{
         use crate::error::{HvError, HvErrorNum::*};
-        HvError::new($num, file!(), line!(), column!(), Some($msg.into()))
+        HvError::new(
+            $num,
+            module_path!(),
+            file!(),
+            line!(),
+            column!(),
+            Some($msg.into()),
+        )
     }};
 }
 
 #[macro_export]
 macro_rules! hv_result_err {
+    ($num: ident, $fmt: literal, $($arg: tt)+) => {
+        Err(hv_err!($num, $fmt, $($arg)+))
+    };
     ($num: ident) => {
         Err(hv_err!($num))
     };
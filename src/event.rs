@@ -0,0 +1,232 @@
+//! Event channel from the hypervisor to the root cell.
+//!
+//! The root cell registers one page of its RAM as an event ring, and an
+//! interrupt vector. The hypervisor appends events to the ring and raises the
+//! vector on a CPU of the root cell chosen by the `EventRoute` policy, the
+//! registering CPU by default. Notifications are throttled during interrupt
+//! storms, see `irq_storm`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use numeric_enum_macro::numeric_enum;
+
+use crate::cpuset::CpuSet;
+use crate::error::HvResult;
+use crate::hal::LocalIrqChip;
+use crate::lock::SpinLock;
+use crate::memory::addr::{is_aligned, GuestPhysAddr};
+use crate::memory::{MemFlags, PAGE_SIZE};
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum HvEventType {
+        /// Package thermal status changed, `data[0]` is the new status.
+        ThermalAlert = 1,
+        /// A machine check bank has a valid error, `data` is `[bank, status, addr]`.
+        MachineCheck = 2,
+        /// A write or instruction fetch hit a monitored range, `data` is
+        /// `[gpaddr, rip, access]` where `access` is 1 for write, 2 for fetch.
+        IntegrityViolation = 3,
+        /// A monitored range has been modified, `data` is `[gpaddr, size, 0]`.
+        IntegrityModified = 4,
+        /// An asynchronous hypercall operation is completed, `data` is
+        /// `[token, status, 0]`, see `hypercall::async_op`.
+        AsyncComplete = 5,
+    }
+}
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum EventRoute {
+        /// Notify the CPU which set up the event channel.
+        Registrar = 0,
+        /// Notify a fixed CPU.
+        Fixed = 1,
+        /// Notify the least recently notified CPU in a set, to spread the
+        /// interrupts.
+        LeastRecent = 2,
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvEvent {
+    pub event_type: u32,
+    /// The CPU which detected the event.
+    pub cpu_id: u32,
+    pub data: [u64; 3],
+}
+
+/// Number of events in the ring, must be a power of 2 to keep the free running
+/// counters consistent when they wrap around.
+const EVENT_RING_SIZE: usize = 64;
+
+/// Layout of the event ring page shared with the root cell. `head` is only
+/// written by the hypervisor and `tail` only by the root cell, both are free
+/// running counters. The hypervisor never reads `head` back, as the root cell
+/// may have overwritten it.
+#[repr(C)]
+struct EventRing {
+    head: AtomicU32,
+    tail: AtomicU32,
+    /// Number of events dropped since the ring was full.
+    dropped: AtomicU32,
+    _reserved: u32,
+    events: [HvEvent; EVENT_RING_SIZE],
+}
+
+struct EventChannel {
+    ring: &'static mut EventRing,
+    /// Copy of `ring.head`.
+    head: u32,
+    /// Hardware ID of the registering CPU.
+    apic_id: u32,
+    vector: u8,
+    /// Whether `vector` was allocated by `setup()`, to be freed with the channel.
+    owns_vector: bool,
+    route: EventRoute,
+    /// Candidate CPUs for `Fixed` (a single one) and `LeastRecent`.
+    targets: CpuSet,
+    last_target: u32,
+}
+
+impl EventChannel {
+    fn next_target(&mut self) -> u32 {
+        let target = match self.route {
+            EventRoute::Registrar => self.apic_id,
+            EventRoute::Fixed | EventRoute::LeastRecent => {
+                let last = self.last_target;
+                let mut targets = self.targets.iter();
+                match targets.clone().find(|&cpu| cpu > last) {
+                    Some(cpu) => cpu,
+                    None => targets.next().unwrap_or(self.apic_id),
+                }
+            }
+        };
+        self.last_target = target;
+        target
+    }
+}
+
+static EVENT_CHANNEL: SpinLock<Option<EventChannel>> = SpinLock::new(None);
+
+/// Registers the event ring at `gpaddr` of the root cell. Events are notified
+/// by raising `vector` on the current CPU, allocated from the vector pool of
+/// the cell if 0. Returns the vector.
+pub fn setup(gpaddr: GuestPhysAddr, vector: u8) -> HvResult<u8> {
+    if !is_aligned(gpaddr) {
+        return hv_result_err!(EINVAL, "Event ring is not page aligned");
+    }
+    let cell = crate::cell::root_cell();
+    if vector != 0 && vector < 32 {
+        return hv_result_err!(EINVAL, "Event vector must not be an exception");
+    }
+    if cell.vectors.in_pool(vector) && !cell.vectors.is_allocated(vector) {
+        return hv_result_err!(EINVAL, "Event vector is in the pool but not allocated");
+    }
+    let vaddr = cell.guest_ram_to_hv(gpaddr, PAGE_SIZE, MemFlags::READ | MemFlags::WRITE)?;
+    let owns_vector = vector == 0;
+    let vector = if owns_vector {
+        cell.vectors.alloc(1)?
+    } else {
+        vector
+    };
+    let ring = unsafe { &mut *(vaddr as *mut EventRing) };
+    ring.head.store(0, Ordering::Relaxed);
+    ring.tail.store(0, Ordering::Relaxed);
+    ring.dropped.store(0, Ordering::Relaxed);
+
+    info!(
+        "Event channel set up: ring={:#x}, vector={:#x}",
+        gpaddr, vector
+    );
+    let mut channel = EVENT_CHANNEL.lock();
+    if let Some(old) = channel.take() {
+        if old.owns_vector {
+            cell.vectors.free(old.vector, 1)?;
+        }
+    }
+    *channel = Some(EventChannel {
+        ring,
+        head: 0,
+        apic_id: crate::arch::local_irq_chip().id(),
+        vector,
+        owns_vector,
+        route: EventRoute::Registrar,
+        targets: CpuSet::new(),
+        last_target: 0,
+    });
+    Ok(vector)
+}
+
+/// Sets the routing policy of event notifications. For `Fixed`, `arg` is the
+/// hardware ID of the target CPU. For `LeastRecent`, `arg` is a bitmap of the
+/// candidate hardware IDs, or 0 for all CPUs of the root cell.
+pub fn set_route(route: EventRoute, arg: u64) -> HvResult {
+    let cell_cpus = crate::cell::root_cell().cpu_set;
+    let mut targets = CpuSet::new();
+    match route {
+        EventRoute::Registrar => {}
+        EventRoute::Fixed => {
+            targets.insert(arg as u32);
+        }
+        EventRoute::LeastRecent if arg == 0 => targets = cell_cpus,
+        EventRoute::LeastRecent => {
+            for cpu in (0..64).filter(|&cpu| arg & (1 << cpu) != 0) {
+                targets.insert(cpu);
+            }
+        }
+    }
+    if route != EventRoute::Registrar && (targets.is_empty() || !targets.is_subset_of(&cell_cpus)) {
+        return hv_result_err!(EINVAL, "Event targets are not CPUs of the root cell");
+    }
+
+    let mut channel = EVENT_CHANNEL.lock();
+    let channel = match channel.as_mut() {
+        Some(channel) => channel,
+        None => return hv_result_err!(ENODEV, "Event channel is not set up"),
+    };
+    info!("Event route set: {:?} {:?}", route, targets);
+    channel.route = route;
+    channel.targets = targets;
+    Ok(())
+}
+
+/// Unregisters the event ring, e.g. before the root cell frees it, and frees
+/// its vector if allocated by `setup()`.
+pub fn shutdown() {
+    if let Some(old) = EVENT_CHANNEL.lock().take() {
+        if old.owns_vector {
+            crate::cell::root_cell().vectors.free(old.vector, 1).ok();
+        }
+    }
+}
+
+/// Sends an event to the root cell. Returns `false` if the event channel is
+/// not set up or the ring is full.
+pub fn send(event_type: HvEventType, data: [u64; 3]) -> bool {
+    let mut channel = EVENT_CHANNEL.lock();
+    let channel = match channel.as_mut() {
+        Some(channel) => channel,
+        None => return false,
+    };
+    let ring = &mut channel.ring;
+    let head = channel.head;
+    let tail = ring.tail.load(Ordering::Acquire);
+    if head.wrapping_sub(tail) as usize >= EVENT_RING_SIZE {
+        ring.dropped.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    ring.events[head as usize % EVENT_RING_SIZE] = HvEvent {
+        event_type: event_type as u32,
+        cpu_id: crate::percpu::PerCpu::current().id,
+        data,
+    };
+    channel.head = head.wrapping_add(1);
+    ring.head.store(channel.head, Ordering::Release);
+    let target = channel.next_target();
+    crate::irq_storm::raise(target, channel.vector);
+    true
+}
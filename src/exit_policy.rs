@@ -0,0 +1,92 @@
+//! Handling of the VM exits without handler.
+//!
+//! By default, a VM exit whose reason the hypervisor does not handle injects
+//! a #GP into the guest. For bring-up on unusual hardware, another
+//! `ExitPolicy` may be selected per exit reason, by the configuration
+//! (`HvSystemConfig::exit_policies`) or the `ExitPolicySet` hypercall: skip
+//! the instruction with a warning, so that the guest goes on while the
+//! missing handlers are enumerated, or panic to stop at the first one.
+//!
+//! Exit reasons are the VMX basic exit reasons, or the SVM exit codes with
+//! the `amd` feature. A `ConfigReload` replaces the policies set by
+//! hypercall with the ones of the configuration.
+
+use crate::config::{ExitPolicy, HvSystemConfig, MAX_EXIT_POLICIES};
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+
+/// Exit reasons with another policy than `ExitPolicy::Fault`.
+static POLICIES: SpinLock<[Option<(u32, ExitPolicy)>; MAX_EXIT_POLICIES]> =
+    SpinLock::new([None; MAX_EXIT_POLICIES]);
+
+/// Loads the policies of the configuration. The first entry of a reason
+/// applies.
+pub fn load(config: &HvSystemConfig) {
+    let entries = config.exit_policies().iter().filter_map(|entry| {
+        let policy = ExitPolicy::try_from(entry.policy).ok()?;
+        (policy != ExitPolicy::Fault).then(|| (entry.exit_reason, policy))
+    });
+    let mut policies = POLICIES.lock();
+    *policies = [None; MAX_EXIT_POLICIES];
+    for (slot, entry) in policies.iter_mut().zip(entries) {
+        *slot = Some(entry);
+    }
+}
+
+/// Returns the policy of `exit_reason`.
+pub fn get(exit_reason: u32) -> ExitPolicy {
+    POLICIES
+        .lock()
+        .iter()
+        .flatten()
+        .find(|&&(reason, _)| reason == exit_reason)
+        .map_or(ExitPolicy::Fault, |&(_, policy)| policy)
+}
+
+/// Sets the policy of `exit_reason`, `ExitPolicy::Fault` removes its entry.
+pub fn set(exit_reason: u32, policy: ExitPolicy) -> HvResult {
+    let mut policies = POLICIES.lock();
+    for slot in policies.iter_mut() {
+        if matches!(slot, Some((reason, _)) if *reason == exit_reason) {
+            *slot = None;
+        }
+    }
+    if policy != ExitPolicy::Fault {
+        match policies.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some((exit_reason, policy)),
+            None => return hv_result_err!(ENOMEM, "Exit policy table is full"),
+        }
+    }
+    info!("Unhandled VM exit reason {:#x}: {:?}", exit_reason, policy);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_exit_policy_set_get() {
+        *POLICIES.lock() = [None; MAX_EXIT_POLICIES];
+        assert_eq!(get(0x10), ExitPolicy::Fault);
+        assert!(set(0x10, ExitPolicy::Skip).is_ok());
+        assert_eq!(get(0x10), ExitPolicy::Skip);
+        // Setting a reason again replaces its entry.
+        assert!(set(0x10, ExitPolicy::Panic).is_ok());
+        assert_eq!(get(0x10), ExitPolicy::Panic);
+        assert_eq!(POLICIES.lock().iter().flatten().count(), 1);
+        // `Fault` removes the entry.
+        assert!(set(0x10, ExitPolicy::Fault).is_ok());
+        assert_eq!(get(0x10), ExitPolicy::Fault);
+        assert!(POLICIES.lock().iter().all(|slot| slot.is_none()));
+
+        for reason in 0..MAX_EXIT_POLICIES as u32 {
+            assert!(set(reason, ExitPolicy::Skip).is_ok());
+        }
+        assert!(set(0x100, ExitPolicy::Skip).is_err());
+        assert_eq!(get(0x100), ExitPolicy::Fault);
+        assert!(set(0, ExitPolicy::Fault).is_ok());
+        assert!(set(0x100, ExitPolicy::Skip).is_ok());
+        assert_eq!(get(0x100), ExitPolicy::Skip);
+        assert_eq!(get(0), ExitPolicy::Fault);
+    }
+}
@@ -0,0 +1,130 @@
+//! Fault injection into VM exit handlers.
+//!
+//! Built with `FAULT_INJECT=on`, the fault points fail periodically while a
+//! CPU handles a VM exit, in a fixed pattern: every `INJECT_PERIOD`-th check
+//! of a point fails. Faults are never injected while enabling or disabling
+//! the hypervisor. A failed exit handler must degrade to a fault injected
+//! into the guest, and a failed hypercall to an error returned to the guest.
+//!
+//! After each exit with injected faults, the root cell locks are checked not
+//! to be left held by the error paths. The number of faults injected at each
+//! point is readable with the `HypervisorGetInfo` hypercall.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use numeric_enum_macro::numeric_enum;
+
+use crate::arch::cpu;
+use crate::consts::MAX_CPUS;
+use crate::percpu::PerCpu;
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum FaultPoint {
+        /// Frame allocation fails with `ENOMEM`.
+        FrameAlloc = 0,
+        /// The VM exit handler fails with `EIO` before handling the exit.
+        ExitHandler = 1,
+        /// A hypercall fails with `EBUSY`, as if a lock it needs could not
+        /// be taken.
+        LockBusy = 2,
+    }
+}
+
+pub const NUM_FAULT_POINTS: usize = 3;
+
+/// Number of checks of each point between two injected faults.
+const INJECT_PERIOD: [u64; NUM_FAULT_POINTS] = [97, 1009, 101];
+/// Time for another CPU to release a lock before it is reported as leaked.
+const LOCK_WAIT_US: u64 = 1000; // 1ms
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+static CHECKS: [AtomicU64; NUM_FAULT_POINTS] = [ZERO; NUM_FAULT_POINTS];
+static INJECTED: [AtomicU64; NUM_FAULT_POINTS] = [ZERO; NUM_FAULT_POINTS];
+/// Number of CPUs handling a VM exit.
+static ACTIVE_EXITS: AtomicU32 = AtomicU32::new(0);
+/// Whether each CPU is handling a VM exit.
+static IN_EXIT: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+/// Whether faults have been injected in the current VM exit of each CPU.
+static INJECTED_IN_EXIT: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+
+/// Whether the check of `point` must fail. Always `false` without the
+/// `fault-inject` feature.
+pub fn should_fail(point: FaultPoint) -> bool {
+    if !cfg!(feature = "fault-inject") || ACTIVE_EXITS.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    // Some CPU handles a VM exit, so the per-CPU data of all CPUs is set up.
+    let cpu_id = PerCpu::current().id as usize;
+    if !IN_EXIT[cpu_id].load(Ordering::Relaxed) {
+        return false;
+    }
+    let idx = point as usize;
+    if (CHECKS[idx].fetch_add(1, Ordering::Relaxed) + 1) % INJECT_PERIOD[idx] != 0 {
+        return false;
+    }
+    INJECTED[idx].fetch_add(1, Ordering::Relaxed);
+    INJECTED_IN_EXIT[cpu_id].store(true, Ordering::Relaxed);
+    warn!("Injected fault at {:?} on CPU {}", point, cpu_id);
+    true
+}
+
+/// Called when the CPU `cpu_id` starts handling a VM exit.
+pub fn begin_exit(cpu_id: u32) {
+    if cfg!(feature = "fault-inject") {
+        IN_EXIT[cpu_id as usize].store(true, Ordering::Relaxed);
+        ACTIVE_EXITS.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Called when the CPU `cpu_id` is done with a VM exit, after the guest
+/// fault has been injected if the handler failed.
+pub fn end_exit(cpu_id: u32) {
+    if !cfg!(feature = "fault-inject") {
+        return;
+    }
+    IN_EXIT[cpu_id as usize].store(false, Ordering::Relaxed);
+    ACTIVE_EXITS.fetch_sub(1, Ordering::Release);
+    if INJECTED_IN_EXIT[cpu_id as usize].swap(false, Ordering::Relaxed) {
+        check_locks(cpu_id);
+    }
+}
+
+/// Waits for the lock tested by `try_lock` to be free, returns `false` if it
+/// stays held.
+fn wait_unlocked(try_lock: impl Fn() -> bool) -> bool {
+    let deadline = cpu::current_cycle() + LOCK_WAIT_US * cpu::frequency() as u64;
+    while !try_lock() {
+        if cpu::current_cycle() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn check_locks(cpu_id: u32) {
+    let cell = crate::cell::root_cell();
+    if !wait_unlocked(|| cell.gpm.try_write().is_some()) {
+        error!(
+            "Guest memory set lock left held after an injected fault on CPU {}",
+            cpu_id
+        );
+    }
+    if !wait_unlocked(|| cell.mmio.try_write().is_some()) {
+        error!(
+            "MMIO registry lock left held after an injected fault on CPU {}",
+            cpu_id
+        );
+    }
+}
+
+/// Number of faults injected at `point`.
+pub fn injected_count(point: FaultPoint) -> u64 {
+    INJECTED[point as usize].load(Ordering::Relaxed)
+}
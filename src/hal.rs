@@ -0,0 +1,59 @@
+//! Hardware abstraction layer.
+//!
+//! Arch-neutral interfaces used by the cell and per-CPU code. Each arch
+//! implements them and exports the implementations as `arch::ArchVcpu`,
+//! `arch::NestedPageTable` and `arch::local_irq_chip()`.
+
+use core::fmt::Debug;
+
+use crate::arch::LinuxContext;
+use crate::cell::Cell;
+use crate::error::HvResult;
+use crate::memory::{GenericPageTable, GuestPhysAddr};
+
+/// A virtual CPU running the guest on the current physical CPU.
+pub trait Vcpu: Debug + Sized {
+    /// Enables hardware virtualization and sets up a vCPU with the state of
+    /// `linux`, running in `cell`.
+    fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self>;
+
+    /// Enters guest mode. Never returns if successful.
+    fn enter(&mut self, linux: &LinuxContext) -> HvResult;
+
+    /// Saves the guest state back to `linux` and disables hardware
+    /// virtualization.
+    fn exit(&self, linux: &mut LinuxContext) -> HvResult;
+
+    /// Injects a general protection fault into the guest.
+    fn inject_fault(&mut self) -> HvResult;
+
+    /// Whether the guest runs in the most privileged mode.
+    fn guest_is_privileged(&self) -> bool;
+
+    /// Whether the last VM exit is caused by a hypercall.
+    fn in_hypercall(&self) -> bool;
+
+    /// The reason of the VMX abort which shut down the CPU of this vCPU, if
+    /// any. Read from other CPUs.
+    fn abort_indicator(&self) -> Option<u32> {
+        None
+    }
+
+    /// Number of frames allocated for the hardware structures of this vCPU.
+    fn num_frames(&self) -> usize;
+}
+
+/// The second stage page table translating guest physical addresses of a cell.
+pub trait NestedPaging: GenericPageTable<VA = GuestPhysAddr> {
+    /// The root pointer to program into a vCPU (e.g. EPTP or nCR3).
+    fn nested_root(&self) -> u64;
+}
+
+/// The interrupt controller local to each physical CPU.
+pub trait LocalIrqChip {
+    /// Hardware ID of the current CPU, used as IPI destination.
+    fn id(&self) -> u32;
+
+    /// Sends a fixed interrupt `vector` to the CPU with the hardware ID `dest`.
+    fn send_ipi(&self, dest: u32, vector: u8);
+}
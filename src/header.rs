@@ -1,9 +1,85 @@
+//! Image header and loader handshake.
+//!
+//! The driver reads the header of the loaded image, fills in `max_cpus` and
+//! `rt_cpus`, then calls `entry` on each CPU.
+//!
+//! Which CPUs run the root cell and which ones the RTOS is not implied by
+//! these counts: the system configuration maps each CPU to its role by APIC
+//! ID, and the driver must call `entry` exactly on the root cell CPUs. The
+//! counts must match the configured sets, which are checked against the
+//! MADT while enabling.
+//!
+//! Since loader protocol version 2, the header ends with a handshake area:
+//! before enabling, the driver checks `hv_version`, `config_revision` and
+//! `hv_features`, and writes its protocol version, the features it can use
+//! and the ones it needs. The primary CPU negotiates while enabling and
+//! writes back the enabled features and the result. The header page stays
+//! readable by the root cell afterwards. Drivers unaware of the handshake
+//! leave it zeroed, and get all features.
+//!
+//! Since loader protocol version 3, the header also carries the build
+//! identifier, see `build_info`.
+//!
+//! Since loader protocol version 4, the hypervisor can also be loaded by an
+//! EFI driver before Linux boots. The EFI driver loads the image and the
+//! system configuration as the Linux driver would, fills in `max_cpus` and
+//! `rt_cpus`, maps the hypervisor memory at `HV_BASE`, and calls `efi_entry`
+//! once on the boot CPU. It sets up the hypervisor heap, frame allocator and
+//! page table, then sets `efi_inited`. The memory must be reserved in the EFI
+//! memory map. The Linux driver then finds `efi_inited` set, and calls `entry`
+//! on each CPU without reloading the image; the rest of the initialization is
+//! done as usual.
+
 use core::fmt::{Debug, Formatter, Result};
 
+use bitflags::bitflags;
+
+use crate::config::CONFIG_REVISION;
 use crate::consts::{HV_HEADER_PTR, PER_CPU_SIZE};
+use crate::error::HvResult;
 
 const HEADER_SIGNATURE: [u8; 8] = *b"RVMIMAGE";
 
+/// Loader protocol version implemented by the hypervisor.
+pub const LOADER_PROTOCOL_VERSION: u32 = 4;
+
+bitflags! {
+    /// Features negotiated by the loader handshake.
+    pub struct LoaderFeatures: u64 {
+        /// The read-only stats window, see `stats_window`.
+        const STATS_WINDOW      = 1 << 0;
+        /// The event channel hypercalls.
+        const EVENT_CHANNEL     = 1 << 1;
+        /// The `AsyncSubmit` hypercall.
+        const ASYNC_OPS         = 1 << 2;
+        /// PCI devices assigned to the RTOS.
+        const RTOS_PCI_DEVICES  = 1 << 3;
+        /// Hypercall rate limiting.
+        const HYPERCALL_LIMIT   = 1 << 4;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct HvHandshake {
+    /// Loader protocol version of the driver, 0 if unaware of the handshake.
+    pub driver_version: u32,
+    /// Loader protocol version of the hypervisor.
+    pub hv_version: u32,
+    /// `HvSystemConfig` revision expected by the hypervisor.
+    pub config_revision: u32,
+    /// 0 or a negative errno, written back by the hypervisor.
+    pub result: i32,
+    /// Features the driver can use.
+    pub driver_caps: LoaderFeatures,
+    /// Features the driver can not work without.
+    pub driver_needs: LoaderFeatures,
+    /// Features supported by the hypervisor.
+    pub hv_features: LoaderFeatures,
+    /// Features enabled, written back by the hypervisor.
+    pub enabled: LoaderFeatures,
+}
+
 #[repr(C)]
 pub struct HvHeader {
     pub signature: [u8; 8],
@@ -12,6 +88,11 @@ pub struct HvHeader {
     pub entry: usize,
     pub max_cpus: u32,
     pub rt_cpus: u32,
+    pub handshake: HvHandshake,
+    pub build_id: [u8; 32],
+    pub efi_entry: usize,
+    /// Set by the hypervisor once initialized by `efi_entry`.
+    pub efi_inited: u32,
 }
 
 impl HvHeader {
@@ -30,6 +111,57 @@ impl HvHeader {
             self.max_cpus
         }
     }
+
+    /// Whether `features` are enabled by the handshake.
+    pub fn has_features(&self, features: LoaderFeatures) -> bool {
+        self.handshake.enabled.contains(features)
+    }
+}
+
+/// Negotiates the loader features with the driver, and writes back the
+/// result in the header. Called by the primary CPU while enabling.
+pub fn negotiate() -> HvResult {
+    let handshake = unsafe { &mut (*(HV_HEADER_PTR as *mut HvHeader)).handshake };
+    let hv_features = handshake.hv_features;
+    let res = match handshake.driver_version {
+        0 => {
+            handshake.enabled = hv_features;
+            Ok(())
+        }
+        version if version > LOADER_PROTOCOL_VERSION => {
+            hv_result_err!(
+                ENOSYS,
+                format!("Unknown loader protocol version {}", version)
+            )
+        }
+        _ if !hv_features.contains(handshake.driver_needs) => hv_result_err!(
+            ENOSYS,
+            format!(
+                "Loader features {:?} are not supported",
+                handshake.driver_needs - hv_features
+            )
+        ),
+        _ => {
+            handshake.enabled = handshake.driver_caps & hv_features;
+            Ok(())
+        }
+    };
+    handshake.result = match &res {
+        Ok(()) => 0,
+        Err(e) => e.code(),
+    };
+    info!("Loader handshake: {:#x?}", handshake);
+    res
+}
+
+/// Tells the Linux driver whether the hypervisor has been initialized by
+/// `efi_entry`, and must not be reloaded.
+///
+/// # Safety
+///
+/// Must not be called concurrently, as it writes to the header.
+pub unsafe fn set_efi_inited(inited: bool) {
+    (*(HV_HEADER_PTR as *mut HvHeader)).efi_inited = inited as u32;
 }
 
 #[repr(C)]
@@ -40,10 +172,15 @@ struct HvHeaderStuff {
     entry: unsafe extern "C" fn(),
     max_cpus: u32,
     rt_cpus: u32,
+    handshake: HvHandshake,
+    build_id: [u8; 32],
+    efi_entry: unsafe extern "C" fn(),
+    efi_inited: u32,
 }
 
 extern "C" {
     fn __entry_offset();
+    fn __efi_entry_offset();
     fn __core_size();
 }
 
@@ -56,6 +193,19 @@ static HEADER_STUFF: HvHeaderStuff = HvHeaderStuff {
     entry: __entry_offset,
     max_cpus: 0,
     rt_cpus: 0,
+    handshake: HvHandshake {
+        driver_version: 0,
+        hv_version: LOADER_PROTOCOL_VERSION,
+        config_revision: CONFIG_REVISION as u32,
+        result: 0,
+        driver_caps: LoaderFeatures::empty(),
+        driver_needs: LoaderFeatures::empty(),
+        hv_features: LoaderFeatures::all(),
+        enabled: LoaderFeatures::empty(),
+    },
+    build_id: crate::build_info::BUILD_ID,
+    efi_entry: __efi_entry_offset,
+    efi_inited: 0,
 };
 
 impl Debug for HvHeader {
@@ -68,6 +218,10 @@ impl Debug for HvHeader {
             .field("max_cpus", &self.max_cpus)
             .field("rt_cpus", &self.rt_cpus)
             .field("vm_cpus", &self.vm_cpus())
+            .field("handshake", &self.handshake)
+            .field("build_id", &self.build_id)
+            .field("efi_entry", &self.efi_entry)
+            .field("efi_inited", &self.efi_inited)
             .finish()
     }
 }
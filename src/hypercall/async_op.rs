@@ -0,0 +1,166 @@
+//! Asynchronous hypercall operations.
+//!
+//! Long-running operations on the RTOS memory are submitted by the
+//! `AsyncSubmit` hypercall, which returns a token immediately. The work is
+//! done in chunks of `CHUNK_SIZE` bytes at the end of VM exits, and the
+//! completion is reported through the event channel as `AsyncComplete` with
+//! `data` of `[token, status, 0]`, where `status` is 0 or a negative errno.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use numeric_enum_macro::numeric_enum;
+use sha2::{Digest, Sha256};
+
+use crate::arch::RtCpuStatus;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::event::{self, HvEventType};
+use crate::lock::SpinLock;
+use crate::memory::addr::{phys_to_virt, PhysAddr};
+
+/// Max number of operations in the queue.
+const MAX_PENDING_OPS: usize = 8;
+
+/// Bytes processed in one VM exit.
+const CHUNK_SIZE: usize = 0x10_0000;
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum AsyncOpType {
+        /// Zero the whole RTOS memory. The RT CPUs must not be running.
+        ScrubRtMemory = 0,
+        /// Re-hash the first `arg` bytes (0 for all) of the RTOS memory and
+        /// compare with the measurement taken by `RtStart`.
+        VerifyRtImage = 1,
+    }
+}
+
+enum OpState {
+    Scrub,
+    Verify(Sha256),
+}
+
+struct AsyncOp {
+    token: u32,
+    start: PhysAddr,
+    size: usize,
+    done: usize,
+    state: OpState,
+}
+
+static NEXT_TOKEN: AtomicU32 = AtomicU32::new(1);
+static PENDING_OPS: SpinLock<Vec<AsyncOp>> = SpinLock::new(Vec::new());
+
+impl AsyncOp {
+    /// Processes the next chunk, returns whether the operation is done.
+    fn step(&mut self) -> bool {
+        let len = CHUNK_SIZE.min(self.size - self.done);
+        let ptr = phys_to_virt(self.start + self.done) as *mut u8;
+        match &mut self.state {
+            OpState::Scrub => unsafe { ptr.write_bytes(0, len) },
+            OpState::Verify(hasher) => {
+                hasher.update(unsafe { core::slice::from_raw_parts(ptr, len) })
+            }
+        }
+        self.done += len;
+        self.done == self.size
+    }
+
+    fn finish(self) -> HvResult {
+        match self.state {
+            OpState::Scrub => {
+                info!("RTOS memory scrubbed");
+                Ok(())
+            }
+            OpState::Verify(hasher) => {
+                let hash: crate::attest::HashValue = hasher.finalize().into();
+                match crate::attest::rtos_measurement() {
+                    Some(expected) if expected == hash => Ok(()),
+                    Some(_) => hv_result_err!(EIO, "RTOS image does not match its measurement"),
+                    None => hv_result_err!(ENOENT, "RTOS is not measured"),
+                }
+            }
+        }
+    }
+}
+
+fn rt_cpus_running() -> bool {
+    crate::arch::rt_cpu_info()
+        .iter()
+        .any(|info| info.status == RtCpuStatus::Started)
+}
+
+/// Queues an operation, returns its token.
+pub fn submit(op_type: AsyncOpType, arg: u64) -> HvResult<u32> {
+    let rtos_memory = &HvSystemConfig::get().rtos_memory;
+    let (start, size) = (
+        rtos_memory.phys_start as PhysAddr,
+        rtos_memory.size as usize,
+    );
+    let state = match op_type {
+        AsyncOpType::ScrubRtMemory if rt_cpus_running() => {
+            return hv_result_err!(EBUSY, "RT CPUs are running");
+        }
+        AsyncOpType::ScrubRtMemory => OpState::Scrub,
+        AsyncOpType::VerifyRtImage if crate::attest::rtos_measurement().is_none() => {
+            return hv_result_err!(ENOENT, "RTOS is not measured");
+        }
+        AsyncOpType::VerifyRtImage => OpState::Verify(Sha256::new()),
+    };
+    let size = match (op_type, arg as usize) {
+        (AsyncOpType::VerifyRtImage, image_size) if image_size != 0 => {
+            if image_size > size {
+                return hv_result_err!(EINVAL, "RTOS image is too large");
+            }
+            image_size
+        }
+        _ => size,
+    };
+
+    let mut pending = PENDING_OPS.lock();
+    if pending.len() >= MAX_PENDING_OPS {
+        return hv_result_err!(EBUSY, "Too many pending operations");
+    }
+    let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+    pending.push(AsyncOp {
+        token,
+        start,
+        size,
+        done: 0,
+        state,
+    });
+    debug!("Async operation {:?} queued: token={}", op_type, token);
+    Ok(token)
+}
+
+/// Whether there are operations not completed yet.
+pub fn is_busy() -> bool {
+    !PENDING_OPS.lock().is_empty()
+}
+
+/// Processes one chunk of the first pending operation. Called at the end of
+/// each VM exit, skipped if another CPU is already doing it.
+pub fn poll() {
+    let mut pending = match PENDING_OPS.try_lock() {
+        Some(pending) => pending,
+        None => return,
+    };
+    match pending.first_mut() {
+        Some(op) if op.step() => {}
+        _ => return,
+    }
+    let op = pending.remove(0);
+    drop(pending);
+
+    let token = op.token;
+    let status = match op.finish() {
+        Ok(()) => 0,
+        Err(err) => {
+            warn!("Async operation {} failed: {:?}", token, err);
+            err.code()
+        }
+    };
+    event::send(HvEventType::AsyncComplete, [token as _, status as u64, 0]);
+}
@@ -0,0 +1,133 @@
+//! Hypercall rate limiting and anomaly accounting.
+//!
+//! Each cell has a token bucket for its hypercalls, configured by
+//! `HvSystemConfig::hypercall_limit`, so that a misbehaving root cell can not
+//! monopolize hypervisor CPU time by hammering expensive hypercalls. Calls
+//! over the limit fail with `EAGAIN` without being executed. Hypercalls that
+//! tear things down (`HypervisorDisable`, `RtShutdown`) are never limited.
+//!
+//! The limit is reloadable with the `ConfigReload` hypercall, which refills
+//! the bucket.
+//!
+//! Calls not allowed by the hypercall mask of the cell configuration fail
+//! with `EPERM`.
+//!
+//! Rejected calls are counted by `HypercallAnomaly`, readable with the
+//! `HypervisorGetInfo` hypercall.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use numeric_enum_macro::numeric_enum;
+
+use crate::arch::cpu;
+use crate::config::HvSystemConfig;
+use crate::lock::SpinLock;
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum HypercallAnomaly {
+        /// Unknown hypercall code.
+        Unsupported = 0,
+        /// Hypercall issued from the wrong privilege level.
+        WrongMode = 1,
+        /// Hypercall failed with `EINVAL`.
+        InvalidArgs = 2,
+        /// Hypercall rejected by the rate limit.
+        RateLimited = 3,
+        /// Hypercall not allowed by the hypercall mask of the cell.
+        Denied = 4,
+    }
+}
+
+pub const NUM_HYPERCALL_ANOMALIES: usize = 5;
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Cycles of credit consumed by one hypercall, 0 for no limit.
+    cost: u64,
+    /// Max credit, in cycles.
+    capacity: u64,
+    /// Accumulated credit in TSC cycles, at most `capacity`.
+    credit: u64,
+    last_cycle: u64,
+}
+
+#[derive(Debug)]
+pub struct HypercallLimiter {
+    /// Whether a limit is configured, to skip the bucket lock otherwise.
+    limited: AtomicBool,
+    bucket: SpinLock<TokenBucket>,
+    anomalies: [AtomicU64; NUM_HYPERCALL_ANOMALIES],
+}
+
+impl TokenBucket {
+    /// A full bucket with the limit of the current configuration.
+    fn from_config() -> Self {
+        let limit = &HvSystemConfig::get().hypercall_limit;
+        let (rate, burst) = (limit.rate as u64, limit.burst as u64);
+        let cost = match rate {
+            0 => 0,
+            _ => (cpu::frequency() as u64 * 1_000_000 / rate).max(1),
+        };
+        Self {
+            cost,
+            capacity: cost.saturating_mul(burst),
+            credit: cost.saturating_mul(burst),
+            last_cycle: cpu::current_cycle(),
+        }
+    }
+}
+
+impl HypercallLimiter {
+    pub fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        let bucket = TokenBucket::from_config();
+        Self {
+            limited: AtomicBool::new(bucket.cost != 0),
+            bucket: SpinLock::new(bucket),
+            anomalies: [ZERO; NUM_HYPERCALL_ANOMALIES],
+        }
+    }
+
+    /// Applies the limit of the configuration, after it was reloaded.
+    pub fn reload(&self) {
+        let mut bucket = self.bucket.lock();
+        *bucket = TokenBucket::from_config();
+        self.limited.store(bucket.cost != 0, Ordering::Relaxed);
+    }
+
+    /// Takes a token for one hypercall. Returns `false` (and counts a
+    /// `RateLimited` anomaly) if the cell is over its limit.
+    pub fn try_acquire(&self) -> bool {
+        if !self.limited.load(Ordering::Relaxed) {
+            return true;
+        }
+        let mut bucket = self.bucket.lock();
+        if bucket.cost == 0 {
+            return true;
+        }
+        let now = cpu::current_cycle();
+        let elapsed = now.saturating_sub(bucket.last_cycle);
+        bucket.last_cycle = now;
+        bucket.credit = bucket.credit.saturating_add(elapsed).min(bucket.capacity);
+        if bucket.credit >= bucket.cost {
+            bucket.credit -= bucket.cost;
+            true
+        } else {
+            drop(bucket);
+            self.record(HypercallAnomaly::RateLimited);
+            false
+        }
+    }
+
+    pub fn record(&self, anomaly: HypercallAnomaly) {
+        self.anomalies[anomaly as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of hypercalls rejected as `anomaly`.
+    pub fn anomaly_count(&self, anomaly: HypercallAnomaly) -> u64 {
+        self.anomalies[anomaly as usize].load(Ordering::Relaxed)
+    }
+}
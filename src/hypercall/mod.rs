@@ -1,13 +1,31 @@
+pub mod async_op;
+pub mod limit;
+
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use bit_field::BitField;
 use numeric_enum_macro::numeric_enum;
 
 use crate::arch::{vmm::VcpuAccessGuestState, GuestPageTableImmut};
-use crate::error::HvResult;
+use crate::cell::CellState;
+use crate::config::ExitPolicy;
+use crate::error::{HvError, HvErrorNum, HvResult};
+use crate::fault_inject::{should_fail, FaultPoint};
+use crate::hal::Vcpu;
+use crate::header::{HvHeader, LoaderFeatures};
+use crate::logging::RateLimit;
 use crate::memory::addr::PhysAddr;
+use crate::memory::gaccess::copy_bytes_from_guest;
+use crate::memory::{
+    frame_usage, reclaim, release_trailing, GenericPageTableImmut, MemFlags, PageSize, PAGE_SIZE,
+};
 use crate::percpu::PerCpu;
+use crate::stats::{report_init_phase, InitPhase, Instant};
+
+use self::limit::HypercallAnomaly;
 
 numeric_enum! {
     #[repr(u32)]
@@ -16,6 +34,120 @@ numeric_enum! {
         HypervisorDisable = 0,
         RtStart = 1,
         RtShutdown = 2,
+        HypervisorGetInfo = 3,
+        EventChannelSetup = 4,
+        Attest = 5,
+        ProtectRange = 6,
+        AsyncSubmit = 7,
+        EventChannelRoute = 8,
+        StatsControl = 9,
+        MemRelease = 10,
+        KexecPrepare = 11,
+        LatencyRangeSetup = 12,
+        VectorAlloc = 13,
+        VectorFree = 14,
+        CrashPrepare = 15,
+        ConfigReload = 16,
+        ConsoleWrite = 17,
+        LogNotifySetup = 18,
+        LogDrained = 19,
+        QueryGpa = 20,
+        ExitPolicySet = 21,
+    }
+}
+
+/// `ProtectRange`: remove write permission.
+const PROTECT_NO_WRITE: u64 = 1 << 0;
+/// `ProtectRange`: remove execute permission.
+const PROTECT_NO_EXEC: u64 = 1 << 1;
+/// `ProtectRange`: monitor the integrity of the range, see `integrity`.
+const PROTECT_MONITOR: u64 = 1 << 2;
+
+/// `MemRelease`: release the memory instead of only reporting its size.
+const MEM_RELEASE_APPLY: u64 = 1 << 0;
+
+/// Minimum time between two warnings about calls rejected by the rate limit.
+const RATE_LIMIT_WARN_INTERVAL_MS: u64 = 1000;
+
+/// Max number of bytes printed by a `ConsoleWrite`.
+const CONSOLE_WRITE_MAX: u64 = 256;
+/// Max number of bytes printed by a `ConsoleWrite` from CPL 3, which any
+/// process of the guest may issue: about 3ms in the VM exit at 115200 baud.
+const CONSOLE_WRITE_USER_MAX: u64 = 32;
+
+/// `QueryGpa`: shift of the page size of the mapping in the result, 0 for
+/// 4 KiB, 1 for 2 MiB and 2 for 1 GiB.
+const QUERY_GPA_SIZE_SHIFT: usize = 10;
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum HvInfoType {
+        /// Number of RT CPUs tried by the last `RtStart`.
+        NumRtCpus = 0,
+        /// Startup status of the RT CPU indexed by `arg1`, encoded as
+        /// `status | attempts << 8 | apic_id << 32`.
+        RtCpuStatus = 1,
+        /// Memory encryption features, see `arch::mem_encrypt::MemEncryptFeatures`.
+        MemEncryption = 2,
+        /// Number of errors in the subsystem indexed by `arg1`, see `HvErrorSubsystem`.
+        ErrorCount = 3,
+        /// Number of monitored ranges found modified, reported as events.
+        IntegrityCheck = 4,
+        /// Intercepted guest operations, see `arch::vmm::InterceptFlags`.
+        Intercepts = 5,
+        /// Number of frames in the usage indexed by `arg1`, see `HvMemUsageType`.
+        MemUsage = 6,
+        /// Number of hypercalls of the root cell rejected as the anomaly
+        /// indexed by `arg1`, see `limit::HypercallAnomaly`.
+        HypercallAnomalies = 7,
+        /// Size in bytes of the chunks of the memory access sampling, 0 if
+        /// not sampled yet, see `mem_heat`.
+        MemHeatChunkSize = 8,
+        /// Access samples of the chunk indexed by `arg1`, encoded as
+        /// `hits | samples << 32`.
+        MemHeat = 9,
+        /// Number of faults injected at the `fault_inject::FaultPoint`
+        /// indexed by `arg1`.
+        InjectedFaults = 10,
+        /// Number of exits in the latency bucket `arg1 & 0xff` of the RIP
+        /// range `arg1 >> 8`, see `rip_latency`.
+        RipLatency = 11,
+        /// State of the root cell (`arg1` = 0) or the RTOS (`arg1` = 1), see
+        /// `cell::CellState`.
+        CellState = 12,
+        /// Number of interrupt storms detected on the vector `arg1`, see
+        /// `irq_storm`.
+        IrqStorms = 13,
+        /// Number of pages of the root cell nested page table reaching
+        /// hypervisor memory, or RTOS memory once started, logged as
+        /// warnings.
+        IsolationAudit = 14,
+    }
+}
+
+numeric_enum! {
+    #[repr(u64)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum HvMemUsageType {
+        /// Frames allocated from the hypervisor memory.
+        AllocatedFrames = 0,
+        /// Frames managed by the frame allocator.
+        TotalFrames = 1,
+        /// Frames of the root cell nested page table.
+        CellPageTableFrames = 2,
+        /// Frames of the root cell vCPU structures (e.g. VMCS).
+        CellVcpuFrames = 3,
+        /// Frames of the stats window.
+        StatsWindowFrames = 4,
+    }
+}
+
+impl HvInfoType {
+    /// Whether the query has no side effects, and may be issued from user
+    /// space.
+    fn is_read_only(self) -> bool {
+        !matches!(self, Self::IntegrityCheck | Self::IsolationAudit)
     }
 }
 
@@ -23,59 +155,171 @@ impl HyperCallCode {
     fn is_privileged(self) -> bool {
         (self as u32).get_bits(30..32) == 0
     }
+
+    /// Whether the privileged call may also be issued from CPL 3, with the
+    /// same convention, if `HvSystemFlags::USER_HYPERCALLS` is set.
+    fn is_user_allowed(self) -> bool {
+        matches!(self, Self::HypervisorGetInfo | Self::ConsoleWrite)
+    }
+
+    /// Loader feature needed by the call, see `header::negotiate()`.
+    fn loader_feature(self) -> LoaderFeatures {
+        match self {
+            Self::EventChannelSetup | Self::EventChannelRoute => LoaderFeatures::EVENT_CHANNEL,
+            Self::AsyncSubmit => LoaderFeatures::ASYNC_OPS,
+            _ => LoaderFeatures::empty(),
+        }
+    }
+
+    /// Whether the call is refused once `KexecPrepare` has quiesced the
+    /// hypervisor.
+    fn is_blocked_by_kexec(self) -> bool {
+        matches!(
+            self,
+            Self::RtStart | Self::EventChannelSetup | Self::AsyncSubmit | Self::LogNotifySetup
+        )
+    }
+
+    /// Whether the call changes the memory layout or the configuration, and
+    /// is refused in lockdown mode.
+    fn is_frozen_by_lockdown(self) -> bool {
+        matches!(
+            self,
+            Self::ProtectRange
+                | Self::EventChannelRoute
+                | Self::StatsControl
+                | Self::MemRelease
+                | Self::LatencyRangeSetup
+                | Self::ConfigReload
+                | Self::ExitPolicySet
+        )
+    }
+
+    /// Whether the call may also be issued through `FAST_HYPERCALL_MSR`: the
+    /// frequent queries and console writes.
+    fn is_fast(self) -> bool {
+        matches!(self, Self::HypervisorGetInfo | Self::ConsoleWrite)
+    }
+
+    /// Whether the call is subject to the rate limit of the cell.
+    fn is_rate_limited(self) -> bool {
+        !matches!(
+            self,
+            Self::HypervisorDisable | Self::RtShutdown | Self::CrashPrepare
+        )
+    }
 }
 
 pub type HyperCallResult = HvResult<usize>;
 
+/// Whether the lockdown mode of the configuration is in effect: from the
+/// activation of the root cell until the hypervisor is disabled.
+fn locked_down() -> bool {
+    crate::config::HvSystemConfig::get().lockdown()
+        && matches!(
+            crate::cell::root_cell().lifecycle.get(),
+            CellState::Running | CellState::Failed
+        )
+}
+
+/// Set by `KexecPrepare` or `CrashPrepare`, until the hypervisor is disabled.
+static KEXEC_PREPARED: AtomicBool = AtomicBool::new(false);
+
 pub struct HyperCall<'a> {
     cpu_data: &'a mut PerCpu,
-    _gpt: GuestPageTableImmut,
+    gpt: GuestPageTableImmut,
+    /// Whether the call was issued from CPL 3.
+    from_user: bool,
+    /// Whether a fault was injected to the caller instead of returning.
+    faulted: bool,
 }
 
 impl<'a> HyperCall<'a> {
     pub fn new(cpu_data: &'a mut PerCpu) -> Self {
         Self {
-            _gpt: cpu_data.vcpu.guest_page_table(),
+            gpt: cpu_data.vcpu.guest_page_table(),
+            from_user: !cpu_data.vcpu.guest_is_privileged(),
+            faulted: false,
             cpu_data,
         }
     }
 
-    pub fn hypercall(&mut self, code: u32, arg0: u64, _arg1: u64) -> HvResult {
+    /// Whether the call injected a fault to the caller, which must then see
+    /// the faulting instruction again.
+    pub fn faulted(&self) -> bool {
+        self.faulted
+    }
+
+    fn fault(&mut self) -> HvResult {
+        self.faulted = true;
+        self.cpu_data.fault()
+    }
+
+    pub fn hypercall(&mut self, code: u32, arg0: u64, arg1: u64) -> HvResult {
+        let cell = crate::cell::root_cell();
+        let limiter = &cell.hypercall_limiter;
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
             Err(_) => {
                 warn!("Hypercall not supported: {}", code);
+                limiter.record(HypercallAnomaly::Unsupported);
                 return Ok(());
             }
         };
 
-        if self.cpu_data.vcpu.guest_is_privileged() {
+        let user_allowed =
+            code.is_user_allowed() && crate::config::HvSystemConfig::get().user_hypercalls();
+        if !self.from_user {
             if !code.is_privileged() {
                 warn!("Cannot call {:?} in privileged mode", code);
-                self.cpu_data.fault()?;
+                limiter.record(HypercallAnomaly::WrongMode);
+                self.fault()?;
                 return Ok(());
             }
-        } else if code.is_privileged() {
+        } else if code.is_privileged() && !user_allowed {
             warn!("Cannot call {:?} in non-privileged mode", code);
-            self.cpu_data.fault()?;
+            limiter.record(HypercallAnomaly::WrongMode);
+            self.fault()?;
             return Ok(());
         }
 
+        self.cpu_data.guest_fetches.clear();
+        crate::stats_window::update_cpu_stats(self.cpu_data.id, |stats| {
+            stats.hypercalls.fetch_add(1, Ordering::Relaxed);
+        });
+
         debug!("HyperCall: {:?} => arg0={:#x}", code, arg0);
-        let ret = match code {
-            HyperCallCode::HypervisorDisable => self.hypervisor_disable(),
-            HyperCallCode::RtStart => self.start_rtos(arg0 as _),
-            HyperCallCode::RtShutdown => self.shutdown_rtos(),
+        let mut rate_limited = false;
+        let ret = if !cell.config.hypercall_allowed((code as u32).get_bits(0..30)) {
+            limiter.record(HypercallAnomaly::Denied);
+            hv_result_err!(EPERM, "{:?} is not allowed to the cell", code)
+        } else if code.is_rate_limited() && !limiter.try_acquire() {
+            rate_limited = true;
+            hv_result_err!(EAGAIN, "Hypercall rate limit exceeded")
+        } else {
+            self.dispatch(code, arg0, arg1)
         };
-        if ret.is_err() {
+        if rate_limited {
+            // As frequent as the cell calls, unlike the other failures.
+            static WARNINGS: RateLimit = RateLimit::new(RATE_LIMIT_WARN_INTERVAL_MS);
+            if let Some(suppressed) = WARNINGS.check() {
+                warn!(
+                    "HyperCall: {:?} <= {:x?} ({} warnings suppressed)",
+                    code, ret, suppressed
+                );
+            }
+        } else if ret.is_err() {
             warn!("HyperCall: {:?} <= {:x?}", code, ret);
         } else {
             debug!("HyperCall: {:?} <= {:x?}", code, ret);
         }
+        if matches!(&ret, Err(e) if e.num() == HvErrorNum::EINVAL) {
+            limiter.record(HypercallAnomaly::InvalidArgs);
+        }
 
         if !code.is_privileged() {
             if ret.is_err() {
-                self.cpu_data.fault()?;
+                self.fault()?;
             }
         } else {
             let val = match ret {
@@ -88,35 +332,522 @@ impl<'a> HyperCall<'a> {
         Ok(())
     }
 
+    /// Handles a hypercall issued through `arch::vmm::FAST_HYPERCALL_MSR`, the
+    /// same way as `hypercall()` but only for the fast calls. Returns `ENOSYS`
+    /// for the others.
+    pub fn fast_hypercall(&mut self, code: u32, arg0: u64, arg1: u64) -> HvResult {
+        match HyperCallCode::try_from(code) {
+            Ok(c) if c.is_fast() => self.hypercall(code, arg0, arg1),
+            _ => {
+                warn!("Hypercall not supported on the fast path: {}", code);
+                let limiter = &crate::cell::root_cell().hypercall_limiter;
+                limiter.record(HypercallAnomaly::Unsupported);
+                let err = hv_err!(ENOSYS);
+                self.cpu_data.vcpu.set_return_val(err.code() as _);
+                Ok(())
+            }
+        }
+    }
+
+    fn dispatch(&mut self, code: HyperCallCode, arg0: u64, arg1: u64) -> HyperCallResult {
+        if !HvHeader::get().has_features(code.loader_feature()) {
+            return hv_result_err!(ENOSYS, "Not enabled by the loader handshake");
+        }
+        if code.is_blocked_by_kexec() && KEXEC_PREPARED.load(Ordering::Acquire) {
+            return hv_result_err!(EBUSY, "Prepared for kexec");
+        }
+        if code.is_frozen_by_lockdown() && locked_down() {
+            return hv_result_err!(EPERM, "Configuration is locked down");
+        }
+        if should_fail(FaultPoint::LockBusy) {
+            return hv_result_err!(EBUSY, "Injected fault");
+        }
+        match code {
+            HyperCallCode::HypervisorDisable => self.hypervisor_disable(),
+            HyperCallCode::RtStart => self.start_rtos(arg0 as _, arg1 as _),
+            HyperCallCode::RtShutdown => self.shutdown_rtos(),
+            HyperCallCode::HypervisorGetInfo => self.hypervisor_get_info(arg0, arg1),
+            HyperCallCode::EventChannelSetup => self.event_channel_setup(arg0, arg1),
+            HyperCallCode::Attest => self.attest(arg0, arg1),
+            HyperCallCode::ProtectRange => self.protect_range(arg0, arg1),
+            HyperCallCode::AsyncSubmit => self.async_submit(arg0, arg1),
+            HyperCallCode::EventChannelRoute => self.event_channel_route(arg0, arg1),
+            HyperCallCode::StatsControl => self.stats_control(arg0),
+            HyperCallCode::MemRelease => self.mem_release(arg0, arg1),
+            HyperCallCode::KexecPrepare => self.kexec_prepare(),
+            HyperCallCode::LatencyRangeSetup => self.latency_range_setup(arg0, arg1),
+            HyperCallCode::VectorAlloc => self.vector_alloc(arg0),
+            HyperCallCode::VectorFree => self.vector_free(arg0, arg1),
+            HyperCallCode::CrashPrepare => self.crash_prepare(arg0, arg1),
+            HyperCallCode::ConfigReload => self.config_reload(arg0, arg1),
+            HyperCallCode::ConsoleWrite => self.console_write(arg0, arg1),
+            HyperCallCode::LogNotifySetup => self.log_notify_setup(arg0),
+            HyperCallCode::LogDrained => self.log_drained(arg0),
+            HyperCallCode::QueryGpa => self.query_gpa(arg0),
+            HyperCallCode::ExitPolicySet => self.exit_policy_set(arg0, arg1),
+        }
+    }
+
     fn hypervisor_disable(&mut self) -> HyperCallResult {
         let cpus = PerCpu::activated_cpus();
 
+        let now = Instant::now();
         static TRY_DISABLE_CPUS: AtomicU32 = AtomicU32::new(0);
         TRY_DISABLE_CPUS.fetch_add(1, Ordering::SeqCst);
-        while TRY_DISABLE_CPUS.load(Ordering::Acquire) < cpus {
+        // CPUs shut down by a VMX abort never get here.
+        while TRY_DISABLE_CPUS.load(Ordering::Acquire) + PerCpu::aborted_cpus() < cpus {
             core::hint::spin_loop();
         }
+        report_init_phase(self.cpu_data.id, InitPhase::Disable, now.elapsed());
 
+        crate::event::shutdown();
+        crate::stats_window::log_notify_shutdown();
+        crate::cell::root_cell()
+            .lifecycle
+            .transition(CellState::ShutDown)?;
+        // The kexec-ed kernel may enable the hypervisor again.
+        KEXEC_PREPARED.store(false, Ordering::Release);
         self.cpu_data.deactivate_vmm(0)?;
         unreachable!()
     }
 
-    fn start_rtos(&mut self, entry_paddr: PhysAddr) -> HyperCallResult {
+    /// Starts the RTOS at `entry_paddr`. The RTOS image of `image_size` bytes
+    /// (0 for the whole RTOS memory but the boot information page) is measured
+    /// before starting.
+    fn start_rtos(&mut self, entry_paddr: PhysAddr, image_size: usize) -> HyperCallResult {
         let sys_config = crate::config::HvSystemConfig::get();
         let rt_mem_start = sys_config.rtos_memory.phys_start;
-        let rt_mem_end = rt_mem_start + sys_config.rtos_memory.size;
-        if !(rt_mem_start..rt_mem_end).contains(&(entry_paddr as u64)) {
+        // The last page is overwritten with the boot information.
+        let rt_mem_size = sys_config.rtos_memory.size.saturating_sub(PAGE_SIZE as u64);
+        if !(rt_mem_start..rt_mem_start + rt_mem_size).contains(&(entry_paddr as u64)) {
             return hv_result_err!(EINVAL);
         }
+        if async_op::is_busy() {
+            return hv_result_err!(EBUSY, "Async operations on RTOS memory are pending");
+        }
+        let image_size = match image_size {
+            0 => rt_mem_size,
+            size if size as u64 <= rt_mem_size => size as u64,
+            _ => return hv_result_err!(EINVAL, "RTOS image is too large"),
+        };
 
+        let lifecycle = crate::cell::rtos_lifecycle();
+        lifecycle.transition(CellState::Loaded)?;
+        // Measured below, the RTOS memory must no longer be writable by the
+        // root cell, not even through the translations cached by its CPUs.
+        let hidden = crate::cell::root_cell()
+            .hide_rtos_memory()
+            .and_then(|()| crate::arch::flush_nested_tlbs());
+        if let Err(err) = hidden {
+            return Self::fail_rtos_start(err);
+        }
         info!("Starting RTOS: entry={:#x}", entry_paddr);
-        unsafe { crate::arch::start_rt_cpus(entry_paddr)? };
+        crate::attest::measure_rtos(rt_mem_start as _, image_size as _);
+        let now = Instant::now();
+        let res = unsafe { crate::arch::start_rt_cpus(entry_paddr) };
+        report_init_phase(self.cpu_data.id, InitPhase::RtStart, now.elapsed());
+        match res {
+            Ok(()) => lifecycle.transition(CellState::Running)?,
+            Err(err) => {
+                // Some RT CPUs may have started.
+                unsafe { crate::arch::shutdown_rt_cpus()? };
+                return Self::fail_rtos_start(err);
+            }
+        }
         Ok(0)
     }
 
+    /// Gives the RTOS memory back to the root cell after a failed start, and
+    /// returns `err`.
+    fn fail_rtos_start(err: HvError) -> HyperCallResult {
+        crate::cell::rtos_lifecycle().transition(CellState::Failed)?;
+        crate::attest::clear_rtos_measurement();
+        crate::cell::root_cell().restore_rtos_memory()?;
+        Err(err)
+    }
+
     fn shutdown_rtos(&mut self) -> HyperCallResult {
         info!("Shutting down RTOS...");
         unsafe { crate::arch::shutdown_rt_cpus()? };
+        crate::attest::clear_rtos_measurement();
+        crate::cell::rtos_lifecycle().transition(CellState::ShutDown)?;
+        crate::cell::root_cell().restore_rtos_memory()?;
         Ok(0)
     }
+
+    fn hypervisor_get_info(&mut self, info_type: u64, arg1: u64) -> HyperCallResult {
+        let info_type = HvInfoType::try_from(info_type).map_err(|_| hv_err!(EINVAL))?;
+        if self.from_user && !info_type.is_read_only() {
+            return hv_result_err!(EPERM, "{:?} is not allowed from user space", info_type);
+        }
+        match info_type {
+            HvInfoType::NumRtCpus => Ok(crate::arch::rt_cpu_info().len()),
+            HvInfoType::RtCpuStatus => {
+                let rt_cpus = crate::arch::rt_cpu_info();
+                let info = rt_cpus.get(arg1 as usize).ok_or_else(|| hv_err!(EINVAL))?;
+                let status = info.status as usize;
+                Ok(status | (info.attempts as usize) << 8 | (info.apic_id as usize) << 32)
+            }
+            HvInfoType::ErrorCount => crate::error::error_count(arg1 as usize)
+                .map(|count| count as usize)
+                .ok_or_else(|| hv_err!(EINVAL)),
+            HvInfoType::MemEncryption => Ok(crate::arch::mem_encrypt::info().features.bits() as _),
+            HvInfoType::IntegrityCheck => Ok(crate::integrity::verify()),
+            HvInfoType::Intercepts => Ok(crate::arch::vmm::intercepts().bits() as _),
+            HvInfoType::MemUsage => {
+                let usage_type = HvMemUsageType::try_from(arg1).map_err(|_| hv_err!(EINVAL))?;
+                Ok(self.mem_usage(usage_type))
+            }
+            HvInfoType::HypercallAnomalies => {
+                let anomaly = HypercallAnomaly::try_from(arg1).map_err(|_| hv_err!(EINVAL))?;
+                Ok(crate::cell::root_cell()
+                    .hypercall_limiter
+                    .anomaly_count(anomaly) as _)
+            }
+            HvInfoType::MemHeatChunkSize => Ok(crate::mem_heat::chunk_size()),
+            HvInfoType::MemHeat => crate::mem_heat::chunk_hits(arg1 as usize)
+                .map(|(hits, samples)| hits as usize | (samples as usize) << 32)
+                .ok_or_else(|| hv_err!(EINVAL)),
+            HvInfoType::InjectedFaults => {
+                let point = FaultPoint::try_from(arg1).map_err(|_| hv_err!(EINVAL))?;
+                Ok(crate::fault_inject::injected_count(point) as _)
+            }
+            HvInfoType::CellState => match arg1 {
+                0 => Ok(crate::cell::root_cell().lifecycle.get() as _),
+                1 => Ok(crate::cell::rtos_lifecycle().get() as _),
+                _ => hv_result_err!(EINVAL),
+            },
+            HvInfoType::IrqStorms => {
+                let vector = u8::try_from(arg1).map_err(|_| hv_err!(EINVAL))?;
+                Ok(crate::irq_storm::storm_count(vector) as _)
+            }
+            HvInfoType::IsolationAudit => Ok(crate::cell::root_cell().audit_isolation()),
+            HvInfoType::RipLatency => {
+                crate::rip_latency::bucket_count((arg1 >> 8) as usize, (arg1 & 0xff) as usize)
+                    .map(|count| count as usize)
+                    .ok_or_else(|| hv_err!(EINVAL))
+            }
+        }
+    }
+
+    fn mem_usage(&self, usage_type: HvMemUsageType) -> usize {
+        match usage_type {
+            HvMemUsageType::AllocatedFrames => frame_usage().0,
+            HvMemUsageType::TotalFrames => frame_usage().1,
+            HvMemUsageType::CellPageTableFrames => {
+                crate::cell::root_cell().mem_usage().page_table_frames
+            }
+            HvMemUsageType::CellVcpuFrames => crate::cell::root_cell().mem_usage().vcpu_frames,
+            HvMemUsageType::StatsWindowFrames => {
+                crate::stats_window::window_region().map_or(0, |(_, size)| size / PAGE_SIZE)
+            }
+        }
+    }
+
+    /// Registers the event ring. A `vector` of 0 is allocated from the vector
+    /// pool of the cell, and returned.
+    fn event_channel_setup(&mut self, ring_gpaddr: u64, vector: u64) -> HyperCallResult {
+        if vector > u8::MAX as u64 {
+            return hv_result_err!(EINVAL);
+        }
+        let vector = crate::event::setup(ring_gpaddr as _, vector as u8)?;
+        Ok(vector as _)
+    }
+
+    /// Sets up the interrupt raised once half of the log ring of the stats
+    /// window is unread. A `vector` of 0 is allocated from the vector pool of
+    /// the cell, and returned.
+    fn log_notify_setup(&mut self, vector: u64) -> HyperCallResult {
+        if vector > u8::MAX as u64 {
+            return hv_result_err!(EINVAL);
+        }
+        let vector = crate::stats_window::log_notify_setup(vector as u8)?;
+        Ok(vector as _)
+    }
+
+    /// Re-arms the log notification once the log ring is read up to
+    /// `position`, a value of `log_written`.
+    fn log_drained(&mut self, position: u64) -> HyperCallResult {
+        crate::stats_window::log_drained(position)?;
+        Ok(0)
+    }
+
+    /// Allocates `count` consecutive vectors of the cell, see `vector`.
+    fn vector_alloc(&mut self, count: u64) -> HyperCallResult {
+        let vectors = &crate::cell::root_cell().vectors;
+        Ok(vectors.alloc(count.min(u8::MAX as u64) as usize)? as _)
+    }
+
+    fn vector_free(&mut self, first: u64, count: u64) -> HyperCallResult {
+        if first > u8::MAX as u64 {
+            return hv_result_err!(EINVAL);
+        }
+        let vectors = &crate::cell::root_cell().vectors;
+        vectors.free(first as u8, count.min(u8::MAX as u64) as usize)?;
+        Ok(0)
+    }
+
+    /// Sets the `EventRoute` policy `route` with `arg`, see `event::set_route`.
+    fn event_channel_route(&mut self, route: u64, arg: u64) -> HyperCallResult {
+        let route = crate::event::EventRoute::try_from(route).map_err(|_| hv_err!(EINVAL))?;
+        crate::event::set_route(route, arg)?;
+        Ok(0)
+    }
+
+    fn attest(&mut self, report_gpaddr: u64, nonce: u64) -> HyperCallResult {
+        crate::attest::attest(report_gpaddr as _, nonce)?;
+        Ok(0)
+    }
+
+    /// Downgrades the permissions of the root cell RAM at `gpaddr`. The page
+    /// aligned size and the `PROTECT_*` flags are packed in `size_flags`.
+    fn protect_range(&mut self, gpaddr: u64, size_flags: u64) -> HyperCallResult {
+        let flags_mask = PAGE_SIZE as u64 - 1;
+        let (size, flags) = (size_flags & !flags_mask, size_flags & flags_mask);
+        if flags & !(PROTECT_NO_WRITE | PROTECT_NO_EXEC | PROTECT_MONITOR) != 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let mut remove = MemFlags::empty();
+        if flags & PROTECT_NO_WRITE != 0 {
+            remove |= MemFlags::WRITE;
+        }
+        if flags & PROTECT_NO_EXEC != 0 {
+            remove |= MemFlags::EXECUTE;
+        }
+        crate::cell::root_cell().protect_range(gpaddr as _, size as _, remove)?;
+        if flags & PROTECT_MONITOR != 0 {
+            crate::integrity::add_range(gpaddr as _, size as _)?;
+        }
+        Ok(0)
+    }
+
+    /// Queues the `AsyncOpType` `op_type` with `arg`, returns a token to match
+    /// the completion event.
+    fn async_submit(&mut self, op_type: u64, arg: u64) -> HyperCallResult {
+        let op_type = async_op::AsyncOpType::try_from(op_type).map_err(|_| hv_err!(EINVAL))?;
+        Ok(async_op::submit(op_type, arg)? as _)
+    }
+
+    /// Enables (`enable` = 1) or disables (0) statistics collection, returns
+    /// the previous state.
+    fn stats_control(&mut self, enable: u64) -> HyperCallResult {
+        if enable > 1 {
+            return hv_result_err!(EINVAL);
+        }
+        info!(
+            "Statistics collection {}",
+            if enable != 0 { "enabled" } else { "disabled" }
+        );
+        Ok(crate::stats::set_enabled(enable != 0) as _)
+    }
+
+    /// Records the exit latency of guest RIP `[start, start + size)`, where
+    /// `size` is in the low 32 bits of `arg1` and the range index in bits
+    /// 32..40. A size of 0 removes the range.
+    fn latency_range_setup(&mut self, start: u64, arg1: u64) -> HyperCallResult {
+        if arg1 >> 40 != 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let (size, idx) = (arg1 & 0xffff_ffff, (arg1 >> 32) as usize);
+        crate::rip_latency::set_range(idx, start, size)?;
+        Ok(0)
+    }
+
+    /// Returns the size of the unused memory at the end of the hypervisor
+    /// memory, keeping `keep_size` bytes free for later allocations. With
+    /// `MEM_RELEASE_APPLY` in `flags`, the memory is also taken out of the
+    /// frame allocator and mapped into the root cell, for the driver to give
+    /// it back to Linux.
+    fn mem_release(&mut self, flags: u64, keep_size: u64) -> HyperCallResult {
+        if flags & !MEM_RELEASE_APPLY != 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let apply = flags & MEM_RELEASE_APPLY != 0;
+        let keep = (keep_size as usize)
+            .checked_add(PAGE_SIZE - 1)
+            .ok_or_else(|| hv_err!(EINVAL, "Invalid size to keep"))?
+            / PAGE_SIZE;
+        let range = release_trailing(keep, !apply);
+        let size = range.end - range.start;
+        if apply && size != 0 {
+            if let Err(e) = crate::cell::root_cell().map_released_hv_memory(range.start, size) {
+                reclaim(range);
+                return Err(e);
+            }
+            info!(
+                "Hypervisor memory [{:#x}, {:#x}) released to the root cell",
+                range.start, range.end
+            );
+            crate::stats_window::mem_released(range);
+        }
+        Ok(size)
+    }
+
+    /// Quiesces the hypervisor before Linux jumps into a new kernel with
+    /// kexec, which may reuse any memory of the current one: stops the RTOS
+    /// and the event channel, and refuses to start them again. The driver then
+    /// disables the hypervisor with `HypervisorDisable` on all CPUs. To enable
+    /// it again from the new kernel, the image must be reloaded, even if it
+    /// was initialized by an EFI driver.
+    fn kexec_prepare(&mut self) -> HyperCallResult {
+        if KEXEC_PREPARED.swap(true, Ordering::AcqRel) {
+            return Ok(0);
+        }
+        info!("Preparing for kexec...");
+        let res = if async_op::is_busy() {
+            hv_result_err!(EBUSY, "Async operations are pending")
+        } else {
+            self.shutdown_rtos().map(|_| ())
+        };
+        if let Err(e) = res {
+            KEXEC_PREPARED.store(false, Ordering::Release);
+            return Err(e);
+        }
+        crate::event::shutdown();
+        crate::stats_window::log_notify_shutdown();
+        unsafe { crate::header::set_efi_inited(false) };
+        Ok(0)
+    }
+
+    /// Prepares for Linux jumping into the crash kernel loaded in guest RAM
+    /// `[start, start + size)` after a panic, called by the driver on the
+    /// panicking CPU. The other CPUs are stopped by Linux and never call
+    /// `HypervisorDisable`, so the hypervisor stays enabled below the crash
+    /// kernel: the crash kernel region is checked to be plain RAM of the root
+    /// cell, writable and executable, and out of hypervisor and RTOS memory.
+    /// The event channel is stopped, as the crash kernel does not expect its
+    /// interrupts, and the root cell is marked failed. The RTOS keeps running,
+    /// the crash kernel cannot reach its memory.
+    fn crash_prepare(&mut self, start: u64, size: u64) -> HyperCallResult {
+        let sys_config = crate::config::HvSystemConfig::get();
+        let end = match start.checked_add(size) {
+            Some(end) if size != 0 => end,
+            _ => return hv_result_err!(EINVAL),
+        };
+        let overlaps = |r: &crate::config::HvMemoryRegion| {
+            let (r_start, r_size) = (r.phys_start, r.size);
+            start < r_start + r_size && r_start < end
+        };
+        if overlaps(&sys_config.hypervisor_memory) || overlaps(&sys_config.rtos_memory) {
+            return hv_result_err!(EINVAL, "Crash kernel overlaps hypervisor or RTOS memory");
+        }
+        let cell = crate::cell::root_cell();
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+        cell.check_mapped(start as _, size as _, flags)?;
+
+        // Nothing is changed if the cell cannot be marked failed.
+        cell.lifecycle.transition(CellState::Failed)?;
+        if !KEXEC_PREPARED.swap(true, Ordering::AcqRel) {
+            warn!(
+                "Root cell crashed, crash kernel at [{:#x}, {:#x})",
+                start, end
+            );
+        }
+        crate::event::shutdown();
+        crate::stats_window::log_notify_shutdown();
+        Ok(0)
+    }
+
+    /// Reloads the non-structural parameters from the system configuration
+    /// of `size` bytes in guest RAM at `gpaddr`, see `config::reload()`.
+    fn config_reload(&mut self, gpaddr: u64, size: u64) -> HyperCallResult {
+        if size as usize != crate::config::HvSystemConfig::get().size() {
+            return hv_result_err!(EINVAL, "Configuration size changed");
+        }
+        let blob = copy_bytes_from_guest(gpaddr as _, size as _)?;
+        crate::config::reload(&blob)?;
+        Ok(0)
+    }
+
+    /// Translates `gpaddr` through the nested page table of the cell. Returns
+    /// the host address of the page mapping it, with the page size at
+    /// `QUERY_GPA_SIZE_SHIFT` and the `MemFlags` of the mapping in the low
+    /// bits, or `ENOENT` if not mapped (e.g. an emulated device).
+    fn query_gpa(&mut self, gpaddr: u64) -> HyperCallResult {
+        let gpm = crate::cell::root_cell().gpm.read();
+        let (paddr, flags, size) = gpm
+            .page_table()
+            .query(gpaddr as _)
+            .map_err(|_| hv_err!(ENOENT, "{:#x} is not mapped", gpaddr))?;
+        let size_order = match size {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+        };
+        let frame = paddr - size.page_offset(paddr);
+        Ok(frame | size_order << QUERY_GPA_SIZE_SHIFT | flags.bits() as usize)
+    }
+
+    /// Sets the `ExitPolicy` of the VM exit reason `exit_reason` without
+    /// handler, see `exit_policy`.
+    fn exit_policy_set(&mut self, exit_reason: u64, policy: u64) -> HyperCallResult {
+        let exit_reason = u32::try_from(exit_reason).map_err(|_| hv_err!(EINVAL))?;
+        let policy = u32::try_from(policy)
+            .ok()
+            .and_then(|policy| ExitPolicy::try_from(policy).ok())
+            .ok_or_else(|| hv_err!(EINVAL))?;
+        crate::exit_policy::set(exit_reason, policy)?;
+        Ok(0)
+    }
+
+    /// Prints `size` bytes at guest virtual address `gvaddr` on the hypervisor
+    /// console. From CPL 3, the buffer must be accessible to user space, and
+    /// at most `CONSOLE_WRITE_USER_MAX` bytes.
+    fn console_write(&mut self, gvaddr: u64, size: u64) -> HyperCallResult {
+        let max_size = if self.from_user {
+            CONSOLE_WRITE_USER_MAX
+        } else {
+            CONSOLE_WRITE_MAX
+        };
+        if size > max_size {
+            return hv_result_err!(EINVAL, "Console write of {} bytes is too large", size);
+        }
+        let end = gvaddr.checked_add(size).ok_or_else(|| hv_err!(EINVAL))? as usize;
+        let mut addr = gvaddr as usize;
+        let mut buf = Vec::with_capacity(size as usize);
+        while addr < end {
+            let (gpaddr, flags, page_size) = self.gpt.query_effective(addr)?;
+            if self.from_user && !flags.contains(MemFlags::USER) {
+                return hv_result_err!(EFAULT, "Console buffer {:#x} is not user accessible", addr);
+            }
+            let chunk = (end - addr).min(page_size as usize - addr % page_size as usize);
+            buf.extend(copy_bytes_from_guest(gpaddr, chunk)?);
+            addr += chunk;
+        }
+        crate::console::print(format_args!("{}", String::from_utf8_lossy(&buf)));
+        Ok(size as _)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_lockdown() {
+        let frozen: Vec<_> = (0..32)
+            .filter_map(|code| HyperCallCode::try_from(code).ok())
+            .filter(|code| code.is_frozen_by_lockdown())
+            .collect();
+        assert_eq!(
+            frozen,
+            [
+                HyperCallCode::ProtectRange,
+                HyperCallCode::EventChannelRoute,
+                HyperCallCode::StatsControl,
+                HyperCallCode::MemRelease,
+                HyperCallCode::LatencyRangeSetup,
+                HyperCallCode::ConfigReload,
+                HyperCallCode::ExitPolicySet,
+            ]
+        );
+        // Shutting down and recovering stay possible.
+        for code in [
+            HyperCallCode::HypervisorDisable,
+            HyperCallCode::RtShutdown,
+            HyperCallCode::KexecPrepare,
+            HyperCallCode::CrashPrepare,
+        ] {
+            assert!(!code.is_frozen_by_lockdown());
+        }
+    }
 }
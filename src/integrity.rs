@@ -0,0 +1,146 @@
+//! Kernel code-integrity monitoring.
+//!
+//! Ranges of root cell RAM protected by the `ProtectRange` hypercall with
+//! `PROTECT_MONITOR` are hashed when they are added. A write or instruction
+//! fetch blocked by the nested page table in a monitored range is logged and
+//! reported through the event channel as `IntegrityViolation`. `verify()`
+//! re-hashes all ranges and reports the modified ones as `IntegrityModified`.
+//!
+//! With the `protect-desc-tables` feature, the pages of the IDT loaded by the
+//! root cell when the hypervisor is enabled are protected and monitored the
+//! same way. Linux maps it read-only already, so legitimate writes are not
+//! expected once it is running. The GDT is left alone: Linux rewrites its TLS
+//! entries on every context switch.
+
+use alloc::vec::Vec;
+
+use crate::attest::{sha256, HashValue};
+use crate::error::HvResult;
+use crate::event::{self, HvEventType};
+use crate::lock::SpinLock;
+use crate::memory::addr::GuestPhysAddr;
+use crate::memory::MemFlags;
+
+/// Max number of monitored ranges.
+const MAX_MONITORED_RANGES: usize = 16;
+
+/// `IntegrityViolation`: the access was a write.
+const ACCESS_WRITE: u64 = 1 << 0;
+/// `IntegrityViolation`: the access was an instruction fetch.
+const ACCESS_EXEC: u64 = 1 << 1;
+
+struct MonitoredRange {
+    start: GuestPhysAddr,
+    size: usize,
+    hash: HashValue,
+}
+
+static RANGES: SpinLock<Vec<MonitoredRange>> = SpinLock::new(Vec::new());
+
+fn hash_range(start: GuestPhysAddr, size: usize) -> HvResult<HashValue> {
+    let vaddr = crate::cell::root_cell().guest_ram_to_hv(start, size, MemFlags::READ)?;
+    Ok(sha256(unsafe {
+        core::slice::from_raw_parts(vaddr as *const u8, size)
+    }))
+}
+
+/// Starts monitoring root cell RAM `[start, start + size)`, which should have
+/// been protected already.
+pub fn add_range(start: GuestPhysAddr, size: usize) -> HvResult {
+    let end = match start.checked_add(size) {
+        Some(end) if size != 0 => end,
+        _ => return hv_result_err!(EINVAL, "Invalid monitored range"),
+    };
+    let mut ranges = RANGES.lock();
+    if ranges.len() >= MAX_MONITORED_RANGES {
+        return hv_result_err!(ENOMEM, "Too many monitored ranges");
+    }
+    if ranges
+        .iter()
+        .any(|r| start < r.start + r.size && r.start < end)
+    {
+        return hv_result_err!(EEXIST, "Range is already monitored");
+    }
+    let hash = hash_range(start, size)?;
+    ranges.push(MonitoredRange { start, size, hash });
+    info!("Monitoring guest RAM [{:#x}, {:#x})", start, end);
+    Ok(())
+}
+
+/// Write-protects and monitors the guest pages of the IDT of `linux`. The IDT
+/// is shared by all CPUs, so pages already monitored are skipped.
+#[cfg(feature = "protect-desc-tables")]
+pub fn protect_desc_tables(
+    vcpu: &crate::arch::ArchVcpu,
+    linux: &crate::arch::LinuxContext,
+) -> HvResult {
+    use crate::memory::addr::{align_down, align_up};
+    use crate::memory::{GenericPageTableImmut, PAGE_SIZE};
+
+    static PROTECT_LOCK: SpinLock<()> = SpinLock::new(());
+    let _lock = PROTECT_LOCK.lock();
+    let gpt = vcpu.guest_page_table();
+    let base = linux.idt.base.as_u64() as usize;
+    for gvaddr in
+        (align_down(base)..align_up(base + linux.idt.limit as usize + 1)).step_by(PAGE_SIZE)
+    {
+        let gpaddr = align_down(gpt.query(gvaddr)?.0);
+        if RANGES.lock().iter().any(|r| r.start == gpaddr) {
+            continue;
+        }
+        crate::cell::root_cell().protect_range(gpaddr, PAGE_SIZE, MemFlags::WRITE)?;
+        add_range(gpaddr, PAGE_SIZE)?;
+        info!("IDT page {:#x} protected", gpaddr);
+    }
+    Ok(())
+}
+
+/// Checks a blocked guest access to `gpaddr` at `rip`. Returns `true` and
+/// notifies the root cell if the access hits a monitored range.
+pub fn check_violation(gpaddr: GuestPhysAddr, write: bool, exec: bool, rip: u64) -> bool {
+    let hit = RANGES
+        .lock()
+        .iter()
+        .any(|r| (r.start..r.start + r.size).contains(&gpaddr));
+    if !hit || !(write || exec) {
+        return false;
+    }
+    warn!(
+        "Integrity violation @ {:#x} RIP({:#x}): write={}, exec={}",
+        gpaddr, rip, write, exec
+    );
+    let mut access = 0;
+    if write {
+        access |= ACCESS_WRITE;
+    }
+    if exec {
+        access |= ACCESS_EXEC;
+    }
+    event::send(HvEventType::IntegrityViolation, [gpaddr as _, rip, access]);
+    true
+}
+
+/// Re-hashes all monitored ranges and notifies the root cell of each modified
+/// one. Returns the number of modified ranges.
+pub fn verify() -> usize {
+    let ranges = RANGES.lock();
+    let mut modified = 0;
+    for r in ranges.iter() {
+        match hash_range(r.start, r.size) {
+            Ok(hash) if hash == r.hash => continue,
+            Ok(_) => {}
+            Err(e) => warn!("Failed to hash range {:#x}: {:?}", r.start, e),
+        }
+        warn!(
+            "Monitored guest RAM [{:#x}, {:#x}) is modified",
+            r.start,
+            r.start + r.size
+        );
+        event::send(
+            HvEventType::IntegrityModified,
+            [r.start as _, r.size as _, 0],
+        );
+        modified += 1;
+    }
+    modified
+}
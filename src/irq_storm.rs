@@ -0,0 +1,118 @@
+//! Interrupt storm detection for the vectors raised by the hypervisor.
+//!
+//! The hypervisor raises vectors in the root cell on behalf of mediated
+//! sources, like the event channel fed by machine checks, integrity alerts
+//! and asynchronous operations. A misbehaving source could make it raise them
+//! fast enough to livelock the root cell. The raises of each vector are
+//! counted in windows of `WINDOW_US`: past `THRESHOLD` raises in a window,
+//! the vector is in a storm and further raises are held back until the window
+//! is over, then a single one is delivered for all of them. The event ring
+//! keeps the events meanwhile, so they are coalesced, not lost.
+//!
+//! Storms are logged when they start and counted per vector, see
+//! `HvInfoType::IrqStorms`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::arch::cpu;
+use crate::hal::LocalIrqChip;
+use crate::lock::SpinLock;
+
+/// Length of a counting window.
+const WINDOW_US: u64 = 1000; // 1ms
+/// Max number of raises of a vector delivered in a window.
+const THRESHOLD: u32 = 100;
+
+#[derive(Clone, Copy)]
+struct VectorState {
+    /// TSC at the start of the current window.
+    window_start: u64,
+    raises: u32,
+    /// Whether the last window exceeded the threshold.
+    in_storm: bool,
+    storms: u32,
+    /// Destination of the raises held back in the current window.
+    held: Option<u32>,
+}
+
+impl VectorState {
+    const INIT: Self = Self {
+        window_start: 0,
+        raises: 0,
+        in_storm: false,
+        storms: 0,
+        held: None,
+    };
+}
+
+static VECTORS: SpinLock<[VectorState; 256]> = SpinLock::new([VectorState::INIT; 256]);
+/// Number of vectors with a held back raise.
+static HELD_VECTORS: AtomicU32 = AtomicU32::new(0);
+
+fn window_cycles() -> u64 {
+    WINDOW_US * cpu::frequency() as u64
+}
+
+/// Raises `vector` on the CPU with hardware ID `dest`, unless the vector is
+/// in a storm.
+pub fn raise(dest: u32, vector: u8) {
+    let now = cpu::current_cycle();
+    let mut vectors = VECTORS.lock();
+    let state = &mut vectors[vector as usize];
+    if now.wrapping_sub(state.window_start) >= window_cycles() {
+        // This raise also delivers the one held back, if any.
+        if state.held.take().is_some() {
+            HELD_VECTORS.fetch_sub(1, Ordering::Relaxed);
+        }
+        state.in_storm &= state.raises > THRESHOLD;
+        state.window_start = now;
+        state.raises = 0;
+    }
+    state.raises += 1;
+    if state.raises <= THRESHOLD {
+        drop(vectors);
+        crate::arch::local_irq_chip().send_ipi(dest, vector);
+        return;
+    }
+    if !state.in_storm {
+        state.in_storm = true;
+        state.storms += 1;
+        warn!(
+            "Interrupt storm on vector {:#x}: more than {} raises in {}us, throttled",
+            vector, THRESHOLD, WINDOW_US
+        );
+    }
+    if state.held.replace(dest).is_none() {
+        HELD_VECTORS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Delivers the raises held back in the windows which are over. Called at
+/// the end of each VM exit, skipped if another CPU is already doing it.
+pub fn poll() {
+    if HELD_VECTORS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut vectors = match VECTORS.try_lock() {
+        Some(vectors) => vectors,
+        None => return,
+    };
+    let now = cpu::current_cycle();
+    let window = window_cycles();
+    for (vector, state) in vectors.iter_mut().enumerate() {
+        if now.wrapping_sub(state.window_start) < window {
+            continue;
+        }
+        if let Some(dest) = state.held.take() {
+            HELD_VECTORS.fetch_sub(1, Ordering::Relaxed);
+            state.window_start = now;
+            state.raises = 1;
+            crate::arch::local_irq_chip().send_ipi(dest, vector as u8);
+        }
+    }
+}
+
+/// Number of storms detected on `vector`.
+pub fn storm_count(vector: u8) -> u32 {
+    VECTORS.lock()[vector as usize].storms
+}
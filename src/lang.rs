@@ -1,7 +1,9 @@
 use core::alloc::Layout;
 use core::panic::PanicInfo;
 
+use crate::cell::CellState;
 use crate::error::HvResult;
+use crate::hal::Vcpu;
 use crate::percpu::{CpuState, PerCpu};
 
 fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
@@ -19,8 +21,12 @@ fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    crate::memory::set_emergency(true);
     let cpu_data = PerCpu::current_mut();
     error!("\n{}\nCurrent Cpu: {:#x?}", info, cpu_data);
+    if let Some(cell) = crate::cell::try_root_cell() {
+        let _ = cell.lifecycle.transition(CellState::Failed);
+    }
     let err = try_handle_panic(cpu_data);
     error!("Try handle panic failed: {:?}", err);
     loop {}
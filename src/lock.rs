@@ -0,0 +1,294 @@
+//! Spin lock recording its owner.
+//!
+//! A `SpinLock` remembers which CPU holds it and where it was taken, and
+//! keeps local interrupts disabled while held. Taking a lock already held by
+//! the current CPU can only happen from an NMI or exception interrupting the
+//! owner, and would spin forever: it panics instead, naming both places.
+//! Locks taken from NMI context use `lock_reentrant()`, which proceeds
+//! without the lock in that case (the console), or `lock_unless_owned()`,
+//! which gives up (the log ring).
+//!
+//! While a CPU spins, the lock it waits for is published, so that the
+//! watchdog can tell who holds it when that CPU gets stuck.
+//!
+//! The owner is published by the compare-and-swap taking the lock, so that an
+//! NMI interrupting the owner right after it always sees that it holds the
+//! lock. Read-write locks are still the ones of `spin`.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Debug, Formatter};
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+use crate::arch::cpu;
+use crate::consts::MAX_CPUS;
+
+/// `LockOwner::state` of a free lock.
+const UNLOCKED: u32 = 0;
+/// `LockOwner::state` of a lock held by a CPU not entered into the
+/// hypervisor, which has no ID.
+const UNKNOWN_OWNER: u32 = u32::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NULL: AtomicPtr<LockOwner> = AtomicPtr::new(null_mut());
+
+/// Owner of the lock each CPU is spinning on, if any.
+static WAITING_ON: [AtomicPtr<LockOwner>; MAX_CPUS] = [NULL; MAX_CPUS];
+
+struct LockOwner {
+    /// `UNLOCKED`, or the ID of the owner CPU plus one.
+    state: AtomicU32,
+    location: AtomicPtr<Location<'static>>,
+}
+
+pub struct SpinLock<T: ?Sized> {
+    owner: LockOwner,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    /// False if the guard was granted to an NMI or exception interrupting
+    /// the owner, which must not release the lock.
+    release: bool,
+    irq_enabled: bool,
+}
+
+/// `LockOwner::state` of the locks taken by the current CPU.
+#[cfg(not(test))]
+fn current_owner() -> u32 {
+    crate::percpu::PerCpu::try_current_mut().map_or(UNKNOWN_OWNER, |c| c.id + 1)
+}
+
+/// Host tests have no per-CPU data.
+#[cfg(test)]
+fn current_owner() -> u32 {
+    UNKNOWN_OWNER
+}
+
+/// Like `cpu::local_irq_save()`, which host tests cannot run.
+fn local_irq_save() -> bool {
+    !cfg!(test) && cpu::local_irq_save()
+}
+
+impl LockOwner {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            location: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    fn get(&self) -> Option<(u32, &'static Location<'static>)> {
+        let state = self.state.load(Ordering::Relaxed);
+        let location = self.location.load(Ordering::Relaxed);
+        if state == UNLOCKED || state == UNKNOWN_OWNER || location.is_null() {
+            return None;
+        }
+        Some((state - 1, unsafe { &*location }))
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: LockOwner::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Takes the lock, spinning until it is free.
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        match self.acquire(Location::caller()) {
+            Ok(guard) => guard,
+            Err(guard) => {
+                let location = self.owner.get().map(|(_, l)| l);
+                drop(guard);
+                panic!(
+                    "Deadlock: lock taken at {} is already held by this CPU at {:?}",
+                    Location::caller(),
+                    location
+                );
+            }
+        }
+    }
+
+    /// Takes the lock, unless the current CPU already holds it, in which case
+    /// the caller interrupted the owner and shares the data with it. Only
+    /// for data that tolerates such interleaving, like console output.
+    #[track_caller]
+    pub fn lock_reentrant(&self) -> SpinLockGuard<T> {
+        self.acquire(Location::caller()).unwrap_or_else(|g| g)
+    }
+
+    /// Takes the lock, unless the current CPU already holds it, in which case
+    /// the caller interrupted the owner and gets `None`. For data the caller
+    /// can do without, like log records.
+    #[track_caller]
+    pub fn lock_unless_owned(&self) -> Option<SpinLockGuard<T>> {
+        self.acquire(Location::caller()).ok()
+    }
+
+    /// Takes the lock if it is free.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let irq_enabled = local_irq_save();
+        if self.try_acquire(current_owner(), Location::caller()) {
+            Some(SpinLockGuard {
+                lock: self,
+                release: true,
+                irq_enabled,
+            })
+        } else {
+            cpu::local_irq_restore(irq_enabled);
+            None
+        }
+    }
+
+    fn try_acquire(&self, owner: u32, location: &'static Location<'static>) -> bool {
+        if self
+            .owner
+            .state
+            .compare_exchange(UNLOCKED, owner, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        self.owner
+            .location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        true
+    }
+
+    fn release(&self) {
+        self.owner.state.store(UNLOCKED, Ordering::Release);
+    }
+
+    /// Spins until the lock is taken, or returns a non-releasing guard in
+    /// `Err` if the current CPU already holds it.
+    fn acquire(
+        &self,
+        location: &'static Location<'static>,
+    ) -> Result<SpinLockGuard<T>, SpinLockGuard<T>> {
+        let irq_enabled = local_irq_save();
+        let owner = current_owner();
+        let mut guard = SpinLockGuard {
+            lock: self,
+            release: true,
+            irq_enabled,
+        };
+        if self.try_acquire(owner, location) {
+            return Ok(guard);
+        }
+        if owner != UNKNOWN_OWNER && self.owner.state.load(Ordering::Relaxed) == owner {
+            guard.release = false;
+            return Err(guard);
+        }
+        let waiting = WAITING_ON.get(owner.wrapping_sub(1) as usize);
+        if let Some(w) = waiting {
+            w.store(&self.owner as *const _ as *mut _, Ordering::Release);
+        }
+        while !self.try_acquire(owner, location) {
+            while self.owner.state.load(Ordering::Relaxed) != UNLOCKED {
+                core::hint::spin_loop();
+            }
+        }
+        if let Some(w) = waiting {
+            w.store(null_mut(), Ordering::Release);
+        }
+        Ok(guard)
+    }
+}
+
+/// The owner of the lock the CPU `cpu_id` is spinning on, and where it took
+/// the lock.
+pub fn waiting_on(cpu_id: u32) -> Option<(u32, &'static Location<'static>)> {
+    let owner = WAITING_ON.get(cpu_id as usize)?.load(Ordering::Acquire);
+    if owner.is_null() {
+        return None;
+    }
+    // The lock outlives the CPU spinning on it.
+    unsafe { &*owner }.get()
+}
+
+impl<T: ?Sized + Debug> Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("SpinLock").field("data", &&*guard).finish(),
+            None => write!(f, "SpinLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.release {
+            self.lock.release();
+        }
+        cpu::local_irq_restore(self.irq_enabled);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_owner() {
+        let lock = SpinLock::new(0);
+        let location = Location::caller();
+        assert!(lock.owner.get().is_none());
+        assert!(lock.try_acquire(3 + 1, location));
+        // The owner is known as soon as the lock is taken.
+        assert_eq!(lock.owner.get().map(|(id, _)| id), Some(3));
+        assert!(!lock.try_acquire(5 + 1, location));
+        assert_eq!(lock.owner.get().map(|(id, _)| id), Some(3));
+        lock.release();
+        assert!(lock.owner.get().is_none());
+        assert!(lock.try_acquire(UNKNOWN_OWNER, location));
+        assert!(lock.owner.get().is_none());
+        assert!(lock.try_lock().is_none());
+        lock.release();
+    }
+
+    #[test]
+    fn test_guard() {
+        let lock = SpinLock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+        let guard = lock.lock();
+        // Without per-CPU data, the owner is never the current CPU.
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(lock.lock_unless_owned().is_some());
+        assert_eq!(lock.into_inner(), 2);
+    }
+}
@@ -1,23 +1,234 @@
+//! Logger and `print!()`.
+//!
+//! Logging adapts to rare events: the first `BOOST_OCCURRENCES` occurrences
+//! of each VM exit reason and error path are handled with the most verbose
+//! level of the build, on the CPU handling them only (until the end of the
+//! VM exit), then the configured level applies again. Failures on new paths
+//! thus come with their debug and trace records, without running the field
+//! with a verbose configuration.
+
 use {
-    core::fmt,
+    crate::consts::MAX_CPUS,
+    core::fmt::{self, Write},
+    core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
     log::{self, Level, LevelFilter, Log, Metadata, Record},
 };
 
+/// Occurrences of each exit reason and error path logged in detail.
+const BOOST_OCCURRENCES: u32 = 4;
+/// Number of exit reasons and error paths tracked. Once full, new ones are
+/// not logged in detail.
+const BOOST_SLOTS: usize = 256;
+
+const KEY_EXIT_REASON: u64 = 1 << 63;
+const KEY_ERROR_PATH: u64 = 1 << 62;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U64: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_U32: AtomicU32 = AtomicU32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+
+/// Exit reasons and error paths seen, 0 for a free slot.
+static SEEN_KEYS: [AtomicU64; BOOST_SLOTS] = [ZERO_U64; BOOST_SLOTS];
+static SEEN_COUNTS: [AtomicU32; BOOST_SLOTS] = [ZERO_U32; BOOST_SLOTS];
+/// Whether each CPU logs at the level of the build.
+static BOOSTED: [AtomicBool; MAX_CPUS] = [FALSE; MAX_CPUS];
+static NUM_BOOSTED: AtomicU32 = AtomicU32::new(0);
+/// The level of the configuration, capped by the level of the build.
+static CONFIGURED_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether to output JSON-lines log records instead of colored text.
+fn log_format_json() -> bool {
+    cfg!(feature = "log-json")
+}
+
+/// The most verbose level selected by the `log-*` features.
+fn max_level() -> LevelFilter {
+    if cfg!(feature = "log-trace") {
+        LevelFilter::Trace
+    } else if cfg!(feature = "log-debug") {
+        LevelFilter::Debug
+    } else if cfg!(feature = "log-info") {
+        LevelFilter::Info
+    } else if cfg!(feature = "log-warn") {
+        LevelFilter::Warn
+    } else if cfg!(feature = "log-error") {
+        LevelFilter::Error
+    } else {
+        LevelFilter::Off
+    }
+}
+
+fn configured_level() -> LevelFilter {
+    match CONFIGURED_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Lets the `log` macros through up to the level of the build while a CPU is
+/// boosted, the logger filters the records of the other CPUs.
+fn update_max_level() {
+    if NUM_BOOSTED.load(Ordering::Acquire) > 0 {
+        log::set_max_level(max_level());
+    } else {
+        log::set_max_level(configured_level());
+    }
+}
+
 pub fn init() {
     log::set_logger(&SimpleLogger).unwrap();
-    log::set_max_level(match option_env!("LOG") {
-        Some("error") => LevelFilter::Error,
-        Some("warn") => LevelFilter::Warn,
-        Some("info") => LevelFilter::Info,
-        Some("debug") => LevelFilter::Debug,
-        Some("trace") => LevelFilter::Trace,
-        _ => LevelFilter::Off,
-    });
+    CONFIGURED_LEVEL.store(max_level() as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Caps the max level at `level` of the configuration (1 = error, ...,
+/// 5 = trace), or restores the level of the build if 0.
+pub fn set_level(level: u32) {
+    let filter = match level {
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5 => LevelFilter::Trace,
+        _ => LevelFilter::max(),
+    };
+    CONFIGURED_LEVEL.store(filter.min(max_level()) as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Counts an occurrence of `key`, returns the number of occurrences so far
+/// (capped past `BOOST_OCCURRENCES`), or `None` if there is no free slot.
+fn count_occurrence(key: u64) -> Option<u32> {
+    let start = (key ^ key >> 29).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56;
+    for i in 0..BOOST_SLOTS {
+        let slot = (start as usize + i) % BOOST_SLOTS;
+        // Only free slots are claimed: a failed CAS on the slot of another
+        // key would still take its cache line exclusive, on every VM exit.
+        let seen = &SEEN_KEYS[slot];
+        let mut k = seen.load(Ordering::Acquire);
+        if k == 0 {
+            k = match seen.compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => key,
+                Err(k) => k,
+            };
+        }
+        if k != key {
+            continue;
+        }
+        let count = &SEEN_COUNTS[slot];
+        if count.load(Ordering::Relaxed) > BOOST_OCCURRENCES {
+            return Some(BOOST_OCCURRENCES + 1);
+        }
+        return Some(count.fetch_add(1, Ordering::Relaxed) + 1);
+    }
+    None
+}
+
+/// Limits a record the guest can trigger at will to one per interval, so
+/// that it cannot flood the console.
+pub struct RateLimit {
+    interval_ms: u64,
+    /// Cycle before which occurrences are suppressed.
+    next_cycle: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl RateLimit {
+    pub const fn new(interval_ms: u64) -> Self {
+        Self {
+            interval_ms,
+            next_cycle: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether an occurrence may be logged now, with the number of
+    /// occurrences suppressed since the last logged one.
+    pub fn check(&self) -> Option<u64> {
+        let now = crate::arch::cpu::current_cycle();
+        let next = self.next_cycle.load(Ordering::Relaxed);
+        let interval = self.interval_ms * 1000 * crate::arch::cpu::frequency() as u64;
+        if now < next
+            || self
+                .next_cycle
+                .compare_exchange(next, now + interval, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Boosts the current CPU if `key` is among its first occurrences.
+fn boost(key: u64, what: fmt::Arguments) {
+    let cpu_id = match crate::percpu::PerCpu::try_current_mut() {
+        Some(cpu) => cpu.id as usize,
+        None => return,
+    };
+    let count = match count_occurrence(key) {
+        Some(count) if count <= BOOST_OCCURRENCES => count,
+        _ => return,
+    };
+    if let Some(boosted) = BOOSTED.get(cpu_id) {
+        if !boosted.swap(true, Ordering::AcqRel) {
+            NUM_BOOSTED.fetch_add(1, Ordering::AcqRel);
+            update_max_level();
+        }
+        info!(
+            "Detailed logging for occurrence {}/{} of {}",
+            count, BOOST_OCCURRENCES, what
+        );
+    }
+}
+
+/// Notes a VM exit with raw exit reason `reason`, before it is handled.
+pub fn note_exit_reason(reason: u32) {
+    boost(
+        KEY_EXIT_REASON | reason as u64,
+        format_args!("exit reason {:#x}", reason),
+    );
+}
+
+/// Notes an error constructed at `file:line`.
+pub fn note_error_path(file: &'static str, line: u32) {
+    let key = ((file.as_ptr() as u64) << 16 ^ line as u64) & !(KEY_EXIT_REASON | KEY_ERROR_PATH);
+    boost(
+        KEY_ERROR_PATH | key,
+        format_args!("error path {}:{}", file, line),
+    );
+}
+
+/// Whether the current CPU logs in detail, e.g. to dump the guest state only
+/// for the first failures of a kind.
+pub fn is_boosted() -> bool {
+    crate::percpu::PerCpu::try_current_mut()
+        .and_then(|cpu| BOOSTED.get(cpu.id as usize))
+        .map_or(false, |b| b.load(Ordering::Relaxed))
+}
+
+/// Ends the detailed logging of the current CPU. Called at the end of each
+/// VM exit.
+pub fn unboost() {
+    let cpu_id = crate::percpu::PerCpu::current().id as usize;
+    if let Some(boosted) = BOOSTED.get(cpu_id) {
+        if boosted.swap(false, Ordering::AcqRel) {
+            NUM_BOOSTED.fetch_sub(1, Ordering::AcqRel);
+            update_max_level();
+        }
+    }
 }
 
-#[allow(dead_code)]
 pub fn print(args: fmt::Arguments) {
-    crate::arch::serial::putfmt(args);
+    crate::console::print(args);
 }
 
 #[cfg(not(test))]
@@ -63,8 +274,57 @@ enum ColorCode {
     BrightWhite = 97,
 }
 
+/// Escapes the formatted output as a JSON string (without quotes).
+struct JsonEscaped<'a>(fmt::Arguments<'a>);
+
+struct JsonEscapeWriter<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl Write for JsonEscapeWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for JsonEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        JsonEscapeWriter(f).write_fmt(self.0)
+    }
+}
+
 struct SimpleLogger;
 
+/// A log record in JSON-lines format.
+struct JsonRecord<'a> {
+    record: &'a Record<'a>,
+    time_micros: u64,
+    cpu_id: u32,
+}
+
+impl fmt::Display for JsonRecord<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{{\"ts_us\":{},\"level\":\"{}\",\"cpu\":{},\"module\":\"{}\",\"msg\":\"{}\"}}",
+            self.time_micros,
+            self.record.level(),
+            self.cpu_id,
+            self.record.module_path().unwrap_or(""),
+            JsonEscaped(*self.record.args()),
+        )
+    }
+}
+
 impl Log for SimpleLogger {
     fn enabled(&self, _metadata: &Metadata) -> bool {
         true
@@ -73,9 +333,25 @@ impl Log for SimpleLogger {
         if !self.enabled(record.metadata()) {
             return;
         }
+        let cpu_id = crate::percpu::PerCpu::current().id;
+        if record.level() > configured_level() && !is_boosted() {
+            return;
+        }
 
         let time_micros = crate::arch::cpu::current_time_nanos() / 1000;
-        let cpu_id = crate::percpu::PerCpu::current().id;
+        if log_format_json() {
+            let record = JsonRecord {
+                record,
+                time_micros,
+                cpu_id,
+            };
+            if !crate::console::is_log_ring() {
+                print(format_args!("{}", record));
+            }
+            crate::stats_window::log_write(format_args!("{}", record));
+            return;
+        }
+
         let level = record.level();
         let level_color = match level {
             Level::Error => ColorCode::BrightRed,
@@ -92,15 +368,54 @@ impl Log for SimpleLogger {
             Level::Trace => ColorCode::BrightBlack,
         };
 
-        print(with_color!(
-            ColorCode::White,
-            "[{:>4}.{:06} {} {} {}\n",
+        if !crate::console::is_log_ring() {
+            print(with_color!(
+                ColorCode::White,
+                "[{:>4}.{:06} {} {} {}\n",
+                time_micros / 1_000_000,
+                time_micros % 1_000_000,
+                with_color!(level_color, "{:<5}", level),
+                with_color!(ColorCode::White, "{}]", cpu_id),
+                with_color!(args_color, "{}", record.args()),
+            ));
+        }
+        crate::stats_window::log_write(format_args!(
+            "[{:>4}.{:06} {:<5} {}] {}\n",
             time_micros / 1_000_000,
             time_micros % 1_000_000,
-            with_color!(level_color, "{:<5}", level),
-            with_color!(ColorCode::White, "{}]", cpu_id),
-            with_color!(args_color, "{}", record.args()),
+            level,
+            cpu_id,
+            record.args(),
         ));
     }
     fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_count_occurrence() {
+        let key = KEY_EXIT_REASON | 0x1234;
+        for count in 1..=BOOST_OCCURRENCES {
+            assert_eq!(count_occurrence(key), Some(count));
+        }
+        assert_eq!(count_occurrence(key), Some(BOOST_OCCURRENCES + 1));
+        assert_eq!(count_occurrence(key), Some(BOOST_OCCURRENCES + 1));
+        assert_eq!(count_occurrence(KEY_ERROR_PATH | 0x1234), Some(1));
+
+        // Keys go on past the occupied slots, until none is left.
+        let num_keys = SEEN_KEYS
+            .iter()
+            .filter(|k| k.load(Ordering::Relaxed) != 0)
+            .count();
+        for reason in 0..(BOOST_SLOTS - num_keys) as u64 {
+            assert_eq!(
+                count_occurrence(KEY_EXIT_REASON | 0x10000 | reason),
+                Some(1)
+            );
+        }
+        assert_eq!(count_occurrence(KEY_EXIT_REASON | 0x20000), None);
+        assert_eq!(count_occurrence(key), Some(BOOST_OCCURRENCES + 1));
+    }
+}
@@ -20,14 +20,33 @@ mod logging;
 #[macro_use]
 mod error;
 
+mod attest;
+mod build_info;
 mod cell;
 mod config;
+mod console;
 mod consts;
+mod cpuset;
+mod event;
+mod exit_policy;
+mod fault_inject;
+mod hal;
 mod header;
 mod hypercall;
+mod integrity;
+mod irq_storm;
+mod lock;
+#[cfg(feature = "mem-bench")]
+mod mem_bench;
+mod mem_heat;
 mod memory;
+mod mirror;
+mod mmio;
 mod percpu;
+mod rip_latency;
 mod stats;
+mod stats_window;
+mod vector;
 
 #[cfg(not(test))]
 mod lang;
@@ -36,17 +55,22 @@ mod lang;
 #[path = "arch/x86_64/mod.rs"]
 mod arch;
 
-use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
 
+use cell::CellState;
 use config::HvSystemConfig;
 use error::HvResult;
 use header::HvHeader;
 use percpu::PerCpu;
+use stats::{InitPhase, Instant};
 
 static INITED_CPUS: AtomicU32 = AtomicU32::new(0);
+static INIT_MEMORY_OK: AtomicU32 = AtomicU32::new(0);
 static INIT_EARLY_OK: AtomicU32 = AtomicU32::new(0);
 static INIT_LATE_OK: AtomicU32 = AtomicU32::new(0);
 static ERROR_NUM: AtomicI32 = AtomicI32::new(0);
+static MEMORY_INIT_CYCLES: AtomicU64 = AtomicU64::new(0);
+static HV_PT_INIT_CYCLES: AtomicU64 = AtomicU64::new(0);
 
 fn has_err() -> bool {
     ERROR_NUM.load(Ordering::Acquire) != 0
@@ -67,9 +91,12 @@ fn wait_for_counter(counter: &AtomicU32, max_value: u32) -> HvResult {
     wait_for(|| counter.load(Ordering::Acquire) < max_value)
 }
 
-fn primary_init_early() -> HvResult {
+/// Memory initialization of the primary CPU. Done by `efi_entry()` if the
+/// hypervisor is loaded by an EFI driver, otherwise when the primary CPU
+/// enters from Linux.
+fn primary_init_memory() -> HvResult {
     logging::init();
-    info!("Primary CPU init early...");
+    info!("Primary CPU init memory...");
 
     let system_config = HvSystemConfig::get();
     println!(
@@ -77,29 +104,58 @@ fn primary_init_early() -> HvResult {
         Initializing hypervisor...\n\
         config_signature = {:?}\n\
         config_revision = {}\n\
-        build_mode = {}\n\
-        log_level = {}\n\
-        arch = {}\n\
-        vendor = {}\n\
-        stats = {}\n\
+        build_id = {:02x?}\n\
+        build_profile = {}\n\
+        target = {}\n\
+        features = {}\n\
         ",
         core::str::from_utf8(&system_config.signature),
         system_config.revision,
-        option_env!("MODE").unwrap_or(""),
-        option_env!("LOG").unwrap_or(""),
-        option_env!("ARCH").unwrap_or(""),
-        option_env!("VENDOR").unwrap_or(""),
-        option_env!("STATS").unwrap_or("off"),
+        build_info::BUILD_ID,
+        build_info::PROFILE,
+        build_info::TARGET,
+        build_info::FEATURES,
     );
 
+    let now = Instant::now();
     memory::init_heap();
+    let mut memory_cycles = now.elapsed();
     system_config.check()?;
-    info!("Hypervisor header: {:#x?}", HvHeader::get());
+    system_config.apply_runtime_params();
     debug!("System config: {:#x?}", system_config);
 
+    let now = Instant::now();
     memory::init_frame_allocator();
+    memory_cycles += now.elapsed();
+    let now = Instant::now();
     memory::init_hv_page_table()?;
+    MEMORY_INIT_CYCLES.store(memory_cycles, Ordering::Relaxed);
+    HV_PT_INIT_CYCLES.store(now.elapsed(), Ordering::Relaxed);
+
+    INIT_MEMORY_OK.store(1, Ordering::Release);
+    Ok(())
+}
+
+fn primary_init_early() -> HvResult {
+    if INIT_MEMORY_OK.load(Ordering::Acquire) == 0 {
+        primary_init_memory()?;
+    }
+    info!("Primary CPU init early...");
+    header::negotiate()?;
+    info!("Hypervisor header: {:#x?}", HvHeader::get());
+    stats_window::init()?;
+
+    let cpu_id = PerCpu::current().id;
+    let memory_cycles = MEMORY_INIT_CYCLES.load(Ordering::Relaxed);
+    let hv_pt_cycles = HV_PT_INIT_CYCLES.load(Ordering::Relaxed);
+    stats::report_init_phase(cpu_id, InitPhase::MemoryInit, memory_cycles);
+    stats::report_init_phase(cpu_id, InitPhase::HvPageTable, hv_pt_cycles);
+    let now = Instant::now();
     cell::init()?;
+    stats::report_init_phase(cpu_id, InitPhase::CellInit, now.elapsed());
+    // The MMIO registers and ACPI tables used below are only mapped by the
+    // hypervisor page table, the other CPUs switch to it in `PerCpu::init()`.
+    unsafe { memory::hv_page_table().read().activate() };
     arch::init_early()?;
 
     INIT_EARLY_OK.store(1, Ordering::Release);
@@ -108,11 +164,18 @@ fn primary_init_early() -> HvResult {
 
 fn primary_init_late() -> HvResult {
     info!("Primary CPU init late...");
+    console::init();
+    mirror::init();
+    cell::root_cell().lifecycle.transition(CellState::Running)?;
+    if HvSystemConfig::get().lockdown() {
+        info!("Configuration locked down until the hypervisor is disabled.");
+    }
     INIT_LATE_OK.store(1, Ordering::Release);
     Ok(())
 }
 
 fn main(cpu_data: &mut PerCpu, linux_sp: usize) -> HvResult {
+    cpu_data.save_linux(linux_sp);
     let is_primary = cpu_data.id == 0;
     let vm_cpus = HvHeader::get().vm_cpus();
     wait_for(|| PerCpu::entered_cpus() < vm_cpus)?;
@@ -128,11 +191,18 @@ fn main(cpu_data: &mut PerCpu, linux_sp: usize) -> HvResult {
         wait_for_counter(&INIT_EARLY_OK, 1)?;
     }
 
-    cpu_data.init(linux_sp, cell::root_cell())?;
+    cpu_data.init(cell::root_cell())?;
     println!("CPU {} init OK.", cpu_data.id);
     INITED_CPUS.fetch_add(1, Ordering::SeqCst);
     wait_for_counter(&INITED_CPUS, vm_cpus)?;
 
+    #[cfg(feature = "mem-bench")]
+    if is_primary {
+        mem_bench::run();
+    } else {
+        mem_bench::stress();
+    }
+
     if is_primary {
         primary_init_late()?;
     } else {
@@ -146,11 +216,31 @@ extern "sysv64" fn vm_cpu_entry(cpu_data: &mut PerCpu, linux_sp: usize) -> i32 {
     if let Err(e) = main(cpu_data, linux_sp) {
         error!("{:?}", e);
         ERROR_NUM.store(e.code(), Ordering::Release);
+        crate::arch::iommu::disable();
     }
     let code = ERROR_NUM.load(Ordering::Acquire);
     println!(
         "CPU {} return back to driver with code {}.",
         cpu_data.id, code
     );
+    cpu_data.restore_linux_page_table();
+    code
+}
+
+/// Entry of the primary CPU when the hypervisor is loaded by an EFI driver,
+/// before Linux boots, on the stack set up by `arch::efi_entry`. Returns 0 or
+/// a negative errno.
+extern "sysv64" fn efi_main() -> i32 {
+    let code = match primary_init_memory() {
+        Ok(()) => {
+            unsafe { header::set_efi_inited(true) };
+            0
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            e.code()
+        }
+    };
+    println!("Return back to the EFI driver with code {}.", code);
     code
 }
@@ -0,0 +1,159 @@
+//! Boot-time memory latency and bandwidth benchmark.
+//!
+//! Built with `MEM_BENCH=on`, the hypervisor measures memory access latency
+//! and read bandwidth while enabling, first with the other CPUs idle, then
+//! while they stream writes over a buffer larger than the last level cache as
+//! Linux workloads would. The results are printed on the console, so that the
+//! cache and memory bandwidth partitioning settings can be checked: with
+//! effective isolation, both runs give about the same numbers.
+//!
+//! RT CPUs only run RTOS code, so the measurement runs on the primary CPU,
+//! which should be given the same partitioning settings (e.g. class of
+//! service) as the RT CPUs for the results to be meaningful.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::arch::cpu;
+use crate::error::HvResult;
+use crate::memory::{Frame, PAGE_SIZE};
+
+/// Size of the buffer walked by the latency measurement.
+const LATENCY_BUF_SIZE: usize = 2 * 1024 * 1024;
+/// Size of the buffer shared by the stressing CPUs.
+const STRESS_BUF_SIZE: usize = 16 * 1024 * 1024;
+const CACHE_LINE_SIZE: usize = 64;
+/// Number of passes over the latency buffer for each measurement.
+const PASSES: usize = 4;
+/// Time for the stressing CPUs to fill the caches before measuring.
+const STRESS_WARMUP_US: u64 = 10 * 1000; // 10ms
+
+const PHASE_IDLE: u32 = 0;
+const PHASE_STRESS: u32 = 1;
+const PHASE_DONE: u32 = 2;
+
+static PHASE: AtomicU32 = AtomicU32::new(PHASE_IDLE);
+/// Virtual address of the stress buffer.
+static STRESS_BUF: AtomicUsize = AtomicUsize::new(0);
+/// Number of CPUs done with the stress buffer.
+static STRESS_DONE_CPUS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy)]
+struct BenchResult {
+    /// Average cycles per dependent load.
+    latency_cycles: u64,
+    /// Sequential read bandwidth in MB/s.
+    bandwidth_mbps: u64,
+}
+
+/// Link the cache lines of `buf` into a single cycle in a pseudo-random
+/// order, so that prefetchers can not hide the latency.
+fn build_chain(buf: &mut Frame) {
+    let lines = buf.size() / CACHE_LINE_SIZE;
+    let base = buf.as_mut_ptr() as *mut usize;
+    let mut order = (0..lines).collect::<alloc::vec::Vec<_>>();
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    for i in (1..lines).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        order.swap(i, seed as usize % (i + 1));
+    }
+    for i in 0..lines {
+        let next = order[(i + 1) % lines];
+        unsafe { *base.add(order[i] * CACHE_LINE_SIZE / 8) = next * CACHE_LINE_SIZE / 8 };
+    }
+}
+
+fn measure(buf: &Frame) -> BenchResult {
+    let base = buf.as_ptr() as *const usize;
+    let loads = buf.size() / CACHE_LINE_SIZE * PASSES;
+    let mut idx = 0;
+    let start = cpu::current_cycle();
+    for _ in 0..loads {
+        idx = unsafe { core::ptr::read_volatile(base.add(idx)) };
+    }
+    let latency_cycles = (cpu::current_cycle() - start) / loads as u64;
+
+    let start = cpu::current_cycle();
+    for _ in 0..PASSES {
+        for i in 0..buf.size() / 8 {
+            unsafe { core::ptr::read_volatile(base.add(i)) };
+        }
+    }
+    let cycles = (cpu::current_cycle() - start).max(1);
+    // bytes / (cycles / MHz) = bytes per us = MB/s
+    let bandwidth_mbps = (buf.size() * PASSES) as u64 * cpu::frequency() as u64 / cycles;
+    BenchResult {
+        latency_cycles,
+        bandwidth_mbps,
+    }
+}
+
+fn print_result(name: &str, res: &BenchResult) {
+    println!(
+        "  {:<8} latency {:>4} cycles ({:>4} ns), read bandwidth {:>6} MB/s",
+        name,
+        res.latency_cycles,
+        res.latency_cycles * 1000 / cpu::frequency() as u64,
+        res.bandwidth_mbps
+    );
+}
+
+fn bench() -> HvResult {
+    let mut buf = Frame::new_contiguous(LATENCY_BUF_SIZE / PAGE_SIZE, 0)?;
+    build_chain(&mut buf);
+    // Warm up the caches and the TLB.
+    measure(&buf);
+    let idle = measure(&buf);
+
+    let stress_buf = Frame::new_contiguous(STRESS_BUF_SIZE / PAGE_SIZE, 0)?;
+    STRESS_BUF.store(stress_buf.as_mut_ptr() as usize, Ordering::Release);
+    PHASE.store(PHASE_STRESS, Ordering::Release);
+    let warmup_end = cpu::current_cycle() + STRESS_WARMUP_US * cpu::frequency() as u64;
+    while cpu::current_cycle() < warmup_end {
+        core::hint::spin_loop();
+    }
+    let stress = measure(&buf);
+    PHASE.store(PHASE_DONE, Ordering::Release);
+    // Keep the stress buffer until no CPU writes to it.
+    let stress_cpus = crate::header::HvHeader::get().vm_cpus() - 1;
+    while STRESS_DONE_CPUS.load(Ordering::Acquire) < stress_cpus {
+        core::hint::spin_loop();
+    }
+    drop(stress_buf);
+
+    println!(
+        "Memory benchmark ({} KB buffer, {} stressing CPUs):",
+        LATENCY_BUF_SIZE / 1024,
+        stress_cpus
+    );
+    print_result("idle", &idle);
+    print_result("stressed", &stress);
+    Ok(())
+}
+
+/// Run the benchmark on the primary CPU, while the other CPUs are in
+/// `stress()`. Failures are only reported, as the benchmark is not needed by
+/// the hypervisor.
+pub fn run() {
+    if let Err(e) = bench() {
+        warn!("Memory benchmark failed: {:?}", e);
+    }
+    PHASE.store(PHASE_DONE, Ordering::Release);
+}
+
+/// Stream writes over a buffer on secondary CPUs until `run()` completes.
+pub fn stress() {
+    while PHASE.load(Ordering::Acquire) == PHASE_IDLE {
+        core::hint::spin_loop();
+    }
+    let base = STRESS_BUF.load(Ordering::Acquire) as *mut u64;
+    let mut value = 0;
+    while PHASE.load(Ordering::Acquire) == PHASE_STRESS {
+        for i in (0..STRESS_BUF_SIZE / 8).step_by(CACHE_LINE_SIZE / 8) {
+            unsafe { core::ptr::write_volatile(base.add(i), value) };
+        }
+        value += 1;
+    }
+    STRESS_DONE_CPUS.fetch_add(1, Ordering::Release);
+}
@@ -0,0 +1,124 @@
+//! Memory access pattern sampling.
+//!
+//! While statistics are enabled, the accessed flags of the root cell nested
+//! page table are collected and cleared every `SAMPLE_INTERVAL_US`. The guest
+//! physical address space below the end of the cell RAM is split into at most
+//! `MAX_CHUNKS` power-of-two chunks (2 MiB at least), and each chunk counts the
+//! samples in which one of its pages was accessed. Users read the counts with
+//! the `HypervisorGetInfo` hypercall to find the hot parts of memory, e.g. to
+//! place shared buffers and tune cache partitioning.
+//!
+//! The nested page table is walked under its read lock, `WALK_BATCH_SIZE`
+//! bytes of guest physical memory at a time, so that the CPUs changing
+//! mappings, e.g. on nested page faults, are not stalled for a whole walk.
+//!
+//! Pages are only accounted at the granularity they are mapped with, so a hot
+//! 1 GiB page marks all its chunks. EPT accessed flags require the processor
+//! support for EPT A/D bits; without it, no page is ever reported.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::cpu;
+use crate::lock::SpinLock;
+use crate::memory::MemFlags;
+
+const MAX_CHUNKS: usize = 1024;
+const MIN_CHUNK_SHIFT: u32 = 21; // 2 MiB
+const SAMPLE_INTERVAL_US: u64 = 100 * 1000; // 100ms
+const WALK_BATCH_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+struct MemHeat {
+    ram_end: usize,
+    chunk_shift: u32,
+    samples: u32,
+    hits: [u32; MAX_CHUNKS],
+}
+
+static MEM_HEAT: SpinLock<Option<MemHeat>> = SpinLock::new(None);
+static NEXT_SAMPLE_CYCLE: AtomicU64 = AtomicU64::new(0);
+
+impl MemHeat {
+    fn new() -> Self {
+        let ram_end = crate::cell::root_cell()
+            .config
+            .mem_regions()
+            .iter()
+            .filter(|r| !r.flags.contains(MemFlags::IO))
+            .map(|r| r.virt_start + r.size)
+            .max()
+            .unwrap_or(0) as usize;
+        let mut chunk_shift = MIN_CHUNK_SHIFT;
+        while (ram_end >> chunk_shift) >= MAX_CHUNKS {
+            chunk_shift += 1;
+        }
+        Self {
+            ram_end,
+            chunk_shift,
+            samples: 0,
+            hits: [0; MAX_CHUNKS],
+        }
+    }
+
+    fn sample(&mut self) {
+        let mut accessed = [0u64; MAX_CHUNKS / 64];
+        let shift = self.chunk_shift;
+        let mut start = 0;
+        while start < self.ram_end {
+            let end = self.ram_end.min(start + WALK_BATCH_SIZE);
+            let res = crate::cell::root_cell().gpm.read().collect_accessed(
+                MemFlags::READ,
+                start..end,
+                |start, size| {
+                    let last = ((start + size - 1) >> shift).min(MAX_CHUNKS - 1);
+                    for chunk in (start >> shift)..=last {
+                        accessed[chunk / 64] |= 1 << (chunk % 64);
+                    }
+                },
+            );
+            if let Err(e) = res {
+                warn!("Failed to sample accessed pages: {:?}", e);
+                return;
+            }
+            start = end;
+        }
+        for (chunk, hits) in self.hits.iter_mut().enumerate() {
+            if accessed[chunk / 64] & (1 << (chunk % 64)) != 0 {
+                *hits = hits.saturating_add(1);
+            }
+        }
+        self.samples = self.samples.saturating_add(1);
+    }
+}
+
+/// Samples the accessed pages if statistics are enabled and the interval
+/// expired. Called on VM exits of root CPUs.
+pub fn poll() {
+    if !crate::stats::enabled() {
+        return;
+    }
+    let now = cpu::current_cycle();
+    if now < NEXT_SAMPLE_CYCLE.load(Ordering::Relaxed) {
+        return;
+    }
+    // Only one CPU samples at a time.
+    if let Some(mut heat) = MEM_HEAT.try_lock() {
+        NEXT_SAMPLE_CYCLE.store(
+            now + SAMPLE_INTERVAL_US * cpu::frequency() as u64,
+            Ordering::Relaxed,
+        );
+        heat.get_or_insert_with(MemHeat::new).sample();
+    }
+}
+
+/// Size of the chunks in bytes, 0 if no sample has been taken yet.
+pub fn chunk_size() -> usize {
+    MEM_HEAT.lock().as_ref().map_or(0, |h| 1 << h.chunk_shift)
+}
+
+/// Returns the number of samples in which chunk `idx` was accessed, and the
+/// total number of samples.
+pub fn chunk_hits(idx: usize) -> Option<(u32, u32)> {
+    let heat = MEM_HEAT.lock();
+    let heat = heat.as_ref()?;
+    heat.hits.get(idx).map(|&hits| (hits, heat.samples))
+}
@@ -1,12 +1,19 @@
 //! Physical memory allocation.
+//!
+//! With the `frame-debug` feature, each allocated frame is tagged with the
+//! source location of its allocation, and freed frames are filled with
+//! `POISON_BYTE`. The state of each frame is kept in bitmaps, the locations
+//! only while allocated. Allocating a frame twice, freeing a free frame, or
+//! allocating a freed frame whose poison has been overwritten panics.
 
 use bitmap_allocator::BitAlloc;
-
-use spin::Mutex;
+use core::ops::Range;
 
 use super::addr::{align_down, align_up, is_aligned, phys_to_virt, virt_to_phys, PhysAddr};
 use crate::consts::PAGE_SIZE;
 use crate::error::HvResult;
+use crate::fault_inject::{should_fail, FaultPoint};
+use crate::lock::SpinLock;
 
 // Support max 1M * 4096 = 1GB memory.
 type FrameAlloc = bitmap_allocator::BitAlloc1M;
@@ -14,6 +21,10 @@ type FrameAlloc = bitmap_allocator::BitAlloc1M;
 struct FrameAllocator {
     base: PhysAddr,
     inner: FrameAlloc,
+    /// Number of frames managed.
+    total: usize,
+    /// Number of frames allocated.
+    used: usize,
 }
 
 /// A safe wrapper for physical frame allocation.
@@ -23,13 +34,15 @@ pub struct Frame {
     frame_count: usize,
 }
 
-static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
+static FRAME_ALLOCATOR: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::empty());
 
 impl FrameAllocator {
     const fn empty() -> Self {
         Self {
             base: 0,
             inner: FrameAlloc::DEFAULT,
+            total: 0,
+            used: 0,
         }
     }
 
@@ -37,20 +50,28 @@ impl FrameAllocator {
         self.base = align_up(base);
         let page_count = align_up(size) / PAGE_SIZE;
         self.inner.insert(0..page_count);
+        self.total = page_count;
     }
 
     /// # Safety
     ///
     /// This function is unsafe because you need to deallocate manually.
+    #[track_caller]
     unsafe fn alloc(&mut self) -> Option<PhysAddr> {
         let ret = self.inner.alloc().map(|idx| idx * PAGE_SIZE + self.base);
         trace!("Allocate frame: {:x?}", ret);
+        if let Some(paddr) = ret {
+            self.used += 1;
+            #[cfg(feature = "frame-debug")]
+            debug::on_alloc(self.base, paddr, 1);
+        }
         ret
     }
 
     /// # Safety
     ///
     /// This function is unsafe because your need to deallocate manually.
+    #[track_caller]
     unsafe fn alloc_contiguous(
         &mut self,
         frame_count: usize,
@@ -66,6 +87,11 @@ impl FrameAllocator {
             1 << align_log2,
             ret
         );
+        if let Some(paddr) = ret {
+            self.used += frame_count;
+            #[cfg(feature = "frame-debug")]
+            debug::on_alloc(self.base, paddr, frame_count);
+        }
         ret
     }
 
@@ -74,6 +100,9 @@ impl FrameAllocator {
     /// This function is unsafe because the frame must have been allocated.
     unsafe fn dealloc(&mut self, target: PhysAddr) {
         trace!("Deallocate frame: {:x}", target);
+        #[cfg(feature = "frame-debug")]
+        debug::on_dealloc(self.base, target, 1);
+        self.used -= 1;
         self.inner.dealloc((target - self.base) / PAGE_SIZE)
     }
 
@@ -82,17 +111,42 @@ impl FrameAllocator {
     /// This function is unsafe because the frames must have been allocated.
     unsafe fn dealloc_contiguous(&mut self, target: PhysAddr, frame_count: usize) {
         trace!("Deallocate {} frames: {:x}", frame_count, target);
+        #[cfg(feature = "frame-debug")]
+        debug::on_dealloc(self.base, target, frame_count);
+        self.used -= frame_count;
         let start_idx = (target - self.base) / PAGE_SIZE;
         for i in start_idx..start_idx + frame_count {
             self.inner.dealloc(i)
         }
     }
+
+    /// Removes the free frames at the end of the pool from the allocator,
+    /// except `keep` free frames, and returns the index of the first one.
+    fn release_trailing(&mut self, keep: usize, dry_run: bool) -> usize {
+        let trailing = (0..self.total)
+            .rev()
+            .take_while(|&idx| self.inner.test(idx))
+            .count();
+        let count = trailing.min((self.total - self.used).saturating_sub(keep));
+        let start_idx = self.total - count;
+        if !dry_run {
+            #[cfg(feature = "frame-debug")]
+            debug::on_release(start_idx, self.total);
+            self.inner.remove(start_idx..self.total);
+            self.total = start_idx;
+        }
+        start_idx
+    }
 }
 
 #[allow(dead_code)]
 impl Frame {
     /// Allocate one physical frame.
+    #[track_caller]
     pub fn new() -> HvResult<Self> {
+        if should_fail(FaultPoint::FrameAlloc) {
+            return hv_result_err!(ENOMEM, "Injected fault");
+        }
         unsafe {
             FRAME_ALLOCATOR
                 .lock()
@@ -101,11 +155,12 @@ impl Frame {
                     start_paddr,
                     frame_count: 1,
                 })
-                .ok_or(hv_err!(ENOMEM))
+                .ok_or_else(|| hv_err!(ENOMEM))
         }
     }
 
     /// Allocate one physical frame and fill with zero.
+    #[track_caller]
     pub fn new_zero() -> HvResult<Self> {
         let mut f = Self::new()?;
         f.zero();
@@ -113,7 +168,11 @@ impl Frame {
     }
 
     /// Allocate contiguous physical frames.
+    #[track_caller]
     pub fn new_contiguous(frame_count: usize, align_log2: usize) -> HvResult<Self> {
+        if should_fail(FaultPoint::FrameAlloc) {
+            return hv_result_err!(ENOMEM, "Injected fault");
+        }
         unsafe {
             FRAME_ALLOCATOR
                 .lock()
@@ -122,7 +181,7 @@ impl Frame {
                     start_paddr,
                     frame_count,
                 })
-                .ok_or(hv_err!(ENOMEM))
+                .ok_or_else(|| hv_err!(ENOMEM))
         }
     }
 
@@ -195,6 +254,125 @@ impl Drop for Frame {
     }
 }
 
+#[cfg(feature = "frame-debug")]
+mod debug {
+    use alloc::collections::BTreeMap;
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::{phys_to_virt, PhysAddr, PAGE_SIZE};
+    use crate::lock::SpinLock;
+
+    /// Byte pattern filled into freed frames.
+    const POISON_BYTE: u8 = 0x6b;
+    /// Max number of frames of the allocator, see `FrameAlloc`.
+    const MAX_FRAMES: usize = 1 << 20;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    /// One bit per frame: allocated, and freed (filled with `POISON_BYTE`).
+    /// Only updated with the allocator locked.
+    static ALLOCATED: [AtomicU64; MAX_FRAMES / 64] = [ZERO; MAX_FRAMES / 64];
+    static POISONED: [AtomicU64; MAX_FRAMES / 64] = [ZERO; MAX_FRAMES / 64];
+
+    lazy_static! {
+        /// Allocation site of the allocated frames.
+        static ref FRAME_OWNERS: SpinLock<BTreeMap<usize, &'static Location<'static>>> =
+            SpinLock::new(BTreeMap::new());
+    }
+
+    /// Sets bit `idx` of `bitmap` to `value`, returns the previous value.
+    fn set_bit(bitmap: &[AtomicU64], idx: usize, value: bool) -> bool {
+        let mask = 1 << (idx % 64);
+        let old = if value {
+            bitmap[idx / 64].fetch_or(mask, Ordering::Relaxed)
+        } else {
+            bitmap[idx / 64].fetch_and(!mask, Ordering::Relaxed)
+        };
+        old & mask != 0
+    }
+
+    fn frame_slice(paddr: PhysAddr) -> &'static mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(paddr) as *mut u8, PAGE_SIZE) }
+    }
+
+    #[track_caller]
+    pub(super) fn on_alloc(base: PhysAddr, start_paddr: PhysAddr, frame_count: usize) {
+        let caller = Location::caller();
+        let mut owners = FRAME_OWNERS.lock();
+        for paddr in (start_paddr..start_paddr + frame_count * PAGE_SIZE).step_by(PAGE_SIZE) {
+            let idx = (paddr - base) / PAGE_SIZE;
+            if set_bit(&ALLOCATED, idx, true) {
+                panic!(
+                    "Frame {:#x} allocated by {} is allocated again by {}",
+                    paddr, owners[&idx], caller
+                );
+            }
+            if set_bit(&POISONED, idx, false)
+                && frame_slice(paddr).iter().any(|&b| b != POISON_BYTE)
+            {
+                panic!("Frame {:#x} is written after being freed", paddr);
+            }
+            owners.insert(idx, caller);
+        }
+    }
+
+    pub(super) fn on_dealloc(base: PhysAddr, start_paddr: PhysAddr, frame_count: usize) {
+        let mut owners = FRAME_OWNERS.lock();
+        for paddr in (start_paddr..start_paddr + frame_count * PAGE_SIZE).step_by(PAGE_SIZE) {
+            let idx = (paddr - base) / PAGE_SIZE;
+            if !set_bit(&ALLOCATED, idx, false) {
+                panic!("Frame {:#x} freed but not allocated", paddr);
+            }
+            owners.remove(&idx);
+            frame_slice(paddr).fill(POISON_BYTE);
+            set_bit(&POISONED, idx, true);
+        }
+    }
+
+    /// Forgets the poison of the free frames `[start_idx, end_idx)` taken out
+    /// of the allocator, which Linux may write.
+    pub(super) fn on_release(start_idx: usize, end_idx: usize) {
+        for idx in start_idx..end_idx {
+            set_bit(&POISONED, idx, false);
+        }
+    }
+}
+
+/// Returns the number of allocated and total frames.
+pub fn frame_usage() -> (usize, usize) {
+    let allocator = FRAME_ALLOCATOR.lock();
+    (allocator.used, allocator.total)
+}
+
+/// End of the hypervisor memory managed by the allocator, the memory after it
+/// has been released by `release_trailing()`.
+pub fn managed_end() -> PhysAddr {
+    let allocator = FRAME_ALLOCATOR.lock();
+    allocator.base + allocator.total * PAGE_SIZE
+}
+
+/// Takes the free frames at the end of the hypervisor memory out of the
+/// allocator, leaving at least `keep` free frames, and returns their physical
+/// address range. With `dry_run`, the frames are only counted.
+pub fn release_trailing(keep: usize, dry_run: bool) -> Range<PhysAddr> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let total = allocator.total;
+    let start_idx = allocator.release_trailing(keep, dry_run);
+    allocator.base + start_idx * PAGE_SIZE..allocator.base + total * PAGE_SIZE
+}
+
+/// Gives frames taken by `release_trailing()` back to the allocator.
+pub fn reclaim(range: Range<PhysAddr>) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let start_idx = (range.start - allocator.base) / PAGE_SIZE;
+    let end_idx = (range.end - allocator.base) / PAGE_SIZE;
+    assert_eq!(start_idx, allocator.total);
+    allocator.inner.insert(start_idx..end_idx);
+    allocator.total = end_idx;
+}
+
 /// Initialize the physical frame allocator.
 pub(super) fn init() {
     let mem_pool_start = crate::consts::free_memory_start();
@@ -209,3 +387,13 @@ pub(super) fn init() {
         mem_pool_start..mem_pool_end
     );
 }
+
+/// Backs the allocator with host memory, for the host tests building page
+/// tables. Physical addresses are then host virtual addresses.
+#[cfg(test)]
+pub(super) fn init_test() {
+    const TEST_POOL_SIZE: usize = 16 * 1024 * 1024; // 16 MB
+    let layout = std::alloc::Layout::from_size_align(TEST_POOL_SIZE, PAGE_SIZE).unwrap();
+    let pool = unsafe { std::alloc::alloc_zeroed(layout) } as PhysAddr;
+    FRAME_ALLOCATOR.lock().init(pool, TEST_POOL_SIZE);
+}
@@ -1,13 +1,19 @@
 #![allow(dead_code)]
 
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 use core::marker::PhantomData;
 use core::mem::size_of;
+use core::panic::Location;
 
 use super::addr::{page_offset, phys_to_virt, GuestPhysAddr, GuestVirtAddr};
-use super::GenericPageTableImmut;
+use super::{GenericPageTableImmut, MemFlags};
 use crate::arch::GuestPageTableImmut;
 use crate::error::HvResult;
+use crate::percpu::PerCpu;
+
+/// Max number of guest ranges recorded by `FetchLog`.
+const MAX_FETCHES: usize = 8;
 
 pub struct GuestPtr<'a, T> {
     gvaddr: GuestVirtAddr,
@@ -62,7 +68,7 @@ impl<T> GuestPtr<'_, T> {
             return hv_result_err!(EFAULT, "GuestPtr is null");
         }
         if addr % core::mem::align_of::<T>() != 0 {
-            return hv_result_err!(EINVAL, format!("GuestPtr {:#x?} is not aligned", addr));
+            return hv_result_err!(EINVAL, "GuestPtr {:#x?} is not aligned", addr);
         }
         Ok(())
     }
@@ -152,3 +158,92 @@ impl<T> GuestPtr<'_, T> {
         Ok(ptr)
     }
 }
+
+/// Guest RAM ranges fetched by the current hypercall.
+///
+/// Hypercalls must read a guest structure once into hypervisor memory, then
+/// validate and use the copy only: the guest may modify it meanwhile from
+/// another CPU, so reading it again after the validation (a double fetch)
+/// could bypass it. Debug builds record the ranges read by
+/// `copy_from_guest()` and `copy_bytes_from_guest()` with their call site,
+/// and assert that no range is read again by another site.
+///
+/// The guest chooses the addresses and may alias them, e.g. map two pages of
+/// a buffer to the same frame, so overlapping fetches of a single site (a
+/// loop over the buffer) are not reported. Hypercalls fetching independent
+/// guest buffers must do it from a single site for the same reason.
+pub struct FetchLog {
+    fetches: [Option<Fetch>; MAX_FETCHES],
+    len: usize,
+}
+
+/// A guest RAM range `[start, end)` fetched at `site`.
+#[derive(Clone, Copy)]
+struct Fetch {
+    site: &'static Location<'static>,
+    start: GuestPhysAddr,
+    end: GuestPhysAddr,
+}
+
+impl FetchLog {
+    pub const fn new() -> Self {
+        Self {
+            fetches: [None; MAX_FETCHES],
+            len: 0,
+        }
+    }
+
+    /// Forgets the ranges, called at the start of each hypercall.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn record(&mut self, fetch: Fetch) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let double_fetch = self.fetches[..self.len].iter().flatten().find(|other| {
+            fetch.start < other.end && other.start < fetch.end && fetch.site != other.site
+        });
+        if let Some(other) = double_fetch {
+            panic!(
+                "Double fetch of guest memory [{:#x}, {:#x}) at {}, first fetched at {}",
+                fetch.start, fetch.end, fetch.site, other.site
+            );
+        }
+        if self.len < MAX_FETCHES {
+            self.fetches[self.len] = Some(fetch);
+            self.len += 1;
+        }
+    }
+}
+
+/// Returns the hypervisor virtual address of root cell RAM `[gpaddr, gpaddr +
+/// size)`, which the cell must have mapped readable, and records the fetch
+/// with the call site of the copy.
+#[track_caller]
+fn fetch(gpaddr: GuestPhysAddr, size: usize) -> HvResult<*const u8> {
+    let vaddr = crate::cell::root_cell().guest_ram_to_hv(gpaddr, size, MemFlags::READ)?;
+    if let Some(cpu_data) = PerCpu::try_current_mut() {
+        cpu_data.guest_fetches.record(Fetch {
+            site: Location::caller(),
+            start: gpaddr,
+            end: gpaddr + size,
+        });
+    }
+    Ok(vaddr as *const u8)
+}
+
+/// Copies a `T` from root cell RAM at `gpaddr`, see `FetchLog`.
+#[track_caller]
+pub fn copy_from_guest<T: Copy>(gpaddr: GuestPhysAddr) -> HvResult<T> {
+    let ptr = fetch(gpaddr, size_of::<T>())?;
+    Ok(unsafe { (ptr as *const T).read_unaligned() })
+}
+
+/// Copies `size` bytes from root cell RAM at `gpaddr`, see `FetchLog`.
+#[track_caller]
+pub fn copy_bytes_from_guest(gpaddr: GuestPhysAddr, size: usize) -> HvResult<Vec<u8>> {
+    let ptr = fetch(gpaddr, size)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr, size) }.to_vec())
+}
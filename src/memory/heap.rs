@@ -1,17 +1,136 @@
 //! Dynamic memory allocation.
+//!
+//! Besides the main heap, each CPU has a small emergency heap, so that
+//! logging and error formatting still work when the main heap is exhausted.
+//! A CPU in emergency mode (panicking, or dumping its state on a watchdog NMI)
+//! also never spins on the main heap lock, which it may hold itself.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use buddy_system_allocator::LockedHeap;
 
-use crate::consts::HV_HEAP_SIZE;
+use crate::consts::{EMERGENCY_HEAP_SIZE, HV_HEAP_SIZE};
+use crate::percpu::PerCpu;
 
-#[cfg_attr(not(test), global_allocator)]
+const MACHINE_ALIGN: usize = core::mem::size_of::<usize>();
+const HEAP_BLOCK: usize = HV_HEAP_SIZE / MACHINE_ALIGN;
+
+static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
 static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::<32>::new();
+/// Number of CPUs in emergency mode. While 0, allocations do not look up the
+/// per-CPU data, which takes a MSR read, unless the main heap fails.
+static EMERGENCY_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg_attr(not(test), global_allocator)]
+static HV_ALLOCATOR: HvAllocator = HvAllocator;
+
+/// Bump allocator over a per-CPU arena, reset when all its allocations are
+/// freed. Only used by the CPU owning it.
+pub struct EmergencyHeap {
+    emergency: bool,
+    next: usize,
+    live: usize,
+    arena: [u8; EMERGENCY_HEAP_SIZE],
+}
+
+impl EmergencyHeap {
+    pub const fn new() -> Self {
+        Self {
+            emergency: false,
+            next: 0,
+            live: 0,
+            arena: [0; EMERGENCY_HEAP_SIZE],
+        }
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        self.arena.as_ptr_range().contains(&(ptr as *const u8))
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let base = self.arena.as_mut_ptr() as usize;
+        let start = (base + self.next + layout.align() - 1) & !(layout.align() - 1);
+        let end = start + layout.size();
+        if end > base + EMERGENCY_HEAP_SIZE {
+            return null_mut();
+        }
+        self.next = end - base;
+        self.live += 1;
+        start as *mut u8
+    }
+
+    fn dealloc(&mut self) {
+        self.live -= 1;
+        if self.live == 0 {
+            self.next = 0;
+        }
+    }
+}
+
+fn emergency_heap<'a>() -> Option<&'a mut EmergencyHeap> {
+    PerCpu::try_current_mut().map(|c| &mut c.emergency_heap)
+}
+
+/// Whether the current CPU is in emergency mode.
+fn in_emergency() -> bool {
+    EMERGENCY_CPUS.load(Ordering::Acquire) != 0 && emergency_heap().map_or(false, |h| h.emergency)
+}
+
+struct HvAllocator;
+
+unsafe impl GlobalAlloc for HvAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = if in_emergency() {
+            HEAP_ALLOCATOR.try_lock()
+        } else {
+            Some(HEAP_ALLOCATOR.lock())
+        };
+        if let Some(Ok(ptr)) = heap.map(|mut heap| heap.alloc(layout)) {
+            return ptr.as_ptr();
+        }
+        emergency_heap().map_or(null_mut(), |h| h.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !HEAP.as_ptr_range().contains(&(ptr as *const usize)) {
+            // Emergency allocations of other CPUs are leaked.
+            if let Some(h) = emergency_heap().filter(|h| h.contains(ptr)) {
+                h.dealloc();
+            }
+            return;
+        }
+        let heap = if in_emergency() {
+            HEAP_ALLOCATOR.try_lock()
+        } else {
+            Some(HEAP_ALLOCATOR.lock())
+        };
+        // Leak the block rather than spin on a lock this CPU may hold.
+        if let Some(mut heap) = heap {
+            heap.dealloc(NonNull::new_unchecked(ptr), layout);
+        }
+    }
+}
+
+/// Puts the current CPU in or out of emergency mode, returns the previous
+/// mode. Does nothing on CPUs not entered into the hypervisor.
+pub fn set_emergency(emergency: bool) -> bool {
+    let heap = match emergency_heap() {
+        Some(heap) => heap,
+        None => return false,
+    };
+    let old = core::mem::replace(&mut heap.emergency, emergency);
+    match (old, emergency) {
+        (false, true) => EMERGENCY_CPUS.fetch_add(1, Ordering::AcqRel),
+        (true, false) => EMERGENCY_CPUS.fetch_sub(1, Ordering::AcqRel),
+        _ => 0,
+    };
+    old
+}
 
 /// Initialize the global heap allocator.
 pub(super) fn init() {
-    const MACHINE_ALIGN: usize = core::mem::size_of::<usize>();
-    const HEAP_BLOCK: usize = HV_HEAP_SIZE / MACHINE_ALIGN;
-    static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
     let heap_start = unsafe { HEAP.as_ptr() as usize };
     unsafe {
         HEAP_ALLOCATOR
@@ -1,4 +1,7 @@
+use core::ops::Range;
+
 use super::addr::{align_down, virt_to_phys};
+use super::PAGE_SIZE;
 use super::{AlignedPage, MemFlags, MemoryRegion, PhysAddr};
 
 static EMPTY_PAGE: AlignedPage = AlignedPage::new();
@@ -18,10 +21,14 @@ impl Mapper {
     }
 }
 
+/// Physical address of the page all empty-mapped regions point to.
+pub fn empty_page_paddr() -> PhysAddr {
+    virt_to_phys(EMPTY_PAGE.as_ptr() as usize)
+}
+
 impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
     pub fn new_with_empty_mapper(start: VA, size: usize, flags: MemFlags) -> Self {
-        let paddr = virt_to_phys(EMPTY_PAGE.as_ptr() as usize);
-        Self::new(start, size, flags, Mapper::Fixed(paddr))
+        Self::new(start, size, flags, Mapper::Fixed(empty_page_paddr()))
     }
 
     pub fn new_with_offset_mapper(
@@ -40,4 +47,42 @@ impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
             Mapper::Offset(phys_virt_offset),
         )
     }
+
+    /// Physical address mapped at `vaddr` of this region, unless the region
+    /// maps the empty page.
+    pub fn translate(&self, vaddr: VA) -> Option<PhysAddr> {
+        match self.mapper {
+            Mapper::Offset(off) => Some(vaddr.into() - off),
+            Mapper::Fixed(_) => None,
+        }
+    }
+
+    /// Physical memory reachable through this region.
+    pub fn phys_range(&self) -> Range<PhysAddr> {
+        match self.mapper {
+            Mapper::Offset(off) => {
+                let start = self.start.into() - off;
+                start..start + self.size
+            }
+            Mapper::Fixed(paddr) => paddr..paddr + PAGE_SIZE,
+        }
+    }
+
+    /// The part of this region mapping physical memory `range`, if any and
+    /// mapped with an offset.
+    pub fn phys_sub_region(&self, range: &Range<PhysAddr>) -> Option<Self> {
+        let off = match self.mapper {
+            Mapper::Offset(off) => off,
+            Mapper::Fixed(_) => return None,
+        };
+        let phys = self.phys_range();
+        let (start, end) = (phys.start.max(range.start), phys.end.min(range.end));
+        if start >= end {
+            return None;
+        }
+        let mut part = self.clone();
+        part.start = (start + off).into();
+        part.size = end - start;
+        Some(part)
+    }
 }
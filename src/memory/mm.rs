@@ -3,10 +3,12 @@
 //! Memory management.
 
 use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
+use core::ops::Range;
 
 use super::addr::{align_down, align_up};
-use super::{mapper::Mapper, paging::GenericPageTable, MemFlags};
+use super::{mapper::Mapper, paging::GenericPageTable, MemFlags, PhysAddr};
 use crate::error::HvResult;
 
 #[derive(Clone)]
@@ -84,6 +86,9 @@ where
         if region.size == 0 {
             return Ok(());
         }
+        if region.start.into().checked_add(region.size).is_none() {
+            return hv_result_err!(EINVAL, "MemoryRegion overflowed: {:#x?}", region);
+        }
         if !self.test_free_area(&region) {
             warn!(
                 "MemoryRegion overlapped in MemorySet: {:#x?}\n{:#x?}",
@@ -98,6 +103,31 @@ where
 
     /// Find and remove memory region which starts from `start`.
     pub fn delete(&mut self, start: PT::VA) -> HvResult {
+        self.transaction().delete(start)
+    }
+
+    /// Changes the flags of `[start, start + size)` to `flags`. The range must
+    /// be page aligned and lie in a single region, which is split as needed.
+    pub fn protect(&mut self, start: PT::VA, size: usize, flags: MemFlags) -> HvResult {
+        self.transaction().protect(start, size, flags)
+    }
+
+    /// Unmaps physical memory `range` wherever a region maps it with an
+    /// offset. The regions and huge pages covering it are split around it.
+    /// Returns the unmapped parts, which can be inserted again.
+    pub fn unmap_phys(&mut self, range: Range<PhysAddr>) -> HvResult<Vec<MemoryRegion<PT::VA>>> {
+        self.transaction().unmap_phys(range)
+    }
+
+    /// Starts a batch of changes, with a single TLB flush once it is dropped.
+    pub fn transaction(&mut self) -> MemorySetTransaction<PT> {
+        MemorySetTransaction {
+            set: self,
+            need_flush: false,
+        }
+    }
+
+    fn delete_unflushed(&mut self, start: PT::VA) -> HvResult {
         if let Entry::Occupied(e) = self.regions.entry(start) {
             self.pt.unmap(e.get())?;
             e.remove();
@@ -113,11 +143,87 @@ where
         }
     }
 
+    /// Returns the region containing all of `[start, start + size)`, a page
+    /// aligned range, and the end of the range.
+    fn containing_region(
+        &self,
+        start: PT::VA,
+        size: usize,
+    ) -> HvResult<(MemoryRegion<PT::VA>, usize)> {
+        let start_addr = start.into();
+        let end_addr = match start_addr.checked_add(size) {
+            Some(end) => end,
+            None => return hv_result_err!(EINVAL, "Range overflowed"),
+        };
+        if size == 0 || align_down(start_addr) != start_addr || align_down(size) != size {
+            return hv_result_err!(EINVAL);
+        }
+        match self.regions.range(..=start).last() {
+            Some((_, r)) if r.start.into() + r.size >= end_addr => Ok((r.clone(), end_addr)),
+            _ => hv_result_err!(EINVAL, "Range is not in a single memory region"),
+        }
+    }
+
+    /// Replaces `region` with its parts before and after `[start, end)`.
+    fn split_around(&mut self, region: MemoryRegion<PT::VA>, start: usize, end: usize) {
+        let region_end = region.start.into() + region.size;
+        self.regions.remove(&region.start);
+        if region.start.into() < start {
+            let mut before = region.clone();
+            before.size = start - region.start.into();
+            self.regions.insert(before.start, before);
+        }
+        if end < region_end {
+            let mut after = region;
+            after.start = end.into();
+            after.size = region_end - end;
+            self.regions.insert(after.start, after);
+        }
+    }
+
+    fn protect_unflushed(&mut self, start: PT::VA, size: usize, flags: MemFlags) -> HvResult {
+        let (region, end) = self.containing_region(start, size)?;
+        let mut protected = region.clone();
+        protected.start = start;
+        protected.size = size;
+        protected.flags = flags;
+        self.pt.protect(&protected)?;
+
+        self.split_around(region, start.into(), end);
+        self.regions.insert(start, protected);
+        Ok(())
+    }
+
+    fn unmap_unflushed(&mut self, start: PT::VA, size: usize) -> HvResult {
+        let (region, end) = self.containing_region(start, size)?;
+        let mut unmapped = region.clone();
+        unmapped.start = start;
+        unmapped.size = size;
+        self.pt.unmap(&unmapped)?;
+        self.split_around(region, start.into(), end);
+        Ok(())
+    }
+
+    /// Returns the region containing `vaddr`.
+    pub fn find_region(&self, vaddr: PT::VA) -> Option<&MemoryRegion<PT::VA>> {
+        self.regions
+            .range(..=vaddr)
+            .last()
+            .map(|(_, r)| r)
+            .filter(|r| vaddr.into() < r.start.into() + r.size)
+    }
+
+    /// Iterates over the regions in address order.
+    pub fn regions(&self) -> impl Iterator<Item = &MemoryRegion<PT::VA>> {
+        self.regions.values()
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
         }
         self.regions.clear();
+        self.pt.flush(None);
     }
 
     pub unsafe fn activate(&self) {
@@ -127,6 +233,99 @@ where
     pub fn page_table(&self) -> &PT {
         &self.pt
     }
+
+    /// Clears the accessed flags of the pages mapped with `flags` in `range`,
+    /// and calls `func` with the address and size of each page which was
+    /// accessed. Huge pages crossing the boundaries of `range` are reported
+    /// whole.
+    pub fn collect_accessed(
+        &self,
+        flags: MemFlags,
+        range: Range<usize>,
+        mut func: impl FnMut(usize, usize),
+    ) -> HvResult {
+        for region in self.regions.values() {
+            if region.flags.contains(flags) && matches!(region.mapper, Mapper::Offset(_)) {
+                let start = region.start.into().max(range.start);
+                let end = (region.start.into() + region.size).min(range.end);
+                if start < end {
+                    self.pt
+                        .collect_accessed(start.into(), end - start, &mut func)?;
+                }
+            }
+        }
+        self.pt.flush(None);
+        Ok(())
+    }
+}
+
+/// A batch of changes to a `MemorySet`, e.g. to rebuild many regions at once.
+/// Each change is applied immediately, but the TLBs are flushed only once,
+/// when the transaction is committed or dropped. Changes applied before an
+/// error are kept, and flushed as well.
+pub struct MemorySetTransaction<'a, PT: GenericPageTable>
+where
+    PT::VA: Ord,
+{
+    set: &'a mut MemorySet<PT>,
+    need_flush: bool,
+}
+
+impl<PT: GenericPageTable> MemorySetTransaction<'_, PT>
+where
+    PT::VA: Ord,
+{
+    /// Adds a memory region, see `MemorySet::insert()`. Mapping new pages
+    /// needs no flush.
+    pub fn insert(&mut self, region: MemoryRegion<PT::VA>) -> HvResult {
+        self.set.insert(region)
+    }
+
+    /// Removes the memory region starting from `start`.
+    pub fn delete(&mut self, start: PT::VA) -> HvResult {
+        self.need_flush = true;
+        self.set.delete_unflushed(start)
+    }
+
+    /// Changes the flags of `[start, start + size)`, see
+    /// `MemorySet::protect()`.
+    pub fn protect(&mut self, start: PT::VA, size: usize, flags: MemFlags) -> HvResult {
+        self.need_flush = true;
+        self.set.protect_unflushed(start, size, flags)
+    }
+
+    /// Unmaps physical memory `range`, see `MemorySet::unmap_phys()`.
+    pub fn unmap_phys(&mut self, range: Range<PhysAddr>) -> HvResult<Vec<MemoryRegion<PT::VA>>> {
+        let parts: Vec<_> = self
+            .set
+            .regions
+            .values()
+            .filter_map(|r| r.phys_sub_region(&range))
+            .collect();
+        for part in &parts {
+            self.need_flush = true;
+            self.set.unmap_unflushed(part.start, part.size)?;
+        }
+        Ok(parts)
+    }
+
+    pub fn find_region(&self, vaddr: PT::VA) -> Option<&MemoryRegion<PT::VA>> {
+        self.set.find_region(vaddr)
+    }
+
+    /// Ends the transaction and flushes the TLBs if needed.
+    pub fn commit(self) {}
+}
+
+impl<PT: GenericPageTable> Drop for MemorySetTransaction<'_, PT>
+where
+    PT::VA: Ord,
+{
+    fn drop(&mut self) {
+        if self.need_flush {
+            self.set.pt.flush(None);
+        }
+    }
 }
 
 impl<VA: Into<usize> + Copy> Debug for MemoryRegion<VA> {
@@ -51,6 +51,7 @@ mod heap;
 mod mapper;
 mod mm;
 mod paging;
+mod pool;
 
 pub mod addr;
 pub mod gaccess;
@@ -61,16 +62,19 @@ use bitflags::bitflags;
 use spin::{Once, RwLock};
 
 use crate::arch::HostPageTable;
-use crate::config::HvSystemConfig;
+use crate::config::{ConsoleType, HvSystemConfig};
 use crate::consts::HV_BASE;
 use crate::error::HvResult;
 use crate::header::HvHeader;
 
 pub use addr::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr, PhysAddr, VirtAddr};
-pub use frame::Frame;
-pub use mm::{MemoryRegion, MemorySet};
-pub use paging::{GenericPTE, PagingInstr};
+pub use frame::{frame_usage, managed_end, reclaim, release_trailing, Frame};
+pub use heap::{set_emergency, EmergencyHeap};
+pub use mapper::empty_page_paddr;
+pub use mm::{MemoryRegion, MemorySet, MemorySetTransaction};
+pub use paging::{GenericPTE, PageSize, PagingInstr};
 pub use paging::{GenericPageTable, GenericPageTableImmut, Level4PageTable, Level4PageTableImmut};
+pub use pool::{ObjectPool, PoolBox};
 
 pub const PAGE_SIZE: usize = paging::PageSize::Size4K as usize;
 
@@ -81,6 +85,9 @@ bitflags! {
         const EXECUTE       = 1 << 2;
         const DMA           = 1 << 3;
         const IO            = 1 << 4;
+        /// Write-combining I/O memory, e.g. prefetchable BARs. Only honored by
+        /// EPT, other page tables map it as uncached I/O.
+        const WRITE_COMBINE = 1 << 5;
         const NO_HUGEPAGES  = 1 << 8;
         const USER          = 1 << 9;
     }
@@ -106,6 +113,13 @@ pub fn init_frame_allocator() {
     frame::init();
 }
 
+/// Initializes the frame allocator of the host tests, once.
+#[cfg(test)]
+pub fn init_test_frame_allocator() {
+    static INIT: spin::Once<()> = spin::Once::new();
+    INIT.call_once(frame::init_test);
+}
+
 pub fn init_hv_page_table() -> HvResult {
     let header = HvHeader::get();
     let sys_config = HvSystemConfig::get();
@@ -152,6 +166,29 @@ pub fn init_hv_page_table() -> HvResult {
             ))?;
         }
     }
+    // Map the MMIO UART of the console.
+    let console = &sys_config.console;
+    if console.console_type() == ConsoleType::MmioUart {
+        let paddr = addr::align_down(console.address as HostPhysAddr);
+        hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+            addr::phys_to_virt(paddr),
+            paddr,
+            PAGE_SIZE,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))?;
+    }
+    // Map RTOS memory, if not mapped above: the hypervisor measures the RTOS
+    // image, writes the boot information and scrubs the memory.
+    let rt_phys_start = sys_config.rtos_memory.phys_start as HostPhysAddr;
+    let rt_virt_start = addr::phys_to_virt(rt_phys_start);
+    if sys_config.rtos_memory.size != 0 && hv_pt.page_table().query(rt_virt_start).is_err() {
+        hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+            rt_virt_start,
+            rt_phys_start,
+            sys_config.rtos_memory.size as usize,
+            MemFlags::READ | MemFlags::WRITE,
+        ))?;
+    }
     info!("Hypervisor page table init end.");
     debug!("Hypervisor virtual memory set: {:#x?}", hv_pt);
 
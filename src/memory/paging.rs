@@ -1,11 +1,10 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{fmt::Debug, marker::PhantomData, slice};
 
-use spin::Mutex;
-
 use super::addr::{phys_to_virt, PhysAddr};
 use super::{Frame, MemFlags, MemoryRegion};
 use crate::error::{HvError, HvResult};
+use crate::lock::SpinLock;
 
 #[derive(Debug)]
 pub enum PagingError {
@@ -21,7 +20,7 @@ impl From<PagingError> for HvError {
     fn from(err: PagingError) -> Self {
         match err {
             PagingError::NoMemory => hv_err!(ENOMEM),
-            _ => hv_err!(EFAULT, format!("{:?}", err)),
+            _ => hv_err!(EFAULT, "{:?}", err),
         }
     }
 }
@@ -85,6 +84,11 @@ pub trait GenericPTE: Debug + Clone {
     fn set_table(&mut self, paddr: PhysAddr);
     /// Set this entry to zero.
     fn clear(&mut self);
+    /// Clear the accessed flag set by the hardware, and returns whether it
+    /// was set. Always `false` if the format has no accessed flag.
+    fn test_and_clear_accessed(&mut self) -> bool {
+        false
+    }
 }
 
 const ENTRY_COUNT: usize = 512;
@@ -92,6 +96,19 @@ const ENTRY_COUNT: usize = 512;
 pub trait PagingInstr {
     unsafe fn activate(root_paddr: PhysAddr);
     fn flush(vaddr: Option<usize>);
+    /// Returns the generation of the last `flush()`, for `is_flushed()`.
+    fn flush_generation() -> u64 {
+        0
+    }
+    /// Returns whether no CPU can use the translations invalidated by the
+    /// flush of `generation` anymore. By default `flush()` is synchronous.
+    fn is_flushed(_generation: u64) -> bool {
+        true
+    }
+    /// Whether 1 GiB pages can be mapped.
+    fn supports_1g_pages() -> bool {
+        true
+    }
 }
 
 /// A basic read-only page table for address query only.
@@ -108,6 +125,8 @@ pub trait GenericPageTable: GenericPageTableImmut {
     fn new() -> Self;
 
     fn map(&mut self, region: &MemoryRegion<Self::VA>) -> HvResult;
+    /// Unmaps all pages in an already mapped `region`, huge pages crossing
+    /// its boundaries are split first.
     fn unmap(&mut self, region: &MemoryRegion<Self::VA>) -> HvResult;
     fn update(
         &mut self,
@@ -115,9 +134,29 @@ pub trait GenericPageTable: GenericPageTableImmut {
         paddr: PhysAddr,
         flags: MemFlags,
     ) -> PagingResult<PageSize>;
+    /// Changes the flags of all pages in an already mapped `region`, huge
+    /// pages crossing its boundaries are split first.
+    fn protect(&mut self, region: &MemoryRegion<Self::VA>) -> HvResult;
 
     fn clone(&self) -> Self;
 
+    /// Number of frames used by the page table itself.
+    fn table_frames(&self) -> usize;
+
+    /// Clears the accessed flags of the pages in the mapped range `[start,
+    /// start + size)`, and calls `func` with the address and size of each
+    /// page which was accessed. The caller must flush the TLB afterwards.
+    fn collect_accessed(
+        &self,
+        start: Self::VA,
+        size: usize,
+        func: &mut impl FnMut(usize, usize),
+    ) -> HvResult;
+
+    /// Calls `func` with the address, size, physical address and flags of
+    /// each page mapped with any access.
+    fn for_each_page(&self, func: &mut impl FnMut(usize, PageSize, PhysAddr, MemFlags));
+
     unsafe fn activate(&self);
     fn flush(&self, vaddr: Option<Self::VA>);
 }
@@ -192,8 +231,100 @@ where
         }
     }
 
+    /// Calls `func` with the address, size and entry of each present page
+    /// mapped by `table` at `level`.
+    fn walk_pages(
+        &self,
+        table: &[PTE],
+        level: usize,
+        start_vaddr: usize,
+        func: &mut impl FnMut(usize, PageSize, &PTE),
+    ) {
+        for (i, entry) in table.iter().enumerate() {
+            let vaddr = start_vaddr + (i << (12 + (3 - level) * 9));
+            if level == 3 {
+                if entry.is_present() {
+                    func(vaddr, PageSize::Size4K, entry);
+                }
+                continue;
+            }
+            match next_table_mut(entry) {
+                Ok(next) => self.walk_pages(next, level + 1, vaddr, func),
+                Err(PagingError::MappedToHugePage) if level == 1 => {
+                    func(vaddr, PageSize::Size1G, entry)
+                }
+                Err(PagingError::MappedToHugePage) => func(vaddr, PageSize::Size2M, entry),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Like `query()`, but with the permissions granted at every level, as
+    /// the MMU applies them: a permission missing from an entry of a higher
+    /// level is removed from the flags of the page.
+    pub fn query_effective(&self, vaddr: VA) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
+        const PERMS: MemFlags = MemFlags::from_bits_truncate(
+            MemFlags::READ.bits()
+                | MemFlags::WRITE.bits()
+                | MemFlags::EXECUTE.bits()
+                | MemFlags::USER.bits(),
+        );
+        let vaddr = vaddr.into();
+        let indexes = [
+            (p4_index(vaddr), PageSize::Size1G),
+            (p3_index(vaddr), PageSize::Size1G),
+            (p2_index(vaddr), PageSize::Size2M),
+            (p1_index(vaddr), PageSize::Size4K),
+        ];
+        let mut perms = PERMS;
+        let mut table = table_of::<PTE>(self.root_paddr());
+        for (level, &(idx, size)) in indexes.iter().enumerate() {
+            let entry = &table[idx];
+            if !entry.is_present() {
+                return Err(PagingError::NotMapped);
+            }
+            if level == 3 || (level > 0 && entry.is_huge()) {
+                let off = size.page_offset(vaddr);
+                let flags = entry.flags() & (perms | !PERMS);
+                return Ok((entry.addr() + off, flags, size));
+            }
+            perms &= entry.flags();
+            table = next_table_mut(entry)?;
+        }
+        unreachable!()
+    }
+
+    /// Returns the entries translating `vaddr`, from the root table down to
+    /// the leaf or the first entry not pointing to a table. Only tables in
+    /// hypervisor memory are followed, so corrupted entries can be inspected.
+    fn entry_chain(&self, vaddr: VA) -> Vec<PTE> {
+        let vaddr = vaddr.into();
+        let hv_memory = &crate::config::HvSystemConfig::get().hypervisor_memory;
+        let (hv_start, hv_size) = (hv_memory.phys_start as usize, hv_memory.size as usize);
+        let indexes = [
+            p4_index(vaddr),
+            p3_index(vaddr),
+            p2_index(vaddr),
+            p1_index(vaddr),
+        ];
+        let mut chain = Vec::new();
+        let mut table = table_of::<PTE>(self.root_paddr());
+        for idx in indexes {
+            let entry = &table[idx];
+            chain.push(entry.clone());
+            if !(hv_start..hv_start + hv_size).contains(&entry.addr()) {
+                break;
+            }
+            match next_table_mut(entry) {
+                Ok(next) => table = next,
+                Err(_) => break,
+            }
+        }
+        chain
+    }
+
     fn dump(&self, limit: usize) {
-        static LOCK: Mutex<()> = Mutex::new(());
+        static LOCK: SpinLock<()> = SpinLock::new(());
         let _lock = LOCK.lock();
 
         println!("Root: {:x?}", self.root_paddr());
@@ -240,12 +371,23 @@ where
     }
 }
 
+/// An intermediate level table, with the number of its entries in use.
+struct IntrmTable {
+    frame: Frame,
+    used: usize,
+}
+
 /// A extended level-4 page table that can change its mapping. It also tracks all intermediate
 /// level tables. Locks need to be used if change the same page table concurrently.
 struct Level4PageTableUnlocked<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: Level4PageTableImmut<VA, PTE>,
-    /// Intermediate level table frames.
-    intrm_tables: Vec<Frame>,
+    /// Intermediate level tables by physical address, released once empty.
+    intrm_tables: BTreeMap<PhysAddr, IntrmTable>,
+    /// Released tables, not flushed yet.
+    released: Vec<Frame>,
+    /// Released tables with the generation of their flush, freed once no CPU
+    /// can walk them anymore (`PagingInstr::is_flushed()`).
+    retired: Vec<(u64, Frame)>,
     /// Phantom data.
     _phantom: PhantomData<(VA, PTE, I)>,
 }
@@ -259,7 +401,9 @@ where
     fn new() -> Self {
         Self {
             inner: Level4PageTableImmut::new(),
-            intrm_tables: Vec::new(),
+            intrm_tables: BTreeMap::new(),
+            released: Vec::new(),
+            retired: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -267,68 +411,168 @@ where
     unsafe fn from_root(root_paddr: PhysAddr) -> Self {
         Self {
             inner: Level4PageTableImmut::from_root(root_paddr),
-            intrm_tables: Vec::new(),
+            intrm_tables: BTreeMap::new(),
+            released: Vec::new(),
+            retired: Vec::new(),
             _phantom: PhantomData,
         }
     }
 
-    fn alloc_intrm_table(&mut self) -> HvResult<PhysAddr> {
+    fn alloc_intrm_table(&mut self, used: usize) -> HvResult<PhysAddr> {
         let frame = Frame::new_zero()?;
         let paddr = frame.start_paddr();
-        self.intrm_tables.push(frame);
+        self.intrm_tables.insert(paddr, IntrmTable { frame, used });
         Ok(paddr)
     }
 
-    fn _dealloc_intrm_table(&mut self, _paddr: PhysAddr) {}
-
-    fn get_entry_mut_or_create(&mut self, page: Page<VA>) -> PagingResult<&mut PTE> {
-        let vaddr = page.vaddr.into();
-        let p4 = table_of_mut::<PTE>(self.inner.root_paddr());
-        let p4e = &mut p4[p4_index(vaddr)];
+    /// Counts an entry of the table at `paddr` becoming used. The root table
+    /// is not counted.
+    fn inc_used(&mut self, paddr: PhysAddr) {
+        if let Some(table) = self.intrm_tables.get_mut(&paddr) {
+            table.used += 1;
+        }
+    }
 
-        let p3 = next_table_mut_or_create(p4e, || self.alloc_intrm_table())?;
-        let p3e = &mut p3[p3_index(vaddr)];
-        if page.size == PageSize::Size1G {
-            return Ok(p3e);
+    /// Returns the physical addresses of the tables translating `vaddr`, from
+    /// the root table down to the table of the leaf entry, and their number.
+    fn tables_of(&self, vaddr: usize) -> ([PhysAddr; 4], usize) {
+        let mut tables = [self.inner.root_paddr(); 4];
+        let mut n = 1;
+        while n < 4 {
+            let entry = &table_of::<PTE>(tables[n - 1])[index_of(vaddr, n - 1)];
+            if next_table_mut(entry).is_err() {
+                break;
+            }
+            tables[n] = entry.addr();
+            n += 1;
         }
+        (tables, n)
+    }
+
+    /// Called once the leaf entry of `vaddr` is cleared: releases the tables
+    /// left empty, bottom up, and clears their entries in the parent tables.
+    /// With `keep_top`, the tables referenced by the root table are kept, as
+    /// clonees share them. Tables not allocated by this page table (e.g. of
+    /// a clonee) are left alone.
+    fn release_empty_tables(&mut self, vaddr: usize, keep_top: bool) {
+        let (tables, mut n) = self.tables_of(vaddr);
+        let top = if keep_top { 2 } else { 1 };
+        while n > 1 {
+            let table = match self.intrm_tables.get_mut(&tables[n - 1]) {
+                Some(table) => table,
+                None => return,
+            };
+            table.used -= 1;
+            if table.used != 0 || n <= top {
+                return;
+            }
+            if let Some(table) = self.intrm_tables.remove(&tables[n - 1]) {
+                self.released.push(table.frame);
+            }
+            n -= 1;
+            table_of_mut::<PTE>(tables[n - 1])[index_of(vaddr, n - 1)].clear();
+        }
+    }
 
-        let p2 = next_table_mut_or_create(p3e, || self.alloc_intrm_table())?;
-        let p2e = &mut p2[p2_index(vaddr)];
-        if page.size == PageSize::Size2M {
-            return Ok(p2e);
+    /// Frees the retired tables no CPU can walk anymore, and retires the
+    /// released ones after flushing the translations.
+    fn retire_released_tables(&mut self) {
+        self.retired
+            .retain(|&(generation, _)| !I::is_flushed(generation));
+        if !self.released.is_empty() {
+            I::flush(None);
+            let generation = I::flush_generation();
+            let released = self.released.drain(..);
+            self.retired
+                .extend(released.map(|frame| (generation, frame)));
         }
+    }
 
-        let p1 = next_table_mut_or_create(p2e, || self.alloc_intrm_table())?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok(p1e)
+    /// Returns the entry of `page` and the physical address of its table,
+    /// creating the missing intermediate tables.
+    fn get_entry_mut_or_create(&mut self, page: Page<VA>) -> PagingResult<(&mut PTE, PhysAddr)> {
+        let vaddr = page.vaddr.into();
+        let leaf_level = match page.size {
+            PageSize::Size1G => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size4K => 3,
+        };
+        let mut table_paddr = self.inner.root_paddr();
+        for level in 0..leaf_level {
+            let entry = &mut table_of_mut::<PTE>(table_paddr)[index_of(vaddr, level)];
+            if entry.is_unused() {
+                let paddr = self
+                    .alloc_intrm_table(0)
+                    .map_err(|_| PagingError::NoMemory)?;
+                entry.set_table(paddr);
+                self.inc_used(table_paddr);
+            }
+            next_table_mut(entry)?;
+            table_paddr = entry.addr();
+        }
+        let entry = &mut table_of_mut::<PTE>(table_paddr)[index_of(vaddr, leaf_level)];
+        Ok((entry, table_paddr))
     }
 
     fn map_page(&mut self, page: Page<VA>, paddr: PhysAddr, flags: MemFlags) -> PagingResult {
-        let entry = self.get_entry_mut_or_create(page)?;
+        let (entry, table_paddr) = self.get_entry_mut_or_create(page)?;
         if !entry.is_unused() {
             return Err(PagingError::AlreadyMapped);
         }
         entry.set_addr(page.size.align_down(paddr));
         entry.set_flags(flags, page.size.is_huge());
+        self.inc_used(table_paddr);
         Ok(())
     }
 
-    fn unmap_page(&mut self, vaddr: VA) -> PagingResult<(PhysAddr, PageSize)> {
+    fn unmap_page(&mut self, vaddr: VA, keep_top: bool) -> PagingResult<(PhysAddr, PageSize)> {
         let (entry, size) = self.inner.get_entry_mut(vaddr)?;
         if entry.is_unused() {
             return Err(PagingError::NotMapped);
         }
         let paddr = entry.addr();
         entry.clear();
+        self.release_empty_tables(vaddr.into(), keep_top);
         Ok((paddr, size))
     }
 
     fn update(&mut self, vaddr: VA, paddr: PhysAddr, flags: MemFlags) -> PagingResult<PageSize> {
         let (entry, size) = self.inner.get_entry_mut(vaddr)?;
+        let was_unused = entry.is_unused();
         entry.set_addr(paddr);
         entry.set_flags(flags, size.is_huge());
+        if was_unused {
+            let (tables, n) = self.tables_of(vaddr.into());
+            self.inc_used(tables[n - 1]);
+        }
         Ok(size)
     }
+
+    /// Splits the huge page containing `vaddr` into pages of the next smaller
+    /// size, with the same translation and flags. Returns the new page size.
+    fn split_page(&mut self, vaddr: VA) -> PagingResult<PageSize> {
+        let (entry, size) = self.inner.get_entry_mut(vaddr)?;
+        let sub_size = match size {
+            PageSize::Size1G => PageSize::Size2M,
+            PageSize::Size2M => PageSize::Size4K,
+            PageSize::Size4K => return Ok(size),
+        };
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        let (paddr, flags) = (entry.addr(), entry.flags());
+
+        let table_paddr = self
+            .alloc_intrm_table(ENTRY_COUNT)
+            .map_err(|_| PagingError::NoMemory)?;
+        for (i, e) in table_of_mut::<PTE>(table_paddr).iter_mut().enumerate() {
+            e.set_addr(paddr + i * sub_size as usize);
+            e.set_flags(flags, sub_size.is_huge());
+        }
+        let (entry, _) = self.inner.get_entry_mut(vaddr)?;
+        entry.set_table(table_paddr);
+        Ok(sub_size)
+    }
 }
 
 /// A extended level-4 page table implements `GenericPageTable`. It use locks to avoid data
@@ -336,7 +580,7 @@ where
 pub struct Level4PageTable<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: Level4PageTableUnlocked<VA, PTE, I>,
     /// Make sure all accesses to the page table and its clonees is exclusive.
-    clonee_lock: Arc<Mutex<()>>,
+    clonee_lock: Arc<SpinLock<()>>,
 }
 
 impl<VA, PTE, I> Level4PageTable<VA, PTE, I>
@@ -350,6 +594,12 @@ where
         self.inner.inner.dump(limit)
     }
 
+    /// Returns the entries translating `vaddr`, from the root table down.
+    pub fn entry_chain(&self, vaddr: VA) -> Vec<PTE> {
+        let _lock = self.clonee_lock.lock();
+        self.inner.inner.entry_chain(vaddr)
+    }
+
     /// Clone only the top level page table mapping from `src`.
     pub fn clone_from(src: &impl GenericPageTableImmut) -> Self {
         // XXX: The clonee won't track intermediate tables, must ensure it lives shorter than the
@@ -377,7 +627,7 @@ where
     unsafe fn from_root(root_paddr: PhysAddr) -> Self {
         Self {
             inner: Level4PageTableUnlocked::from_root(root_paddr),
-            clonee_lock: Arc::new(Mutex::new(())),
+            clonee_lock: Arc::new(SpinLock::new(())),
         }
     }
 
@@ -400,7 +650,7 @@ where
     fn new() -> Self {
         Self {
             inner: Level4PageTableUnlocked::new(),
-            clonee_lock: Arc::new(Mutex::new(())),
+            clonee_lock: Arc::new(SpinLock::new(())),
         }
     }
 
@@ -411,6 +661,7 @@ where
             region
         );
         let _lock = self.clonee_lock.lock();
+        self.inner.retire_released_tables();
         let mut vaddr = region.start.into();
         let mut size = region.size;
         while size > 0 {
@@ -419,6 +670,7 @@ where
                 && PageSize::Size1G.is_aligned(paddr)
                 && size >= PageSize::Size1G as usize
                 && !region.flags.contains(MemFlags::NO_HUGEPAGES)
+                && I::supports_1g_pages()
             {
                 PageSize::Size1G
             } else if PageSize::Size2M.is_aligned(vaddr)
@@ -454,17 +706,29 @@ where
         );
         let _lock = self.clonee_lock.lock();
         let mut vaddr = region.start.into();
-        let mut size = region.size;
-        while size > 0 {
-            let (_, page_size) = self.inner.unmap_page(vaddr.into()).map_err(|e| {
+        let end = vaddr + region.size;
+        while vaddr < end {
+            let unmapped = self
+                .inner
+                .inner
+                .query(vaddr.into())
+                .and_then(|(_, _, size)| {
+                    let mut page_size = size;
+                    while page_size.is_huge()
+                        && (!page_size.is_aligned(vaddr) || vaddr + page_size as usize > end)
+                    {
+                        page_size = self.inner.split_page(vaddr.into())?;
+                    }
+                    let keep_top = Arc::strong_count(&self.clonee_lock) > 1;
+                    self.inner.unmap_page(vaddr.into(), keep_top)
+                });
+            let (_, page_size) = unmapped.map_err(|e| {
                 error!("failed to unmap page: {:#x?}, {:?}", vaddr, e);
                 e
             })?;
-            assert!(page_size.is_aligned(vaddr));
-            assert!(page_size as usize <= size);
             vaddr += page_size as usize;
-            size -= page_size as usize;
         }
+        self.inner.retire_released_tables();
         Ok(())
     }
 
@@ -473,6 +737,29 @@ where
         self.inner.update(vaddr, paddr, flags)
     }
 
+    fn protect(&mut self, region: &MemoryRegion<VA>) -> HvResult {
+        trace!(
+            "protect mapping in {}: {:#x?}",
+            core::any::type_name::<Self>(),
+            region
+        );
+        let _lock = self.clonee_lock.lock();
+        let mut vaddr = region.start.into();
+        let end = vaddr + region.size;
+        while vaddr < end {
+            let (_, _, mut page_size) = self.inner.inner.query(vaddr.into())?;
+            while page_size.is_huge()
+                && (!page_size.is_aligned(vaddr) || vaddr + page_size as usize > end)
+            {
+                page_size = self.inner.split_page(vaddr.into())?;
+            }
+            let paddr = region.mapper.map_fn(vaddr);
+            self.inner.update(vaddr.into(), paddr, region.flags)?;
+            vaddr += page_size as usize;
+        }
+        Ok(())
+    }
+
     fn clone(&self) -> Self {
         let mut pt = Self::clone_from(self);
         // clone with lock to avoid data racing between it and its clonees.
@@ -480,6 +767,41 @@ where
         pt
     }
 
+    fn table_frames(&self) -> usize {
+        let inner = &self.inner;
+        1 + inner.intrm_tables.len() + inner.released.len() + inner.retired.len()
+    }
+
+    fn collect_accessed(
+        &self,
+        start: VA,
+        size: usize,
+        func: &mut impl FnMut(usize, usize),
+    ) -> HvResult {
+        let _lock = self.clonee_lock.lock();
+        let mut vaddr = start.into();
+        let end = vaddr + size;
+        while vaddr < end {
+            let (entry, page_size) = self.inner.inner.get_entry_mut(vaddr.into())?;
+            let page_start = page_size.align_down(vaddr);
+            if entry.test_and_clear_accessed() {
+                func(page_start, page_size as usize);
+            }
+            vaddr = page_start + page_size as usize;
+        }
+        Ok(())
+    }
+
+    fn for_each_page(&self, func: &mut impl FnMut(usize, PageSize, PhysAddr, MemFlags)) {
+        let _lock = self.clonee_lock.lock();
+        let root = table_of::<PTE>(self.root_paddr());
+        self.inner
+            .inner
+            .walk_pages(root, 0, 0, &mut |vaddr, size, entry| {
+                func(vaddr, size, entry.addr(), entry.flags())
+            });
+    }
+
     unsafe fn activate(&self) {
         I::activate(self.root_paddr())
     }
@@ -489,6 +811,12 @@ where
     }
 }
 
+/// Index in the table at `level` (0 for the root table) of the entry
+/// translating `vaddr`.
+const fn index_of(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + (3 - level) * 9)) & (ENTRY_COUNT - 1)
+}
+
 const fn p4_index(vaddr: usize) -> usize {
     (vaddr >> (12 + 27)) & (ENTRY_COUNT - 1)
 }
@@ -525,15 +853,128 @@ fn next_table_mut<'a, E: GenericPTE>(entry: &E) -> PagingResult<&'a mut [E]> {
     }
 }
 
-fn next_table_mut_or_create<'a, E: GenericPTE>(
-    entry: &mut E,
-    mut allocator: impl FnMut() -> HvResult<PhysAddr>,
-) -> PagingResult<&'a mut [E]> {
-    if entry.is_unused() {
-        let paddr = allocator().map_err(|_| PagingError::NoMemory)?;
-        entry.set_table(paddr);
-        Ok(table_of_mut(paddr))
-    } else {
-        next_table_mut(entry)
+#[cfg(all(test, feature = "intel"))]
+mod tests {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::arch::vmm::NestedPTE;
+
+    const PAGE: usize = PageSize::Size4K as usize;
+    const HUGE_PAGE: usize = PageSize::Size2M as usize;
+
+    /// Flushes completing at once, as by default.
+    struct SyncInstr;
+
+    impl PagingInstr for SyncInstr {
+        unsafe fn activate(_root_paddr: PhysAddr) {}
+        fn flush(_vaddr: Option<usize>) {}
+    }
+
+    /// Generation of the last flush of `DeferredInstr`.
+    static GENERATION: AtomicU64 = AtomicU64::new(0);
+    /// Last generation of `DeferredInstr` no CPU can use anymore.
+    static FLUSHED: AtomicU64 = AtomicU64::new(0);
+
+    /// Flushes completing once `FLUSHED` catches up, as when other CPUs still
+    /// run their guest.
+    struct DeferredInstr;
+
+    impl PagingInstr for DeferredInstr {
+        unsafe fn activate(_root_paddr: PhysAddr) {}
+        fn flush(_vaddr: Option<usize>) {
+            GENERATION.fetch_add(1, Ordering::SeqCst);
+        }
+        fn flush_generation() -> u64 {
+            GENERATION.load(Ordering::SeqCst)
+        }
+        fn is_flushed(generation: u64) -> bool {
+            generation <= FLUSHED.load(Ordering::SeqCst)
+        }
+    }
+
+    type TestPageTable<I> = Level4PageTable<usize, NestedPTE, I>;
+
+    fn region(start: usize, size: usize) -> MemoryRegion<usize> {
+        MemoryRegion::new_with_offset_mapper(start, start - 0x3000_0000, size, MemFlags::READ)
+    }
+
+    /// Entries in use of the intermediate tables, sorted.
+    fn occupancy<I: PagingInstr>(pt: &TestPageTable<I>) -> Vec<usize> {
+        let mut used: Vec<usize> = pt.inner.intrm_tables.values().map(|t| t.used).collect();
+        used.sort_unstable();
+        used
+    }
+
+    #[test]
+    fn test_table_occupancy() {
+        crate::memory::init_test_frame_allocator();
+        let mut pt = TestPageTable::<SyncInstr>::new();
+        pt.map(&region(0x4000_0000, 2 * PAGE)).unwrap();
+        assert_eq!(occupancy(&pt), [1, 1, 2]);
+        pt.unmap(&region(0x4000_0000, PAGE)).unwrap();
+        assert_eq!(occupancy(&pt), [1, 1, 1]);
+        pt.unmap(&region(0x4000_1000, PAGE)).unwrap();
+        assert_eq!(occupancy(&pt), []);
+        assert_eq!(pt.table_frames(), 1);
+
+        // Unmapping a page of a huge page splits it.
+        pt.map(&region(0x4020_0000, HUGE_PAGE)).unwrap();
+        assert_eq!(occupancy(&pt), [1, 1]);
+        pt.unmap(&region(0x4020_0000, PAGE)).unwrap();
+        assert_eq!(occupancy(&pt), [1, 1, ENTRY_COUNT - 1]);
+        pt.unmap(&region(0x4020_1000, HUGE_PAGE - PAGE)).unwrap();
+        assert_eq!(occupancy(&pt), []);
+        assert_eq!(pt.table_frames(), 1);
+    }
+
+    #[test]
+    fn test_released_tables_wait_for_flush() {
+        crate::memory::init_test_frame_allocator();
+        let mut pt = TestPageTable::<DeferredInstr>::new();
+        pt.map(&region(0x4000_0000, PAGE)).unwrap();
+        pt.unmap(&region(0x4000_0000, PAGE)).unwrap();
+        let generation = GENERATION.load(Ordering::SeqCst);
+        assert_eq!(occupancy(&pt), []);
+        assert_eq!(pt.inner.retired.len(), 3);
+        assert!(pt.inner.retired.iter().all(|&(g, _)| g == generation));
+
+        // Not freed while a CPU may still walk them.
+        pt.map(&region(0x8000_0000, PAGE)).unwrap();
+        assert_eq!(pt.inner.retired.len(), 3);
+        assert_eq!(pt.table_frames(), 1 + 3 + 3);
+
+        FLUSHED.store(generation, Ordering::SeqCst);
+        pt.map(&region(0xc000_0000, PAGE)).unwrap();
+        assert!(pt.inner.retired.is_empty());
+        // The second region shares the top table with the first one.
+        assert_eq!(pt.table_frames(), 1 + 3 + 2);
+    }
+
+    #[test]
+    fn test_query_effective() {
+        crate::memory::init_test_frame_allocator();
+        let mut pt = TestPageTable::<SyncInstr>::new();
+        let flags = MemFlags::READ | MemFlags::WRITE;
+        pt.map(&MemoryRegion::new_with_offset_mapper(
+            0x4000_0000,
+            0x1000_0000,
+            PAGE,
+            flags,
+        ))
+        .unwrap();
+        let query = pt.inner.inner.query_effective(0x4000_0123).unwrap();
+        assert_eq!(query, (0x1000_0123, flags, PageSize::Size4K));
+
+        // A read-only entry of a higher level makes the page read-only.
+        let p3e = &mut table_of_mut::<NestedPTE>(pt.root_paddr())[p4_index(0x4000_0000)];
+        let p3 = next_table_mut(p3e).unwrap();
+        let p2e = &mut p3[p3_index(0x4000_0000)];
+        p2e.set_flags(MemFlags::READ | MemFlags::EXECUTE, false);
+        let (_, leaf_flags, _) = pt.query(0x4000_0000).unwrap();
+        assert_eq!(leaf_flags, flags);
+        let (_, flags, _) = pt.inner.inner.query_effective(0x4000_0000).unwrap();
+        assert_eq!(flags, MemFlags::READ);
+        assert!(pt.inner.inner.query_effective(0x4000_1000).is_err());
     }
 }
@@ -0,0 +1,113 @@
+//! Fixed-size object pools for the VM exit path.
+//!
+//! A pool holds up to 64 objects and allocates or frees one with a single
+//! atomic operation on its free mask, so the exit path never takes the heap
+//! lock nor waits for another CPU. Each CPU owns its pools (see `PerCpu`),
+//! but objects may be dropped on any CPU.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub struct ObjectPool<T, const N: usize> {
+    /// Bit `n` is set if slot `n` is free.
+    free: AtomicU64,
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+unsafe impl<T: Send, const N: usize> Sync for ObjectPool<T, N> {}
+
+/// An object allocated from an `ObjectPool`, returned to it on drop.
+pub struct PoolBox<T> {
+    ptr: NonNull<T>,
+    free: &'static AtomicU64,
+    idx: u32,
+}
+
+unsafe impl<T: Send> Send for PoolBox<T> {}
+
+impl<T, const N: usize> ObjectPool<T, N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_SLOT: UnsafeCell<MaybeUninit<T>> = UnsafeCell::new(MaybeUninit::uninit());
+
+    pub const fn new() -> Self {
+        assert!(N <= 64);
+        Self {
+            free: AtomicU64::new(if N == 64 { u64::MAX } else { (1 << N) - 1 }),
+            slots: [Self::EMPTY_SLOT; N],
+        }
+    }
+
+    /// Moves `value` into a free slot, or gives it back if the pool is
+    /// exhausted.
+    pub fn alloc(&'static self, value: T) -> Result<PoolBox<T>, T> {
+        let mut free = self.free.load(Ordering::Relaxed);
+        loop {
+            if free == 0 {
+                return Err(value);
+            }
+            let idx = free.trailing_zeros();
+            match self.free.compare_exchange_weak(
+                free,
+                free & !(1 << idx),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => free = actual,
+            }
+        }
+        let idx = free.trailing_zeros();
+        let slot = unsafe { &mut *self.slots[idx as usize].get() };
+        Ok(PoolBox {
+            ptr: NonNull::from(slot.write(value)),
+            free: &self.free,
+            idx,
+        })
+    }
+
+    /// Number of free slots.
+    pub fn available(&self) -> usize {
+        self.free.load(Ordering::Relaxed).count_ones() as usize
+    }
+}
+
+impl<T> Deref for PoolBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PoolBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for PoolBox<T> {
+    fn drop(&mut self) {
+        unsafe { self.ptr.as_ptr().drop_in_place() };
+        self.free.fetch_or(1 << self.idx, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_object_pool() {
+        static POOL: ObjectPool<u64, 2> = ObjectPool::new();
+        let a = POOL.alloc(1).ok().unwrap();
+        let mut b = POOL.alloc(2).ok().unwrap();
+        assert_eq!(POOL.alloc(3).err(), Some(3));
+        *b += 1;
+        assert_eq!((*a, *b), (1, 3));
+        drop(a);
+        assert_eq!(POOL.available(), 1);
+        assert_eq!(POOL.alloc(4).ok().map(|c| *c), Some(4));
+        assert_eq!(POOL.available(), 1);
+    }
+}
@@ -0,0 +1,170 @@
+//! Mirroring of critical hypervisor structures.
+//!
+//! Built with `MEM_MIRROR=on`, a second copy of the structures whose silent
+//! corruption would misdirect the hypervisor is kept on the heap: the header
+//! of the per-CPU data (ID and self pointer), the table addresses in the root
+//! table of the root cell EPT/NPT (the hardware updates the accessed bits) and
+//! the system configuration with the cell descriptor. Every
+//! `CHECK_INTERVAL_US`, one CPU compares them with their copies at the end of
+//! a VM exit. Legitimate changes go through `update()`.
+//!
+//! A mismatch comes from a single-event upset or a stray write. As there is
+//! no telling which copy is right, the hypervisor panics, naming the
+//! structure, the offset and the flipped bits. A single flipped bit points to
+//! an upset, several changed bytes to a stray write.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::cpu;
+use crate::lock::SpinLock;
+use crate::memory::addr::{phys_to_virt, VirtAddr};
+use crate::memory::{GenericPageTableImmut, PAGE_SIZE};
+use crate::percpu::PerCpu;
+
+/// Interval between two checks of all structures.
+const CHECK_INTERVAL_US: u64 = 100_000; // 100ms
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirrored {
+    /// Header of the per-CPU data of the CPU with this ID.
+    PerCpuHeader(u32),
+    /// Root table of the root cell EPT/NPT, only changed with the memory set
+    /// locked for writing.
+    NptRoot,
+    /// System configuration, including the root cell descriptor.
+    Config,
+}
+
+struct Mirror {
+    what: Mirrored,
+    vaddr: VirtAddr,
+    copy: Vec<u8>,
+    /// Bits compared in each 64-bit word.
+    mask: u64,
+}
+
+impl Mirror {
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.vaddr as *const u8, self.copy.len()) }
+    }
+
+    fn refresh(&mut self) {
+        self.copy = self.bytes().to_vec();
+    }
+
+    /// Panics with a diagnosis if the structure differs from its copy.
+    fn check(&self) {
+        let bytes = self.bytes();
+        let diff = || {
+            bytes
+                .iter()
+                .zip(&self.copy)
+                .enumerate()
+                .map(|(i, (&now, &was))| {
+                    (i, now, was, (now ^ was) & (self.mask >> (i % 8 * 8)) as u8)
+                })
+                .filter(|&(.., flipped)| flipped != 0)
+        };
+        let (offset, now, was, _) = match diff().next() {
+            Some(first) => first,
+            None => return,
+        };
+        let bad_bytes = diff().count();
+        let flipped_bits: u32 = diff().map(|(.., flipped)| flipped.count_ones()).sum();
+        let cause = if flipped_bits == 1 {
+            "single-event upset"
+        } else {
+            "stray write"
+        };
+        panic!(
+            "Mirrored {:?} @ {:#x} corrupted ({} likely): {} bytes, {} bits differ, \
+            first at offset {:#x}: {:#04x} (was {:#04x})",
+            self.what, self.vaddr, cause, bad_bytes, flipped_bits, offset, now, was
+        );
+    }
+}
+
+static MIRRORS: SpinLock<Vec<Mirror>> = SpinLock::new(Vec::new());
+static LAST_CHECK: AtomicU64 = AtomicU64::new(0);
+
+fn register(what: Mirrored, vaddr: VirtAddr, size: usize, mask: u64) {
+    let mut mirror = Mirror {
+        what,
+        vaddr,
+        copy: alloc::vec![0; size],
+        mask,
+    };
+    mirror.refresh();
+    MIRRORS.lock().push(mirror);
+}
+
+/// Mirrors the structures of all CPUs and of the root cell. Called once the
+/// CPUs have initialized, before the root cell runs.
+pub fn init() {
+    if !cfg!(feature = "mem-mirror") {
+        return;
+    }
+    for cpu_id in 0..PerCpu::entered_cpus() {
+        let (vaddr, size) = unsafe { PerCpu::from_id_mut(cpu_id) }.header();
+        register(Mirrored::PerCpuHeader(cpu_id), vaddr, size, !0);
+    }
+    let root = crate::cell::root_cell()
+        .gpm
+        .read()
+        .page_table()
+        .root_paddr();
+    let table_addr = crate::arch::mem_encrypt::phys_addr_mask();
+    register(Mirrored::NptRoot, phys_to_virt(root), PAGE_SIZE, table_addr);
+    let config = crate::config::HvSystemConfig::get();
+    register(
+        Mirrored::Config,
+        config as *const _ as VirtAddr,
+        config.size(),
+        !0,
+    );
+    info!("Mirroring {} structures", MIRRORS.lock().len());
+}
+
+/// Applies `f`, a legitimate change of `what`, and updates its copy.
+pub fn update<R>(what: Mirrored, f: impl FnOnce() -> R) -> R {
+    if !cfg!(feature = "mem-mirror") {
+        return f();
+    }
+    let mut mirrors = MIRRORS.lock();
+    let ret = f();
+    mirrors
+        .iter_mut()
+        .filter(|m| m.what == what)
+        .for_each(Mirror::refresh);
+    ret
+}
+
+/// Compares the structures with their copies every `CHECK_INTERVAL_US`.
+/// Called at the end of each VM exit, skipped if another CPU is already
+/// checking.
+pub fn poll() {
+    if !cfg!(feature = "mem-mirror") {
+        return;
+    }
+    let now = cpu::current_cycle();
+    let last = LAST_CHECK.load(Ordering::Relaxed);
+    if now.wrapping_sub(last) < CHECK_INTERVAL_US * cpu::frequency() as u64 {
+        return;
+    }
+    let mirrors = match MIRRORS.try_lock() {
+        Some(mirrors) => mirrors,
+        None => return,
+    };
+    LAST_CHECK.store(now, Ordering::Relaxed);
+    for m in mirrors.iter() {
+        if m.what == Mirrored::NptRoot {
+            // Being changed if the memory set is locked for writing.
+            if let Some(_gpm) = crate::cell::root_cell().gpm.try_read() {
+                m.check();
+            }
+        } else {
+            m.check();
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Emulated MMIO devices.
+//!
+//! Devices are registered per cell on guest physical ranges that are not
+//! mapped in the nested page table, so every access traps. The arch decodes
+//! the faulting instruction and forwards it to `MmioRegistry::dispatch()`,
+//! which serializes accesses to each device and accounts them.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::fmt::{Debug, Formatter, Result};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+use crate::memory::GuestPhysAddr;
+use crate::stats_window::{trace_io, IoTraceKind};
+
+/// A device emulated by the hypervisor on a guest physical range.
+///
+/// `offset` is relative to the start of the range, `size` is the access size
+/// in bytes: 1, 2, 4 or 8.
+pub trait MmioDevice: Send {
+    /// Name of the device, for debugging.
+    fn name(&self) -> &str;
+
+    fn read(&self, offset: usize, size: u8) -> u64;
+
+    fn write(&mut self, offset: usize, size: u8, value: u64);
+}
+
+/// A decoded guest access to an MMIO range.
+#[derive(Debug)]
+pub struct MmioAccess {
+    pub gpaddr: GuestPhysAddr,
+    /// Access size in bytes: 1, 2, 4 or 8.
+    pub size: u8,
+    pub is_write: bool,
+    /// Value to write, ignored for reads.
+    pub value: u64,
+    /// Guest RIP of the access instruction.
+    pub rip: u64,
+}
+
+struct MmioRegion {
+    start: GuestPhysAddr,
+    size: usize,
+    device: SpinLock<Box<dyn MmioDevice>>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+/// The emulated MMIO devices of a cell.
+pub struct MmioRegistry {
+    regions: BTreeMap<GuestPhysAddr, MmioRegion>,
+}
+
+impl MmioRegion {
+    fn contains(&self, gpaddr: GuestPhysAddr) -> bool {
+        self.start <= gpaddr && gpaddr < self.start + self.size
+    }
+}
+
+impl MmioRegistry {
+    pub fn new() -> Self {
+        Self {
+            regions: BTreeMap::new(),
+        }
+    }
+
+    fn find(&self, gpaddr: GuestPhysAddr) -> Option<&MmioRegion> {
+        self.regions
+            .range(..=gpaddr)
+            .last()
+            .map(|(_, r)| r)
+            .filter(|r| r.contains(gpaddr))
+    }
+
+    /// Whether an emulated device covers `gpaddr`.
+    pub fn contains(&self, gpaddr: GuestPhysAddr) -> bool {
+        self.find(gpaddr).is_some()
+    }
+
+    /// Emulates `device` on `[start, start + size)`. The range must not
+    /// overlap other devices.
+    pub fn register(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        device: Box<dyn MmioDevice>,
+    ) -> HvResult {
+        if size == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let end = start + size;
+        let overlapped = self
+            .regions
+            .range(..end)
+            .last()
+            .map_or(false, |(_, r)| r.start + r.size > start);
+        if overlapped {
+            return hv_result_err!(EEXIST, "MMIO range [{:#x}, {:#x}) overlapped", start, end);
+        }
+        info!(
+            "Registered MMIO device {:?} @ [{:#x}, {:#x})",
+            device.name(),
+            start,
+            end
+        );
+        self.regions.insert(
+            start,
+            MmioRegion {
+                start,
+                size,
+                device: SpinLock::new(device),
+                reads: AtomicU64::new(0),
+                writes: AtomicU64::new(0),
+            },
+        );
+        Ok(())
+    }
+
+    /// Forwards `access` to the device covering it, and returns the value
+    /// read (0 for writes).
+    pub fn dispatch(&self, access: &MmioAccess) -> HvResult<u64> {
+        let region = self
+            .find(access.gpaddr)
+            .ok_or_else(|| hv_err!(ENODEV, "No MMIO device at {:#x}", access.gpaddr))?;
+        let offset = access.gpaddr - region.start;
+        if offset + access.size as usize > region.size {
+            return hv_result_err!(EINVAL, "MMIO access crosses the end of the device");
+        }
+        let value = if access.is_write {
+            region.writes.fetch_add(1, Ordering::Relaxed);
+            trace_io(
+                IoTraceKind::MmioWrite,
+                access.gpaddr as _,
+                access.size,
+                access.value,
+                access.rip,
+            );
+            region
+                .device
+                .lock()
+                .write(offset, access.size, access.value);
+            0
+        } else {
+            region.reads.fetch_add(1, Ordering::Relaxed);
+            let value = region.device.lock().read(offset, access.size);
+            trace_io(
+                IoTraceKind::MmioRead,
+                access.gpaddr as _,
+                access.size,
+                value,
+                access.rip,
+            );
+            value
+        };
+        Ok(value)
+    }
+}
+
+impl Debug for MmioRegion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("MmioRegion")
+            .field("gpaddr_range", &(self.start..self.start + self.size))
+            .field("device", &self.device.lock().name())
+            .field("reads", &self.reads.load(Ordering::Relaxed))
+            .field("writes", &self.writes.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Debug for MmioRegistry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_list().entries(self.regions.values()).finish()
+    }
+}
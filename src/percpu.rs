@@ -1,13 +1,23 @@
 use core::fmt::{Debug, Formatter, Result};
 use core::sync::atomic::{AtomicU32, Ordering};
 
-use crate::arch::vmm::{Vcpu, VcpuAccessGuestState};
-use crate::arch::{cpu, ArchPerCpu, LinuxContext};
-use crate::cell::Cell;
-use crate::consts::{PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
-use crate::error::HvResult;
+use crate::arch::vmm::VcpuAccessGuestState;
+use crate::arch::{cpu, ArchPerCpu, ArchVcpu, LinuxContext};
+use crate::cell::{Cell, CellState};
+use crate::consts::{ERROR_MSG_POOL_SIZE, MAX_CPUS, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
+use crate::error::{HvError, HvResult, MsgBuf};
+use crate::hal::{LocalIrqChip, Vcpu};
 use crate::header::HvHeader;
-use crate::memory::VirtAddr;
+use crate::memory::gaccess::FetchLog;
+use crate::memory::{EmergencyHeap, ObjectPool, VirtAddr};
+use crate::stats::{report_init_phase, InitPhase, Instant};
+
+/// Byte filling the per-CPU data left by a CPU disabling the hypervisor.
+#[cfg(feature = "scrub-percpu")]
+const POISON_BYTE: u8 = 0x6b;
+/// Stack below the stack pointer kept while poisoning, for the calls made.
+#[cfg(feature = "scrub-percpu")]
+const SCRUB_STACK_MARGIN: usize = 1024;
 
 static ENTERED_CPUS: AtomicU32 = AtomicU32::new(0);
 static ACTIVATED_CPUS: AtomicU32 = AtomicU32::new(0);
@@ -25,15 +35,30 @@ pub struct PerCpu {
 
     pub id: u32,
     pub state: CpuState,
-    pub vcpu: Vcpu,
+    /// Whether the guest has run since the hypervisor was enabled, i.e. a
+    /// VM exit has been handled.
+    pub guest_ran: bool,
+    pub vcpu: ArchVcpu,
     arch: ArchPerCpu,
     linux: LinuxContext,
+    pub emergency_heap: EmergencyHeap,
+    pub msg_pool: ObjectPool<MsgBuf, ERROR_MSG_POOL_SIZE>,
+    pub guest_fetches: FetchLog,
     // Stack will be placed here.
 }
 
 impl PerCpu {
     pub fn new<'a>() -> HvResult<&'a mut Self> {
-        if Self::entered_cpus() >= HvHeader::get().max_cpus {
+        let max_cpus = HvHeader::get().max_cpus;
+        if max_cpus as usize > MAX_CPUS {
+            return hv_result_err!(
+                EINVAL,
+                "{} CPUs exceed the maximum of {}",
+                max_cpus,
+                MAX_CPUS
+            );
+        }
+        if Self::entered_cpus() >= max_cpus {
             return hv_result_err!(EINVAL);
         }
 
@@ -42,10 +67,20 @@ impl PerCpu {
         let vaddr = ret as *const _ as VirtAddr;
         ret.id = cpu_id;
         ret.self_vaddr = vaddr;
+        unsafe {
+            core::ptr::write(&mut ret.emergency_heap, EmergencyHeap::new());
+            core::ptr::write(&mut ret.msg_pool, ObjectPool::new());
+            core::ptr::write(&mut ret.guest_fetches, FetchLog::new());
+        }
         cpu::set_thread_pointer(vaddr);
         Ok(ret)
     }
 
+    pub unsafe fn from_id<'a>(cpu_id: u32) -> &'a Self {
+        let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
+        &*(vaddr as *const Self)
+    }
+
     pub unsafe fn from_id_mut<'a>(cpu_id: u32) -> &'a mut Self {
         let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
         &mut *(vaddr as *mut Self)
@@ -59,6 +94,30 @@ impl PerCpu {
         unsafe { &mut *(cpu::thread_pointer() as *mut Self) }
     }
 
+    /// Returns the data of the current CPU, or `None` if it has not entered
+    /// the hypervisor yet. Unlike `current_mut()`, never reads memory through
+    /// the thread pointer of Linux.
+    pub fn try_current_mut<'a>() -> Option<&'a mut Self> {
+        let tp = cpu::thread_pointer_base();
+        let offset = tp.checked_sub(PER_CPU_ARRAY_PTR as VirtAddr)?;
+        let max_cpus = HvHeader::get().max_cpus as usize;
+        if offset % PER_CPU_SIZE != 0 || offset / PER_CPU_SIZE >= max_cpus {
+            return None;
+        }
+        let ret = unsafe { &mut *(tp as *mut Self) };
+        // Set by `new()` before the thread pointer, in whatever order the
+        // CPUs enter.
+        (ret.self_vaddr == tp).then(|| ret)
+    }
+
+    /// Address and size of the header of the data: the self pointer
+    /// referenced by the thread pointer, and the CPU ID.
+    pub fn header(&self) -> (VirtAddr, usize) {
+        let start = self as *const _ as VirtAddr;
+        let end = &self.id as *const _ as VirtAddr + core::mem::size_of::<u32>();
+        (start, end - start)
+    }
+
     pub fn stack_top(&self) -> VirtAddr {
         self as *const _ as VirtAddr + PER_CPU_SIZE - 8
     }
@@ -71,21 +130,46 @@ impl PerCpu {
         ACTIVATED_CPUS.load(Ordering::Acquire)
     }
 
-    pub fn init(&mut self, linux_sp: usize, cell: &Cell) -> HvResult {
-        info!("CPU {} init...", self.id);
-
-        // Save CPU state used for linux.
+    /// Saves the CPU state used for Linux, while its page table is still
+    /// active.
+    pub fn save_linux(&mut self, linux_sp: usize) {
         self.state = CpuState::HvDisabled;
         self.linux = LinuxContext::load_from(linux_sp);
+    }
+
+    /// Switches back to the page table of Linux after a failed enable: the
+    /// Linux stack is not mapped by the hypervisor page table.
+    pub fn restore_linux_page_table(&self) {
+        self.linux.restore_page_table();
+    }
+
+    pub fn init(&mut self, cell: &Cell) -> HvResult {
+        info!("CPU {} init...", self.id);
 
         // Activate hypervisor page table on each cpu.
         unsafe { crate::memory::hv_page_table().read().activate() };
+        self.linux.save_timer();
 
+        let now = Instant::now();
         self.arch.init(self.id)?;
+        report_init_phase(self.id, InitPhase::CpuInit, now.elapsed());
+        let hw_id = crate::arch::local_irq_chip().id();
+        if !cell.cpu_set.contains(hw_id) {
+            return hv_result_err!(
+                EINVAL,
+                format!("CPU {} (hardware ID {}) is not in the cell", self.id, hw_id)
+            );
+        }
 
         // Initialize vCPU. Use `ptr::write()` to avoid dropping
-        unsafe { core::ptr::write(&mut self.vcpu, Vcpu::new(&self.linux, cell)?) };
+        let now = Instant::now();
+        unsafe { core::ptr::write(&mut self.vcpu, ArchVcpu::new(&self.linux, cell)?) };
+        report_init_phase(self.id, InitPhase::VcpuSetup, now.elapsed());
+        #[cfg(feature = "protect-desc-tables")]
+        crate::integrity::protect_desc_tables(&self.vcpu, &self.linux)?;
+        self.linux.check_timer(self.id);
 
+        self.guest_ran = false;
         self.state = CpuState::HvEnabled;
         Ok(())
     }
@@ -99,16 +183,80 @@ impl PerCpu {
     }
 
     pub fn deactivate_vmm(&mut self, ret_code: usize) -> HvResult {
+        self.leave_hypervisor(Some(ret_code))
+    }
+
+    /// Returns to Linux, with `ret_code` in RAX if any, or the guest
+    /// registers as they are.
+    fn leave_hypervisor(&mut self, ret_code: Option<usize>) -> HvResult {
         println!("Deactivating hypervisor on CPU {}...", self.id);
         ACTIVATED_CPUS.fetch_sub(1, Ordering::SeqCst);
+        // Linux may reuse the hypervisor memory, including the DMA remapping
+        // tables, once any CPU has left.
+        crate::arch::iommu::disable();
 
-        self.vcpu.set_return_val(ret_code);
+        if let Some(ret_code) = ret_code {
+            self.vcpu.set_return_val(ret_code);
+        }
         self.vcpu.exit(&mut self.linux)?;
         self.linux.restore();
         self.state = CpuState::HvDisabled;
+        #[cfg(feature = "scrub-percpu")]
+        self.scrub_and_return_to_linux();
+        #[cfg(not(feature = "scrub-percpu"))]
         self.linux.return_to_linux(self.vcpu.regs());
     }
 
+    /// Returns to Linux like `LinuxContext::return_to_linux()`, but leaves
+    /// no Linux or guest state in the per-CPU data of this CPU: the guest
+    /// registers are staged on the Linux stack, then all the data after the
+    /// header and state is poisoned, up to the stack in use, and the saved
+    /// Linux context is zeroed. This covers the vCPU, the emergency heap, the
+    /// message pool and the guest fetch log. Guest-derived data in shared
+    /// memory, e.g. the heap or the log ring, is not scrubbed.
+    #[cfg(feature = "scrub-percpu")]
+    fn scrub_and_return_to_linux(&mut self) -> ! {
+        let regs = self.linux.stage_return(self.vcpu.regs());
+        let gs_base = self.linux.gs.base;
+        let base = self as *mut Self as *mut u8;
+        let start = &self.vcpu as *const _ as usize - base as usize;
+        let linux = &self.linux as *const _ as usize - base as usize;
+        let sp: usize;
+        unsafe { core::arch::asm!("mov {}, rsp", out(reg) sp) };
+        let end = sp - SCRUB_STACK_MARGIN - base as usize;
+        unsafe {
+            core::ptr::write_bytes(base.add(start), POISON_BYTE, end - start);
+            core::ptr::write_bytes(base.add(linux), 0, core::mem::size_of::<LinuxContext>());
+            LinuxContext::return_staged(gs_base, regs)
+        }
+    }
+
+    /// Leaves the hypervisor after a failed VM entry. On the first entry,
+    /// `err` is returned to Linux as the result of the driver's enable call
+    /// on this CPU. Once the guest has run, Linux goes on where it was with
+    /// its registers untouched, the error is only logged. The other CPUs
+    /// stay in the hypervisor until disabled, which no longer waits for this
+    /// one.
+    pub fn abort_entry(&mut self, err: HvError) -> ! {
+        error!("CPU {} failed to enter the guest: {:?}", self.id, err);
+        let _ = crate::cell::root_cell()
+            .lifecycle
+            .transition(CellState::Failed);
+        let ret_code = (!self.guest_ran).then(|| err.code() as usize);
+        let res = self.leave_hypervisor(ret_code);
+        error!("Failed to leave the hypervisor: {:?}", res);
+        loop {}
+    }
+
+    /// Number of activated CPUs shut down by a VMX abort.
+    pub fn aborted_cpus() -> u32 {
+        (0..Self::entered_cpus())
+            .map(|id| unsafe { Self::from_id(id) })
+            .filter(|cpu_data| cpu_data.state == CpuState::HvEnabled)
+            .filter(|cpu_data| cpu_data.vcpu.abort_indicator().is_some())
+            .count() as u32
+    }
+
     pub fn fault(&mut self) -> HvResult {
         warn!("VCPU fault: {:#x?}", self);
         self.vcpu.inject_fault()?;
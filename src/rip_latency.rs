@@ -0,0 +1,100 @@
+//! Exit latency histograms of guest code ranges.
+//!
+//! The root cell registers up to `MAX_RIP_RANGES` guest RIP ranges of interest
+//! (e.g. the IRQ entry code of Linux) with the `LatencyRangeSetup` hypercall.
+//! While statistics are enabled, every VM exit whose guest RIP falls in a
+//! range adds its handler time to the histogram of the range, read back with
+//! the `RipLatency` info type. Bucket `n` counts the exits which took
+//! `[2^(n+8), 2^(n+9))` TSC cycles, the first and last buckets also take the
+//! shorter and longer ones.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::error::HvResult;
+
+pub const MAX_RIP_RANGES: usize = 8;
+pub const NUM_LATENCY_BUCKETS: usize = 16;
+
+const MIN_BUCKET_SHIFT: u32 = 8;
+
+struct RipRange {
+    start: AtomicU64,
+    end: AtomicU64,
+    buckets: [AtomicU64; NUM_LATENCY_BUCKETS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_RANGE: RipRange = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    RipRange {
+        start: ZERO,
+        end: ZERO,
+        buckets: [ZERO; NUM_LATENCY_BUCKETS],
+    }
+};
+
+static RANGES: [RipRange; MAX_RIP_RANGES] = [EMPTY_RANGE; MAX_RIP_RANGES];
+/// Bit `n` is set if range `n` is registered.
+static ACTIVE_RANGES: AtomicU32 = AtomicU32::new(0);
+
+fn bucket(cycles: u64) -> usize {
+    let log2 = 63u32.saturating_sub(cycles.leading_zeros());
+    (log2.saturating_sub(MIN_BUCKET_SHIFT) as usize).min(NUM_LATENCY_BUCKETS - 1)
+}
+
+/// Whether the exit path needs to call `record()`.
+pub fn active() -> bool {
+    ACTIVE_RANGES.load(Ordering::Relaxed) != 0 && crate::stats::enabled()
+}
+
+/// Registers `[start, start + size)` as range `idx` and resets its histogram,
+/// or unregisters it if `size` is 0.
+pub fn set_range(idx: usize, start: u64, size: u64) -> HvResult {
+    let range = match RANGES.get(idx) {
+        Some(range) => range,
+        None => return hv_result_err!(EINVAL, "Invalid RIP range index"),
+    };
+    let end = match start.checked_add(size) {
+        Some(end) => end,
+        None => return hv_result_err!(EINVAL, "RIP range overflows"),
+    };
+    ACTIVE_RANGES.fetch_and(!(1 << idx), Ordering::AcqRel);
+    range.end.store(0, Ordering::Release);
+    for count in &range.buckets {
+        count.store(0, Ordering::Relaxed);
+    }
+    if size != 0 {
+        range.start.store(start, Ordering::Relaxed);
+        range.end.store(end, Ordering::Release);
+        ACTIVE_RANGES.fetch_or(1 << idx, Ordering::AcqRel);
+        info!(
+            "Exit latency of RIP [{:#x}, {:#x}) recorded in range {}",
+            start, end, idx
+        );
+    }
+    Ok(())
+}
+
+/// Adds an exit at guest `rip` which took `cycles` to the matching ranges.
+pub fn record(rip: u64, cycles: u64) {
+    let active = ACTIVE_RANGES.load(Ordering::Acquire);
+    for (idx, range) in RANGES.iter().enumerate() {
+        if active & (1 << idx) == 0 {
+            continue;
+        }
+        let end = range.end.load(Ordering::Acquire);
+        if range.start.load(Ordering::Relaxed) <= rip && rip < end {
+            range.buckets[bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the number of exits in bucket `bucket` of range `idx`.
+pub fn bucket_count(idx: usize, bucket: usize) -> Option<u64> {
+    let range = RANGES.get(idx)?;
+    range
+        .buckets
+        .get(bucket)
+        .map(|count| count.load(Ordering::Relaxed))
+}
@@ -1,10 +1,62 @@
 #![allow(dead_code)]
 
-#[cfg(feature = "stats")]
+//! Performance statistics.
+//!
+//! Collection can be switched at runtime by the `StatsControl` hypercall, and
+//! is enabled by default if built with `STATS=on`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
 pub use _stats::*;
 
-#[cfg(not(feature = "stats"))]
-pub use _stats_empty::*;
+static STATS_ENABLED: AtomicBool = AtomicBool::new(cfg!(feature = "stats"));
+
+/// Whether statistics are being collected.
+pub fn enabled() -> bool {
+    STATS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables or disables statistics collection, returns the previous state.
+pub fn set_enabled(enabled: bool) -> bool {
+    let old = STATS_ENABLED.swap(enabled, Ordering::Relaxed);
+    crate::stats_window::set_stats_enabled(enabled);
+    old
+}
+
+/// Phases of enabling and disabling the hypervisor, timed on each CPU.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy)]
+pub enum InitPhase {
+    /// Heap and frame allocator initialization (primary CPU).
+    MemoryInit = 0,
+    /// Building the hypervisor page table (primary CPU).
+    HvPageTable = 1,
+    /// Building the root cell and its nested page table (primary CPU).
+    CellInit = 2,
+    /// Per-CPU descriptor tables and interrupt controller.
+    CpuInit = 3,
+    /// Enabling virtualization and setting up the VMCS/VMCB.
+    VcpuSetup = 4,
+    /// Starting the RT CPUs.
+    RtStart = 5,
+    /// Waiting for all CPUs to disable the hypervisor.
+    Disable = 6,
+}
+
+pub const NUM_INIT_PHASES: usize = 7;
+
+/// Reports `cycles` spent in `phase` on `cpu_id` to the log and the stats
+/// window, regardless of whether statistics are enabled.
+pub fn report_init_phase(cpu_id: u32, phase: InitPhase, cycles: u64) {
+    info!(
+        "CPU {} {:?}: {} cycles ({} us)",
+        cpu_id,
+        phase,
+        cycles,
+        cycles / crate::arch::cpu::frequency() as u64
+    );
+    crate::stats_window::record_init_phase(cpu_id, phase, cycles);
+}
 
 mod _stats {
     use core::sync::atomic::{AtomicU64, Ordering};
@@ -24,11 +76,17 @@ mod _stats {
         }
 
         pub fn add(&mut self, value: u64) {
+            if !super::enabled() {
+                return;
+            }
             *self.count.get_mut() += 1;
             *self.sum.get_mut() += value;
         }
 
         pub fn atomic_add(&self, value: u64) {
+            if !super::enabled() {
+                return;
+            }
             self.count.fetch_add(1, Ordering::Release);
             self.sum.fetch_add(value, Ordering::Release);
         }
@@ -64,28 +122,6 @@ mod _stats {
     }
 }
 
-mod _stats_empty {
-    #[derive(Default)]
-    pub struct StatsValue;
-    impl StatsValue {
-        pub fn new() -> Self {
-            Self
-        }
-        pub fn add(&mut self, _value: u64) {}
-        pub fn atomic_add(&self, _value: u64) {}
-    }
-
-    pub struct Instant;
-    impl Instant {
-        pub fn now() -> Self {
-            Self
-        }
-        pub fn elapsed(&self) -> u64 {
-            0
-        }
-    }
-}
-
 #[cfg(all(test, feature = "stats"))]
 mod test {
     use super::*;
@@ -0,0 +1,739 @@
+//! Zero-copy export of statistics and logs to the root cell.
+//!
+//! A window of hypervisor pages is mapped read-only into the root cell at the
+//! GPA declared by `HvSystemConfig::stats_window_gpa`, so a Linux daemon can
+//! poll metrics without any VM exits.
+//!
+//! Window layout (version 12):
+//!
+//!     +--------------------------------------+ - offset 0
+//!     | StatsHeader                          |
+//!     +--------------------------------------+ - cpu_stats_offset
+//!     | CpuStats 0                           |
+//!     | CpuStats 1                           |
+//!     | ...                                  |
+//!     | CpuStats num_cpus-1                  |
+//!     +--------------------------------------+ - log_offset
+//!     | Log ring (log_size bytes)            |
+//!     +--------------------------------------+ - trace_offset
+//!     | I/O trace ring (trace_size records)  |
+//!     +--------------------------------------+ - exit_trace_offset
+//!     | Exit trace ring (exit_trace_size     |
+//!     | records)                             |
+//!     +--------------------------------------+ - mem_map_offset
+//!     | Memory map (mem_map_count entries)   |
+//!     +--------------------------------------+
+//!
+//! Each `CpuStats` is only written by its CPU (by the root CPU sampling the
+//! RTOS deadlines for RT CPUs) and protected by a sequence counter: it is odd
+//! while an update is in progress, readers must retry if it is odd or changed
+//! during the read. They are only updated while
+//! `stats_enabled` is set. The log ring is written at offset
+//! `log_written % log_size`, `log_written` is a free running counter.
+//!
+//! Instead of polling the log ring, the driver may ask for an interrupt once
+//! half of it is unread with `LogNotifySetup`, and report how far it has read
+//! with `LogDrained`, which re-arms the notification. Records are overwritten
+//! if the driver does not keep up.
+//!
+//! With the `io-record` feature, every intercepted MMIO/PIO access is recorded
+//! into the I/O trace ring, so that the way the guest programmed a device can
+//! be replayed offline. Record `n` is stored in slot `n % trace_size`, and its
+//! `seq` is set to `n + 1` after all other fields are written.
+//!
+//! While statistics are enabled, every VM exit is recorded into the exit
+//! trace ring the same way, with its duration and hypercall code, for
+//! timeline views of the per-CPU exits (`rvm-ctl trace` converts them to the
+//! Chrome trace format). The exits during the last deadline miss of each RT
+//! CPU are counted from this ring, to tell hypervisor interference apart.
+//!
+//! The memory map describes the physical ranges taken from Linux by the
+//! hypervisor and the RTOS, and how the root cell sees them, e.g. to exclude
+//! them from kdump. Entries nested in another one (shared or released pages
+//! of hypervisor memory) follow it. `mem_map_count` is 0 while the map is
+//! being rewritten, after `MemRelease`.
+
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use spin::Once;
+
+use crate::config::HvSystemConfig;
+use crate::error::{HvErrorSubsystem, HvResult, NUM_ERROR_SUBSYSTEMS};
+use crate::header::{HvHeader, LoaderFeatures};
+use crate::lock::SpinLock;
+use crate::memory::addr::{align_up, PhysAddr};
+use crate::memory::{Frame, MemFlags, PAGE_SIZE};
+use crate::stats::{InitPhase, NUM_INIT_PHASES};
+
+pub const STATS_WINDOW_MAGIC: u32 = u32::from_le_bytes(*b"RVMS");
+pub const STATS_WINDOW_VERSION: u32 = 12;
+
+/// Size of the log ring in bytes.
+const LOG_RING_SIZE: usize = 16 * 1024; // 16 KB
+
+/// Number of records in the I/O trace ring.
+const TRACE_RING_LEN: usize = if cfg!(feature = "io-record") { 1024 } else { 0 };
+
+/// Number of records in the exit trace ring.
+const EXIT_TRACE_RING_LEN: usize = 2048;
+
+/// Capacity of the memory map.
+const MEM_MAP_LEN: usize = 8;
+
+/// Number of RT CPUs whose counters reported by the RTOS are published.
+pub const MAX_RT_REPORTED_CPUS: usize = 32;
+
+/// `MemMapEntry::access`: readable by the root cell.
+pub const MEM_MAP_READ: u32 = 1 << 0;
+/// `MemMapEntry::access`: writable by the root cell.
+pub const MEM_MAP_WRITE: u32 = 1 << 1;
+
+#[repr(C)]
+pub struct StatsHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub num_cpus: u32,
+    pub cpu_stats_offset: u32,
+    pub cpu_stats_size: u32,
+    pub log_offset: u32,
+    pub log_size: u32,
+    /// 1 if `CpuStats` are being updated, see `stats::set_enabled()`.
+    pub stats_enabled: AtomicU32,
+    pub log_written: AtomicU64,
+    /// Number of errors in each subsystem, indexed by `HvErrorSubsystem`.
+    pub error_counts: [AtomicU64; NUM_ERROR_SUBSYSTEMS],
+    pub trace_offset: u32,
+    pub trace_size: u32,
+    pub trace_written: AtomicU64,
+    pub exit_trace_offset: u32,
+    pub exit_trace_size: u32,
+    pub exit_trace_written: AtomicU64,
+    /// TSC frequency in MHz.
+    pub tsc_mhz: u32,
+    /// Meaning of `ExitTraceRecord::reason`: 1 for Intel VMX exit reasons, 2
+    /// for AMD SVM exit codes.
+    pub exit_reason_vendor: u32,
+    pub mem_map_offset: u32,
+    pub mem_map_size: u32,
+    pub mem_map_count: AtomicU32,
+    _reserved: u32,
+    /// Counters of the devices assigned to the RTOS, as reported by the RTOS
+    /// in its boot information: the hypervisor does not intercept them, and
+    /// cannot check them. Interrupts are those handled by the RT CPU with
+    /// the APIC ID at the same index, 0 past the RT CPUs.
+    pub rt_reported_apic_ids: [AtomicU32; MAX_RT_REPORTED_CPUS],
+    pub rt_reported_irqs: [AtomicU64; MAX_RT_REPORTED_CPUS],
+    /// Bytes transferred by DMA.
+    pub rt_reported_dma_bytes: AtomicU64,
+}
+
+#[repr(C, align(64))]
+pub struct CpuStats {
+    pub seq: AtomicU32,
+    _reserved: u32,
+    pub vm_exits: AtomicU64,
+    pub hypercalls: AtomicU64,
+    /// Total TSC cycles spent in VM exit handlers.
+    pub exit_cycles: AtomicU64,
+    /// TSC cycles of the last run of each phase, indexed by `InitPhase`.
+    pub init_cycles: [AtomicU64; NUM_INIT_PHASES],
+    /// Number of SMIs since reset, sampled on VM exits. Stays 0 if the CPU
+    /// does not count SMIs.
+    pub smi_count: AtomicU64,
+    /// TSC of the last `smi_count` sample, to derive SMI rates.
+    pub smi_sample_tsc: AtomicU64,
+    /// Periodic cycles completed by a RT CPU, as reported by the RTOS.
+    pub rt_cycles: AtomicU64,
+    /// Cycles of a RT CPU which ended past their deadline.
+    pub deadline_misses: AtomicU64,
+    /// TSC at the start of the last missed cycle.
+    pub last_miss_tsc: AtomicU64,
+    /// TSC cycles past the deadline at the end of the last missed cycle.
+    pub last_miss_overrun: AtomicU64,
+    /// Root cell VM exits traced during the last missed cycle, and the TSC
+    /// cycles spent in them.
+    pub last_miss_exits: AtomicU64,
+    pub last_miss_exit_cycles: AtomicU64,
+    /// TSC cycles spent in the VM exits of hypercalls issued with VMCALL,
+    /// and the number and cycles of those issued through the fast path, to
+    /// compare their latencies.
+    pub hypercall_cycles: AtomicU64,
+    pub fast_hypercalls: AtomicU64,
+    pub fast_hypercall_cycles: AtomicU64,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum IoTraceKind {
+    PioRead = 1,
+    PioWrite = 2,
+    MmioRead = 3,
+    MmioWrite = 4,
+}
+
+#[repr(C)]
+pub struct IoTraceRecord {
+    /// Record number plus one, 0 while the record is being written.
+    pub seq: AtomicU64,
+    pub tsc: u64,
+    /// Guest RIP of the access instruction.
+    pub rip: u64,
+    /// Port number or guest physical address.
+    pub addr: u64,
+    pub value: u64,
+    pub cpu_id: u32,
+    pub kind: u8,
+    /// Access size in bytes.
+    pub size: u8,
+    _reserved: u16,
+}
+
+#[repr(C)]
+pub struct ExitTraceRecord {
+    /// Record number plus one, 0 while the record is being written.
+    pub seq: AtomicU64,
+    /// TSC at the start of the exit handler.
+    pub tsc: u64,
+    /// TSC cycles spent in the exit handler.
+    pub cycles: u64,
+    pub cpu_id: u32,
+    /// Raw exit reason, see `StatsHeader::exit_reason_vendor`.
+    pub reason: u32,
+    /// Hypercall code plus one, 0 if the exit was not a hypercall.
+    pub hypercall: u32,
+    _reserved: u32,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub enum MemMapOwner {
+    /// Hypervisor memory, hidden from the root cell.
+    Hypervisor = 1,
+    /// Hypervisor pages mapped read-only into the root cell (the header page
+    /// and this window).
+    HypervisorShared = 2,
+    /// Hypervisor memory given to the root cell by `MemRelease`.
+    HypervisorReleased = 3,
+    Rtos = 4,
+    /// Boot information page of the RTOS.
+    RtosBootInfo = 5,
+}
+
+#[repr(C)]
+pub struct MemMapEntry {
+    /// Host physical address.
+    pub start: u64,
+    pub size: u64,
+    /// Guest physical address in the root cell, `u64::MAX` if not mapped.
+    pub root_gpa: u64,
+    /// A `MemMapOwner`.
+    pub owner: u32,
+    /// `MEM_MAP_*` flags of the root cell.
+    pub access: u32,
+}
+
+struct StatsWindow {
+    frame: Frame,
+    num_cpus: usize,
+    log_offset: usize,
+    trace_offset: usize,
+    exit_trace_offset: usize,
+    mem_map_offset: usize,
+}
+
+static STATS_WINDOW: Once<StatsWindow> = Once::new();
+static LOG_LOCK: SpinLock<()> = SpinLock::new(());
+
+impl StatsWindow {
+    fn header(&self) -> &StatsHeader {
+        unsafe { &*(self.frame.as_ptr() as *const StatsHeader) }
+    }
+
+    fn cpu_stats(&self, cpu_id: usize) -> Option<&CpuStats> {
+        if cpu_id < self.num_cpus {
+            let ptr =
+                self.frame.as_ptr() as usize + cpu_stats_offset() + cpu_id * size_of::<CpuStats>();
+            Some(unsafe { &*(ptr as *const CpuStats) })
+        } else {
+            None
+        }
+    }
+
+    fn log_ring(&self) -> *mut u8 {
+        (self.frame.as_ptr() as usize + self.log_offset) as *mut u8
+    }
+
+    fn trace_ring(&self) -> *mut IoTraceRecord {
+        (self.frame.as_ptr() as usize + self.trace_offset) as *mut IoTraceRecord
+    }
+
+    fn exit_trace_ring(&self) -> *mut ExitTraceRecord {
+        (self.frame.as_ptr() as usize + self.exit_trace_offset) as *mut ExitTraceRecord
+    }
+
+    fn mem_map(&self) -> *mut MemMapEntry {
+        (self.frame.as_ptr() as usize + self.mem_map_offset) as *mut MemMapEntry
+    }
+}
+
+const fn cpu_stats_offset() -> usize {
+    align_up(size_of::<StatsHeader>())
+}
+
+/// Allocates the window if configured.
+pub fn init() -> HvResult {
+    if HvSystemConfig::get().stats_window_gpa == 0
+        || !HvHeader::get().has_features(LoaderFeatures::STATS_WINDOW)
+    {
+        return Ok(());
+    }
+    let num_cpus = HvHeader::get().max_cpus as usize;
+    let log_offset = cpu_stats_offset() + align_up(num_cpus * size_of::<CpuStats>());
+    let trace_offset = log_offset + LOG_RING_SIZE;
+    let exit_trace_offset = trace_offset + align_up(TRACE_RING_LEN * size_of::<IoTraceRecord>());
+    let mem_map_offset =
+        exit_trace_offset + align_up(EXIT_TRACE_RING_LEN * size_of::<ExitTraceRecord>());
+    let size = mem_map_offset + align_up(MEM_MAP_LEN * size_of::<MemMapEntry>());
+    let mut frame = Frame::new_contiguous(size / PAGE_SIZE, 0)?;
+    frame.zero();
+
+    let header = unsafe { &mut *(frame.as_mut_ptr() as *mut StatsHeader) };
+    header.magic = STATS_WINDOW_MAGIC;
+    header.version = STATS_WINDOW_VERSION;
+    header.num_cpus = num_cpus as u32;
+    header.cpu_stats_offset = cpu_stats_offset() as u32;
+    header.cpu_stats_size = size_of::<CpuStats>() as u32;
+    header.log_offset = log_offset as u32;
+    header.log_size = LOG_RING_SIZE as u32;
+    header.trace_offset = trace_offset as u32;
+    header.trace_size = TRACE_RING_LEN as u32;
+    header.exit_trace_offset = exit_trace_offset as u32;
+    header.exit_trace_size = EXIT_TRACE_RING_LEN as u32;
+    header.tsc_mhz = crate::arch::cpu::frequency() as u32;
+    header.exit_reason_vendor = if cfg!(feature = "amd") { 2 } else { 1 };
+    header.mem_map_offset = mem_map_offset as u32;
+    header.mem_map_size = MEM_MAP_LEN as u32;
+    header
+        .stats_enabled
+        .store(crate::stats::enabled() as u32, Ordering::Relaxed);
+
+    info!("Stats window allocated: {:#x?}", frame);
+    STATS_WINDOW.call_once(|| StatsWindow {
+        frame,
+        num_cpus,
+        log_offset,
+        trace_offset,
+        exit_trace_offset,
+        mem_map_offset,
+    });
+    publish_mem_map();
+    Ok(())
+}
+
+/// Returns the host physical address and size of the window, if any.
+pub fn window_region() -> Option<(PhysAddr, usize)> {
+    STATS_WINDOW
+        .get()
+        .map(|w| (w.frame.start_paddr(), w.frame.size()))
+}
+
+/// Publishes whether statistics are being collected.
+pub fn set_stats_enabled(enabled: bool) {
+    if let Some(window) = STATS_WINDOW.get() {
+        window
+            .header()
+            .stats_enabled
+            .store(enabled as u32, Ordering::Relaxed);
+    }
+}
+
+/// Updates the statistics of `cpu_id` with the sequence counter held, if
+/// statistics are enabled.
+pub fn update_cpu_stats(cpu_id: u32, f: impl FnOnce(&CpuStats)) {
+    if !crate::stats::enabled() {
+        return;
+    }
+    if let Some(stats) = STATS_WINDOW
+        .get()
+        .and_then(|w| w.cpu_stats(cpu_id as usize))
+    {
+        stats.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        f(stats);
+        stats.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Records the cycles spent in an init phase of `cpu_id`.
+pub fn record_init_phase(cpu_id: u32, phase: InitPhase, cycles: u64) {
+    if let Some(stats) = STATS_WINDOW
+        .get()
+        .and_then(|w| w.cpu_stats(cpu_id as usize))
+    {
+        stats.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        stats.init_cycles[phase as usize].store(cycles, Ordering::Relaxed);
+        stats.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Updates the error counter of `subsystem`. The counts of concurrent errors
+/// may arrive out of order, so the counter only moves forward.
+pub fn update_error_count(subsystem: HvErrorSubsystem, count: u64) {
+    if let Some(window) = STATS_WINDOW.get() {
+        window.header().error_counts[subsystem as usize].fetch_max(count, Ordering::Relaxed);
+    }
+}
+
+/// Publishes the device counters reported by the RTOS: the interrupts
+/// handled by each RT CPU, as `(apic_id, count)`, and the DMA volume.
+pub fn set_rt_reported_stats(irqs: impl Iterator<Item = (u32, u64)>, dma_bytes: u64) {
+    if let Some(window) = STATS_WINDOW.get() {
+        let header = window.header();
+        let slots = header
+            .rt_reported_apic_ids
+            .iter()
+            .zip(&header.rt_reported_irqs);
+        for ((apic_id, count), (apic_id_slot, count_slot)) in irqs.zip(slots) {
+            apic_id_slot.store(apic_id, Ordering::Relaxed);
+            count_slot.store(count, Ordering::Relaxed);
+        }
+        header
+            .rt_reported_dma_bytes
+            .store(dma_bytes, Ordering::Relaxed);
+    }
+}
+
+/// Interrupt of the root cell once half of the log ring is unread, see
+/// `log_notify_setup()`.
+struct LogNotify {
+    /// Hardware ID of the registering CPU.
+    apic_id: u32,
+    vector: u8,
+    /// Whether `vector` was allocated by `log_notify_setup()`.
+    owns_vector: bool,
+}
+
+static LOG_NOTIFY: SpinLock<Option<LogNotify>> = SpinLock::new(None);
+/// `log_written` up to which the root cell has read the log ring.
+static LOG_DRAINED: AtomicU64 = AtomicU64::new(0);
+/// Set once the root cell is notified, until it reports draining the ring.
+static LOG_NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+/// Notifies the current CPU with `vector` once half of the log ring is
+/// unread. A `vector` of 0 is allocated from the vector pool of the root
+/// cell. Returns the vector.
+pub fn log_notify_setup(vector: u8) -> HvResult<u8> {
+    let window = match STATS_WINDOW.get() {
+        Some(window) => window,
+        None => return hv_result_err!(ENODEV, "Stats window is disabled"),
+    };
+    let cell = crate::cell::root_cell();
+    if vector != 0 && vector < 32 {
+        return hv_result_err!(EINVAL, "Log vector must not be an exception");
+    }
+    if cell.vectors.in_pool(vector) && !cell.vectors.is_allocated(vector) {
+        return hv_result_err!(EINVAL, "Log vector is in the pool but not allocated");
+    }
+    let owns_vector = vector == 0;
+    let vector = if owns_vector {
+        cell.vectors.alloc(1)?
+    } else {
+        vector
+    };
+
+    let mut notify = LOG_NOTIFY.lock();
+    if let Some(old) = notify.take() {
+        if old.owns_vector {
+            cell.vectors.free(old.vector, 1)?;
+        }
+    }
+    let written = window.header().log_written.load(Ordering::Acquire);
+    LOG_DRAINED.store(written, Ordering::Release);
+    LOG_NOTIFIED.store(false, Ordering::Release);
+    *notify = Some(LogNotify {
+        apic_id: crate::arch::local_irq_chip().id(),
+        vector,
+        owns_vector,
+    });
+    info!("Log notification set up: vector={:#x}", vector);
+    Ok(vector)
+}
+
+/// Stops the log notification, like the event channel, and frees its vector
+/// if allocated by `log_notify_setup()`.
+pub fn log_notify_shutdown() {
+    if let Some(old) = LOG_NOTIFY.lock().take() {
+        if old.owns_vector {
+            crate::cell::root_cell().vectors.free(old.vector, 1).ok();
+        }
+    }
+}
+
+/// Records that the root cell has read the log ring up to `position`, a
+/// value of `log_written`, and re-arms the notification.
+pub fn log_drained(position: u64) -> HvResult {
+    let window = match STATS_WINDOW.get() {
+        Some(window) => window,
+        None => return hv_result_err!(ENODEV, "Stats window is disabled"),
+    };
+    if position > window.header().log_written.load(Ordering::Acquire) {
+        return hv_result_err!(EINVAL, "Log position {:#x} not written yet", position);
+    }
+    LOG_DRAINED.store(position, Ordering::Release);
+    LOG_NOTIFIED.store(false, Ordering::Release);
+    Ok(())
+}
+
+/// Raises the log notification if half of the log ring is unread. Called at
+/// the end of each VM exit rather than by `log_write()`, which may run in
+/// NMI context or with the interrupt locks held.
+pub fn poll_log_notify() {
+    if LOG_NOTIFIED.load(Ordering::Acquire) {
+        return;
+    }
+    let window = match STATS_WINDOW.get() {
+        Some(window) => window,
+        None => return,
+    };
+    let written = window.header().log_written.load(Ordering::Acquire);
+    let unread = written.saturating_sub(LOG_DRAINED.load(Ordering::Acquire));
+    if unread < (LOG_RING_SIZE / 2) as u64 {
+        return;
+    }
+    if let Some(notify) = LOG_NOTIFY.try_lock() {
+        if let Some(notify) = notify.as_ref() {
+            if !LOG_NOTIFIED.swap(true, Ordering::AcqRel) {
+                crate::irq_storm::raise(notify.apic_id, notify.vector);
+            }
+        }
+    }
+}
+
+struct LogRingWriter<'a>(&'a StatsWindow);
+
+impl Write for LogRingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let header = self.0.header();
+        let ring = self.0.log_ring();
+        let mut written = header.log_written.load(Ordering::Relaxed) as usize;
+        for &b in s.as_bytes() {
+            unsafe { ring.add(written % LOG_RING_SIZE).write_volatile(b) };
+            written += 1;
+        }
+        header.log_written.store(written as u64, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Appends a log record to the log ring.
+pub fn log_write(args: fmt::Arguments) {
+    if let Some(window) = STATS_WINDOW.get() {
+        // An NMI interrupting the writer of a record drops its own.
+        if let Some(_guard) = LOG_LOCK.lock_unless_owned() {
+            LogRingWriter(window).write_fmt(args).ok();
+        }
+    }
+}
+
+/// Records an intercepted MMIO/PIO access into the I/O trace ring, if the
+/// `io-record` feature is enabled.
+pub fn trace_io(kind: IoTraceKind, addr: u64, size: u8, value: u64, rip: u64) {
+    let window = match STATS_WINDOW.get() {
+        Some(w) if TRACE_RING_LEN > 0 => w,
+        _ => return,
+    };
+    let n = window
+        .header()
+        .trace_written
+        .fetch_add(1, Ordering::Relaxed);
+    let record = unsafe { &mut *window.trace_ring().add(n as usize % TRACE_RING_LEN) };
+    record.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    record.tsc = crate::arch::cpu::current_cycle();
+    record.rip = rip;
+    record.addr = addr;
+    record.value = value;
+    record.cpu_id = crate::percpu::PerCpu::current().id;
+    record.kind = kind as u8;
+    record.size = size;
+    record.seq.store(n + 1, Ordering::Release);
+}
+
+/// Records a VM exit of `cpu_id` into the exit trace ring, if statistics are
+/// enabled. `hypercall` is the hypercall code if the exit was a hypercall.
+pub fn trace_exit(cpu_id: u32, tsc: u64, cycles: u64, reason: u32, hypercall: Option<u32>) {
+    let window = match STATS_WINDOW.get() {
+        Some(w) if crate::stats::enabled() => w,
+        _ => return,
+    };
+    let n = window
+        .header()
+        .exit_trace_written
+        .fetch_add(1, Ordering::Relaxed);
+    let record = unsafe {
+        &mut *window
+            .exit_trace_ring()
+            .add(n as usize % EXIT_TRACE_RING_LEN)
+    };
+    record.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    record.tsc = tsc;
+    record.cycles = cycles;
+    record.cpu_id = cpu_id;
+    record.reason = reason;
+    record.hypercall = hypercall.map_or(0, |code| code + 1);
+    record.seq.store(n + 1, Ordering::Release);
+}
+
+/// Returns the number of VM exits in the exit trace ring which started in
+/// `[start, end)`, and the TSC cycles spent in them. Exits overwritten in the
+/// ring are missed.
+pub fn exits_between(start: u64, end: u64) -> (u64, u64) {
+    let window = match STATS_WINDOW.get() {
+        Some(w) => w,
+        None => return (0, 0),
+    };
+    let (mut count, mut cycles) = (0, 0);
+    for i in 0..EXIT_TRACE_RING_LEN {
+        let record = unsafe { &*window.exit_trace_ring().add(i) };
+        let seq = record.seq.load(Ordering::Acquire);
+        let (tsc, record_cycles) = unsafe {
+            (
+                core::ptr::read_volatile(&record.tsc),
+                core::ptr::read_volatile(&record.cycles),
+            )
+        };
+        fence(Ordering::Acquire);
+        if seq == 0 || record.seq.load(Ordering::Relaxed) != seq {
+            continue; // being written
+        }
+        if (start..end).contains(&tsc) {
+            count += 1;
+            cycles += record_cycles;
+        }
+    }
+    (count, cycles)
+}
+
+/// Start of the hypervisor memory released to the root cell, `usize::MAX` if
+/// none.
+static HV_RELEASED_START: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Returns the GPA and the `MEM_MAP_*` access of the root cell to physical
+/// `[start, start + size)`, if a single memory region maps it.
+fn root_cell_view(start: u64, size: u64) -> (u64, u32) {
+    let regions = HvSystemConfig::get().root_cell.config().mem_regions();
+    let region = regions.iter().find(|r| {
+        let (phys_start, region_size) = (r.phys_start, r.size);
+        !r.flags.contains(MemFlags::IO)
+            && phys_start <= start
+            && start + size <= phys_start + region_size
+    });
+    match region {
+        Some(r) => {
+            let mut access = 0;
+            if r.flags.contains(MemFlags::READ) {
+                access |= MEM_MAP_READ;
+            }
+            if r.flags.contains(MemFlags::WRITE) {
+                access |= MEM_MAP_WRITE;
+            }
+            (r.virt_start + (start - r.phys_start), access)
+        }
+        None => (u64::MAX, 0),
+    }
+}
+
+/// Rewrites the memory map.
+fn publish_mem_map() {
+    let window = match STATS_WINDOW.get() {
+        Some(w) => w,
+        None => return,
+    };
+    let sys_config = HvSystemConfig::get();
+    let hv_start = sys_config.hypervisor_memory.phys_start;
+    let hv_end = hv_start + sys_config.hypervisor_memory.size;
+    let released_start = (HV_RELEASED_START.load(Ordering::Acquire) as u64).min(hv_end);
+    let (window_paddr, window_size) = window_region().unwrap();
+    let rt_start = sys_config.rtos_memory.phys_start;
+    let rt_size = sys_config.rtos_memory.size;
+
+    let entry = |start: u64, size: u64, owner: MemMapOwner, view: (u64, u32)| MemMapEntry {
+        start,
+        size,
+        root_gpa: view.0,
+        owner: owner as u32,
+        access: view.1,
+    };
+    let hidden = (u64::MAX, 0);
+    let shared = |gpa: u64| (gpa, MEM_MAP_READ);
+    let mut entries = [
+        Some(entry(
+            hv_start,
+            hv_end - hv_start,
+            MemMapOwner::Hypervisor,
+            hidden,
+        )),
+        Some(entry(
+            hv_start,
+            PAGE_SIZE as u64,
+            MemMapOwner::HypervisorShared,
+            shared(hv_start),
+        )),
+        Some(entry(
+            window_paddr as u64,
+            window_size as u64,
+            MemMapOwner::HypervisorShared,
+            shared(sys_config.stats_window_gpa),
+        )),
+        None,
+        None,
+        None,
+    ];
+    if released_start < hv_end {
+        let size = hv_end - released_start;
+        let view = (released_start, MEM_MAP_READ | MEM_MAP_WRITE);
+        entries[3] = Some(entry(
+            released_start,
+            size,
+            MemMapOwner::HypervisorReleased,
+            view,
+        ));
+    }
+    if rt_size >= PAGE_SIZE as u64 {
+        let boot_info = rt_start + rt_size - PAGE_SIZE as u64;
+        let size = rt_size - PAGE_SIZE as u64;
+        entries[4] = Some(entry(
+            rt_start,
+            size,
+            MemMapOwner::Rtos,
+            root_cell_view(rt_start, size),
+        ));
+        let view = root_cell_view(boot_info, PAGE_SIZE as u64);
+        entries[5] = Some(entry(
+            boot_info,
+            PAGE_SIZE as u64,
+            MemMapOwner::RtosBootInfo,
+            view,
+        ));
+    }
+
+    let header = window.header();
+    header.mem_map_count.store(0, Ordering::Release);
+    let mut count = 0;
+    for e in entries.into_iter().flatten().take(MEM_MAP_LEN) {
+        unsafe { window.mem_map().add(count).write_volatile(e) };
+        count += 1;
+    }
+    header.mem_map_count.store(count as u32, Ordering::Release);
+}
+
+/// Records that hypervisor memory `range` was released to the root cell.
+pub fn mem_released(range: Range<PhysAddr>) {
+    HV_RELEASED_START.fetch_min(range.start, Ordering::AcqRel);
+    publish_mem_map();
+}
@@ -0,0 +1,124 @@
+//! Interrupt vectors handed out by the hypervisor.
+//!
+//! The vector pool of each cell, configured by `HvCellDesc::vector_pool_start`
+//! and `vector_pool_size`, is managed here, so that the users of hypervisor
+//! notifications (the event channel, future virtual devices and passthrough
+//! MSIs) never pick the same one. The pool must be made of vectors the cell
+//! never uses otherwise, e.g. vectors its kernel reserves. Vectors outside of
+//! it are still accepted as is, at the caller's risk; without a pool, callers
+//! must pass their own.
+
+use crate::error::HvResult;
+use crate::lock::SpinLock;
+
+/// Allocation state of the vector pool of a cell.
+#[derive(Debug)]
+pub struct VectorAllocator {
+    /// First vector of the pool.
+    start: u8,
+    /// Number of vectors in the pool, at most `MAX_VECTOR_POOL_SIZE`.
+    size: usize,
+    /// Bit `n` is set if vector `start + n` is allocated.
+    used: SpinLock<u32>,
+}
+
+impl VectorAllocator {
+    /// The allocator of the `size` vectors from `start`, as returned by
+    /// `CellConfig::vector_pool()`.
+    pub const fn new((start, size): (u8, u8)) -> Self {
+        Self {
+            start,
+            size: size as usize,
+            used: SpinLock::new(0),
+        }
+    }
+
+    pub fn in_pool(&self, vector: u8) -> bool {
+        (vector as usize).wrapping_sub(self.start as usize) < self.size
+    }
+
+    fn mask(&self, first: u8, count: usize) -> HvResult<u32> {
+        let start = (first as usize).wrapping_sub(self.start as usize);
+        if start >= self.size || count > self.size - start {
+            return hv_result_err!(EINVAL, "Vectors are not in the pool");
+        }
+        Ok((((1u64 << count) - 1) << start) as u32)
+    }
+
+    /// Allocates `count` consecutive vectors, aligned to the next power of
+    /// two of `count` as multi-message MSIs require. Returns the first one.
+    pub fn alloc(&self, count: usize) -> HvResult<u8> {
+        if self.size == 0 {
+            return hv_result_err!(ENODEV, "No vector pool configured");
+        }
+        if count == 0 || count > self.size {
+            return hv_result_err!(EINVAL, "Invalid vector count");
+        }
+        let align = count.next_power_of_two();
+        let mut used = self.used.lock();
+        for start in (0..=self.size - count).step_by(align) {
+            let first = self.start + start as u8;
+            let bits = self.mask(first, count)?;
+            if *used & bits == 0 {
+                *used |= bits;
+                info!(
+                    "Vectors [{:#x}, {:#x}) allocated",
+                    first,
+                    first as usize + count
+                );
+                return Ok(first);
+            }
+        }
+        hv_result_err!(EBUSY, "Vector pool exhausted")
+    }
+
+    /// Frees `count` vectors from `first`, which must all be allocated.
+    pub fn free(&self, first: u8, count: usize) -> HvResult {
+        if count == 0 {
+            return hv_result_err!(EINVAL, "Invalid vector count");
+        }
+        let bits = self.mask(first, count)?;
+        let mut used = self.used.lock();
+        if *used & bits != bits {
+            return hv_result_err!(EINVAL, "Vectors are not allocated");
+        }
+        *used &= !bits;
+        Ok(())
+    }
+
+    pub fn is_allocated(&self, vector: u8) -> bool {
+        self.mask(vector, 1)
+            .map_or(false, |bit| *self.used.lock() & bit != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_vector_alloc() {
+        const START: u8 = 0xe0;
+        let vectors = VectorAllocator::new((START, 16));
+        assert_eq!(vectors.alloc(1).ok(), Some(START));
+        // Multi-message blocks are aligned.
+        assert_eq!(vectors.alloc(4).ok(), Some(START + 4));
+        assert_eq!(vectors.alloc(3).ok(), Some(START + 8));
+        assert!(vectors.alloc(8).is_err());
+        assert!(vectors.free(START + 1, 1).is_err());
+        assert!(vectors.free(START + 4, 4).is_ok());
+        assert!(!vectors.is_allocated(START + 4));
+        assert_eq!(vectors.alloc(2).ok(), Some(START + 2));
+        assert!(!vectors.in_pool(START - 1));
+        assert!(vectors.in_pool(START + 15));
+        assert!(!vectors.in_pool(START + 16));
+        assert!(vectors.free(START + 14, 4).is_err());
+
+        let vectors = VectorAllocator::new((0xf0, 16));
+        assert!(vectors.in_pool(0xff));
+        assert_eq!(vectors.alloc(16).ok(), Some(0xf0));
+
+        let vectors = VectorAllocator::new((0, 0));
+        assert!(!vectors.in_pool(0));
+        assert!(vectors.alloc(1).is_err());
+    }
+}